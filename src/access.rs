@@ -0,0 +1,180 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+use mcprotocol::chat::Chat;
+use uuid::Uuid;
+
+use crate::cfg;
+use crate::player::ClientInfo;
+use crate::ProxyInfo;
+
+/// Lets players join while maintenance mode is on.
+pub const MAINTENANCE_BYPASS_PERMISSION: &str = "umbrella.maintenance.bypass";
+
+/// Whether the entry, a uuid or a name, refers to the client.
+fn names_client(entry: &str, client_info: &ClientInfo) -> bool {
+    match Uuid::parse_str(entry) {
+        Ok(uuid) => client_info.profile.id == uuid,
+        Err(_) => client_info.profile.name.eq_ignore_ascii_case(entry),
+    }
+}
+
+/// Starts out as configured and is only changed at runtime afterwards, reloading the config
+/// doesn't touch it.
+pub struct Maintenance {
+    enabled: AtomicBool,
+}
+
+impl Maintenance {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled: AtomicBool::new(enabled),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn may_join(&self, proxy_info: &ProxyInfo, client_info: &ClientInfo) -> bool {
+        !self.is_enabled()
+            || proxy_info
+                .config()
+                .maintenance
+                .bypass
+                .iter()
+                .any(|entry| names_client(entry, client_info))
+            || proxy_info.permissions.has_permission(
+                &client_info.profile.id,
+                MAINTENANCE_BYPASS_PERMISSION,
+                None,
+            )
+    }
+
+    /// Switches maintenance mode, kicking everyone who may not stay when it's turned on.
+    /// Returns how many players were kicked.
+    pub async fn set(&self, proxy_info: &ProxyInfo, enabled: bool) -> usize {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        log::info!(
+            "Maintenance mode {}.",
+            if enabled { "enabled" } else { "disabled" }
+        );
+        if !enabled {
+            return 0;
+        }
+        let message = proxy_info.config().maintenance.message.clone();
+        let mut kicked = 0;
+        for player in proxy_info.players.players().await {
+            if !self.may_join(proxy_info, &player.client_info) {
+                player.handle.kick(Chat::literal(message.clone()));
+                kicked += 1;
+            }
+        }
+        kicked
+    }
+}
+
+#[derive(serde_derive::Deserialize, serde_derive::Serialize, Debug, Default)]
+struct WhitelistFile {
+    #[serde(default)]
+    uuids: Vec<Uuid>,
+    /// For players whose uuid isn't known, matched case insensitively.
+    #[serde(default)]
+    names: Vec<String>,
+}
+
+/// Players allowed to join while the whitelist is enabled, by uuid or name.
+pub struct Whitelist {
+    path: PathBuf,
+    data: RwLock<WhitelistFile>,
+}
+
+impl Whitelist {
+    /// Loads the file, creating an empty one if it doesn't exist yet.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let whitelist = Self {
+            path: path.to_path_buf(),
+            data: RwLock::new(WhitelistFile::default()),
+        };
+        if path.exists() {
+            whitelist.reload()?;
+        } else {
+            whitelist.save(&whitelist.data.read().unwrap())?;
+        }
+        Ok(whitelist)
+    }
+
+    fn save(&self, data: &WhitelistFile) -> anyhow::Result<()> {
+        cfg::write_atomically(&self.path, &serde_json::to_string_pretty(data)?)?;
+        Ok(())
+    }
+
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let data: WhitelistFile = serde_json::from_reader(fs::File::open(&self.path)?)?;
+        *self.data.write().unwrap() = data;
+        Ok(())
+    }
+
+    pub fn contains(&self, client_info: &ClientInfo) -> bool {
+        let data = self.data.read().unwrap();
+        data.uuids.contains(&client_info.profile.id)
+            || data
+                .names
+                .iter()
+                .any(|name| client_info.profile.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Adds a uuid or name, returning false if it was already listed.
+    pub fn add(&self, entry: &str) -> anyhow::Result<bool> {
+        let mut data = self.data.write().unwrap();
+        let added = match Uuid::parse_str(entry) {
+            Ok(uuid) if !data.uuids.contains(&uuid) => {
+                data.uuids.push(uuid);
+                true
+            }
+            Ok(_) => false,
+            Err(_)
+                if !data
+                    .names
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(entry)) =>
+            {
+                data.names.push(entry.to_string());
+                true
+            }
+            Err(_) => false,
+        };
+        if added {
+            self.save(&data)?;
+        }
+        Ok(added)
+    }
+
+    /// Removes a uuid or name, returning false if it wasn't listed.
+    pub fn remove(&self, entry: &str) -> anyhow::Result<bool> {
+        let mut data = self.data.write().unwrap();
+        let len = data.uuids.len() + data.names.len();
+        match Uuid::parse_str(entry) {
+            Ok(uuid) => data.uuids.retain(|existing| *existing != uuid),
+            Err(_) => data
+                .names
+                .retain(|existing| !existing.eq_ignore_ascii_case(entry)),
+        }
+        let removed = data.uuids.len() + data.names.len() != len;
+        if removed {
+            self.save(&data)?;
+        }
+        Ok(removed)
+    }
+
+    pub fn entries(&self) -> Vec<String> {
+        let data = self.data.read().unwrap();
+        data.uuids
+            .iter()
+            .map(Uuid::to_string)
+            .chain(data.names.iter().cloned())
+            .collect()
+    }
+}
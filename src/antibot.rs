@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex as StdMutex;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use uuid::Uuid;
+
+use crate::cfg::{AntiBotCheck, AntiBotConfig};
+use crate::player::ClientInfo;
+
+/// Letters and digits that can't be mistaken for each other.
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+/// The map only draws digits.
+const MAP_ALPHABET: &[u8] = b"0123456789";
+const CODE_LEN: usize = 5;
+
+/// The check a player has to pass in the limbo before going on to their server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Check {
+    Falling,
+    /// Typing the code sent in chat.
+    Chat(String),
+    /// Typing the code drawn on the map in the player's hand.
+    Map(String),
+}
+
+#[derive(Default)]
+struct AntiBotState {
+    login_window: Option<Instant>,
+    logins: u32,
+    active_until: Option<Instant>,
+    verified: HashMap<(Uuid, IpAddr), Instant>,
+}
+
+/// Checks players before they reach a backend, but only while logins come in faster than the
+/// configured threshold. Players who passed are remembered by uuid and address.
+#[derive(Default)]
+pub struct AntiBot {
+    state: StdMutex<AntiBotState>,
+}
+
+fn client_key(client_info: &ClientInfo) -> (Uuid, IpAddr) {
+    (client_info.profile.id, client_info.remote_addr.ip())
+}
+
+fn generate_code(alphabet: &[u8]) -> String {
+    let mut rng = rand::thread_rng();
+    (0..CODE_LEN)
+        .map(|_| alphabet[rng.gen_range(0..alphabet.len())] as char)
+        .collect()
+}
+
+impl AntiBot {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts the login and returns whether the client behind `key` has to be checked.
+    fn needs_check(&self, config: &AntiBotConfig, key: (Uuid, IpAddr), now: Instant) -> bool {
+        if !config.enabled {
+            return false;
+        }
+        let mut state = self.state.lock().unwrap();
+        match state.login_window {
+            Some(started) if now.duration_since(started) < Duration::from_secs(1) => {}
+            _ => {
+                state.login_window = Some(now);
+                state.logins = 0;
+            }
+        }
+        state.logins += 1;
+        if state.logins > config.logins_per_second {
+            if state.active_until.map_or(true, |until| until <= now) {
+                log::warn!("Login rate above threshold, enabling bot verification.");
+            }
+            state.active_until = Some(now + Duration::from_secs(config.active_seconds));
+        }
+        if state.active_until.map_or(true, |until| until <= now) {
+            return false;
+        }
+        state.verified.retain(|_, expires| *expires > now);
+        !state.verified.contains_key(&key)
+    }
+
+    /// Counts the login and returns the check the player has to pass, if any.
+    pub fn start_check(&self, config: &AntiBotConfig, client_info: &ClientInfo) -> Option<Check> {
+        if !self.needs_check(config, client_key(client_info), Instant::now()) {
+            return None;
+        }
+        Some(match config.check {
+            AntiBotCheck::Falling => Check::Falling,
+            AntiBotCheck::Chat => Check::Chat(generate_code(CODE_ALPHABET)),
+            AntiBotCheck::Map => Check::Map(generate_code(MAP_ALPHABET)),
+        })
+    }
+
+    fn verify(&self, config: &AntiBotConfig, key: (Uuid, IpAddr), now: Instant) {
+        let expires = now + Duration::from_secs(config.verified_seconds);
+        self.state.lock().unwrap().verified.insert(key, expires);
+    }
+
+    /// Lets the player's uuid and address through without a check for `verified_seconds`.
+    pub fn pass(&self, config: &AntiBotConfig, client_info: &ClientInfo) {
+        self.verify(config, client_key(client_info), Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(logins_per_second: u32) -> AntiBotConfig {
+        AntiBotConfig {
+            enabled: true,
+            logins_per_second,
+            ..AntiBotConfig::default()
+        }
+    }
+
+    fn key(last_octet: u8) -> (Uuid, IpAddr) {
+        (Uuid::nil(), IpAddr::from([10, 0, 0, last_octet]))
+    }
+
+    #[test]
+    fn checks_only_above_the_login_rate() {
+        let antibot = AntiBot::new();
+        let config = config(2);
+        let now = Instant::now();
+        assert!(!antibot.needs_check(&config, key(1), now));
+        assert!(!antibot.needs_check(&config, key(2), now));
+        assert!(antibot.needs_check(&config, key(3), now));
+        // stays on after the rate drops
+        let later = now + Duration::from_secs(5);
+        assert!(antibot.needs_check(&config, key(4), later));
+        let after = later + Duration::from_secs(config.active_seconds + 1);
+        assert!(!antibot.needs_check(&config, key(5), after));
+    }
+
+    #[test]
+    fn remembers_verified_players() {
+        let antibot = AntiBot::new();
+        let config = config(0);
+        let now = Instant::now();
+        assert!(antibot.needs_check(&config, key(1), now));
+        antibot.verify(&config, key(1), now);
+        assert!(!antibot.needs_check(&config, key(1), now));
+        // the same uuid from another address is checked again
+        assert!(antibot.needs_check(&config, key(2), now));
+        let expired = now + Duration::from_secs(config.verified_seconds + 1);
+        assert!(antibot.needs_check(&config, key(1), expired));
+    }
+
+    #[test]
+    fn never_checks_when_disabled() {
+        let antibot = AntiBot::new();
+        let config = AntiBotConfig {
+            logins_per_second: 0,
+            ..AntiBotConfig::default()
+        };
+        assert!(!antibot.needs_check(&config, key(1), Instant::now()));
+    }
+
+    #[test]
+    fn map_codes_are_digits() {
+        let code = generate_code(MAP_ALPHABET);
+        assert_eq!(code.len(), CODE_LEN);
+        assert!(code.bytes().all(|byte| byte.is_ascii_digit()));
+    }
+}
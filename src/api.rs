@@ -0,0 +1,478 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use mcprotocol::chat::Chat;
+use serde_json::{json, Value};
+
+use crate::backend::ForwardToServerType;
+use crate::moderation::{parse_duration, Punishment, PunishmentKind, PunishmentTarget};
+use crate::registry::RegisteredPlayer;
+use crate::ProxyInfo;
+
+type ApiState = State<Arc<ProxyInfo>>;
+
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(json!({ "error": self.message }))).into_response()
+    }
+}
+
+type ApiResult = Result<Json<Value>, ApiError>;
+
+#[derive(serde_derive::Deserialize)]
+struct KickRequest {
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+#[derive(serde_derive::Deserialize)]
+struct MessageRequest {
+    message: String,
+}
+
+#[derive(serde_derive::Deserialize)]
+struct TransferRequest {
+    server: String,
+}
+
+#[derive(serde_derive::Deserialize)]
+struct MaintenanceRequest {
+    enabled: bool,
+}
+
+#[derive(serde_derive::Deserialize)]
+struct BanRequest {
+    target: String,
+    #[serde(default)]
+    reason: Option<String>,
+    /// Like `1d12h`, permanent if left out.
+    #[serde(default)]
+    duration: Option<String>,
+    #[serde(default)]
+    issuer: Option<String>,
+}
+
+/// Serves the admin API until the listener fails. Tokens are read from the current config on
+/// every request so they follow reloads, the bind address does not.
+pub async fn serve(proxy_info: Arc<ProxyInfo>, bind: String) -> anyhow::Result<()> {
+    let addr: SocketAddr = bind.parse()?;
+    let router = Router::new()
+        .route("/players", get(list_players))
+        .route("/players/:player", get(get_player))
+        .route("/players/:player/kick", post(kick_player))
+        .route("/players/:player/message", post(message_player))
+        .route("/players/:player/transfer", post(transfer_player))
+        .route("/servers", get(list_servers))
+        .route("/bans", get(list_bans).post(ban))
+        .route("/bans/:target", delete(unban))
+        .route("/maintenance", get(get_maintenance).post(set_maintenance))
+        .route("/broadcast", post(broadcast))
+        .route("/reload", post(reload))
+        .route_layer(middleware::from_fn_with_state(
+            proxy_info.clone(),
+            authorize,
+        ))
+        .route("/openapi.json", get(openapi))
+        .with_state(proxy_info);
+
+    log::info!("Admin API listening on {}", addr);
+    axum::Server::bind(&addr)
+        .serve(router.into_make_service())
+        .await?;
+    Ok(())
+}
+
+/// Compares without exiting early so the time taken doesn't leak how much of a token matched.
+fn token_matches(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn authorize<B>(
+    State(proxy_info): ApiState,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ApiError> {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let authorized = match (token, proxy_info.config().admin_api.as_ref()) {
+        (Some(token), Some(config)) => config
+            .tokens
+            .iter()
+            .any(|expected| token_matches(expected, token)),
+        _ => false,
+    };
+    if !authorized {
+        return Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "Missing or invalid bearer token",
+        ));
+    }
+    Ok(next.run(request).await)
+}
+
+fn player_json(player: &RegisteredPlayer) -> Value {
+    json!({
+        "uuid": player.uuid(),
+        "name": player.name(),
+        "server": player.current_server,
+        "ping_ms": player.ping.map(|ping| ping.as_millis() as u64),
+        "protocol_version": player.client_info.protocol_version,
+        "address": player.client_info.remote_addr.ip().to_string(),
+        "connected_at": player.connected_at.to_rfc3339(),
+    })
+}
+
+async fn find_player(proxy_info: &ProxyInfo, target: &str) -> Result<RegisteredPlayer, ApiError> {
+    proxy_info
+        .players
+        .find(target)
+        .await
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, format!("{} is not online", target)))
+}
+
+/// Turns a failed send into an error, the player's connection closed in the meantime.
+fn delivered(sent: bool) -> ApiResult {
+    if sent {
+        Ok(Json(json!({ "ok": true })))
+    } else {
+        Err(ApiError::new(StatusCode::GONE, "The player disconnected"))
+    }
+}
+
+async fn list_players(State(proxy_info): ApiState) -> ApiResult {
+    let mut players = proxy_info.players.players().await;
+    players.sort_by(|a, b| a.name().cmp(b.name()));
+    Ok(Json(Value::Array(
+        players.iter().map(player_json).collect(),
+    )))
+}
+
+async fn get_player(State(proxy_info): ApiState, Path(player): Path<String>) -> ApiResult {
+    Ok(Json(player_json(&find_player(&proxy_info, &player).await?)))
+}
+
+async fn kick_player(
+    State(proxy_info): ApiState,
+    Path(player): Path<String>,
+    request: Option<Json<KickRequest>>,
+) -> ApiResult {
+    let player = find_player(&proxy_info, &player).await?;
+    let reason = request
+        .and_then(|Json(request)| request.reason)
+        .unwrap_or_else(|| "You have been kicked.".to_string());
+    delivered(player.handle.kick(Chat::literal(reason)))
+}
+
+async fn message_player(
+    State(proxy_info): ApiState,
+    Path(player): Path<String>,
+    Json(request): Json<MessageRequest>,
+) -> ApiResult {
+    let player = find_player(&proxy_info, &player).await?;
+    delivered(player.handle.send_message(Chat::literal(request.message)))
+}
+
+async fn transfer_player(
+    State(proxy_info): ApiState,
+    Path(player): Path<String>,
+    Json(request): Json<TransferRequest>,
+) -> ApiResult {
+    let player = find_player(&proxy_info, &player).await?;
+    if !proxy_info.config().servers.contains_key(&request.server) {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("The server {} does not exist", request.server),
+        ));
+    }
+    delivered(
+        player
+            .handle
+            .transfer(ForwardToServerType::ById(request.server)),
+    )
+}
+
+async fn list_servers(State(proxy_info): ApiState) -> ApiResult {
+    let counts = proxy_info.players.server_counts().await;
+    let config = proxy_info.config();
+    let mut server_ids: Vec<&String> = config.servers.keys().collect();
+    server_ids.sort();
+    let servers = server_ids
+        .into_iter()
+        .map(|server_id| {
+            let server_info = &config.servers[server_id];
+            let health = proxy_info.health.get(server_id);
+            json!({
+                "id": server_id,
+                "name": server_info.server_name,
+                "address": server_info.address(),
+                "players": counts.get(server_id).copied().unwrap_or(0),
+                "restricted": server_info.restricted,
+                "reachable": health.map(|health| health.reachable),
+                "checked_at": health.map(|health| health.checked_at.to_rfc3339()),
+            })
+        })
+        .collect();
+    Ok(Json(Value::Array(servers)))
+}
+
+async fn broadcast(State(proxy_info): ApiState, Json(request): Json<MessageRequest>) -> ApiResult {
+    let players = proxy_info.players.players().await;
+    for player in &players {
+        player
+            .handle
+            .send_message(Chat::literal(request.message.clone()));
+    }
+    Ok(Json(json!({ "recipients": players.len() })))
+}
+
+async fn reload(State(proxy_info): ApiState) -> ApiResult {
+    blocking(move || proxy_info.reload()).await?;
+    Ok(Json(json!({ "ok": true })))
+}
+
+fn internal_error(err: anyhow::Error) -> ApiError {
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err))
+}
+
+/// Runs work which reads files or waits on the database on the blocking pool.
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+) -> Result<T, ApiError> {
+    match tokio::task::spawn_blocking(work).await {
+        Ok(result) => result.map_err(internal_error),
+        Err(err) => Err(internal_error(err.into())),
+    }
+}
+
+fn to_json(value: impl serde::Serialize) -> ApiResult {
+    serde_json::to_value(value)
+        .map(Json)
+        .map_err(|err| internal_error(err.into()))
+}
+
+async fn list_bans(State(proxy_info): ApiState) -> ApiResult {
+    let bans = blocking(move || proxy_info.moderation.store().active(PunishmentKind::Ban)).await?;
+    to_json(bans)
+}
+
+async fn ban(State(proxy_info): ApiState, Json(request): Json<BanRequest>) -> ApiResult {
+    let duration = match request.duration.as_deref() {
+        Some(duration) => Some(parse_duration(duration).ok_or_else(|| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                format!("{} is not a valid duration", duration),
+            )
+        })?),
+        None => None,
+    };
+    let punishment = Punishment::new(
+        PunishmentKind::Ban,
+        PunishmentTarget::resolve(&proxy_info, &request.target).await,
+        request
+            .reason
+            .unwrap_or_else(|| "No reason given.".to_string()),
+        request.issuer.unwrap_or_else(|| "API".to_string()),
+        duration,
+    );
+    let kicked = proxy_info
+        .moderation
+        .punish(&proxy_info, punishment)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(json!({ "kicked": kicked })))
+}
+
+async fn unban(State(proxy_info): ApiState, Path(target): Path<String>) -> ApiResult {
+    let removed = proxy_info
+        .moderation
+        .store()
+        .remove(PunishmentKind::Ban, &PunishmentTarget::parse(&target))
+        .map_err(internal_error)?;
+    if !removed {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("{} is not banned", target),
+        ));
+    }
+    Ok(Json(json!({ "ok": true })))
+}
+
+async fn get_maintenance(State(proxy_info): ApiState) -> ApiResult {
+    Ok(Json(
+        json!({ "enabled": proxy_info.maintenance.is_enabled() }),
+    ))
+}
+
+async fn set_maintenance(
+    State(proxy_info): ApiState,
+    Json(request): Json<MaintenanceRequest>,
+) -> ApiResult {
+    let kicked = proxy_info
+        .maintenance
+        .set(&proxy_info, request.enabled)
+        .await;
+    Ok(Json(
+        json!({ "enabled": request.enabled, "kicked": kicked }),
+    ))
+}
+
+async fn openapi() -> Json<Value> {
+    Json(openapi_document())
+}
+
+fn operation(summary: &str, body: Option<Value>) -> Value {
+    let mut operation = json!({
+        "summary": summary,
+        "security": [{ "bearer": [] }],
+        "responses": {
+            "200": { "description": "Success" },
+            "401": { "description": "Missing or invalid bearer token" },
+            "404": { "description": "Unknown player or server" },
+        },
+    });
+    if let Some(schema) = body {
+        operation["requestBody"] = json!({
+            "required": true,
+            "content": { "application/json": { "schema": schema } },
+        });
+    }
+    operation
+}
+
+fn player_parameter() -> Value {
+    json!([{
+        "name": "player",
+        "in": "path",
+        "required": true,
+        "description": "Player name or uuid",
+        "schema": { "type": "string" },
+    }])
+}
+
+fn string_object(field: &str, required: bool) -> Value {
+    json!({
+        "type": "object",
+        "required": if required { vec![field] } else { vec![] },
+        "properties": { field: { "type": "string" } },
+    })
+}
+
+pub fn openapi_document() -> Value {
+    json!({
+        "openapi": "3.0.3",
+        "info": { "title": "Umbrella admin API", "version": env!("CARGO_PKG_VERSION") },
+        "components": {
+            "securitySchemes": { "bearer": { "type": "http", "scheme": "bearer" } },
+        },
+        "paths": {
+            "/players": { "get": operation("List online players", None) },
+            "/players/{player}": {
+                "parameters": player_parameter(),
+                "get": operation("Look up an online player", None),
+            },
+            "/players/{player}/kick": {
+                "parameters": player_parameter(),
+                "post": operation("Kick a player", Some(string_object("reason", false))),
+            },
+            "/players/{player}/message": {
+                "parameters": player_parameter(),
+                "post": operation("Send a player a message", Some(string_object("message", true))),
+            },
+            "/players/{player}/transfer": {
+                "parameters": player_parameter(),
+                "post": operation("Move a player to another server", Some(string_object("server", true))),
+            },
+            "/servers": { "get": operation("List servers with player counts and health", None) },
+            "/broadcast": {
+                "post": operation("Send every player a message", Some(string_object("message", true))),
+            },
+            "/bans": {
+                "get": operation("List active bans", None),
+                "post": operation("Ban a player name, uuid or IP range", Some(json!({
+                    "type": "object",
+                    "required": ["target"],
+                    "properties": {
+                        "target": { "type": "string" },
+                        "reason": { "type": "string" },
+                        "duration": { "type": "string", "description": "Like 1d12h, permanent if left out" },
+                        "issuer": { "type": "string" },
+                    },
+                }))),
+            },
+            "/bans/{target}": {
+                "parameters": [{
+                    "name": "target",
+                    "in": "path",
+                    "required": true,
+                    "description": "Banned name, uuid or IP range, with the slash URL encoded",
+                    "schema": { "type": "string" },
+                }],
+                "delete": operation("Lift a ban", None),
+            },
+            "/maintenance": {
+                "get": operation("Whether maintenance mode is on", None),
+                "post": operation("Turn maintenance mode on or off", Some(json!({
+                    "type": "object",
+                    "required": ["enabled"],
+                    "properties": { "enabled": { "type": "boolean" } },
+                }))),
+            },
+            "/reload": { "post": operation("Reload the configuration", None) },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reports_failed_blocking_work_as_internal_errors() {
+        assert_eq!(blocking(|| Ok(5)).await.ok(), Some(5));
+        let err = blocking(|| -> anyhow::Result<()> { anyhow::bail!("config.json is broken") })
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(err.message, "config.json is broken");
+    }
+
+    #[test]
+    fn fails_on_values_without_a_json_form() {
+        assert_eq!(to_json(vec![1, 2]).ok().unwrap().0, json!([1, 2]));
+        // JSON object keys have to be strings
+        let map: HashMap<(i32, i32), i32> = HashMap::from([((1, 2), 3)]);
+        let err = to_json(map).err().unwrap();
+        assert_eq!(err.status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -1,31 +1,42 @@
 use drax::transport::encryption::EncryptedWriter;
 use drax::transport::frame::PacketFrame;
-use drax::transport::DraxTransport;
-use drax::VarInt;
-use mcprotocol::chat;
 use mcprotocol::chat::Chat;
+use mcprotocol::pin_fut;
 use mcprotocol::pipeline::{AsyncMinecraftProtocolPipeline, MinecraftProtocolWriter};
-use mcprotocol::prelude::Uuid;
-use mcprotocol::protocol::login::MojangIdentifiedKey;
-use mcprotocol::protocol::GameProfile;
+use mcprotocol::protocol::play::cb::{Commands, Disconnect, KeepAlive, PluginMessage};
 use mcprotocol::registry::MappedAsyncPacketRegistry;
-use std::net::SocketAddr;
+use std::fmt::Display;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::net::tcp::OwnedReadHalf;
-use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 
 use crate::cfg::ServerInfo;
+use crate::command::brigadier;
+use crate::event::{Cancellable, PluginMessageDirection, PluginMessageEvent};
+use crate::net::{ReadHalf, WriteHalf};
+use crate::player::{ClientInfo, PlayerSession};
 use crate::ProxyInfo;
-use crate::player::ClientInfo;
 
 mod auth;
+mod bungee_channel;
+
+pub use bungee_channel::is_bungee_channel;
 mod transition;
 
+pub type ClientWriter = Arc<Mutex<MinecraftProtocolWriter<EncryptedWriter<WriteHalf>>>>;
+pub type ServerWriter = Arc<Mutex<MinecraftProtocolWriter<WriteHalf>>>;
+
+pub type BackendEvent = Result<EndpointResolution, drax::transport::Error>;
+
 pub struct BackendContext {
-    client_info: ClientInfo,
-    client_write: MinecraftProtocolWriter<EncryptedWriter<OwnedWriteHalf>>,
-    server_write: MinecraftProtocolWriter<OwnedWriteHalf>,
+    server_id: String,
+    session: PlayerSession,
+    server_write: ServerWriter,
+    /// Whether the client is already in a world, from another server or the anti-bot limbo.
+    /// See `transition`.
+    switching: bool,
 }
 
 pub enum ForwardToServerType {
@@ -37,12 +48,14 @@ pub enum EndpointResolution {
     DoNothing,
     DisconnectGracefully,
     ForwardToServer(ForwardToServerType),
+    /// The backend disconnected the player with a reason.
+    Kicked(Chat),
 }
 
 pub struct BackendEndpoint {
     backend_context: BackendContext,
     server_read: AsyncMinecraftProtocolPipeline<
-        OwnedReadHalf,
+        ReadHalf,
         BackendContext,
         EndpointResolution,
         MappedAsyncPacketRegistry<BackendContext, EndpointResolution>,
@@ -50,53 +63,105 @@ pub struct BackendEndpoint {
 }
 
 pub struct BackendEndpointWithNoContext {
+    server_id: String,
     server_read: AsyncMinecraftProtocolPipeline<
-        OwnedReadHalf,
+        ReadHalf,
         BackendContext,
         EndpointResolution,
         MappedAsyncPacketRegistry<BackendContext, EndpointResolution>,
     >,
-    server_write: MinecraftProtocolWriter<OwnedWriteHalf>,
+    server_write: MinecraftProtocolWriter<WriteHalf>,
+}
+
+impl BackendEndpointWithNoContext {
+    pub fn server_id(&self) -> &str {
+        &self.server_id
+    }
+
+    /// Sends packets the client sent before it had a server, such as in the limbo.
+    pub async fn replay(&mut self, packets: Vec<Vec<u8>>) -> Result<(), drax::transport::Error> {
+        for data in packets {
+            self.server_write
+                .write_buffered_packet(PacketFrame { data })
+                .await?;
+        }
+        Ok(())
+    }
 }
 
 impl BackendEndpoint {
     pub async fn create_partial_connection(
         proxy_info: Arc<ProxyInfo>,
+        server_id: &str,
         server_info: &ServerInfo,
         client_info: &ClientInfo,
     ) -> anyhow::Result<BackendEndpointWithNoContext> {
         let auth::ConnectedServerBase { read, write, .. } =
-            auth::connect_server_client(proxy_info, server_info, client_info).await?;
+            auth::connect_server_client(proxy_info, server_id, server_info, client_info).await?;
         Ok(BackendEndpointWithNoContext {
+            server_id: server_id.to_string(),
             server_read: read.clear_registry(),
             server_write: write,
         })
     }
 
-    pub fn merge(
-        old_server: BackendEndpoint,
+    /// Binds a freshly connected server to the player's session. The returned
+    /// `ServerWriter` is shared with the endpoint so the client side can relay to it.
+    /// `switching` is set when the client is already in a world.
+    pub fn attach(
+        session: PlayerSession,
         new_server: BackendEndpointWithNoContext,
-    ) -> BackendEndpoint {
-        let BackendEndpoint {
-            backend_context, ..
-        } = old_server;
-        let BackendContext {
-            client_info,
-            client_write,
-            ..
-        } = backend_context;
+        switching: bool,
+    ) -> (BackendEndpoint, ServerWriter) {
         let BackendEndpointWithNoContext {
-            server_read,
+            server_id,
+            mut server_read,
             server_write,
         } = new_server;
-        BackendEndpoint {
-            backend_context: BackendContext {
-                client_info,
-                client_write,
-                server_write,
+        server_read.register(pin_fut!(handle_keep_alive));
+        server_read.register(pin_fut!(handle_commands));
+        server_read.register(pin_fut!(handle_disconnect));
+        server_read.register(pin_fut!(handle_plugin_message));
+        server_read.register(pin_fut!(transition::handle_join_game));
+        let server_write = Arc::new(Mutex::new(server_write));
+        (
+            BackendEndpoint {
+                backend_context: BackendContext {
+                    server_id,
+                    session,
+                    server_write: server_write.clone(),
+                    switching,
+                },
+                server_read,
             },
-            server_read,
-        }
+            server_write,
+        )
+    }
+
+    /// Drives the server read side on its own task, reporting every resolution other than
+    /// `DoNothing` back to the owning player. The task ends on the first error or disconnect.
+    pub fn spawn(
+        mut self,
+        timeout: Duration,
+        events: UnboundedSender<BackendEvent>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let event = self.read_next_server_packet_with_timeout(timeout).await;
+                let finished = matches!(
+                    event,
+                    Err(_)
+                        | Ok(EndpointResolution::DisconnectGracefully)
+                        | Ok(EndpointResolution::Kicked(_))
+                );
+                if matches!(event, Ok(EndpointResolution::DoNothing)) {
+                    continue;
+                }
+                if events.send(event).is_err() || finished {
+                    return;
+                }
+            }
+        })
     }
 
     pub async fn read_next_server_packet_with_timeout(
@@ -112,7 +177,10 @@ impl BackendEndpoint {
             Err(err) => match err {
                 mcprotocol::registry::RegistryError::NoHandlerFound(_, data) => {
                     self.backend_context
+                        .session
                         .client_write
+                        .lock()
+                        .await
                         .write_buffered_packet(PacketFrame { data })
                         .await?;
                     Ok(EndpointResolution::DoNothing)
@@ -122,3 +190,99 @@ impl BackendEndpoint {
         }
     }
 }
+
+/// Resolution for a packet the proxy handled and passed on to the client itself.
+fn forwarded<E: Display>(ctx: &BackendContext, result: Result<(), E>) -> EndpointResolution {
+    match result {
+        Ok(_) => EndpointResolution::DoNothing,
+        Err(err) => {
+            log::debug!(
+                "Failed to forward packet to {}: {}",
+                ctx.session.client_info.profile.name,
+                err
+            );
+            EndpointResolution::DisconnectGracefully
+        }
+    }
+}
+
+async fn handle_keep_alive(ctx: &mut BackendContext, packet: KeepAlive) -> EndpointResolution {
+    ctx.session.keep_alives.sent(packet.id);
+    let result = ctx
+        .session
+        .client_write
+        .lock()
+        .await
+        .write_packet(&packet)
+        .await;
+    forwarded(ctx, result)
+}
+
+async fn handle_commands(ctx: &mut BackendContext, mut packet: Commands) -> EndpointResolution {
+    let source = ctx.session.command_source().await;
+    let labels = ctx.session.proxy_info.commands.visible_labels(&source);
+    brigadier::inject_proxy_commands(&mut packet, &labels);
+    let result = ctx
+        .session
+        .client_write
+        .lock()
+        .await
+        .write_packet(&packet)
+        .await;
+    forwarded(ctx, result)
+}
+
+async fn handle_disconnect(_: &mut BackendContext, packet: Disconnect) -> EndpointResolution {
+    EndpointResolution::Kicked(packet.reason)
+}
+
+async fn handle_plugin_message(
+    ctx: &mut BackendContext,
+    packet: PluginMessage,
+) -> EndpointResolution {
+    if bungee_channel::is_bungee_channel(&packet.channel) {
+        bungee_channel::handle(ctx, &packet.channel, &packet.data).await;
+        return EndpointResolution::DoNothing;
+    }
+    if let Err(denied) = ctx.session.channels.check(
+        &ctx.session.proxy_info.config().plugin_channels.to_client,
+        PluginMessageDirection::ToClient,
+        &packet.channel,
+        packet.data.len(),
+    ) {
+        log::debug!(
+            "Dropped message on {} from {}: {:?}",
+            packet.channel,
+            ctx.server_id,
+            denied
+        );
+        return EndpointResolution::DoNothing;
+    }
+    let event = ctx
+        .session
+        .proxy_info
+        .events
+        .fire(PluginMessageEvent {
+            client_info: ctx.session.client_info.clone(),
+            session_id: ctx.session.session_id,
+            direction: PluginMessageDirection::ToClient,
+            channel: packet.channel,
+            data: packet.data,
+            cancelled: false,
+        })
+        .await;
+    if event.is_cancelled() {
+        return EndpointResolution::DoNothing;
+    }
+    let result = ctx
+        .session
+        .client_write
+        .lock()
+        .await
+        .write_packet(&PluginMessage {
+            channel: event.channel,
+            data: event.data,
+        })
+        .await;
+    forwarded(ctx, result)
+}
@@ -1,11 +1,13 @@
-use crate::player::ClientInfo;
 use crate::cfg::{ForwardingMethod, ServerInfo};
+use crate::net::{self, ReadHalf, Stream, WriteHalf};
+use crate::player::ClientInfo;
+use crate::proxy_protocol;
 use crate::ProxyInfo;
 use mcprotocol::pipeline::{BlankAsyncProtocolPipeline, MinecraftProtocolWriter};
 use mcprotocol::registry::RegistryError;
 use std::sync::Arc;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::TcpStream;
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
 
 #[derive(Debug, Clone)]
 pub struct ServerStubInfo {
@@ -21,91 +23,124 @@ impl From<&ServerInfo> for ServerStubInfo {
             server_id: value.server_id.as_ref().cloned(),
             server_name: value.server_name.clone(),
             server_ip: value.server_ip.clone(),
-            server_port: value.server_port,
+            server_port: value.port(),
         }
     }
 }
 
 pub struct ConnectedServerBase {
     pub info: ServerStubInfo,
-    pub read: BlankAsyncProtocolPipeline<OwnedReadHalf>,
-    pub write: MinecraftProtocolWriter<OwnedWriteHalf>,
+    pub read: BlankAsyncProtocolPipeline<ReadHalf>,
+    pub write: MinecraftProtocolWriter<WriteHalf>,
 }
 
 pub async fn connect_server_client(
     proxy_info: Arc<ProxyInfo>,
+    server_id: &str,
     server: &ServerInfo,
     client: &ClientInfo,
 ) -> Result<ConnectedServerBase, RegistryError> {
-    let connection = TcpStream::connect(format!("{}:{}", server.server_ip, server.server_port))
+    let started = Instant::now();
+    let result = connect(&proxy_info, server, client).await;
+    proxy_info
+        .metrics
+        .backend_connect_latency
+        .with_label_values(&[server_id, if result.is_ok() { "ok" } else { "error" }])
+        .observe(started.elapsed().as_secs_f64());
+    let (read, write) = result?;
+    Ok(ConnectedServerBase {
+        info: ServerStubInfo::from(server),
+        read,
+        write,
+    })
+}
+
+async fn connect(
+    proxy_info: &ProxyInfo,
+    server: &ServerInfo,
+    client: &ClientInfo,
+) -> Result<
+    (
+        BlankAsyncProtocolPipeline<ReadHalf>,
+        MinecraftProtocolWriter<WriteHalf>,
+    ),
+    RegistryError,
+> {
+    let config = proxy_info.config();
+    let mut connection = Stream::connect(&proxy_info.dns, &config.dns, server)
         .await
-        .map_err(drax::transport::Error::TokioError)?;
+        .map_err(|err| drax::transport::Error::Unknown(Some(format!("{:#}", err))))?;
+    if let Some(version) = server.proxy_protocol {
+        let destination = connection
+            .peer_addr()
+            .map_err(drax::transport::Error::TokioError)?
+            .unwrap_or(net::UNKNOWN_ADDR);
+        let header = proxy_protocol::encode_header(version, client.remote_addr, destination);
+        connection
+            .write_all(&header)
+            .await
+            .map_err(drax::transport::Error::TokioError)?;
+    }
 
-    match server
+    let (address, velocity_key) = match server
         .forwarding
         .as_ref()
-        .unwrap_or_else(|| &proxy_info.config.auth.default_forwarding)
+        .unwrap_or_else(|| &config.auth.default_forwarding)
     {
-        ForwardingMethod::BungeeLegacy => {
-            unimplemented!()
-        }
+        ForwardingMethod::BungeeLegacy => (bungee::handshake_address(server, client), None),
         ForwardingMethod::VelocityModern { secret_key } => {
-            let (read, write) =
-                velocity::velocity_client_connect(server, connection, client, secret_key).await?;
-            return Ok(ConnectedServerBase {
-                info: ServerStubInfo::from(server),
-                read,
-                write,
-            });
+            (server.server_ip.clone(), Some(velocity::key(secret_key)))
         }
-    }
-}
-
-mod bungee {
-    use crate::player::ClientInfo;
-    use tokio::net::TcpStream;
-
-    pub async fn bungee_client_connect(stream: TcpStream, client_info: &ClientInfo) {}
+    };
+    login::login(server, address, connection, client, velocity_key).await
 }
 
-mod velocity {
-    use crate::player::ClientInfo;
+mod login {
     use crate::cfg::ServerInfo;
-    use drax::transport::{DraxTransport, TransportProcessorContext};
-    use hmac::Hmac;
+    use crate::legacy;
+    use crate::net::{ReadHalf, Stream, WriteHalf};
+    use crate::player::ClientInfo;
+    use mcprotocol::chat::Chat;
     use mcprotocol::pin_fut;
     use mcprotocol::pipeline::{
         buffer_packet, AsyncMinecraftProtocolPipeline, BlankAsyncProtocolPipeline,
         MinecraftProtocolWriter,
     };
     use mcprotocol::protocol::handshaking::sb::{Handshake, NextState};
-    use mcprotocol::protocol::login::cb::LoginPluginRequest;
+    use mcprotocol::protocol::login::cb::{
+        Disconnect, LoginPluginRequest, LoginSuccess, SetCompression,
+    };
     use mcprotocol::protocol::login::sb::{LoginPluginResponse, LoginStart};
-    use mcprotocol::protocol::GameProfile;
     use mcprotocol::registry::{RegistryError, UNKNOWN_VERSION};
-    use sha2::digest::Mac;
-    use sha2::Sha256;
-    use std::cmp::{max, min};
-    use std::io::Cursor;
-    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-    use tokio::net::TcpStream;
 
-    type Hmac256 = Hmac<Sha256>;
+    use super::velocity::{self, Hmac256};
 
-    struct VelocityContext {
-        hmac: Hmac256,
-        sig_holder: Option<uuid::Uuid>,
+    struct LoginContext<'a> {
+        client_info: &'a ClientInfo,
+        /// Set when forwarding through Velocity's player info query.
+        velocity_key: Option<Hmac256>,
     }
 
-    pub async fn velocity_client_connect(
+    /// What the backend's last login packet asks for.
+    enum LoginStep {
+        Reply(LoginPluginResponse),
+        Compression(i32),
+        Success,
+        Disconnected(Chat),
+    }
+
+    /// Logs the client in on the backend, handing `server_address` over in the handshake.
+    /// Returns once the backend sent LoginSuccess, with compression set up as it asked.
+    pub async fn login(
         server_info: &ServerInfo,
-        stream: TcpStream,
+        server_address: String,
+        stream: Stream,
         client_info: &ClientInfo,
-        key: &String,
+        velocity_key: Option<Hmac256>,
     ) -> Result<
         (
-            BlankAsyncProtocolPipeline<OwnedReadHalf>,
-            MinecraftProtocolWriter<OwnedWriteHalf>,
+            BlankAsyncProtocolPipeline<ReadHalf>,
+            MinecraftProtocolWriter<WriteHalf>,
         ),
         RegistryError,
     > {
@@ -113,8 +148,8 @@ mod velocity {
 
         let handshake = Handshake {
             protocol_version: client_info.protocol_version,
-            server_address: server_info.server_ip.clone(),
-            server_port: server_info.server_port,
+            server_address,
+            server_port: server_info.port(),
             next_state: NextState::Login,
         };
         let buffered_handshake = buffer_packet(&handshake, UNKNOWN_VERSION)?;
@@ -135,16 +170,119 @@ mod velocity {
         write.write_buffered_packet(buffered_handshake).await?;
         write.write_packet(&login_start).await?;
         read.register(pin_fut!(handle_plugin_request));
+        read.register(pin_fut!(handle_set_compression));
+        read.register(pin_fut!(handle_login_success));
+        read.register(pin_fut!(handle_disconnect));
 
-        let hmac = Hmac256::new_from_slice(key.as_bytes()).expect("Hmac can be any length");
-
-        let resp = read.execute_next_packet(&mut (hmac, client_info)).await??;
-        write.write_packet(&resp).await?;
+        let mut ctx = LoginContext {
+            client_info,
+            velocity_key,
+        };
+        // nothing may be relayed before LoginSuccess, the client is already in play
+        loop {
+            match read.execute_next_packet(&mut ctx).await?? {
+                LoginStep::Reply(response) => write.write_packet(&response).await?,
+                LoginStep::Compression(threshold) => {
+                    read.enable_decompression(threshold as isize);
+                    write.enable_compression(threshold as isize);
+                }
+                LoginStep::Success => break,
+                LoginStep::Disconnected(reason) => {
+                    return Err(RegistryError::DraxTransportError(
+                        drax::transport::Error::Unknown(Some(format!(
+                            "Disconnected during login: {}",
+                            legacy::to_legacy_text(&reason)
+                        ))),
+                    ))
+                }
+            }
+        }
         Ok((read.clear_registry(), write))
     }
 
     async fn handle_plugin_request(
-        ctx: &mut (Hmac256, &ClientInfo),
+        ctx: &mut LoginContext<'_>,
+        request: LoginPluginRequest,
+    ) -> Result<LoginStep, RegistryError> {
+        if let Some(key) = &ctx.velocity_key {
+            if request.channel == velocity::PLAYER_INFO_CHANNEL {
+                return velocity::player_info(key, ctx.client_info, request).map(LoginStep::Reply);
+            }
+        }
+        // like the vanilla client, queries nobody here understands are answered as such
+        Ok(LoginStep::Reply(LoginPluginResponse {
+            message_id: request.message_id,
+            successful: false,
+            data: vec![],
+        }))
+    }
+
+    async fn handle_set_compression(
+        _: &mut LoginContext<'_>,
+        packet: SetCompression,
+    ) -> Result<LoginStep, RegistryError> {
+        Ok(LoginStep::Compression(packet.threshold))
+    }
+
+    async fn handle_login_success(
+        _: &mut LoginContext<'_>,
+        _: LoginSuccess,
+    ) -> Result<LoginStep, RegistryError> {
+        Ok(LoginStep::Success)
+    }
+
+    async fn handle_disconnect(
+        _: &mut LoginContext<'_>,
+        packet: Disconnect,
+    ) -> Result<LoginStep, RegistryError> {
+        Ok(LoginStep::Disconnected(packet.reason))
+    }
+}
+
+mod bungee {
+    use crate::cfg::ServerInfo;
+    use crate::player::ClientInfo;
+
+    /// BungeeCord's IP forwarding packs the client's address, UUID and profile properties
+    /// into the handshake's server address, separated by null characters.
+    pub fn handshake_address(server_info: &ServerInfo, client_info: &ClientInfo) -> String {
+        let properties = serde_json::to_string(&client_info.profile.properties)
+            .unwrap_or_else(|_| "[]".to_string());
+        format!(
+            "{}\0{}\0{}\0{}",
+            server_info.server_ip,
+            client_info.remote_addr.ip(),
+            client_info.profile.id.simple(),
+            properties
+        )
+    }
+}
+
+mod velocity {
+    use crate::player::ClientInfo;
+    use drax::transport::{DraxTransport, TransportProcessorContext};
+    use hmac::Hmac;
+    use mcprotocol::protocol::login::cb::LoginPluginRequest;
+    use mcprotocol::protocol::login::sb::LoginPluginResponse;
+    use mcprotocol::protocol::GameProfile;
+    use mcprotocol::registry::RegistryError;
+    use sha2::digest::Mac;
+    use sha2::Sha256;
+    use std::cmp::{max, min};
+    use std::io::Cursor;
+
+    pub type Hmac256 = Hmac<Sha256>;
+
+    pub const PLAYER_INFO_CHANNEL: &str = "velocity:player_info";
+
+    pub fn key(secret_key: &str) -> Hmac256 {
+        Hmac256::new_from_slice(secret_key.as_bytes()).expect("Hmac can be any length")
+    }
+
+    /// The signed answer to the backend's player info query.
+    pub fn player_info(
+        key: &Hmac256,
+        client_info: &ClientInfo,
         request: LoginPluginRequest,
     ) -> Result<LoginPluginResponse, RegistryError> {
         let mask = if request.data.is_empty() {
@@ -152,7 +290,10 @@ mod velocity {
         } else {
             max(1, min(3, request.data[0]))
         };
-        let mask = match (ctx.1.sig_holder.as_ref(), ctx.1.mojang_key.as_ref()) {
+        let mask = match (
+            client_info.sig_holder.as_ref(),
+            client_info.mojang_key.as_ref(),
+        ) {
             (Some(_), Some(_)) => min(mask, 3),
             (Some(_), None) => min(mask, 2),
             _ => 1,
@@ -160,24 +301,30 @@ mod velocity {
         let mut data = Cursor::new(Vec::new());
         let mut tpx = TransportProcessorContext::new();
         drax::extension::write_var_int_sync(mask as i32, &mut tpx, &mut data)?;
-        drax::extension::write_string(32767, &ctx.1.remote_addr.to_string(), &mut tpx, &mut data)?;
-        GameProfile::write_to_transport(&ctx.1.profile, &mut tpx, &mut data)?;
+        drax::extension::write_string(
+            32767,
+            &client_info.remote_addr.to_string(),
+            &mut tpx,
+            &mut data,
+        )?;
+        GameProfile::write_to_transport(&client_info.profile, &mut tpx, &mut data)?;
         if mask > 1 {
-            ctx.1
+            client_info
                 .mojang_key
                 .as_ref()
                 .unwrap()
                 .write_to_transport(&mut tpx, &mut data)?;
         }
         if mask > 2 {
-            ctx.1
+            client_info
                 .sig_holder
                 .unwrap()
                 .write_to_transport(&mut tpx, &mut data)?;
         }
         let data = data.into_inner();
-        ctx.0.update(&data);
-        let sig: Vec<u8> = ctx.0.clone().finalize().into_bytes().to_vec();
+        let mut hmac = key.clone();
+        hmac.update(&data);
+        let sig: Vec<u8> = hmac.finalize().into_bytes().to_vec();
 
         Ok(LoginPluginResponse {
             message_id: request.message_id,
@@ -186,3 +333,31 @@ mod velocity {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::cfg::ProxyProtocolVersion;
+    use crate::player::test_client_info;
+
+    #[test]
+    fn packs_the_client_into_the_bungee_handshake_address() {
+        let server: ServerInfo = serde_json::from_value(json!({
+            "server_name": "Lobby",
+            "server_ip": "10.0.0.2",
+            "forwarding": { "auth_method": "bungee" },
+            "proxy_protocol": "v2",
+        }))
+        .unwrap();
+        assert_eq!(server.proxy_protocol, Some(ProxyProtocolVersion::V2));
+        let id = Uuid::from_u128(0xABC);
+        let client = test_client_info("Steve", id, "1.2.3.4:5555".parse().unwrap());
+        assert_eq!(
+            bungee::handshake_address(&server, &client),
+            format!("10.0.0.2\x001.2.3.4\x00{}\x00[]", id.simple())
+        );
+    }
+}
@@ -0,0 +1,421 @@
+use mcprotocol::chat::Chat;
+use mcprotocol::protocol::play::sb::PluginMessage;
+
+use crate::backend::{BackendContext, ForwardToServerType};
+use crate::registry::RegisteredPlayer;
+
+const LEGACY_CHANNEL: &str = "BungeeCord";
+const CHANNEL: &str = "bungeecord:main";
+
+pub fn is_bungee_channel(channel: &str) -> bool {
+    channel == LEGACY_CHANNEL || channel == CHANNEL
+}
+
+/// Reads the `DataOutputStream` encoding used by Bukkit plugins for this channel.
+struct MessageReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> MessageReader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    fn read_short(&mut self) -> Option<u16> {
+        self.read_bytes(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_utf(&mut self) -> Option<String> {
+        let len = self.read_short()? as usize;
+        decode_modified_utf8(self.read_bytes(len)?)
+    }
+}
+
+/// Java's modified UTF-8, which `DataOutputStream` strings use: NUL takes two bytes and
+/// characters outside the BMP are written as a surrogate pair of three bytes each.
+fn encode_modified_utf8(value: &str, max_len: usize) -> Vec<u8> {
+    let mut out = vec![];
+    for c in value.chars() {
+        let mut encoded = vec![];
+        let mut units = [0; 2];
+        for &unit in c.encode_utf16(&mut units).iter() {
+            match unit {
+                0x01..=0x7F => encoded.push(unit as u8),
+                0x00 | 0x80..=0x7FF => {
+                    encoded.push(0xC0 | (unit >> 6) as u8);
+                    encoded.push(0x80 | (unit & 0x3F) as u8);
+                }
+                _ => {
+                    encoded.push(0xE0 | (unit >> 12) as u8);
+                    encoded.push(0x80 | ((unit >> 6) & 0x3F) as u8);
+                    encoded.push(0x80 | (unit & 0x3F) as u8);
+                }
+            }
+        }
+        // whole characters only, Java would throw rather than cut one in half
+        if out.len() + encoded.len() > max_len {
+            break;
+        }
+        out.extend_from_slice(&encoded);
+    }
+    out
+}
+
+/// `None` on malformed bytes and on surrogates without their other half.
+fn decode_modified_utf8(mut bytes: &[u8]) -> Option<String> {
+    let continuation = |byte: u8| (byte & 0xC0 == 0x80).then(|| (byte & 0x3F) as u16);
+    let mut units = vec![];
+    while let Some(&first) = bytes.first() {
+        let (unit, len) = match first {
+            0x00..=0x7F => (first as u16, 1),
+            0xC0..=0xDF => {
+                let second = continuation(*bytes.get(1)?)?;
+                (((first & 0x1F) as u16) << 6 | second, 2)
+            }
+            0xE0..=0xEF => {
+                let second = continuation(*bytes.get(1)?)?;
+                let third = continuation(*bytes.get(2)?)?;
+                (((first & 0x0F) as u16) << 12 | second << 6 | third, 3)
+            }
+            _ => return None,
+        };
+        units.push(unit);
+        bytes = &bytes[len..];
+    }
+    String::from_utf16(&units).ok()
+}
+
+#[derive(Default)]
+struct MessageWriter {
+    data: Vec<u8>,
+}
+
+impl MessageWriter {
+    /// Strings too long for the length prefix are cut short.
+    fn write_utf(mut self, value: &str) -> Self {
+        let bytes = encode_modified_utf8(value, u16::MAX as usize);
+        self.data
+            .extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+        self.data.extend_from_slice(&bytes);
+        self
+    }
+
+    fn write_short(mut self, value: u16) -> Self {
+        self.data.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn write_int(mut self, value: i32) -> Self {
+        self.data.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn write_bytes(mut self, value: &[u8]) -> Self {
+        self.data.extend_from_slice(value);
+        self
+    }
+}
+
+/// Bungee leaves the dashes out of uuids on this channel.
+fn simple_uuid(player: &RegisteredPlayer) -> String {
+    player.uuid().simple().to_string()
+}
+
+async fn find_player(ctx: &BackendContext, name: &str) -> Option<RegisteredPlayer> {
+    ctx.session.proxy_info.players.by_name(name).await
+}
+
+async fn reply(ctx: &BackendContext, channel: &str, message: MessageWriter) {
+    let result = ctx
+        .server_write
+        .lock()
+        .await
+        .write_packet(&PluginMessage {
+            channel: channel.to_string(),
+            data: message.data,
+        })
+        .await;
+    if let Err(err) = result {
+        log::debug!(
+            "Failed to answer on {} to {}: {}",
+            channel,
+            ctx.server_id,
+            err
+        );
+    }
+}
+
+/// Delivers a `Forward` payload to a server through one of the players on it. Servers
+/// without players can't be reached and the message is dropped.
+async fn forward_to_server(ctx: &BackendContext, server_id: &str, channel: &str, data: &[u8]) {
+    let players = ctx.session.proxy_info.players.players_on(server_id).await;
+    if let Some(player) = players.first() {
+        player
+            .handle
+            .send_plugin_message(channel.to_string(), data.to_vec());
+    }
+}
+
+/// Carries out a message a backend sent on the BungeeCord channel. Unknown subchannels and
+/// malformed messages are ignored, as Bungee does.
+pub async fn handle(ctx: &BackendContext, channel: &str, data: &[u8]) {
+    let mut reader = MessageReader { data };
+    if handle_subchannel(ctx, channel, &mut reader).await.is_none() {
+        log::debug!(
+            "Ignoring malformed BungeeCord message from {}.",
+            ctx.server_id
+        );
+    }
+}
+
+async fn handle_subchannel(
+    ctx: &BackendContext,
+    channel: &str,
+    reader: &mut MessageReader<'_>,
+) -> Option<()> {
+    let proxy_info = &ctx.session.proxy_info;
+    let players = &proxy_info.players;
+    let subchannel = reader.read_utf()?;
+    match subchannel.as_str() {
+        "Connect" => {
+            let server_id = reader.read_utf()?;
+            if let Some(player) = players.by_session(ctx.session.session_id).await {
+                player.handle.transfer(ForwardToServerType::ById(server_id));
+            }
+        }
+        "ConnectOther" => {
+            let name = reader.read_utf()?;
+            let server_id = reader.read_utf()?;
+            if let Some(player) = find_player(ctx, &name).await {
+                player.handle.transfer(ForwardToServerType::ById(server_id));
+            }
+        }
+        "IP" => {
+            let address = ctx.session.client_info.remote_addr;
+            let message = MessageWriter::default()
+                .write_utf("IP")
+                .write_utf(&address.ip().to_string())
+                .write_int(address.port() as i32);
+            reply(ctx, channel, message).await;
+        }
+        "PlayerCount" => {
+            let server_id = reader.read_utf()?;
+            let count = if server_id == "ALL" {
+                players.player_count().await
+            } else {
+                players.server_count(&server_id).await
+            };
+            let message = MessageWriter::default()
+                .write_utf("PlayerCount")
+                .write_utf(&server_id)
+                .write_int(count as i32);
+            reply(ctx, channel, message).await;
+        }
+        "PlayerList" => {
+            let server_id = reader.read_utf()?;
+            let listed = if server_id == "ALL" {
+                players.players().await
+            } else {
+                players.players_on(&server_id).await
+            };
+            let names: Vec<&str> = listed.iter().map(RegisteredPlayer::name).collect();
+            let message = MessageWriter::default()
+                .write_utf("PlayerList")
+                .write_utf(&server_id)
+                .write_utf(&names.join(", "));
+            reply(ctx, channel, message).await;
+        }
+        "GetServers" => {
+            let mut server_ids: Vec<String> = proxy_info.config().servers.keys().cloned().collect();
+            server_ids.sort();
+            let message = MessageWriter::default()
+                .write_utf("GetServers")
+                .write_utf(&server_ids.join(", "));
+            reply(ctx, channel, message).await;
+        }
+        "GetServer" => {
+            let message = MessageWriter::default()
+                .write_utf("GetServer")
+                .write_utf(&ctx.server_id);
+            reply(ctx, channel, message).await;
+        }
+        "Message" => {
+            let name = reader.read_utf()?;
+            let message = reader.read_utf()?;
+            if name == "ALL" {
+                for player in players.players().await {
+                    player.handle.send_message(Chat::literal(message.clone()));
+                }
+            } else if let Some(player) = find_player(ctx, &name).await {
+                player.handle.send_message(Chat::literal(message));
+            }
+        }
+        "Forward" => {
+            let target = reader.read_utf()?;
+            let forwarded_channel = reader.read_utf()?;
+            let len = reader.read_short()? as usize;
+            let payload = reader.read_bytes(len)?;
+            let forwarded = MessageWriter::default()
+                .write_utf(&forwarded_channel)
+                .write_short(len as u16)
+                .write_bytes(payload);
+            if target == "ALL" || target == "ONLINE" {
+                // servers without players are skipped either way, see `forward_to_server`
+                let mut server_ids: Vec<String> =
+                    proxy_info.config().servers.keys().cloned().collect();
+                server_ids.retain(|server_id| *server_id != ctx.server_id);
+                for server_id in server_ids {
+                    forward_to_server(ctx, &server_id, channel, &forwarded.data).await;
+                }
+            } else {
+                forward_to_server(ctx, &target, channel, &forwarded.data).await;
+            }
+        }
+        "ForwardToPlayer" => {
+            let name = reader.read_utf()?;
+            let forwarded_channel = reader.read_utf()?;
+            let len = reader.read_short()? as usize;
+            let payload = reader.read_bytes(len)?;
+            if let Some(player) = find_player(ctx, &name).await {
+                let forwarded = MessageWriter::default()
+                    .write_utf(&forwarded_channel)
+                    .write_short(len as u16)
+                    .write_bytes(payload);
+                player
+                    .handle
+                    .send_plugin_message(channel.to_string(), forwarded.data);
+            }
+        }
+        "UUID" => {
+            let message = MessageWriter::default()
+                .write_utf("UUID")
+                .write_utf(&ctx.session.client_info.profile.id.simple().to_string());
+            reply(ctx, channel, message).await;
+        }
+        "UUIDOther" => {
+            let name = reader.read_utf()?;
+            if let Some(player) = find_player(ctx, &name).await {
+                let message = MessageWriter::default()
+                    .write_utf("UUIDOther")
+                    .write_utf(player.name())
+                    .write_utf(&simple_uuid(&player));
+                reply(ctx, channel, message).await;
+            }
+        }
+        "ServerIP" => {
+            let server_id = reader.read_utf()?;
+            let server_info = proxy_info.config().servers.get(&server_id).cloned();
+            if let Some(server_info) = server_info {
+                let message = MessageWriter::default()
+                    .write_utf("ServerIP")
+                    .write_utf(&server_id)
+                    .write_utf(&server_info.server_ip)
+                    .write_short(server_info.port());
+                reply(ctx, channel, message).await;
+            }
+        }
+        "KickPlayer" => {
+            let name = reader.read_utf()?;
+            let reason = reader.read_utf()?;
+            if let Some(player) = find_player(ctx, &name).await {
+                player.handle.kick(Chat::literal(reason));
+            }
+        }
+        _ => {}
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_both_channel_names() {
+        assert!(is_bungee_channel("BungeeCord"));
+        assert!(is_bungee_channel("bungeecord:main"));
+        assert!(!is_bungee_channel("bungeecord"));
+    }
+
+    #[test]
+    fn reads_what_it_writes() {
+        let message = MessageWriter::default()
+            .write_utf("Forward")
+            .write_utf("ALL")
+            .write_short(3)
+            .write_bytes(b"abc");
+        let mut reader = MessageReader {
+            data: &message.data,
+        };
+        assert_eq!(reader.read_utf().as_deref(), Some("Forward"));
+        assert_eq!(reader.read_utf().as_deref(), Some("ALL"));
+        assert_eq!(reader.read_short(), Some(3));
+        assert_eq!(reader.read_bytes(3), Some(&b"abc"[..]));
+        assert_eq!(reader.read_short(), None);
+    }
+
+    #[test]
+    fn writes_java_data_output_encoding() {
+        let message = MessageWriter::default().write_utf("IP").write_int(25565);
+        assert_eq!(message.data, [0, 2, b'I', b'P', 0, 0, 0x63, 0xDD]);
+    }
+
+    #[test]
+    fn writes_java_modified_utf8() {
+        assert_eq!(encode_modified_utf8("a\0b", 16), [b'a', 0xC0, 0x80, b'b']);
+        assert_eq!(
+            encode_modified_utf8("é€", 16),
+            [0xC3, 0xA9, 0xE2, 0x82, 0xAC]
+        );
+        // U+1F600 is the surrogate pair D83D DE00
+        assert_eq!(
+            encode_modified_utf8("😀", 16),
+            [0xED, 0xA0, 0xBD, 0xED, 0xB8, 0x80]
+        );
+        for value in ["a\0b", "é€", "😀 x", ""] {
+            let encoded = encode_modified_utf8(value, 16);
+            assert_eq!(decode_modified_utf8(&encoded).as_deref(), Some(value));
+        }
+    }
+
+    #[test]
+    fn cuts_long_strings_between_characters() {
+        let value = "é".repeat(40_000);
+        let message = MessageWriter::default().write_utf(&value);
+        assert_eq!(&message.data[..2], &65534u16.to_be_bytes());
+        let mut reader = MessageReader {
+            data: &message.data,
+        };
+        assert_eq!(reader.read_utf(), Some("é".repeat(32_767)));
+        assert_eq!(encode_modified_utf8("a😀", 6), [b'a']);
+    }
+
+    #[test]
+    fn rejects_malformed_modified_utf8() {
+        assert_eq!(decode_modified_utf8(&[0xC3]), None);
+        assert_eq!(decode_modified_utf8(&[0xE2, 0x82, 0x41]), None);
+        assert_eq!(decode_modified_utf8(&[0xF0, 0x9F, 0x98, 0x80]), None);
+        // half a surrogate pair
+        assert_eq!(decode_modified_utf8(&[0xED, 0xA0, 0xBD]), None);
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        let mut reader = MessageReader {
+            data: &[0, 5, b'a', b'b'],
+        };
+        assert_eq!(reader.read_utf(), None);
+        let mut reader = MessageReader { data: &[0] };
+        assert_eq!(reader.read_short(), None);
+        let mut reader = MessageReader {
+            data: &[0, 1, 0xFF],
+        };
+        assert_eq!(reader.read_utf(), None);
+    }
+}
@@ -1 +1,55 @@
+use mcprotocol::protocol::play::cb::{JoinGame, Respawn};
 
+use super::{forwarded, BackendContext, EndpointResolution};
+
+const OVERWORLD: &str = "minecraft:overworld";
+const NETHER: &str = "minecraft:the_nether";
+
+/// A dimension other than the one the client joins, respawning there first makes it drop the
+/// old server's world even when both servers use the same dimension.
+fn other_dimension(dimension: &str) -> &'static str {
+    if dimension == OVERWORLD {
+        NETHER
+    } else {
+        OVERWORLD
+    }
+}
+
+fn respawn(packet: &JoinGame, dimension_type: &str, dimension_name: &str) -> Respawn {
+    Respawn {
+        dimension_type: dimension_type.to_string(),
+        dimension_name: dimension_name.to_string(),
+        hashed_seed: packet.hashed_seed,
+        game_mode: packet.game_mode,
+        previous_game_mode: packet.previous_game_mode,
+        is_debug: packet.is_debug,
+        is_flat: packet.is_flat,
+        copy_metadata: false,
+        death_location: packet.death_location.clone(),
+    }
+}
+
+/// Passes the join on to the client. The client is already in a world after a switch or the
+/// limbo, so the join only updates its registries and entity id there, and two respawns move
+/// it into the new world.
+pub async fn handle_join_game(ctx: &mut BackendContext, packet: JoinGame) -> EndpointResolution {
+    let mut client_write = ctx.session.client_write.lock().await;
+    let mut result = client_write.write_packet(&packet).await;
+    if ctx.switching && result.is_ok() {
+        let other = other_dimension(&packet.dimension_type);
+        result = client_write
+            .write_packet(&respawn(&packet, other, other))
+            .await;
+        if result.is_ok() {
+            result = client_write
+                .write_packet(&respawn(
+                    &packet,
+                    &packet.dimension_type,
+                    &packet.dimension_name,
+                ))
+                .await;
+        }
+    }
+    drop(client_write);
+    forwarded(ctx, result)
+}
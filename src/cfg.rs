@@ -1,8 +1,14 @@
 use log::LevelFilter;
 use mcprotocol::chat::Chat;
 use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use uuid::Uuid;
 
-#[derive(serde_derive::Deserialize, Debug)]
+use crate::cidr::Cidr;
+
+#[derive(serde_derive::Deserialize, Debug, Clone)]
 #[serde(tag = "auth_method", content = "auth_data")]
 pub enum ForwardingMethod {
     #[serde(rename = "bungee")]
@@ -25,15 +31,125 @@ pub enum IncomingAuthMethod {
     VelocityModern { secret_key: String },
 }
 
-#[derive(serde_derive::Deserialize, Debug)]
+#[derive(serde_derive::Deserialize, Debug, Clone)]
 pub struct ServerInfo {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub server_id: Option<String>,
     pub server_name: String,
+    /// An IP address or a hostname. Only sent in the handshake when `socket_path` is set.
+    #[serde(default = "local_ip")]
     pub server_ip: String,
-    pub server_port: u16,
+    /// Hostnames without a port are looked up through their `_minecraft._tcp` SRV record
+    /// first, like the vanilla client does, and use `DEFAULT_PORT` if there is none.
+    #[serde(default)]
+    pub server_port: Option<u16>,
+    /// Unix domain socket to connect to instead of `server_ip` and `server_port`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub socket_path: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub forwarding: Option<ForwardingMethod>,
+    /// Restricted servers require `permission`, or `umbrella.server.<id>` if unset.
+    #[serde(default)]
+    pub restricted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub permission: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub whitelist: Option<Vec<Uuid>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol_range: Option<ProtocolRange>,
+    /// Sends the client's address in a PROXY protocol header before the handshake, on top of
+    /// whatever forwarding is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+#[derive(serde_derive::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocolVersion {
+    #[serde(rename = "v1")]
+    V1,
+    #[serde(rename = "v2")]
+    V2,
+}
+
+#[derive(serde_derive::Deserialize, Debug, Clone, Copy)]
+pub struct ProtocolRange {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<i32>,
+}
+
+impl ProtocolRange {
+    pub fn contains(&self, protocol_version: i32) -> bool {
+        self.min.map_or(true, |min| protocol_version >= min)
+            && self.max.map_or(true, |max| protocol_version <= max)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessDenied {
+    Permission,
+    Whitelist,
+    Protocol,
+}
+
+fn local_ip() -> String {
+    "127.0.0.1".to_string()
+}
+
+pub const DEFAULT_PORT: u16 = 25565;
+
+impl ServerInfo {
+    /// `host:port`, `host` for servers left to SRV lookups, or `unix:<path>` for servers on a
+    /// Unix socket.
+    pub fn address(&self) -> String {
+        match (&self.socket_path, self.server_port) {
+            (Some(path), _) => format!("unix:{}", path),
+            (None, Some(port)) => format!("{}:{}", self.server_ip, port),
+            (None, None) => self.server_ip.clone(),
+        }
+    }
+
+    /// The port sent to the server in the handshake and reported to plugins.
+    pub fn port(&self) -> u16 {
+        self.server_port.unwrap_or(DEFAULT_PORT)
+    }
+
+    pub fn required_permission(&self, server_id: &str) -> Option<String> {
+        if !self.restricted {
+            return None;
+        }
+        Some(
+            self.permission
+                .clone()
+                .unwrap_or_else(|| format!("umbrella.server.{}", server_id)),
+        )
+    }
+
+    pub fn check_access(
+        &self,
+        server_id: &str,
+        uuid: &Uuid,
+        protocol_version: i32,
+        has_permission: impl Fn(&str) -> bool,
+    ) -> Result<(), AccessDenied> {
+        if let Some(permission) = self.required_permission(server_id) {
+            if !has_permission(&permission) {
+                return Err(AccessDenied::Permission);
+            }
+        }
+        if let Some(whitelist) = &self.whitelist {
+            if !whitelist.contains(uuid) {
+                return Err(AccessDenied::Whitelist);
+            }
+        }
+        if let Some(range) = &self.protocol_range {
+            if !range.contains(protocol_version) {
+                return Err(AccessDenied::Protocol);
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(serde_derive::Deserialize, Debug)]
@@ -56,6 +172,20 @@ pub enum Players {
     },
 }
 
+impl Players {
+    /// The online and max player counts to report with `online` players connected.
+    pub fn counts(&self, online: i32) -> (i32, i32) {
+        match self {
+            Players::Incremental => (online, online + 1),
+            Players::Static {
+                max_players,
+                online_players,
+            } => (*online_players, *max_players),
+            Players::Capped { max_players } => (online, *max_players),
+        }
+    }
+}
+
 fn incremental() -> Players {
     Players::Incremental
 }
@@ -67,10 +197,662 @@ pub struct StatusConfig {
     pub players: Players,
 }
 
+#[derive(serde_derive::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateLoginAction {
+    #[serde(rename = "kick_existing")]
+    KickExisting,
+    #[serde(rename = "reject_new")]
+    RejectNew,
+    #[serde(rename = "allow")]
+    AllowBoth,
+}
+
+fn kick_existing() -> DuplicateLoginAction {
+    DuplicateLoginAction::KickExisting
+}
+
+#[derive(serde_derive::Deserialize, Debug)]
+pub struct DuplicateLoginConfig {
+    #[serde(default = "kick_existing")]
+    pub action: DuplicateLoginAction,
+    /// Only kick the existing session when the new login comes from another IP,
+    /// otherwise the new login is rejected.
+    #[serde(default)]
+    pub kick_only_from_different_ip: bool,
+}
+
+impl Default for DuplicateLoginConfig {
+    fn default() -> Self {
+        Self {
+            action: kick_existing(),
+            kick_only_from_different_ip: false,
+        }
+    }
+}
+
+#[derive(serde_derive::Deserialize, Debug, Default)]
+pub struct CommandsConfig {
+    /// Extra labels for proxy commands, e.g. `"hub": "server lobby"`.
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+}
+
+fn permissions_file() -> String {
+    "./permissions.json".to_string()
+}
+
+#[derive(serde_derive::Deserialize, Debug)]
+pub struct PermissionsConfig {
+    #[serde(default = "permissions_file")]
+    pub file: String,
+}
+
+impl Default for PermissionsConfig {
+    fn default() -> Self {
+        Self {
+            file: permissions_file(),
+        }
+    }
+}
+
+fn plugin_directory() -> String {
+    "./plugins".to_string()
+}
+
+fn fuel_per_call() -> u64 {
+    10_000_000
+}
+
+fn max_memory_bytes() -> usize {
+    16 * 1024 * 1024
+}
+
+#[derive(serde_derive::Deserialize, Debug)]
+pub struct WasmPluginsConfig {
+    /// Directory scanned for `.wasm` modules at startup.
+    #[serde(default = "plugin_directory")]
+    pub directory: String,
+    /// Fuel each plugin gets for a single call into it, a plugin running out traps.
+    #[serde(default = "fuel_per_call")]
+    pub fuel_per_call: u64,
+    /// Maximum size of a plugin's linear memory.
+    #[serde(default = "max_memory_bytes")]
+    pub max_memory_bytes: usize,
+}
+
+impl Default for WasmPluginsConfig {
+    fn default() -> Self {
+        Self {
+            directory: plugin_directory(),
+            fuel_per_call: fuel_per_call(),
+            max_memory_bytes: max_memory_bytes(),
+        }
+    }
+}
+
+fn script_timeout_ms() -> u64 {
+    50
+}
+
+fn script_max_operations() -> u64 {
+    1_000_000
+}
+
+#[derive(serde_derive::Deserialize, Debug)]
+pub struct ScriptsConfig {
+    /// Rhai script defining the routing and status hooks, none by default.
+    #[serde(default)]
+    pub file: Option<String>,
+    /// Wall clock limit for a single hook call.
+    #[serde(default = "script_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default = "script_max_operations")]
+    pub max_operations: u64,
+}
+
+impl Default for ScriptsConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            timeout_ms: script_timeout_ms(),
+            max_operations: script_max_operations(),
+        }
+    }
+}
+
+fn max_payload_bytes() -> usize {
+    32767
+}
+
+#[derive(serde_derive::Deserialize, Debug)]
+pub struct ChannelPolicy {
+    /// Channels let through, every channel when empty. A trailing `*` matches any suffix.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    #[serde(default = "max_payload_bytes")]
+    pub max_payload_bytes: usize,
+    /// Messages per second for each player, 0 for no limit.
+    #[serde(default)]
+    pub rate_limit: u32,
+}
+
+impl Default for ChannelPolicy {
+    fn default() -> Self {
+        Self {
+            allow: vec![],
+            deny: vec![],
+            max_payload_bytes: max_payload_bytes(),
+            rate_limit: 0,
+        }
+    }
+}
+
+fn to_client_policy() -> ChannelPolicy {
+    ChannelPolicy {
+        max_payload_bytes: 1048576,
+        ..Default::default()
+    }
+}
+
+#[derive(serde_derive::Deserialize, Debug)]
+pub struct PluginChannelsConfig {
+    #[serde(default)]
+    pub to_server: ChannelPolicy,
+    #[serde(default = "to_client_policy")]
+    pub to_client: ChannelPolicy,
+}
+
+impl Default for PluginChannelsConfig {
+    fn default() -> Self {
+        Self {
+            to_server: ChannelPolicy::default(),
+            to_client: to_client_policy(),
+        }
+    }
+}
+
+#[derive(serde_derive::Deserialize, Debug)]
+pub struct AdminApiConfig {
+    pub bind: String,
+    /// Accepted bearer tokens.
+    pub tokens: Vec<String>,
+}
+
+#[derive(serde_derive::Deserialize, Debug)]
+pub struct MetricsConfig {
+    pub bind: String,
+}
+
+fn query_version() -> String {
+    "Umbrella".to_string()
+}
+
+fn query_map() -> String {
+    "world".to_string()
+}
+
+/// GameSpy4 query, what `enable-query` turns on for vanilla servers.
+#[derive(serde_derive::Deserialize, Debug)]
+pub struct QueryConfig {
+    /// UDP address to listen on, usually the same port as the game.
+    pub bind: String,
+    #[serde(default = "query_version")]
+    pub version: String,
+    #[serde(default = "query_map")]
+    pub map: String,
+    /// Plugin names listed in the full stat.
+    #[serde(default)]
+    pub plugins: Vec<String>,
+}
+
+fn punishments_file() -> String {
+    "./punishments.json".to_string()
+}
+
+#[derive(serde_derive::Deserialize, Debug)]
+#[serde(tag = "type")]
+pub enum ModerationStorage {
+    #[serde(rename = "json")]
+    Json {
+        #[serde(default = "punishments_file")]
+        file: String,
+    },
+    #[serde(rename = "sqlite")]
+    Sqlite { file: String },
+}
+
+impl Default for ModerationStorage {
+    fn default() -> Self {
+        ModerationStorage::Json {
+            file: punishments_file(),
+        }
+    }
+}
+
+fn ban_screen() -> String {
+    "§cYou are banned from this network.\n\n§7Reason: §f{reason}\n§7Banned by: §f{issuer}\n§7Time remaining: §f{remaining}".to_string()
+}
+
+fn mute_message() -> String {
+    "§cYou are muted for {remaining}. Reason: {reason}".to_string()
+}
+
+/// Templates may use `{reason}`, `{issuer}`, `{expires}` and `{remaining}`.
+#[derive(serde_derive::Deserialize, Debug)]
+pub struct ModerationConfig {
+    /// Only read at startup.
+    #[serde(default)]
+    pub storage: ModerationStorage,
+    #[serde(default = "ban_screen")]
+    pub ban_screen: String,
+    #[serde(default = "mute_message")]
+    pub mute_message: String,
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        Self {
+            storage: ModerationStorage::default(),
+            ban_screen: ban_screen(),
+            mute_message: mute_message(),
+        }
+    }
+}
+
+fn maintenance_message() -> String {
+    "§cThe network is under maintenance, please come back later.".to_string()
+}
+
+fn maintenance_motd() -> Chat {
+    Chat::literal("§cMaintenance")
+}
+
+fn maintenance_version() -> String {
+    "§cMaintenance".to_string()
+}
+
+#[derive(serde_derive::Deserialize, Debug)]
+pub struct MaintenanceConfig {
+    /// Whether to start in maintenance mode, after that it's toggled at runtime.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Kick message for players who may not join.
+    #[serde(default = "maintenance_message")]
+    pub message: String,
+    #[serde(default = "maintenance_motd")]
+    pub motd: Chat,
+    /// Shown in place of the player count in the server list.
+    #[serde(default = "maintenance_version")]
+    pub version: String,
+    /// Uuids or names allowed in anyway, on top of the bypass permission.
+    #[serde(default)]
+    pub bypass: Vec<String>,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            message: maintenance_message(),
+            motd: maintenance_motd(),
+            version: maintenance_version(),
+            bypass: vec![],
+        }
+    }
+}
+
+fn whitelist_file() -> String {
+    "./whitelist.json".to_string()
+}
+
+fn whitelist_message() -> String {
+    "§cYou are not whitelisted on this network.".to_string()
+}
+
+#[derive(serde_derive::Deserialize, Debug)]
+pub struct WhitelistConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Only read at startup.
+    #[serde(default = "whitelist_file")]
+    pub file: String,
+    #[serde(default = "whitelist_message")]
+    pub message: String,
+}
+
+impl Default for WhitelistConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            file: whitelist_file(),
+            message: whitelist_message(),
+        }
+    }
+}
+
+/// At most `count` per `seconds`, a count of 0 disables the limit.
+#[derive(serde_derive::Deserialize, Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub count: u32,
+    pub seconds: u64,
+}
+
+fn connection_limit() -> RateLimit {
+    RateLimit {
+        count: 20,
+        seconds: 10,
+    }
+}
+
+fn login_limit() -> RateLimit {
+    RateLimit {
+        count: 3,
+        seconds: 10,
+    }
+}
+
+fn status_limit() -> RateLimit {
+    RateLimit {
+        count: 10,
+        seconds: 10,
+    }
+}
+
+fn max_connections_per_ip() -> u32 {
+    10
+}
+
+fn handshakes_per_second() -> u32 {
+    500
+}
+
+fn block_after_violations() -> u32 {
+    20
+}
+
+fn block_seconds() -> u64 {
+    300
+}
+
+/// Limits per client address unless noted otherwise.
+#[derive(serde_derive::Deserialize, Debug)]
+pub struct ThrottleConfig {
+    /// New connections of any kind.
+    #[serde(default = "connection_limit")]
+    pub connections: RateLimit,
+    #[serde(default = "login_limit")]
+    pub logins: RateLimit,
+    #[serde(default = "status_limit")]
+    pub statuses: RateLimit,
+    /// Connections open at the same time, 0 for no cap.
+    #[serde(default = "max_connections_per_ip")]
+    pub max_connections_per_ip: u32,
+    /// Across all addresses, 0 for no ceiling.
+    #[serde(default = "handshakes_per_second")]
+    pub handshakes_per_second: u32,
+    /// Refusals within a minute after which the address is blocked, 0 to never block.
+    #[serde(default = "block_after_violations")]
+    pub block_after_violations: u32,
+    #[serde(default = "block_seconds")]
+    pub block_seconds: u64,
+    /// Also ban blocked addresses for the block duration, so the block shows up in the ban
+    /// list and survives restarts.
+    #[serde(default)]
+    pub auto_ban: bool,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            connections: connection_limit(),
+            logins: login_limit(),
+            statuses: status_limit(),
+            max_connections_per_ip: max_connections_per_ip(),
+            handshakes_per_second: handshakes_per_second(),
+            block_after_violations: block_after_violations(),
+            block_seconds: block_seconds(),
+            auto_ban: false,
+        }
+    }
+}
+
+#[derive(serde_derive::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AntiBotCheck {
+    /// The client has to fall through the limbo's empty world the way the game's physics make
+    /// it fall.
+    #[serde(rename = "falling")]
+    Falling,
+    /// The player has to type a code sent to them in chat.
+    #[serde(rename = "chat")]
+    Chat,
+    /// The player has to type the number drawn on a map in their hand.
+    #[serde(rename = "map")]
+    Map,
+}
+
+fn antibot_check() -> AntiBotCheck {
+    AntiBotCheck::Falling
+}
+
+fn antibot_falling_moves() -> u32 {
+    10
+}
+
+fn antibot_logins_per_second() -> u32 {
+    10
+}
+
+fn antibot_active_seconds() -> u64 {
+    120
+}
+
+fn antibot_verified_seconds() -> u64 {
+    24 * 60 * 60
+}
+
+fn antibot_timeout_seconds() -> u64 {
+    60
+}
+
+fn antibot_attempts() -> u32 {
+    3
+}
+
+fn antibot_prompt() -> String {
+    "§eType §f{code} §ein chat to continue.".to_string()
+}
+
+fn antibot_map_prompt() -> String {
+    "§eType the number on the map in chat to continue.".to_string()
+}
+
+fn antibot_failed_message() -> String {
+    "§cYou failed the bot check.".to_string()
+}
+
+/// Players have to pass `check` in the proxy's limbo before they are sent to `try`.
+#[derive(serde_derive::Deserialize, Debug)]
+pub struct AntiBotConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "antibot_check")]
+    pub check: AntiBotCheck,
+    /// Moves a client has to fall the expected distance for to pass the falling check.
+    #[serde(default = "antibot_falling_moves")]
+    pub falling_moves: u32,
+    /// Checks start once logins exceed this rate, 0 to check whenever enabled.
+    #[serde(default = "antibot_logins_per_second")]
+    pub logins_per_second: u32,
+    /// How long checks stay on after the rate was last exceeded.
+    #[serde(default = "antibot_active_seconds")]
+    pub active_seconds: u64,
+    /// How long a uuid and address pair that passed is let through.
+    #[serde(default = "antibot_verified_seconds")]
+    pub verified_seconds: u64,
+    #[serde(default = "antibot_timeout_seconds")]
+    pub timeout_seconds: u64,
+    #[serde(default = "antibot_attempts")]
+    pub attempts: u32,
+    /// Sent for the chat check, `{code}` is replaced with the code.
+    #[serde(default = "antibot_prompt")]
+    pub prompt: String,
+    /// Sent for the map check.
+    #[serde(default = "antibot_map_prompt")]
+    pub map_prompt: String,
+    #[serde(default = "antibot_failed_message")]
+    pub failed_message: String,
+}
+
+impl Default for AntiBotConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            check: antibot_check(),
+            falling_moves: antibot_falling_moves(),
+            logins_per_second: antibot_logins_per_second(),
+            active_seconds: antibot_active_seconds(),
+            verified_seconds: antibot_verified_seconds(),
+            timeout_seconds: antibot_timeout_seconds(),
+            attempts: antibot_attempts(),
+            prompt: antibot_prompt(),
+            map_prompt: antibot_map_prompt(),
+            failed_message: antibot_failed_message(),
+        }
+    }
+}
+
+/// PROXY protocol headers in front of incoming connections, from TCP load balancers.
+#[derive(serde_derive::Deserialize, Debug, Default)]
+pub struct ProxyProtocolConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Peers that must send a header. Connections from anywhere else are used as they are,
+    /// so clients can't make up their own address.
+    #[serde(default)]
+    pub trusted: Vec<Cidr>,
+}
+
+#[derive(serde_derive::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsRefresh {
+    /// Expired answers keep being used while a lookup runs in the background.
+    #[serde(rename = "background")]
+    Background,
+    /// Connections wait for the lookup once an answer expires.
+    #[serde(rename = "blocking")]
+    Blocking,
+}
+
+fn dns_min_ttl_seconds() -> u64 {
+    30
+}
+
+fn dns_max_ttl_seconds() -> u64 {
+    3600
+}
+
+fn dns_failure_ttl_seconds() -> u64 {
+    10
+}
+
+fn dns_stale_seconds() -> u64 {
+    600
+}
+
+fn dns_timeout_ms() -> u64 {
+    2000
+}
+
+fn background_refresh() -> DnsRefresh {
+    DnsRefresh::Background
+}
+
+/// Caching of backend hostname lookups. Servers configured by IP address or Unix socket
+/// never hit DNS.
+#[derive(serde_derive::Deserialize, Debug, Clone, Copy)]
+pub struct DnsConfig {
+    /// Answers are cached for their TTL, clamped to these bounds.
+    #[serde(default = "dns_min_ttl_seconds")]
+    pub min_ttl_seconds: u64,
+    #[serde(default = "dns_max_ttl_seconds")]
+    pub max_ttl_seconds: u64,
+    #[serde(default = "background_refresh")]
+    pub refresh: DnsRefresh,
+    /// How long after expiring an answer may still be used, while refreshing in the
+    /// background or when lookups fail. 0 never uses expired answers.
+    #[serde(default = "dns_stale_seconds")]
+    pub stale_seconds: u64,
+    /// Failed lookups without a usable old answer are remembered this long, so a broken
+    /// record doesn't send every login to the DNS server.
+    #[serde(default = "dns_failure_ttl_seconds")]
+    pub failure_ttl_seconds: u64,
+    #[serde(default = "dns_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            min_ttl_seconds: dns_min_ttl_seconds(),
+            max_ttl_seconds: dns_max_ttl_seconds(),
+            refresh: background_refresh(),
+            stale_seconds: dns_stale_seconds(),
+            failure_ttl_seconds: dns_failure_ttl_seconds(),
+            timeout_ms: dns_timeout_ms(),
+        }
+    }
+}
+
+fn shutdown_message() -> String {
+    "The proxy is shutting down.".to_string()
+}
+
+#[derive(serde_derive::Deserialize, Debug)]
+pub struct ShutdownConfig {
+    /// Sent in chat when the shutdown starts and used as the disconnect reason.
+    #[serde(default = "shutdown_message")]
+    pub message: String,
+    /// How long players get to leave on their own before the rest are disconnected.
+    #[serde(default)]
+    pub drain_seconds: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            message: shutdown_message(),
+            drain_seconds: 0,
+        }
+    }
+}
+
+/// One address the proxy accepts players on. Settings left out fall back to the top level
+/// ones of the same name.
+#[derive(serde_derive::Deserialize, Debug)]
+pub struct ListenerConfig {
+    /// `host:port`, with IPv6 hosts in brackets like `[::]:25565`, or `unix:<path>`.
+    pub bind: String,
+    #[serde(default)]
+    pub proxy_protocol: Option<ProxyProtocolConfig>,
+    /// Servers players joining through this listener are sent to first.
+    #[serde(default, rename = "try")]
+    pub initial_try: Option<Vec<String>>,
+    #[serde(default)]
+    pub status: Option<StatusConfig>,
+    #[serde(default)]
+    pub throttle: Option<ThrottleConfig>,
+}
+
 #[derive(serde_derive::Deserialize, Debug)]
 pub struct UmbrellaConfig {
     pub log_level: LevelFilter,
-    pub bind: String,
+    /// Shorthand for a single listener with the top level settings, used when `listeners`
+    /// is empty.
+    #[serde(default)]
+    pub bind: Option<String>,
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
     pub compression_threshold: isize,
     pub servers: HashMap<String, ServerInfo>,
     pub auth: AuthConfig,
@@ -78,4 +860,283 @@ pub struct UmbrellaConfig {
     pub fallback: Vec<String>,
     #[serde(rename = "try")]
     pub initial_try: Vec<String>,
+    #[serde(default)]
+    pub duplicate_login: DuplicateLoginConfig,
+    #[serde(default)]
+    pub commands: CommandsConfig,
+    #[serde(default)]
+    pub permissions: PermissionsConfig,
+    #[serde(default)]
+    pub wasm_plugins: WasmPluginsConfig,
+    #[serde(default)]
+    pub scripts: ScriptsConfig,
+    #[serde(default)]
+    pub plugin_channels: PluginChannelsConfig,
+    /// The admin API is disabled unless configured.
+    #[serde(default)]
+    pub admin_api: Option<AdminApiConfig>,
+    /// Serves Prometheus metrics on `/metrics` when configured.
+    #[serde(default)]
+    pub metrics: Option<MetricsConfig>,
+    /// Answers GameSpy4 queries when configured.
+    #[serde(default)]
+    pub query: Option<QueryConfig>,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub moderation: ModerationConfig,
+    #[serde(default)]
+    pub maintenance: MaintenanceConfig,
+    #[serde(default)]
+    pub whitelist: WhitelistConfig,
+    #[serde(default)]
+    pub throttle: ThrottleConfig,
+    #[serde(default)]
+    pub antibot: AntiBotConfig,
+    #[serde(default)]
+    pub proxy_protocol: ProxyProtocolConfig,
+    #[serde(default)]
+    pub dns: DnsConfig,
+}
+
+impl UmbrellaConfig {
+    /// Addresses to listen on, `bind` if no listeners are configured.
+    pub fn listener_binds(&self) -> Vec<String> {
+        if self.listeners.is_empty() {
+            self.bind.iter().cloned().collect()
+        } else {
+            self.listeners
+                .iter()
+                .map(|listener| listener.bind.clone())
+                .collect()
+        }
+    }
+
+    /// Listeners are looked up by address so reloads can change everything but that.
+    fn listener(&self, bind: &str) -> Option<&ListenerConfig> {
+        self.listeners.iter().find(|listener| listener.bind == bind)
+    }
+
+    pub fn listener_proxy_protocol(&self, bind: &str) -> &ProxyProtocolConfig {
+        self.listener(bind)
+            .and_then(|listener| listener.proxy_protocol.as_ref())
+            .unwrap_or(&self.proxy_protocol)
+    }
+
+    pub fn listener_try(&self, bind: &str) -> &[String] {
+        self.listener(bind)
+            .and_then(|listener| listener.initial_try.as_deref())
+            .unwrap_or(&self.initial_try)
+    }
+
+    pub fn listener_status(&self, bind: &str) -> &StatusConfig {
+        self.listener(bind)
+            .and_then(|listener| listener.status.as_ref())
+            .unwrap_or(&self.status)
+    }
+
+    pub fn listener_throttle(&self, bind: &str) -> &ThrottleConfig {
+        self.listener(bind)
+            .and_then(|listener| listener.throttle.as_ref())
+            .unwrap_or(&self.throttle)
+    }
+}
+
+pub const CONFIG_PATH: &str = "./config.json";
+
+/// Settings which are read without error but do nothing.
+fn ignored_settings(raw: &serde_json::Value) -> Vec<String> {
+    let mut warnings = vec![];
+    // clients only understand the Transfer packet from 1.20.5 on
+    if raw.pointer("/shutdown/transfer_to").is_some() {
+        warnings.push(
+            "shutdown.transfer_to is ignored, the supported client versions can't be transferred."
+                .to_string(),
+        );
+    }
+    warnings
+}
+
+/// Reads the config, along with warnings about it. Logging them is left to the caller, as the
+/// logger is only set up from the config.
+pub fn load() -> anyhow::Result<(UmbrellaConfig, Vec<String>)> {
+    let raw: serde_json::Value = serde_json::from_reader(fs::File::open(Path::new(CONFIG_PATH))?)?;
+    let warnings = ignored_settings(&raw);
+    let config: UmbrellaConfig = serde_json::from_value(raw)?;
+    if config.listener_binds().is_empty() {
+        anyhow::bail!("either bind or listeners has to be set");
+    }
+    Ok((config, warnings))
+}
+
+/// Replaces the file through a temporary one next to it, so a crash mid-write can't leave it
+/// truncated.
+pub fn write_atomically(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".tmp");
+    let temp_path = path.with_file_name(file_name);
+    let mut file = fs::File::create(&temp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(temp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn replaces_files_without_leaving_the_temporary_one() {
+        let path =
+            std::env::temp_dir().join(format!("umbrella-atomic-{}.json", std::process::id()));
+        write_atomically(&path, "[1]").unwrap();
+        write_atomically(&path, "[2]").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[2]");
+        let mut temp_path = path.clone().into_os_string();
+        temp_path.push(".tmp");
+        assert!(!Path::new(&temp_path).exists());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn warns_about_ignored_settings() {
+        let raw = json!({ "shutdown": { "message": "Bye", "transfer_to": "peer:25565" } });
+        assert_eq!(ignored_settings(&raw).len(), 1);
+        let raw = json!({ "shutdown": { "message": "Bye" } });
+        assert!(ignored_settings(&raw).is_empty());
+    }
+
+    fn server(extra: serde_json::Value) -> ServerInfo {
+        let mut value = json!({ "server_name": "Lobby" });
+        value
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn access(
+        server: &ServerInfo,
+        uuid: u128,
+        protocol_version: i32,
+        granted: &[&str],
+    ) -> Result<(), AccessDenied> {
+        server.check_access("lobby", &Uuid::from_u128(uuid), protocol_version, |node| {
+            granted.contains(&node)
+        })
+    }
+
+    #[test]
+    fn unrestricted_servers_let_everyone_in() {
+        let server = server(json!({}));
+        assert_eq!(server.required_permission("lobby"), None);
+        assert_eq!(access(&server, 1, 760, &[]), Ok(()));
+    }
+
+    #[test]
+    fn restricted_servers_need_their_permission() {
+        let default_node = server(json!({ "restricted": true }));
+        assert_eq!(
+            access(&default_node, 1, 760, &["umbrella.server.lobby"]),
+            Ok(())
+        );
+        assert_eq!(
+            access(&default_node, 1, 760, &["umbrella.server.other"]),
+            Err(AccessDenied::Permission)
+        );
+        let custom_node = server(json!({ "restricted": true, "permission": "staff" }));
+        assert_eq!(access(&custom_node, 1, 760, &["staff"]), Ok(()));
+        assert_eq!(
+            access(&custom_node, 1, 760, &["umbrella.server.lobby"]),
+            Err(AccessDenied::Permission)
+        );
+    }
+
+    #[test]
+    fn whitelists_and_protocol_ranges_deny_the_rest() {
+        let whitelisted = server(json!({ "whitelist": [Uuid::from_u128(1)] }));
+        assert_eq!(access(&whitelisted, 1, 760, &[]), Ok(()));
+        assert_eq!(
+            access(&whitelisted, 2, 760, &[]),
+            Err(AccessDenied::Whitelist)
+        );
+        let ranged = server(json!({ "protocol_range": { "min": 760 } }));
+        assert_eq!(access(&ranged, 1, 760, &[]), Ok(()));
+        assert_eq!(access(&ranged, 1, 759, &[]), Err(AccessDenied::Protocol));
+        // the permission is checked first
+        let both = server(json!({ "restricted": true, "whitelist": [] }));
+        assert_eq!(access(&both, 1, 760, &[]), Err(AccessDenied::Permission));
+    }
+
+    fn config(extra: serde_json::Value) -> UmbrellaConfig {
+        let mut value = json!({
+            "log_level": "INFO",
+            "compression_threshold": 256,
+            "servers": {},
+            "try": ["lobby"],
+            "fallback": ["lobby"],
+            "auth": {
+                "force_key_authentication": false,
+                "default_forwarding": { "auth_method": "bungee" },
+                "incoming_auth": { "auth_method": "mojang", "auth_data": {} },
+            },
+            "status": { "motd": "Umbrella" },
+        });
+        value
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn binds_the_single_address_without_listeners() {
+        let config = config(json!({ "bind": "0.0.0.0:25565" }));
+        assert_eq!(config.listener_binds(), ["0.0.0.0:25565"]);
+        assert_eq!(config.listener_try("0.0.0.0:25565"), ["lobby"]);
+    }
+
+    #[test]
+    fn listeners_override_the_top_level_settings() {
+        let config = config(json!({
+            "bind": "0.0.0.0:25565",
+            "listeners": [
+                {
+                    "bind": "[::]:25565",
+                    "try": ["hub"],
+                    "proxy_protocol": { "enabled": true, "trusted": ["10.0.0.0/8"] },
+                    "throttle": { "max_connections_per_ip": 1 },
+                },
+                { "bind": "unix:/run/umbrella.sock" },
+            ],
+        }));
+        assert_eq!(
+            config.listener_binds(),
+            ["[::]:25565", "unix:/run/umbrella.sock"]
+        );
+        assert_eq!(config.listener_try("[::]:25565"), ["hub"]);
+        assert!(config.listener_proxy_protocol("[::]:25565").enabled);
+        assert_eq!(
+            config
+                .listener_throttle("[::]:25565")
+                .max_connections_per_ip,
+            1
+        );
+        let unix = "unix:/run/umbrella.sock";
+        assert_eq!(config.listener_try(unix), ["lobby"]);
+        assert!(!config.listener_proxy_protocol(unix).enabled);
+        assert_eq!(
+            config.listener_throttle(unix).max_connections_per_ip,
+            max_connections_per_ip()
+        );
+    }
+
+    #[test]
+    fn servers_can_be_reached_over_unix_sockets() {
+        let unix = server(json!({ "socket_path": "/run/lobby.sock" }));
+        assert_eq!(unix.address(), "unix:/run/lobby.sock");
+        let tcp = server(json!({ "server_ip": "10.0.0.2", "server_port": 25566 }));
+        assert_eq!(tcp.address(), "10.0.0.2:25566");
+    }
 }
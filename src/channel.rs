@@ -0,0 +1,270 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+
+use crate::backend::is_bungee_channel;
+use crate::cfg::ChannelPolicy;
+use crate::event::PluginMessageDirection;
+
+/// First protocol version (1.13) using namespaced channel names.
+const NAMESPACED_CHANNELS_VERSION: i32 = 393;
+
+const REGISTER_CHANNELS: [&str; 2] = ["minecraft:register", "REGISTER"];
+const UNREGISTER_CHANNELS: [&str; 2] = ["minecraft:unregister", "UNREGISTER"];
+
+#[derive(Debug)]
+pub enum ChannelDenied {
+    /// Channels handled by the proxy itself may not be sent by clients.
+    ProxyInternal,
+    NotAllowed,
+    TooLarge,
+    RateLimited,
+}
+
+fn matches(pattern: &str, channel: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => channel.starts_with(prefix),
+        None => pattern == channel,
+    }
+}
+
+impl ChannelPolicy {
+    fn permits(&self, channel: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|pattern| matches(pattern, channel)))
+            && !self.deny.iter().any(|pattern| matches(pattern, channel))
+    }
+}
+
+pub fn register_channel(protocol_version: i32) -> &'static str {
+    if protocol_version >= NAMESPACED_CHANNELS_VERSION {
+        REGISTER_CHANNELS[0]
+    } else {
+        REGISTER_CHANNELS[1]
+    }
+}
+
+#[derive(Default)]
+struct RateWindow {
+    started: Option<Instant>,
+    count: u32,
+}
+
+impl RateWindow {
+    fn try_acquire(&mut self, limit: u32) -> bool {
+        if limit == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        match self.started {
+            Some(started) if now.duration_since(started) < Duration::from_secs(1) => {}
+            _ => {
+                self.started = Some(now);
+                self.count = 0;
+            }
+        }
+        self.count += 1;
+        self.count <= limit
+    }
+}
+
+#[derive(Default)]
+struct ChannelState {
+    registered: BTreeSet<String>,
+    to_server: RateWindow,
+    to_client: RateWindow,
+}
+
+/// Per player plugin channel state, the channels the client registered and the rate limits
+/// for either direction.
+#[derive(Clone, Default)]
+pub struct ChannelTracker {
+    state: Arc<StdMutex<ChannelState>>,
+}
+
+impl ChannelTracker {
+    pub fn check(
+        &self,
+        policy: &ChannelPolicy,
+        direction: PluginMessageDirection,
+        channel: &str,
+        len: usize,
+    ) -> Result<(), ChannelDenied> {
+        if direction == PluginMessageDirection::ToServer && is_bungee_channel(channel) {
+            return Err(ChannelDenied::ProxyInternal);
+        }
+        if !policy.permits(channel) {
+            return Err(ChannelDenied::NotAllowed);
+        }
+        if len > policy.max_payload_bytes {
+            return Err(ChannelDenied::TooLarge);
+        }
+        let mut state = self.state.lock().unwrap();
+        let window = match direction {
+            PluginMessageDirection::ToServer => &mut state.to_server,
+            PluginMessageDirection::ToClient => &mut state.to_client,
+        };
+        if !window.try_acquire(policy.rate_limit) {
+            return Err(ChannelDenied::RateLimited);
+        }
+        Ok(())
+    }
+
+    /// Records channels the client (un)registers, the payload being null separated names.
+    pub fn track(&self, channel: &str, data: &[u8]) {
+        let register = REGISTER_CHANNELS.contains(&channel);
+        if !register && !UNREGISTER_CHANNELS.contains(&channel) {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        for name in data.split(|byte| *byte == 0) {
+            let name = match std::str::from_utf8(name) {
+                Ok(name) if !name.is_empty() => name,
+                _ => continue,
+            };
+            if register {
+                state.registered.insert(name.to_string());
+            } else {
+                state.registered.remove(name);
+            }
+        }
+    }
+
+    /// Payload re-registering the client's channels with a new backend, `None` if there are none.
+    pub fn registration(&self) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        if state.registered.is_empty() {
+            return None;
+        }
+        let names: Vec<&str> = state.registered.iter().map(String::as_str).collect();
+        Some(names.join("\0").into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allow: &[&str], deny: &[&str]) -> ChannelPolicy {
+        ChannelPolicy {
+            allow: allow.iter().map(|pattern| pattern.to_string()).collect(),
+            deny: deny.iter().map(|pattern| pattern.to_string()).collect(),
+            ..ChannelPolicy::default()
+        }
+    }
+
+    fn registered(tracker: &ChannelTracker) -> Vec<String> {
+        tracker
+            .state
+            .lock()
+            .unwrap()
+            .registered
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn tracks_registrations() {
+        let tracker = ChannelTracker::default();
+        tracker.track("minecraft:register", b"fabric:a\0fabric:b\0\0");
+        tracker.track("REGISTER", b"legacy");
+        assert_eq!(registered(&tracker), ["fabric:a", "fabric:b", "legacy"]);
+        tracker.track("minecraft:unregister", b"fabric:a\0unknown");
+        tracker.track("UNREGISTER", b"legacy");
+        assert_eq!(registered(&tracker), ["fabric:b"]);
+        assert_eq!(tracker.registration(), Some(b"fabric:b".to_vec()));
+    }
+
+    #[test]
+    fn ignores_other_channels_and_invalid_names() {
+        let tracker = ChannelTracker::default();
+        tracker.track("minecraft:brand", b"vanilla");
+        tracker.track("minecraft:register", b"\xFF\xFE\0ok");
+        assert_eq!(registered(&tracker), ["ok"]);
+        tracker.track("minecraft:unregister", b"ok");
+        assert_eq!(tracker.registration(), None);
+    }
+
+    #[test]
+    fn applies_allow_and_deny_patterns() {
+        let tracker = ChannelTracker::default();
+        let policy = policy(&["fabric:*", "minecraft:brand"], &["fabric:secret"]);
+        let check =
+            |channel: &str| tracker.check(&policy, PluginMessageDirection::ToServer, channel, 0);
+        assert!(check("fabric:anything").is_ok());
+        assert!(check("minecraft:brand").is_ok());
+        assert!(matches!(
+            check("fabric:secret"),
+            Err(ChannelDenied::NotAllowed)
+        ));
+        assert!(matches!(
+            check("forge:handshake"),
+            Err(ChannelDenied::NotAllowed)
+        ));
+        let everything = ChannelPolicy::default();
+        assert!(tracker
+            .check(
+                &everything,
+                PluginMessageDirection::ToServer,
+                "forge:handshake",
+                0
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn keeps_clients_off_proxy_channels() {
+        let tracker = ChannelTracker::default();
+        let policy = ChannelPolicy::default();
+        assert!(matches!(
+            tracker.check(
+                &policy,
+                PluginMessageDirection::ToServer,
+                "bungeecord:main",
+                0
+            ),
+            Err(ChannelDenied::ProxyInternal)
+        ));
+        assert!(tracker
+            .check(
+                &policy,
+                PluginMessageDirection::ToClient,
+                "bungeecord:main",
+                0
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn limits_size_and_rate_per_session_and_direction() {
+        let policy = ChannelPolicy {
+            max_payload_bytes: 4,
+            rate_limit: 2,
+            ..ChannelPolicy::default()
+        };
+        let tracker = ChannelTracker::default();
+        let to_server = |tracker: &ChannelTracker| {
+            tracker.check(&policy, PluginMessageDirection::ToServer, "fabric:a", 4)
+        };
+        assert!(matches!(
+            tracker.check(&policy, PluginMessageDirection::ToServer, "fabric:a", 5),
+            Err(ChannelDenied::TooLarge)
+        ));
+        assert!(to_server(&tracker).is_ok());
+        assert!(to_server(&tracker).is_ok());
+        assert!(matches!(
+            to_server(&tracker),
+            Err(ChannelDenied::RateLimited)
+        ));
+        // the other direction and other players have their own windows
+        assert!(tracker
+            .check(&policy, PluginMessageDirection::ToClient, "fabric:a", 4)
+            .is_ok());
+        assert!(to_server(&ChannelTracker::default()).is_ok());
+        // clones share the session's state
+        assert!(matches!(
+            to_server(&tracker.clone()),
+            Err(ChannelDenied::RateLimited)
+        ));
+    }
+}
@@ -0,0 +1,182 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// An address range such as `10.0.0.0/8` or `2001:db8::/32`. A plain address is a range
+/// of one. IPv4-mapped IPv6 addresses are treated as the IPv4 address they carry.
+#[derive(serde_derive::Deserialize, serde_derive::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+        IpAddr::V4(_) => addr,
+    }
+}
+
+fn bits(addr: IpAddr) -> (u128, u8) {
+    match addr {
+        IpAddr::V4(v4) => (u32::from(v4) as u128, 32),
+        IpAddr::V6(v6) => (u128::from(v6), 128),
+    }
+}
+
+/// Clears the bits past the prefix, leaving the network address.
+fn mask(addr: IpAddr, prefix_len: u8) -> IpAddr {
+    let (value, width) = bits(addr);
+    let host_bits = (width - prefix_len) as u32;
+    let network = value
+        .checked_shr(host_bits)
+        .and_then(|value| value.checked_shl(host_bits))
+        .unwrap_or(0);
+    match addr {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(network as u32)),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(network)),
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = canonical(addr);
+        if addr.is_ipv4() != self.network.is_ipv4() {
+            return false;
+        }
+        let (network, width) = bits(self.network);
+        let (addr, _) = bits(addr);
+        let host_bits = (width - self.prefix_len) as u32;
+        network.checked_shr(host_bits) == addr.checked_shr(host_bits)
+    }
+
+    pub fn is_single_address(&self) -> bool {
+        self.prefix_len == bits(self.network).1
+    }
+}
+
+impl From<IpAddr> for Cidr {
+    fn from(addr: IpAddr) -> Self {
+        let network = canonical(addr);
+        Self {
+            network,
+            prefix_len: bits(network).1,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match value.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (value, None),
+        };
+        let cidr = Cidr::from(
+            addr.parse::<IpAddr>()
+                .map_err(|_| format!("{} is not an IP address", addr))?,
+        );
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= cidr.prefix_len)
+                .ok_or_else(|| format!("{} is not a valid prefix length", prefix_len))?,
+            None => cidr.prefix_len,
+        };
+        // `10.1.2.3/8` is taken as `10.0.0.0/8`, so equal ranges compare and print the same
+        Ok(Self {
+            network: mask(cidr.network, prefix_len),
+            prefix_len,
+        })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_single_address() {
+            write!(f, "{}", self.network)
+        } else {
+            write!(f, "{}/{}", self.network, self.prefix_len)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn contains_addresses_in_range() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains(ip("10.255.1.2")));
+        assert!(!cidr.contains(ip("11.0.0.1")));
+        assert!(!cidr.contains(ip("::1")));
+
+        let cidr: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(cidr.contains(ip("2001:db8:ffff::1")));
+        assert!(!cidr.contains(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn zero_prefix_contains_everything() {
+        let cidr: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(cidr.contains(ip("1.2.3.4")));
+        assert!(cidr.contains(ip("255.255.255.255")));
+        assert!(!cidr.contains(ip("::1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_ranges() {
+        let cidr: Cidr = "192.168.0.0/16".parse().unwrap();
+        assert!(cidr.contains(ip("::ffff:192.168.1.1")));
+        let cidr: Cidr = "::ffff:192.168.1.1".parse().unwrap();
+        assert_eq!(cidr.to_string(), "192.168.1.1");
+        assert!(cidr.contains(ip("192.168.1.1")));
+    }
+
+    #[test]
+    fn masks_host_bits() {
+        let cidr: Cidr = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(cidr, "10.0.0.0/8".parse().unwrap());
+        assert_eq!(cidr.to_string(), "10.0.0.0/8");
+        let cidr: Cidr = "2001:db8::1/32".parse().unwrap();
+        assert_eq!(cidr.to_string(), "2001:db8::/32");
+    }
+
+    #[test]
+    fn single_addresses_print_without_prefix() {
+        let cidr: Cidr = "1.2.3.4/32".parse().unwrap();
+        assert!(cidr.is_single_address());
+        assert_eq!(cidr.to_string(), "1.2.3.4");
+        assert_eq!(cidr, Cidr::from(ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+        assert!("example.com".parse::<Cidr>().is_err());
+    }
+}
@@ -1,10 +1,23 @@
-use std::{future::Future, time::Duration};
+use std::{fmt::Display, future::Future, time::Duration};
 
+use drax::transport::frame::PacketFrame;
+use drax::VarInt;
 use mcprotocol::{
-    pipeline::AsyncMinecraftProtocolPipeline,
+    chat::Chat,
+    pin_fut,
+    pipeline::{buffer_packet, AsyncMinecraftProtocolPipeline},
+    protocol::play::cb::{CommandSuggestionsResponse, Suggestion},
+    protocol::play::sb::{
+        ChatCommand, ChatMessage, CommandSuggestionsRequest, KeepAlive, PluginMessage,
+    },
     registry::{AsyncPacketRegistry, MappedAsyncPacketRegistry, RegistryError},
 };
-use tokio::net::tcp::OwnedReadHalf;
+use tokio::{sync::mpsc::UnboundedSender, task::JoinHandle};
+
+use crate::event::{Cancellable, ChatEvent, PluginMessageDirection, PluginMessageEvent};
+use crate::moderation::PunishmentKind;
+use crate::net::ReadHalf;
+use crate::player::PlayerSession;
 
 pub enum ClientFunctionResponse {
     DoNothing,
@@ -12,12 +25,15 @@ pub enum ClientFunctionResponse {
     ForwardPackets(Vec<Vec<u8>>),
 }
 
+pub type ClientEvent = Result<ClientFunctionResponse, drax::transport::Error>;
+
 pub struct Client {
+    context: PlayerSession,
     read: AsyncMinecraftProtocolPipeline<
-        OwnedReadHalf,
-        (), /* todo */
+        ReadHalf,
+        PlayerSession,
         ClientFunctionResponse,
-        MappedAsyncPacketRegistry<() /* todo */, ClientFunctionResponse>,
+        MappedAsyncPacketRegistry<PlayerSession, ClientFunctionResponse>,
     >,
 }
 
@@ -27,10 +43,37 @@ impl Client {
         _2: Send + Sync,
         Reg: AsyncPacketRegistry<_1, _2> + Send + Sync,
     >(
-        current_pipeline: AsyncMinecraftProtocolPipeline<OwnedReadHalf, _1, _2, Reg>,
+        current_pipeline: AsyncMinecraftProtocolPipeline<ReadHalf, _1, _2, Reg>,
+        context: PlayerSession,
     ) -> Client {
-        let pipeline = current_pipeline.clear_registry();
-        Client { read: pipeline }
+        let mut pipeline = current_pipeline.clear_registry();
+        pipeline.register(pin_fut!(handle_keep_alive));
+        pipeline.register(pin_fut!(handle_chat_command));
+        pipeline.register(pin_fut!(handle_chat_message));
+        pipeline.register(pin_fut!(handle_command_suggestions));
+        pipeline.register(pin_fut!(handle_plugin_message));
+        Client {
+            context,
+            read: pipeline,
+        }
+    }
+
+    /// Drives the client read side on its own task so the relay loop only ever waits on
+    /// channels. The task ends on the first transport error.
+    pub fn spawn(
+        mut self,
+        timeout: Duration,
+        events: UnboundedSender<ClientEvent>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let event = self.read_next_packet_with_timeout(timeout).await;
+                let finished = event.is_err();
+                if events.send(event).is_err() || finished {
+                    return;
+                }
+            }
+        })
     }
 
     pub async fn read_next_packet_with_timeout(
@@ -38,7 +81,7 @@ impl Client {
         timeout: Duration,
     ) -> Result<ClientFunctionResponse, drax::transport::Error> {
         match (&mut self.read)
-            .execute_next_packet_timeout(&mut (), timeout)
+            .execute_next_packet_timeout(&mut self.context, timeout)
             .await
         {
             Ok(resp) => Ok(resp),
@@ -51,3 +94,188 @@ impl Client {
         }
     }
 }
+
+fn forward_buffered<E: Display>(buffered: Result<PacketFrame, E>) -> ClientFunctionResponse {
+    match buffered {
+        Ok(frame) => ClientFunctionResponse::ForwardPacket(frame.data),
+        Err(err) => {
+            log::warn!("Failed to re-encode client packet: {}", err);
+            ClientFunctionResponse::DoNothing
+        }
+    }
+}
+
+/// Runs the command if the proxy owns its label, returns false if it belongs to the backend.
+async fn run_proxy_command(ctx: &PlayerSession, line: &str) -> bool {
+    let commands = &ctx.proxy_info.commands;
+    match line.split_whitespace().next() {
+        Some(label) if commands.owns(label) => {}
+        _ => return false,
+    }
+    commands.dispatch(&ctx.command_source().await, line).await
+}
+
+/// Tells the player if they are muted, returning whether they are.
+async fn muted(ctx: &PlayerSession) -> bool {
+    let mute = match ctx
+        .proxy_info
+        .moderation
+        .find(PunishmentKind::Mute, &ctx.client_info)
+        .await
+    {
+        Some(mute) => mute,
+        None => return false,
+    };
+    let message = mute.render(&ctx.proxy_info.config().moderation.mute_message);
+    if let Err(err) = ctx.send_message(Chat::literal(message)).await {
+        log::debug!(
+            "Failed to tell {} they are muted: {}",
+            ctx.client_info.profile.name,
+            err
+        );
+    }
+    true
+}
+
+/// Fires a `ChatEvent`, returning the event or `None` if a handler cancelled it.
+async fn fire_chat(ctx: &PlayerSession, message: &str, is_command: bool) -> Option<ChatEvent> {
+    let event = ctx
+        .proxy_info
+        .events
+        .fire(ChatEvent {
+            client_info: ctx.client_info.clone(),
+            session_id: ctx.session_id,
+            message: message.to_string(),
+            is_command,
+            cancelled: false,
+        })
+        .await;
+    (!event.is_cancelled()).then_some(event)
+}
+
+async fn handle_keep_alive(ctx: &mut PlayerSession, packet: KeepAlive) -> ClientFunctionResponse {
+    if let Some(ping) = ctx.keep_alives.answered(packet.id) {
+        ctx.proxy_info
+            .metrics
+            .keep_alive_rtt
+            .observe(ping.as_secs_f64());
+        ctx.proxy_info.players.set_ping(ctx.session_id, ping).await;
+    }
+    forward_buffered(buffer_packet(&packet, ctx.client_info.protocol_version))
+}
+
+async fn handle_chat_command(
+    ctx: &mut PlayerSession,
+    packet: ChatCommand,
+) -> ClientFunctionResponse {
+    let event = match fire_chat(ctx, &packet.command, true).await {
+        Some(event) => event,
+        None => return ClientFunctionResponse::DoNothing,
+    };
+    // proxy commands work while muted, backend ones could be used to message others
+    if run_proxy_command(ctx, &event.message).await || muted(ctx).await {
+        return ClientFunctionResponse::DoNothing;
+    }
+    forward_buffered(buffer_packet(&packet, ctx.client_info.protocol_version))
+}
+
+async fn handle_chat_message(
+    ctx: &mut PlayerSession,
+    packet: ChatMessage,
+) -> ClientFunctionResponse {
+    // clients before 1.19 send commands as regular chat messages
+    let (message, is_command) = match packet.message.strip_prefix('/') {
+        Some(line) => (line, true),
+        None => (packet.message.as_str(), false),
+    };
+    if !is_command && muted(ctx).await {
+        return ClientFunctionResponse::DoNothing;
+    }
+    let event = match fire_chat(ctx, message, is_command).await {
+        Some(event) => event,
+        None => return ClientFunctionResponse::DoNothing,
+    };
+    if event.is_command && (run_proxy_command(ctx, &event.message).await || muted(ctx).await) {
+        return ClientFunctionResponse::DoNothing;
+    }
+    forward_buffered(buffer_packet(&packet, ctx.client_info.protocol_version))
+}
+
+async fn handle_command_suggestions(
+    ctx: &mut PlayerSession,
+    packet: CommandSuggestionsRequest,
+) -> ClientFunctionResponse {
+    let line = packet.text.strip_prefix('/').unwrap_or(&packet.text);
+    let commands = &ctx.proxy_info.commands;
+    // labels themselves are completed by the client from the command tree
+    match line.split_once(' ') {
+        Some((label, _)) if commands.owns(label) => {}
+        _ => return forward_buffered(buffer_packet(&packet, ctx.client_info.protocol_version)),
+    }
+
+    let matches = commands.suggest(&ctx.command_source().await, line).await;
+    let start = packet.text.rfind(' ').map_or(0, |index| index + 1);
+    let response = CommandSuggestionsResponse {
+        transaction_id: packet.transaction_id,
+        start: packet.text[..start].chars().count() as VarInt,
+        length: packet.text[start..].chars().count() as VarInt,
+        matches: matches
+            .into_iter()
+            .map(|text| Suggestion {
+                text,
+                tooltip: None,
+            })
+            .collect(),
+    };
+    if let Err(err) = ctx.client_write.lock().await.write_packet(&response).await {
+        log::debug!(
+            "Failed to send suggestions to {}: {}",
+            ctx.client_info.profile.name,
+            err
+        );
+    }
+    ClientFunctionResponse::DoNothing
+}
+
+async fn handle_plugin_message(
+    ctx: &mut PlayerSession,
+    packet: PluginMessage,
+) -> ClientFunctionResponse {
+    if let Err(denied) = ctx.channels.check(
+        &ctx.proxy_info.config().plugin_channels.to_server,
+        PluginMessageDirection::ToServer,
+        &packet.channel,
+        packet.data.len(),
+    ) {
+        log::debug!(
+            "Dropped message on {} from {}: {:?}",
+            packet.channel,
+            ctx.client_info.profile.name,
+            denied
+        );
+        return ClientFunctionResponse::DoNothing;
+    }
+    ctx.channels.track(&packet.channel, &packet.data);
+    let event = ctx
+        .proxy_info
+        .events
+        .fire(PluginMessageEvent {
+            client_info: ctx.client_info.clone(),
+            session_id: ctx.session_id,
+            direction: PluginMessageDirection::ToServer,
+            channel: packet.channel,
+            data: packet.data,
+            cancelled: false,
+        })
+        .await;
+    if event.is_cancelled() {
+        return ClientFunctionResponse::DoNothing;
+    }
+    forward_buffered(buffer_packet(
+        &PluginMessage {
+            channel: event.channel,
+            data: event.data,
+        },
+        ctx.client_info.protocol_version,
+    ))
+}
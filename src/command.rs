@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+
+use mcprotocol::chat::Chat;
+
+use crate::cfg::CommandsConfig;
+use crate::registry::RegisteredPlayer;
+use crate::ProxyInfo;
+
+mod admin;
+pub mod brigadier;
+mod builtin;
+mod moderation;
+
+pub type CommandFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+pub type SuggestFuture<'a> = Pin<Box<dyn Future<Output = Vec<String>> + Send + 'a>>;
+
+pub trait ProxyCommand: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Node required to run the command, `None` if anyone may run it.
+    fn permission(&self) -> Option<&str>;
+
+    fn usage(&self) -> &str;
+
+    fn execute<'a>(&'a self, source: &'a CommandSource, args: &'a [&'a str]) -> CommandFuture<'a>;
+
+    /// Candidates for the last, possibly empty, argument. Filtering by what has been
+    /// typed so far is done by the registry.
+    fn suggest<'a>(&'a self, _: &'a CommandSource, _: &'a [&'a str]) -> SuggestFuture<'a> {
+        Box::pin(async { vec![] })
+    }
+}
+
+pub struct CommandSource {
+    pub proxy_info: Arc<ProxyInfo>,
+    /// `None` when the command comes from the console.
+    pub player: Option<RegisteredPlayer>,
+}
+
+impl CommandSource {
+    pub fn name(&self) -> &str {
+        match &self.player {
+            Some(player) => player.name(),
+            None => "CONSOLE",
+        }
+    }
+
+    pub fn send_message(&self, message: impl Into<String>) {
+        let message = message.into();
+        match &self.player {
+            Some(player) => {
+                player.handle.send_message(Chat::literal(message));
+            }
+            None => log::info!("{}", message),
+        }
+    }
+
+    pub fn has_permission(&self, node: &str) -> bool {
+        match &self.player {
+            Some(player) => self.proxy_info.permissions.has_permission(
+                &player.uuid(),
+                node,
+                player.current_server.as_deref(),
+            ),
+            None => true,
+        }
+    }
+}
+
+fn lowercase_aliases(config: &CommandsConfig) -> HashMap<String, String> {
+    config
+        .aliases
+        .iter()
+        .map(|(label, expansion)| (label.to_lowercase(), expansion.clone()))
+        .collect()
+}
+
+/// The candidates starting with what was typed, ignoring case, in order.
+fn narrow(candidates: Vec<String>, typed: &str) -> Vec<String> {
+    let typed = typed.to_lowercase();
+    let mut candidates: Vec<String> = candidates
+        .into_iter()
+        .filter(|candidate| candidate.to_lowercase().starts_with(&typed))
+        .collect();
+    candidates.sort();
+    candidates
+}
+
+pub struct CommandRegistry {
+    commands: RwLock<HashMap<String, Arc<dyn ProxyCommand>>>,
+    aliases: RwLock<HashMap<String, String>>,
+}
+
+impl CommandRegistry {
+    pub fn new(config: &CommandsConfig) -> Self {
+        let registry = Self {
+            commands: RwLock::new(HashMap::new()),
+            aliases: RwLock::new(lowercase_aliases(config)),
+        };
+        builtin::register_all(&registry);
+        admin::register_all(&registry);
+        moderation::register_all(&registry);
+        registry
+    }
+
+    pub fn reload_aliases(&self, config: &CommandsConfig) {
+        *self.aliases.write().unwrap() = lowercase_aliases(config);
+    }
+
+    /// Registers the command, replacing any existing one with the same name.
+    pub fn register(&self, command: Arc<dyn ProxyCommand>) {
+        self.commands
+            .write()
+            .unwrap()
+            .insert(command.name().to_lowercase(), command);
+    }
+
+    pub fn get(&self, label: &str) -> Option<Arc<dyn ProxyCommand>> {
+        self.commands
+            .read()
+            .unwrap()
+            .get(&label.to_lowercase())
+            .cloned()
+    }
+
+    /// Whether the label belongs to the proxy rather than the backend. Aliases only do if
+    /// they expand to a registered command.
+    pub fn owns(&self, label: &str) -> bool {
+        self.resolve(label)
+            .map_or(false, |(label, _)| self.get(&label).is_some())
+    }
+
+    /// Labels the source may run, which are sent to the client as part of its command tree.
+    pub fn visible_labels(&self, source: &CommandSource) -> Vec<String> {
+        let allowed = |label: &str| match self.get(label) {
+            Some(command) => command
+                .permission()
+                .map_or(true, |permission| source.has_permission(permission)),
+            None => false,
+        };
+        let names: Vec<String> = self.commands.read().unwrap().keys().cloned().collect();
+        let mut labels: Vec<String> = names.into_iter().filter(|label| allowed(label)).collect();
+        for (alias, expansion) in self.aliases.read().unwrap().iter() {
+            if let Some(label) = expansion.split_whitespace().next() {
+                if allowed(&label.to_lowercase()) {
+                    labels.push(alias.clone());
+                }
+            }
+        }
+        labels.sort();
+        labels.dedup();
+        labels
+    }
+
+    /// Completes the last argument of the command line (without its leading slash).
+    pub async fn suggest(&self, source: &CommandSource, line: &str) -> Vec<String> {
+        let (label, args) = match self.completion(line) {
+            Some(resolved) => resolved,
+            None => return vec![],
+        };
+        let command = match self.get(&label) {
+            Some(command) => command,
+            None => return vec![],
+        };
+        if let Some(permission) = command.permission() {
+            if !source.has_permission(permission) {
+                return vec![];
+            }
+        }
+        let typed = args.last().cloned().unwrap_or_default();
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        narrow(command.suggest(source, &args).await, &typed)
+    }
+
+    /// Like `resolve`, with an empty last argument if the line ends in whitespace, as that's
+    /// the argument being completed.
+    fn completion(&self, line: &str) -> Option<(String, Vec<String>)> {
+        let (label, mut args) = self.resolve(line)?;
+        if line.ends_with(char::is_whitespace) {
+            args.push(String::new());
+        }
+        Some((label, args))
+    }
+
+    /// Expands aliases, returning the command label and its arguments.
+    fn resolve(&self, line: &str) -> Option<(String, Vec<String>)> {
+        let mut parts = line.split_whitespace();
+        let label = parts.next()?.to_lowercase();
+        let rest: Vec<String> = parts.map(str::to_string).collect();
+        match self.aliases.read().unwrap().get(&label) {
+            Some(expansion) => {
+                let mut expanded = expansion.split_whitespace().map(str::to_string);
+                let label = expanded.next()?.to_lowercase();
+                Some((label, expanded.chain(rest).collect()))
+            }
+            None => Some((label, rest)),
+        }
+    }
+
+    /// Runs the command line (without its leading slash) if the proxy owns it.
+    /// Returns false if the command should be forwarded to the backend instead.
+    pub async fn dispatch(&self, source: &CommandSource, line: &str) -> bool {
+        let (label, args) = match self.resolve(line) {
+            Some(resolved) => resolved,
+            None => return false,
+        };
+        let command = match self.get(&label) {
+            Some(command) => command,
+            None => return false,
+        };
+        if let Some(permission) = command.permission() {
+            if !source.has_permission(permission) {
+                source.send_message("§cYou do not have permission to run this command.");
+                return true;
+            }
+        }
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        if let Err(err) = command.execute(source, &args).await {
+            log::warn!(
+                "Error running command \"{}\" for {}: {}",
+                line,
+                source.name(),
+                err
+            );
+            source.send_message("§cAn error occurred while running this command.");
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry(aliases: &[(&str, &str)]) -> CommandRegistry {
+        CommandRegistry::new(&CommandsConfig {
+            aliases: aliases
+                .iter()
+                .map(|(label, expansion)| (label.to_string(), expansion.to_string()))
+                .collect(),
+        })
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn expands_aliases_in_front_of_the_arguments() {
+        let registry = registry(&[("Hub", "server lobby")]);
+        assert_eq!(
+            registry.resolve("hub extra"),
+            Some(("server".to_string(), strings(&["lobby", "extra"])))
+        );
+        assert_eq!(
+            registry.resolve("SERVER survival"),
+            Some(("server".to_string(), strings(&["survival"])))
+        );
+        assert_eq!(registry.resolve("  "), None);
+    }
+
+    #[test]
+    fn owns_only_aliases_of_registered_commands() {
+        let registry = registry(&[
+            ("hub", "server lobby"),
+            ("spawn", "warp spawn"),
+            ("empty", ""),
+        ]);
+        assert!(registry.owns("server"));
+        assert!(registry.owns("HUB"));
+        assert!(!registry.owns("spawn"));
+        assert!(!registry.owns("empty"));
+        assert!(!registry.owns("warp"));
+    }
+
+    #[test]
+    fn completes_the_argument_after_an_alias() {
+        let registry = registry(&[("hub", "server lobby")]);
+        assert_eq!(
+            registry.completion("hub "),
+            Some(("server".to_string(), strings(&["lobby", ""])))
+        );
+        assert_eq!(
+            registry.completion("server sur"),
+            Some(("server".to_string(), strings(&["sur"])))
+        );
+    }
+
+    #[test]
+    fn narrows_suggestions_to_what_was_typed() {
+        let candidates = strings(&["survival", "lobby", "Skyblock"]);
+        assert_eq!(narrow(candidates.clone(), "s"), ["Skyblock", "survival"]);
+        assert_eq!(narrow(candidates.clone(), "SUR"), ["survival"]);
+        assert_eq!(narrow(candidates, ""), ["Skyblock", "lobby", "survival"]);
+    }
+}
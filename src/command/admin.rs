@@ -0,0 +1,412 @@
+use std::sync::Arc;
+
+use mcprotocol::chat::Chat;
+use uuid::Uuid;
+
+use super::builtin::online_names;
+use crate::command::{CommandFuture, CommandRegistry, CommandSource, ProxyCommand, SuggestFuture};
+use crate::permission::PermissionSubject;
+
+pub fn register_all(registry: &CommandRegistry) {
+    registry.register(Arc::new(ReloadCommand));
+    registry.register(Arc::new(PermCommand));
+    registry.register(Arc::new(KickCommand));
+    registry.register(Arc::new(DebugCommand));
+    registry.register(Arc::new(EndCommand));
+    registry.register(Arc::new(MaintenanceCommand));
+    registry.register(Arc::new(WhitelistCommand));
+}
+
+/// Accepts either a uuid or the name of an online player.
+pub async fn resolve_uuid(source: &CommandSource, target: &str) -> Option<Uuid> {
+    match Uuid::parse_str(target) {
+        Ok(uuid) => Some(uuid),
+        Err(_) => source
+            .proxy_info
+            .players
+            .by_name(target)
+            .await
+            .map(|player| player.uuid()),
+    }
+}
+
+struct ReloadCommand;
+
+impl ProxyCommand for ReloadCommand {
+    fn name(&self) -> &str {
+        "reload"
+    }
+
+    fn permission(&self) -> Option<&str> {
+        Some("umbrella.command.reload")
+    }
+
+    fn usage(&self) -> &str {
+        "/reload"
+    }
+
+    fn execute<'a>(&'a self, source: &'a CommandSource, _: &'a [&'a str]) -> CommandFuture<'a> {
+        Box::pin(async move {
+            match source.proxy_info.reload() {
+                Ok(_) => source.send_message("§aConfiguration reloaded."),
+                Err(err) => {
+                    log::warn!("Failed to reload configuration: {}", err);
+                    source.send_message(format!("§cFailed to reload configuration: {}", err));
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+struct PermCommand;
+
+impl ProxyCommand for PermCommand {
+    fn name(&self) -> &str {
+        "perm"
+    }
+
+    fn permission(&self) -> Option<&str> {
+        Some("umbrella.command.perm")
+    }
+
+    fn usage(&self) -> &str {
+        "/perm <user <player|uuid>|group <group>> <grant|revoke|addgroup|removegroup> <node|group> [server]"
+    }
+
+    fn execute<'a>(&'a self, source: &'a CommandSource, args: &'a [&'a str]) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let (kind, target, action, value, server) = match args {
+                [kind, target, action, value] => (*kind, *target, *action, *value, None),
+                [kind, target, action, value, server] => {
+                    (*kind, *target, *action, *value, Some(*server))
+                }
+                _ => {
+                    source.send_message(format!("§cUsage: {}", self.usage()));
+                    return Ok(());
+                }
+            };
+            let permissions = &source.proxy_info.permissions;
+
+            let subject = match kind {
+                "user" => match resolve_uuid(source, target).await {
+                    Some(uuid) => PermissionSubject::User(uuid),
+                    None => {
+                        source.send_message(format!(
+                            "§c{} is not online, use their uuid instead.",
+                            target
+                        ));
+                        return Ok(());
+                    }
+                },
+                "group" => PermissionSubject::Group(target.to_string()),
+                _ => {
+                    source.send_message(format!("§cUsage: {}", self.usage()));
+                    return Ok(());
+                }
+            };
+
+            let result = match (action, subject) {
+                ("grant", subject) => permissions.grant(subject, value, server),
+                ("revoke", subject) => permissions.revoke(subject, value, server),
+                ("addgroup", PermissionSubject::User(uuid)) => permissions.add_group(&uuid, value),
+                ("removegroup", PermissionSubject::User(uuid)) => {
+                    permissions.remove_group(&uuid, value)
+                }
+                _ => {
+                    source.send_message(format!("§cUsage: {}", self.usage()));
+                    return Ok(());
+                }
+            };
+            match result {
+                Ok(_) => {
+                    source.send_message(format!("§aUpdated permissions of {} {}.", kind, target))
+                }
+                Err(err) => source.send_message(format!("§c{}", err)),
+            }
+            Ok(())
+        })
+    }
+
+    fn suggest<'a>(&'a self, _: &'a CommandSource, args: &'a [&'a str]) -> SuggestFuture<'a> {
+        Box::pin(async move {
+            match args {
+                [_] => vec!["user".to_string(), "group".to_string()],
+                ["user", _, _] => ["grant", "revoke", "addgroup", "removegroup"]
+                    .iter()
+                    .map(|action| action.to_string())
+                    .collect(),
+                ["group", _, _] => vec!["grant".to_string(), "revoke".to_string()],
+                _ => vec![],
+            }
+        })
+    }
+}
+
+struct KickCommand;
+
+impl ProxyCommand for KickCommand {
+    fn name(&self) -> &str {
+        "kick"
+    }
+
+    fn permission(&self) -> Option<&str> {
+        Some("umbrella.command.kick")
+    }
+
+    fn usage(&self) -> &str {
+        "/kick <player> [reason]"
+    }
+
+    fn execute<'a>(&'a self, source: &'a CommandSource, args: &'a [&'a str]) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let (target, reason) = match args {
+                [target] => (*target, "You have been kicked.".to_string()),
+                [target, reason @ ..] => (*target, reason.join(" ")),
+                _ => {
+                    source.send_message(format!("§cUsage: {}", self.usage()));
+                    return Ok(());
+                }
+            };
+            match source.proxy_info.players.find(target).await {
+                Some(player) => {
+                    player.handle.kick(Chat::literal(reason));
+                    source.send_message(format!("§eKicked {}.", player.name()));
+                }
+                None => source.send_message(format!("§c{} is not online.", target)),
+            }
+            Ok(())
+        })
+    }
+
+    fn suggest<'a>(&'a self, source: &'a CommandSource, args: &'a [&'a str]) -> SuggestFuture<'a> {
+        Box::pin(async move {
+            match args.len() {
+                1 => online_names(source).await,
+                _ => vec![],
+            }
+        })
+    }
+}
+
+struct DebugCommand;
+
+impl ProxyCommand for DebugCommand {
+    fn name(&self) -> &str {
+        "debug"
+    }
+
+    fn permission(&self) -> Option<&str> {
+        Some("umbrella.command.debug")
+    }
+
+    fn usage(&self) -> &str {
+        "/debug <player|uuid>"
+    }
+
+    fn execute<'a>(&'a self, source: &'a CommandSource, args: &'a [&'a str]) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let target = match args {
+                [target] => *target,
+                _ => {
+                    source.send_message(format!("§cUsage: {}", self.usage()));
+                    return Ok(());
+                }
+            };
+            let player = match source.proxy_info.players.find(target).await {
+                Some(player) => player,
+                None => {
+                    source.send_message(format!("§c{} is not online.", target));
+                    return Ok(());
+                }
+            };
+            let client_info = &player.client_info;
+            source.send_message(format!("§e{} ({})", player.name(), player.uuid()));
+            source.send_message(format!("§7Session: §f{}", player.session_id));
+            source.send_message(format!("§7Address: §f{}", client_info.remote_addr));
+            source.send_message(format!(
+                "§7Protocol version: §f{}",
+                client_info.protocol_version
+            ));
+            source.send_message(format!(
+                "§7Server: §f{}",
+                player.current_server.as_deref().unwrap_or("none")
+            ));
+            source.send_message(format!(
+                "§7Ping: §f{}",
+                player.ping.map_or("unknown".to_string(), |ping| format!(
+                    "{}ms",
+                    ping.as_millis()
+                ))
+            ));
+            source.send_message(format!(
+                "§7Connected at: §f{}",
+                player.connected_at.format("%Y-%m-%d %H:%M:%S")
+            ));
+            source.send_message(format!(
+                "§7Signed chat key: §f{}",
+                if client_info.mojang_key.is_some() {
+                    "yes"
+                } else {
+                    "no"
+                }
+            ));
+            Ok(())
+        })
+    }
+
+    fn suggest<'a>(&'a self, source: &'a CommandSource, args: &'a [&'a str]) -> SuggestFuture<'a> {
+        Box::pin(async move {
+            match args.len() {
+                1 => online_names(source).await,
+                _ => vec![],
+            }
+        })
+    }
+}
+
+struct EndCommand;
+
+impl ProxyCommand for EndCommand {
+    fn name(&self) -> &str {
+        "end"
+    }
+
+    fn permission(&self) -> Option<&str> {
+        Some("umbrella.command.end")
+    }
+
+    fn usage(&self) -> &str {
+        "/end"
+    }
+
+    fn execute<'a>(&'a self, source: &'a CommandSource, _: &'a [&'a str]) -> CommandFuture<'a> {
+        Box::pin(async move {
+            log::info!("{} requested a shutdown.", source.name());
+            source.proxy_info.shutdown();
+            Ok(())
+        })
+    }
+}
+
+struct MaintenanceCommand;
+
+impl ProxyCommand for MaintenanceCommand {
+    fn name(&self) -> &str {
+        "maintenance"
+    }
+
+    fn permission(&self) -> Option<&str> {
+        Some("umbrella.command.maintenance")
+    }
+
+    fn usage(&self) -> &str {
+        "/maintenance [on|off]"
+    }
+
+    fn execute<'a>(&'a self, source: &'a CommandSource, args: &'a [&'a str]) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let maintenance = &source.proxy_info.maintenance;
+            let enabled = match args {
+                [] => {
+                    source.send_message(format!(
+                        "§eMaintenance mode is {}.",
+                        if maintenance.is_enabled() {
+                            "on"
+                        } else {
+                            "off"
+                        }
+                    ));
+                    return Ok(());
+                }
+                ["on"] => true,
+                ["off"] => false,
+                _ => {
+                    source.send_message(format!("§cUsage: {}", self.usage()));
+                    return Ok(());
+                }
+            };
+            let kicked = maintenance.set(&source.proxy_info, enabled).await;
+            if enabled {
+                source.send_message(format!(
+                    "§eMaintenance mode is now on, {} player(s) were kicked.",
+                    kicked
+                ));
+            } else {
+                source.send_message("§eMaintenance mode is now off.");
+            }
+            Ok(())
+        })
+    }
+
+    fn suggest<'a>(&'a self, _: &'a CommandSource, args: &'a [&'a str]) -> SuggestFuture<'a> {
+        Box::pin(async move {
+            match args.len() {
+                1 => vec!["on".to_string(), "off".to_string()],
+                _ => vec![],
+            }
+        })
+    }
+}
+
+struct WhitelistCommand;
+
+impl ProxyCommand for WhitelistCommand {
+    fn name(&self) -> &str {
+        "whitelist"
+    }
+
+    fn permission(&self) -> Option<&str> {
+        Some("umbrella.command.whitelist")
+    }
+
+    fn usage(&self) -> &str {
+        "/whitelist <add|remove> <player|uuid> or /whitelist list"
+    }
+
+    fn execute<'a>(&'a self, source: &'a CommandSource, args: &'a [&'a str]) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let whitelist = &source.proxy_info.whitelist;
+            match args {
+                ["add", entry] => {
+                    if whitelist.add(entry)? {
+                        source.send_message(format!("§eAdded {} to the whitelist.", entry));
+                    } else {
+                        source.send_message(format!("§c{} is already whitelisted.", entry));
+                    }
+                }
+                ["remove", entry] => {
+                    if whitelist.remove(entry)? {
+                        source.send_message(format!("§eRemoved {} from the whitelist.", entry));
+                    } else {
+                        source.send_message(format!("§c{} is not whitelisted.", entry));
+                    }
+                }
+                ["list"] => {
+                    let entries = whitelist.entries();
+                    source.send_message(format!(
+                        "§eWhitelisted ({}): §f{}",
+                        entries.len(),
+                        entries.join(", ")
+                    ));
+                    if !source.proxy_info.config().whitelist.enabled {
+                        source.send_message("§7The whitelist is not enabled in the config.");
+                    }
+                }
+                _ => source.send_message(format!("§cUsage: {}", self.usage())),
+            }
+            Ok(())
+        })
+    }
+
+    fn suggest<'a>(&'a self, source: &'a CommandSource, args: &'a [&'a str]) -> SuggestFuture<'a> {
+        Box::pin(async move {
+            match args {
+                [_] => vec!["add".to_string(), "remove".to_string(), "list".to_string()],
+                ["add", _] => online_names(source).await,
+                ["remove", _] => source.proxy_info.whitelist.entries(),
+                _ => vec![],
+            }
+        })
+    }
+}
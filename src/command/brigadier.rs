@@ -0,0 +1,223 @@
+use drax::VarInt;
+use mcprotocol::protocol::commands::{ArgumentParser, CommandNode, NodeType, StringKind};
+use mcprotocol::protocol::play::cb::Commands;
+
+/// Suggestion type telling the client to send a tab complete request for the argument.
+const ASK_SERVER: &str = "minecraft:ask_server";
+
+fn literal_name(node: &CommandNode) -> Option<&str> {
+    match &node.node_type {
+        NodeType::Literal { name } => Some(name),
+        _ => None,
+    }
+}
+
+/// Drops the nodes no longer reachable from the root through children or redirects, and
+/// rewrites the indices of the rest to match their new positions.
+fn remove_unreachable(nodes: &mut Vec<CommandNode>, root: &mut VarInt) {
+    let mut reachable = vec![false; nodes.len()];
+    let mut pending = vec![*root];
+    while let Some(index) = pending.pop() {
+        let node = match nodes.get(index as usize) {
+            Some(node) => node,
+            None => continue,
+        };
+        if std::mem::replace(&mut reachable[index as usize], true) {
+            continue;
+        }
+        pending.extend(node.children.iter().copied());
+        pending.extend(node.redirect);
+    }
+
+    let mut next = 0;
+    let remapped: Vec<Option<VarInt>> = reachable
+        .iter()
+        .map(|keep| {
+            keep.then(|| {
+                next += 1;
+                next - 1
+            })
+        })
+        .collect();
+    let remap = |index: VarInt| remapped.get(index as usize).copied().flatten();
+    *nodes = std::mem::take(nodes)
+        .into_iter()
+        .zip(&reachable)
+        .filter(|(_, keep)| **keep)
+        .map(|(mut node, _)| {
+            node.children = node
+                .children
+                .iter()
+                .filter_map(|child| remap(*child))
+                .collect();
+            node.redirect = node.redirect.and_then(remap);
+            node
+        })
+        .collect();
+    *root = remap(*root).unwrap_or_default();
+}
+
+fn inject(nodes: &mut Vec<CommandNode>, root: &mut VarInt, labels: &[String]) {
+    if *root < 0 || *root as usize >= nodes.len() {
+        log::warn!("Commands packet has an out of range root index {}", root);
+        return;
+    }
+
+    let shadowed: Vec<VarInt> = nodes[*root as usize]
+        .children
+        .iter()
+        .copied()
+        .filter(|child| {
+            nodes
+                .get(*child as usize)
+                .and_then(literal_name)
+                .map_or(false, |name| labels.iter().any(|label| label == name))
+        })
+        .collect();
+    nodes[*root as usize]
+        .children
+        .retain(|child| !shadowed.contains(child));
+    remove_unreachable(nodes, root);
+
+    for label in labels {
+        let argument_index = nodes.len() as VarInt;
+        nodes.push(CommandNode {
+            children: vec![],
+            redirect: None,
+            executable: true,
+            node_type: NodeType::Argument {
+                name: "arguments".to_string(),
+                parser: ArgumentParser::String(StringKind::GreedyPhrase),
+                suggestions_type: Some(ASK_SERVER.to_string()),
+            },
+        });
+        let literal_index = nodes.len() as VarInt;
+        nodes.push(CommandNode {
+            children: vec![argument_index],
+            redirect: None,
+            executable: true,
+            node_type: NodeType::Literal {
+                name: label.clone(),
+            },
+        });
+        nodes[*root as usize].children.push(literal_index);
+    }
+}
+
+/// Adds a literal node with a greedy string argument under the root for every label,
+/// replacing any backend command with the same name. Backend nodes only the replaced
+/// commands led to are dropped from the packet.
+pub fn inject_proxy_commands(packet: &mut Commands, labels: &[String]) {
+    inject(&mut packet.nodes, &mut packet.root_index, labels);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(node_type: NodeType, children: Vec<VarInt>, redirect: Option<VarInt>) -> CommandNode {
+        CommandNode {
+            children,
+            redirect,
+            executable: true,
+            node_type,
+        }
+    }
+
+    fn literal(name: &str, children: Vec<VarInt>, redirect: Option<VarInt>) -> CommandNode {
+        node(
+            NodeType::Literal {
+                name: name.to_string(),
+            },
+            children,
+            redirect,
+        )
+    }
+
+    fn name(nodes: &[CommandNode], index: VarInt) -> &str {
+        match &nodes[index as usize].node_type {
+            NodeType::Literal { name } | NodeType::Argument { name, .. } => name.as_str(),
+            NodeType::Root => "",
+        }
+    }
+
+    fn children(nodes: &[CommandNode], index: VarInt) -> Vec<&str> {
+        nodes[index as usize]
+            .children
+            .iter()
+            .map(|child| name(nodes, *child))
+            .collect()
+    }
+
+    fn argument(name: &str) -> CommandNode {
+        node(
+            NodeType::Argument {
+                name: name.to_string(),
+                parser: ArgumentParser::String(StringKind::GreedyPhrase),
+                suggestions_type: None,
+            },
+            vec![],
+            None,
+        )
+    }
+
+    /// `/server <name>`, `/spawn`, `/tp <target>` and `/teleport` redirecting to `/tp`,
+    /// with the root last so every index moves.
+    fn backend_tree() -> (Vec<CommandNode>, VarInt) {
+        let nodes = vec![
+            literal("server", vec![1], None),
+            argument("name"),
+            literal("spawn", vec![], None),
+            literal("tp", vec![4], None),
+            argument("target"),
+            literal("teleport", vec![], Some(3)),
+            node(NodeType::Root, vec![0, 2, 3, 5], None),
+        ];
+        (nodes, 6)
+    }
+
+    #[test]
+    fn drops_shadowed_nodes_and_rewrites_indices() {
+        let (mut nodes, mut root) = backend_tree();
+        inject(&mut nodes, &mut root, &["server".to_string()]);
+        // the backend's server node and its argument are gone
+        assert_eq!(nodes.len(), 5 + 2);
+        assert_eq!(name(&nodes, root), "");
+        assert_eq!(
+            children(&nodes, root),
+            ["spawn", "tp", "teleport", "server"]
+        );
+        let tp = nodes[root as usize].children[1];
+        assert_eq!(children(&nodes, tp), ["target"]);
+        let teleport = nodes[root as usize].children[2];
+        assert_eq!(nodes[teleport as usize].redirect, Some(tp));
+        let server = nodes[root as usize].children[3];
+        assert_eq!(children(&nodes, server), ["arguments"]);
+    }
+
+    #[test]
+    fn keeps_shadowed_nodes_other_commands_redirect_to() {
+        let (mut nodes, mut root) = backend_tree();
+        inject(&mut nodes, &mut root, &["tp".to_string()]);
+        assert_eq!(nodes.len(), 7 + 2);
+        assert_eq!(
+            children(&nodes, root),
+            ["server", "spawn", "teleport", "tp"]
+        );
+        let teleport = nodes[root as usize].children[2];
+        let redirect = nodes[teleport as usize].redirect.unwrap();
+        assert_eq!(name(&nodes, redirect), "tp");
+        assert_eq!(children(&nodes, redirect), ["target"]);
+        // the proxy's tp is a separate node
+        assert_ne!(nodes[root as usize].children[3], redirect);
+    }
+
+    #[test]
+    fn ignores_out_of_range_roots() {
+        let (mut nodes, _) = backend_tree();
+        let mut root = 7;
+        inject(&mut nodes, &mut root, &["server".to_string()]);
+        assert_eq!(nodes.len(), 7);
+        assert_eq!(root, 7);
+    }
+}
@@ -0,0 +1,428 @@
+use std::sync::Arc;
+
+use mcprotocol::chat::Chat;
+
+use crate::backend::ForwardToServerType;
+use crate::command::{CommandFuture, CommandRegistry, CommandSource, ProxyCommand, SuggestFuture};
+use crate::player::{access_denied_message, check_server_access};
+
+pub fn register_all(registry: &CommandRegistry) {
+    registry.register(Arc::new(ServerCommand));
+    registry.register(Arc::new(GlistCommand));
+    registry.register(Arc::new(SendCommand));
+    registry.register(Arc::new(FindCommand));
+    registry.register(Arc::new(AlertCommand));
+    registry.register(Arc::new(PingCommand));
+    registry.register(Arc::new(ServersCommand));
+}
+
+fn sorted_server_ids(source: &CommandSource) -> Vec<String> {
+    let mut server_ids: Vec<String> = source.proxy_info.config().servers.keys().cloned().collect();
+    server_ids.sort();
+    server_ids
+}
+
+/// Servers the source may join, restricted ones they can't join are hidden.
+fn joinable_server_ids(source: &CommandSource) -> Vec<String> {
+    let player = match &source.player {
+        Some(player) => player,
+        None => return sorted_server_ids(source),
+    };
+    let config = source.proxy_info.config();
+    sorted_server_ids(source)
+        .into_iter()
+        .filter(|server_id| {
+            config.servers.get(server_id).map_or(false, |server_info| {
+                check_server_access(
+                    &source.proxy_info,
+                    &player.client_info,
+                    server_id,
+                    server_info,
+                )
+                .is_ok()
+            })
+        })
+        .collect()
+}
+
+pub(super) async fn online_names(source: &CommandSource) -> Vec<String> {
+    source
+        .proxy_info
+        .players
+        .players()
+        .await
+        .iter()
+        .map(|player| player.name().to_string())
+        .collect()
+}
+
+struct ServerCommand;
+
+impl ProxyCommand for ServerCommand {
+    fn name(&self) -> &str {
+        "server"
+    }
+
+    fn permission(&self) -> Option<&str> {
+        Some("umbrella.command.server")
+    }
+
+    fn usage(&self) -> &str {
+        "/server [name]"
+    }
+
+    fn execute<'a>(&'a self, source: &'a CommandSource, args: &'a [&'a str]) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let player = match &source.player {
+                Some(player) => player,
+                None => {
+                    source.send_message("Only players can switch servers.");
+                    return Ok(());
+                }
+            };
+            match args.first() {
+                None => {
+                    source.send_message(format!(
+                        "§eYou are currently connected to {}.",
+                        player.current_server.as_deref().unwrap_or("no server")
+                    ));
+                    source.send_message(format!(
+                        "§eAvailable servers: {}",
+                        joinable_server_ids(source).join(", ")
+                    ));
+                }
+                Some(server_id) => {
+                    let config = source.proxy_info.config();
+                    let server_info = match config.servers.get(*server_id) {
+                        Some(server_info) => server_info,
+                        None => {
+                            source.send_message(format!(
+                                "§cThe server {} does not exist.",
+                                server_id
+                            ));
+                            return Ok(());
+                        }
+                    };
+                    if let Err(denied) = check_server_access(
+                        &source.proxy_info,
+                        &player.client_info,
+                        server_id,
+                        server_info,
+                    ) {
+                        player
+                            .handle
+                            .send_message(access_denied_message(denied, server_id));
+                        return Ok(());
+                    }
+                    source.send_message(format!("§eConnecting you to {}...", server_id));
+                    player
+                        .handle
+                        .transfer(ForwardToServerType::ById(server_id.to_string()));
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn suggest<'a>(&'a self, source: &'a CommandSource, args: &'a [&'a str]) -> SuggestFuture<'a> {
+        Box::pin(async move {
+            match args.len() {
+                1 => joinable_server_ids(source),
+                _ => vec![],
+            }
+        })
+    }
+}
+
+struct GlistCommand;
+
+impl ProxyCommand for GlistCommand {
+    fn name(&self) -> &str {
+        "glist"
+    }
+
+    fn permission(&self) -> Option<&str> {
+        Some("umbrella.command.glist")
+    }
+
+    fn usage(&self) -> &str {
+        "/glist"
+    }
+
+    fn execute<'a>(&'a self, source: &'a CommandSource, _: &'a [&'a str]) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let players = &source.proxy_info.players;
+            for server_id in sorted_server_ids(source) {
+                let mut names: Vec<String> = players
+                    .players_on(&server_id)
+                    .await
+                    .iter()
+                    .map(|player| player.name().to_string())
+                    .collect();
+                names.sort();
+                source.send_message(format!(
+                    "§a[{}] §e({}): §f{}",
+                    server_id,
+                    names.len(),
+                    names.join(", ")
+                ));
+            }
+            source.send_message(format!(
+                "§eTotal players online: {}",
+                players.player_count().await
+            ));
+            Ok(())
+        })
+    }
+}
+
+struct SendCommand;
+
+impl ProxyCommand for SendCommand {
+    fn name(&self) -> &str {
+        "send"
+    }
+
+    fn permission(&self) -> Option<&str> {
+        Some("umbrella.command.send")
+    }
+
+    fn usage(&self) -> &str {
+        "/send <player|all|current> <server>"
+    }
+
+    fn execute<'a>(&'a self, source: &'a CommandSource, args: &'a [&'a str]) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let (target, server_id) = match args {
+                [target, server_id] => (*target, *server_id),
+                _ => {
+                    source.send_message(format!("§cUsage: {}", self.usage()));
+                    return Ok(());
+                }
+            };
+            if !source.proxy_info.config().servers.contains_key(server_id) {
+                source.send_message(format!("§cThe server {} does not exist.", server_id));
+                return Ok(());
+            }
+
+            let players = &source.proxy_info.players;
+            let targets = match target {
+                "all" => players.players().await,
+                "current" => match source
+                    .player
+                    .as_ref()
+                    .and_then(|player| player.current_server.as_ref())
+                {
+                    Some(current) => players.players_on(current).await,
+                    None => {
+                        source.send_message("§cYou are not connected to a server.");
+                        return Ok(());
+                    }
+                },
+                name => match players.by_name(name).await {
+                    Some(player) => vec![player],
+                    None => {
+                        source.send_message(format!("§c{} is not online.", name));
+                        return Ok(());
+                    }
+                },
+            };
+
+            for player in &targets {
+                player.handle.send_message(Chat::literal(format!(
+                    "§eYou have been sent to {}.",
+                    server_id
+                )));
+                player
+                    .handle
+                    .transfer(ForwardToServerType::ById(server_id.to_string()));
+            }
+            source.send_message(format!(
+                "§eSent {} player(s) to {}.",
+                targets.len(),
+                server_id
+            ));
+            Ok(())
+        })
+    }
+
+    fn suggest<'a>(&'a self, source: &'a CommandSource, args: &'a [&'a str]) -> SuggestFuture<'a> {
+        Box::pin(async move {
+            match args.len() {
+                1 => {
+                    let mut targets = online_names(source).await;
+                    targets.push("all".to_string());
+                    targets.push("current".to_string());
+                    targets
+                }
+                2 => sorted_server_ids(source),
+                _ => vec![],
+            }
+        })
+    }
+}
+
+struct FindCommand;
+
+impl ProxyCommand for FindCommand {
+    fn name(&self) -> &str {
+        "find"
+    }
+
+    fn permission(&self) -> Option<&str> {
+        Some("umbrella.command.find")
+    }
+
+    fn usage(&self) -> &str {
+        "/find <player>"
+    }
+
+    fn execute<'a>(&'a self, source: &'a CommandSource, args: &'a [&'a str]) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let name = match args {
+                [name] => *name,
+                _ => {
+                    source.send_message(format!("§cUsage: {}", self.usage()));
+                    return Ok(());
+                }
+            };
+            match source.proxy_info.players.by_name(name).await {
+                Some(player) => source.send_message(format!(
+                    "§e{} is online at {}.",
+                    player.name(),
+                    player.current_server.as_deref().unwrap_or("no server")
+                )),
+                None => source.send_message(format!("§c{} is not online.", name)),
+            }
+            Ok(())
+        })
+    }
+
+    fn suggest<'a>(&'a self, source: &'a CommandSource, args: &'a [&'a str]) -> SuggestFuture<'a> {
+        Box::pin(async move {
+            match args.len() {
+                1 => online_names(source).await,
+                _ => vec![],
+            }
+        })
+    }
+}
+
+struct AlertCommand;
+
+impl ProxyCommand for AlertCommand {
+    fn name(&self) -> &str {
+        "alert"
+    }
+
+    fn permission(&self) -> Option<&str> {
+        Some("umbrella.command.alert")
+    }
+
+    fn usage(&self) -> &str {
+        "/alert <message>"
+    }
+
+    fn execute<'a>(&'a self, source: &'a CommandSource, args: &'a [&'a str]) -> CommandFuture<'a> {
+        Box::pin(async move {
+            if args.is_empty() {
+                source.send_message(format!("§cUsage: {}", self.usage()));
+                return Ok(());
+            }
+            let message = format!("§8[§cAlert§8] §f{}", args.join(" "));
+            log::info!("{}", message);
+            for player in source.proxy_info.players.players().await {
+                player.handle.send_message(Chat::literal(message.clone()));
+            }
+            Ok(())
+        })
+    }
+}
+
+struct PingCommand;
+
+impl ProxyCommand for PingCommand {
+    fn name(&self) -> &str {
+        "ping"
+    }
+
+    fn permission(&self) -> Option<&str> {
+        Some("umbrella.command.ping")
+    }
+
+    fn usage(&self) -> &str {
+        "/ping"
+    }
+
+    fn execute<'a>(&'a self, source: &'a CommandSource, _: &'a [&'a str]) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let session_id = match &source.player {
+                Some(player) => player.session_id,
+                None => {
+                    source.send_message("The console has no ping.");
+                    return Ok(());
+                }
+            };
+            // the snapshot in the source may predate the latest keep alive
+            match source
+                .proxy_info
+                .players
+                .by_session(session_id)
+                .await
+                .and_then(|player| player.ping)
+            {
+                Some(ping) => {
+                    source.send_message(format!("§eYour ping is {}ms.", ping.as_millis()))
+                }
+                None => source.send_message("§eYour ping has not been measured yet."),
+            }
+            Ok(())
+        })
+    }
+}
+
+struct ServersCommand;
+
+impl ProxyCommand for ServersCommand {
+    fn name(&self) -> &str {
+        "servers"
+    }
+
+    fn permission(&self) -> Option<&str> {
+        Some("umbrella.command.servers")
+    }
+
+    fn usage(&self) -> &str {
+        "/servers"
+    }
+
+    fn execute<'a>(&'a self, source: &'a CommandSource, _: &'a [&'a str]) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let counts = source.proxy_info.players.server_counts().await;
+            let config = source.proxy_info.config();
+            for server_id in sorted_server_ids(source) {
+                let server_info = &config.servers[&server_id];
+                let health = match source.proxy_info.health.get(&server_id) {
+                    Some(health) if health.reachable => "§aup".to_string(),
+                    Some(health) => {
+                        format!("§cdown since {}", health.checked_at.format("%H:%M:%S"))
+                    }
+                    None => "§7unchecked".to_string(),
+                };
+                source.send_message(format!(
+                    "§a[{}] §f{} §e({} players{}) {}",
+                    server_id,
+                    server_info.address(),
+                    counts.get(&server_id).copied().unwrap_or(0),
+                    if server_info.restricted {
+                        ", restricted"
+                    } else {
+                        ""
+                    },
+                    health
+                ));
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,162 @@
+use std::sync::Arc;
+
+use super::builtin::online_names;
+use crate::command::{CommandFuture, CommandRegistry, CommandSource, ProxyCommand, SuggestFuture};
+use crate::moderation::{
+    format_duration, parse_duration, Punishment, PunishmentKind, PunishmentTarget,
+};
+
+pub fn register_all(registry: &CommandRegistry) {
+    registry.register(Arc::new(PunishCommand(PunishmentKind::Ban)));
+    registry.register(Arc::new(PunishCommand(PunishmentKind::Mute)));
+    registry.register(Arc::new(PardonCommand(PunishmentKind::Ban)));
+    registry.register(Arc::new(PardonCommand(PunishmentKind::Mute)));
+}
+
+fn suggest_online<'a>(source: &'a CommandSource, args: &'a [&'a str]) -> SuggestFuture<'a> {
+    Box::pin(async move {
+        match args.len() {
+            1 => online_names(source).await,
+            _ => vec![],
+        }
+    })
+}
+
+/// `ban` and `mute`, which take the same arguments.
+struct PunishCommand(PunishmentKind);
+
+impl ProxyCommand for PunishCommand {
+    fn name(&self) -> &str {
+        match self.0 {
+            PunishmentKind::Ban => "ban",
+            PunishmentKind::Mute => "mute",
+        }
+    }
+
+    fn permission(&self) -> Option<&str> {
+        match self.0 {
+            PunishmentKind::Ban => Some("umbrella.command.ban"),
+            PunishmentKind::Mute => Some("umbrella.command.mute"),
+        }
+    }
+
+    fn usage(&self) -> &str {
+        match self.0 {
+            PunishmentKind::Ban => "/ban <player|uuid|ip[/prefix]> [duration] [reason]",
+            PunishmentKind::Mute => "/mute <player|uuid|ip[/prefix]> [duration] [reason]",
+        }
+    }
+
+    fn execute<'a>(&'a self, source: &'a CommandSource, args: &'a [&'a str]) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let (target, rest) = match args {
+                [target, rest @ ..] => (*target, rest),
+                _ => {
+                    source.send_message(format!("§cUsage: {}", self.usage()));
+                    return Ok(());
+                }
+            };
+            // the duration is optional, a reason starting with something like `5m` would be
+            // taken for one
+            let (duration, reason) = match rest.split_first() {
+                Some((first, reason)) => match parse_duration(first) {
+                    Some(duration) => (Some(duration), reason),
+                    None => (None, rest),
+                },
+                None => (None, rest),
+            };
+            let reason = if reason.is_empty() {
+                "No reason given.".to_string()
+            } else {
+                reason.join(" ")
+            };
+
+            let target = PunishmentTarget::resolve(&source.proxy_info, target).await;
+            let punishment = Punishment::new(
+                self.0,
+                target.clone(),
+                reason,
+                source.name().to_string(),
+                duration,
+            );
+            let affected = source
+                .proxy_info
+                .moderation
+                .punish(&source.proxy_info, punishment)
+                .await?;
+            source.send_message(format!(
+                "§e{} {} {} ({} online player(s) affected).",
+                match self.0 {
+                    PunishmentKind::Ban => "Banned",
+                    PunishmentKind::Mute => "Muted",
+                },
+                target,
+                duration.map_or_else(
+                    || "permanently".to_string(),
+                    |duration| { format!("for {}", format_duration(duration)) }
+                ),
+                affected
+            ));
+            Ok(())
+        })
+    }
+
+    fn suggest<'a>(&'a self, source: &'a CommandSource, args: &'a [&'a str]) -> SuggestFuture<'a> {
+        suggest_online(source, args)
+    }
+}
+
+/// `unban` and `unmute`.
+struct PardonCommand(PunishmentKind);
+
+impl ProxyCommand for PardonCommand {
+    fn name(&self) -> &str {
+        match self.0 {
+            PunishmentKind::Ban => "unban",
+            PunishmentKind::Mute => "unmute",
+        }
+    }
+
+    fn permission(&self) -> Option<&str> {
+        match self.0 {
+            PunishmentKind::Ban => Some("umbrella.command.unban"),
+            PunishmentKind::Mute => Some("umbrella.command.unmute"),
+        }
+    }
+
+    fn usage(&self) -> &str {
+        match self.0 {
+            PunishmentKind::Ban => "/unban <player|uuid|ip[/prefix]>",
+            PunishmentKind::Mute => "/unmute <player|uuid|ip[/prefix]>",
+        }
+    }
+
+    fn execute<'a>(&'a self, source: &'a CommandSource, args: &'a [&'a str]) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let target = match args {
+                [target] => *target,
+                _ => {
+                    source.send_message(format!("§cUsage: {}", self.usage()));
+                    return Ok(());
+                }
+            };
+            let store = source.proxy_info.moderation.store();
+            // an online name may have been punished by uuid or by name
+            let resolved = PunishmentTarget::resolve(&source.proxy_info, target).await;
+            let mut removed = store.remove(self.0, &resolved)?;
+            if let PunishmentTarget::Uuid(_) = resolved {
+                removed |= store.remove(self.0, &PunishmentTarget::parse(target))?;
+            }
+            if removed {
+                source.send_message(format!("§eRemoved the {} for {}.", self.0.as_str(), target));
+            } else {
+                source.send_message(format!("§c{} has no active {}.", target, self.0.as_str()));
+            }
+            Ok(())
+        })
+    }
+
+    fn suggest<'a>(&'a self, source: &'a CommandSource, args: &'a [&'a str]) -> SuggestFuture<'a> {
+        suggest_online(source, args)
+    }
+}
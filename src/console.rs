@@ -0,0 +1,158 @@
+use std::sync::{Arc, Mutex as StdMutex};
+
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, ExternalPrinter, Helper};
+use tokio::runtime::Handle;
+
+use crate::command::CommandSource;
+use crate::ProxyInfo;
+
+const HISTORY_FILE: &str = "./.console_history";
+const PROMPT: &str = "> ";
+
+/// Where log lines go. Once the console is running they are printed above the prompt instead
+/// of over whatever is being typed.
+#[derive(Clone, Default)]
+pub struct ConsoleOutput {
+    printer: Arc<StdMutex<Option<Box<dyn ExternalPrinter + Send>>>>,
+}
+
+impl ConsoleOutput {
+    pub fn print(&self, line: String) {
+        match self.printer.lock().unwrap().as_mut() {
+            Some(printer) => {
+                if printer.print(line).is_err() {
+                    eprintln!("Failed to print to the console.");
+                }
+            }
+            None => println!("{}", line),
+        }
+    }
+
+    pub fn into_fern(self) -> fern::Output {
+        fern::Output::call(move |record| self.print(record.args().to_string()))
+    }
+}
+
+struct ConsoleHelper {
+    proxy_info: Arc<ProxyInfo>,
+    handle: Handle,
+}
+
+impl Completer for ConsoleHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map_or(0, |index| index + 1);
+        let source = console_source(&self.proxy_info);
+        let candidates = if start == 0 {
+            let typed = line.to_lowercase();
+            self.proxy_info
+                .commands
+                .visible_labels(&source)
+                .into_iter()
+                .filter(|label| label.starts_with(&typed))
+                .collect()
+        } else {
+            self.handle
+                .block_on(self.proxy_info.commands.suggest(&source, line))
+        };
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ConsoleHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ConsoleHelper {}
+
+impl Validator for ConsoleHelper {}
+
+impl Helper for ConsoleHelper {}
+
+fn console_source(proxy_info: &Arc<ProxyInfo>) -> CommandSource {
+    CommandSource {
+        proxy_info: proxy_info.clone(),
+        player: None,
+    }
+}
+
+/// Reads commands from stdin until it closes or the proxy shuts down, Ctrl-C shuts it down
+/// from here. Commands run with the console's full permissions. This is a plain thread
+/// rather than a blocking task so a pending read doesn't hold up runtime shutdown.
+pub fn spawn(proxy_info: Arc<ProxyInfo>, output: ConsoleOutput) -> anyhow::Result<()> {
+    let handle = Handle::current();
+    std::thread::Builder::new()
+        .name("console".to_string())
+        .spawn(move || {
+            if let Err(err) = run(proxy_info, output, handle) {
+                log::error!("Console stopped: {}", err);
+            }
+        })?;
+    Ok(())
+}
+
+fn run(proxy_info: Arc<ProxyInfo>, output: ConsoleOutput, handle: Handle) -> anyhow::Result<()> {
+    let mut editor = Editor::<ConsoleHelper>::new()?;
+    editor.set_helper(Some(ConsoleHelper {
+        proxy_info: proxy_info.clone(),
+        handle: handle.clone(),
+    }));
+    match editor.create_external_printer() {
+        Ok(printer) => *output.printer.lock().unwrap() = Some(Box::new(printer)),
+        Err(err) => log::warn!("Log output may overwrite console input: {}", err),
+    }
+    // there's no history on the first start
+    let _ = editor.load_history(HISTORY_FILE);
+
+    let source = console_source(&proxy_info);
+    loop {
+        match editor.readline(PROMPT) {
+            Ok(line) => {
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                editor.add_history_entry(line);
+                let line = line.strip_prefix('/').unwrap_or(line);
+                if !handle.block_on(proxy_info.commands.dispatch(&source, line)) {
+                    log::info!("Unknown command. Press tab to list the available commands.");
+                }
+            }
+            Err(ReadlineError::Interrupted) => {
+                proxy_info.shutdown();
+                break;
+            }
+            // stdin is closed or redirected when running as a service, which is no reason to
+            // stop the proxy
+            Err(ReadlineError::Eof) => {
+                log::info!("Console input closed, commands can't be entered anymore.");
+                break;
+            }
+            Err(err) => {
+                output.printer.lock().unwrap().take();
+                return Err(err.into());
+            }
+        }
+        if *proxy_info.shutdown_requested().borrow() {
+            break;
+        }
+    }
+
+    output.printer.lock().unwrap().take();
+    if let Err(err) = editor.save_history(HISTORY_FILE) {
+        log::warn!("Failed to save console history: {}", err);
+    }
+    Ok(())
+}
@@ -0,0 +1,536 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context};
+use tokio::sync::Mutex;
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::TokioAsyncResolver;
+
+use crate::cfg::{DnsConfig, DnsRefresh, DEFAULT_PORT};
+
+pub type LookupFuture<'a, T> = Pin<Box<dyn Future<Output = anyhow::Result<Answer<T>>> + Send + 'a>>;
+
+/// Records from a lookup, cached for `ttl` at most.
+pub struct Answer<T> {
+    pub records: Vec<T>,
+    pub ttl: Duration,
+}
+
+pub struct SrvRecord {
+    pub target: String,
+    pub port: u16,
+    pub priority: u16,
+    pub weight: u16,
+}
+
+/// Where lookups go, see `Umbrella::resolver` for replacing it.
+pub trait Resolver: Send + Sync {
+    /// No records is a valid answer here, not an error.
+    fn lookup_srv<'a>(&'a self, name: &'a str) -> LookupFuture<'a, SrvRecord>;
+
+    fn lookup_ip<'a>(&'a self, host: &'a str) -> LookupFuture<'a, IpAddr>;
+}
+
+/// Resolves through the servers in the system configuration.
+pub struct SystemResolver(TokioAsyncResolver);
+
+impl SystemResolver {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self(TokioAsyncResolver::tokio_from_system_conf()?))
+    }
+}
+
+fn ttl_until(valid_until: Instant) -> Duration {
+    valid_until.saturating_duration_since(Instant::now())
+}
+
+impl Resolver for SystemResolver {
+    fn lookup_srv<'a>(&'a self, name: &'a str) -> LookupFuture<'a, SrvRecord> {
+        Box::pin(async move {
+            let lookup = match self.0.srv_lookup(name).await {
+                Ok(lookup) => lookup,
+                Err(err) => match err.kind() {
+                    ResolveErrorKind::NoRecordsFound { negative_ttl, .. } => {
+                        return Ok(Answer {
+                            records: vec![],
+                            ttl: Duration::from_secs(negative_ttl.unwrap_or(0) as u64),
+                        })
+                    }
+                    _ => return Err(err.into()),
+                },
+            };
+            Ok(Answer {
+                records: lookup
+                    .iter()
+                    .map(|srv| SrvRecord {
+                        target: srv.target().to_utf8().trim_end_matches('.').to_string(),
+                        port: srv.port(),
+                        priority: srv.priority(),
+                        weight: srv.weight(),
+                    })
+                    .collect(),
+                ttl: ttl_until(lookup.as_lookup().valid_until()),
+            })
+        })
+    }
+
+    fn lookup_ip<'a>(&'a self, host: &'a str) -> LookupFuture<'a, IpAddr> {
+        Box::pin(async move {
+            let lookup = self.0.lookup_ip(host).await?;
+            Ok(Answer {
+                records: lookup.iter().collect(),
+                ttl: ttl_until(lookup.valid_until()),
+            })
+        })
+    }
+}
+
+/// Resolves `host`, through its `_minecraft._tcp` SRV record if no port is given. Returns
+/// the address along with the lowest TTL involved.
+async fn lookup(
+    resolver: &dyn Resolver,
+    host: &str,
+    port: Option<u16>,
+) -> anyhow::Result<(SocketAddr, Duration)> {
+    let (target, port, srv_ttl) = match port {
+        Some(port) => (host.to_string(), port, None),
+        None => {
+            let srv = resolver
+                .lookup_srv(&format!("_minecraft._tcp.{}", host))
+                .await?;
+            // weights are meant for spreading load, but every lookup is cached anyway
+            match srv
+                .records
+                .into_iter()
+                .min_by_key(|record| (record.priority, Reverse(record.weight)))
+            {
+                Some(record) => (record.target, record.port, Some(srv.ttl)),
+                None => (host.to_string(), DEFAULT_PORT, None),
+            }
+        }
+    };
+    let ips = resolver.lookup_ip(&target).await?;
+    let ip = ips
+        .records
+        .first()
+        .copied()
+        .ok_or_else(|| anyhow!("{} has no addresses", target))?;
+    let ttl = srv_ttl.map_or(ips.ttl, |srv_ttl| srv_ttl.min(ips.ttl));
+    Ok((SocketAddr::new(ip, port), ttl))
+}
+
+#[derive(Default)]
+struct Entry {
+    /// The last successful answer and when it expires.
+    answer: Option<(SocketAddr, Instant)>,
+    /// The last failure and until when it is remembered.
+    failure: Option<(String, Instant)>,
+    refreshing: bool,
+}
+
+enum Cached {
+    Usable(SocketAddr),
+    /// Expired, but still allowed to be used while a new answer is looked up.
+    Stale(SocketAddr),
+    Failed(String),
+    Missing,
+}
+
+/// Lookups for one host and port. Only one of them runs at a time, connections arriving in
+/// the meantime wait for its answer instead of asking again.
+#[derive(Default)]
+struct Slot {
+    entry: StdMutex<Entry>,
+    lookup: Mutex<()>,
+}
+
+impl Slot {
+    fn cached(&self, config: &DnsConfig) -> Cached {
+        let now = Instant::now();
+        let entry = self.entry.lock().unwrap();
+        let failed = entry
+            .failure
+            .as_ref()
+            .filter(|(_, until)| *until > now)
+            .map(|(err, _)| err.clone());
+        if let Some((addr, expires_at)) = entry.answer {
+            if expires_at > now {
+                return Cached::Usable(addr);
+            }
+            if expires_at + Duration::from_secs(config.stale_seconds) > now {
+                // asking again right after a failure only adds to the DNS server's load
+                return if failed.is_some() {
+                    Cached::Usable(addr)
+                } else {
+                    Cached::Stale(addr)
+                };
+            }
+        }
+        match failed {
+            Some(err) => Cached::Failed(err),
+            None => Cached::Missing,
+        }
+    }
+
+    /// Looks up and stores a new answer. If that fails, an answer still within the stale
+    /// period is used instead.
+    async fn refresh(
+        &self,
+        resolver: &dyn Resolver,
+        config: &DnsConfig,
+        host: &str,
+        port: Option<u16>,
+    ) -> anyhow::Result<SocketAddr> {
+        let result = tokio::time::timeout(
+            Duration::from_millis(config.timeout_ms),
+            lookup(resolver, host, port),
+        )
+        .await
+        .context("timed out")
+        .and_then(|result| result);
+        let now = Instant::now();
+        let mut entry = self.entry.lock().unwrap();
+        match result {
+            Ok((addr, ttl)) => {
+                let ttl = ttl
+                    .max(Duration::from_secs(config.min_ttl_seconds))
+                    .min(Duration::from_secs(config.max_ttl_seconds));
+                entry.answer = Some((addr, now + ttl));
+                entry.failure = None;
+                Ok(addr)
+            }
+            Err(err) => {
+                let err = format!("Failed to resolve {}: {:#}", host, err);
+                entry.failure = Some((
+                    err.clone(),
+                    now + Duration::from_secs(config.failure_ttl_seconds),
+                ));
+                match entry.answer {
+                    Some((addr, expires_at))
+                        if expires_at + Duration::from_secs(config.stale_seconds) > now =>
+                    {
+                        log::warn!("{}, using the previous answer {}.", err, addr);
+                        Ok(addr)
+                    }
+                    _ => Err(anyhow!(err)),
+                }
+            }
+        }
+    }
+}
+
+/// Cache in front of the `Resolver` for backend addresses, following the record TTLs.
+pub struct Dns {
+    resolver: Arc<dyn Resolver>,
+    slots: StdMutex<HashMap<(String, Option<u16>), Arc<Slot>>>,
+}
+
+impl Dns {
+    pub fn new(resolver: Arc<dyn Resolver>) -> Self {
+        Self {
+            resolver,
+            slots: StdMutex::new(HashMap::new()),
+        }
+    }
+
+    fn slot(&self, host: &str, port: Option<u16>) -> Arc<Slot> {
+        self.slots
+            .lock()
+            .unwrap()
+            .entry((host.to_string(), port))
+            .or_default()
+            .clone()
+    }
+
+    /// The address to connect to for `host`, see `lookup` for how it's resolved. IP addresses
+    /// are returned right away.
+    pub async fn resolve(
+        &self,
+        config: &DnsConfig,
+        host: &str,
+        port: Option<u16>,
+    ) -> anyhow::Result<SocketAddr> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(SocketAddr::new(ip, port.unwrap_or(DEFAULT_PORT)));
+        }
+        let slot = self.slot(host, port);
+        match slot.cached(config) {
+            Cached::Usable(addr) => return Ok(addr),
+            Cached::Stale(addr) if config.refresh == DnsRefresh::Background => {
+                self.refresh_in_background(slot, *config, host, port);
+                return Ok(addr);
+            }
+            Cached::Failed(err) => return Err(anyhow!(err)),
+            Cached::Stale(_) | Cached::Missing => {}
+        }
+
+        let _lookup = slot.lookup.lock().await;
+        // whoever held the lock before may have just stored an answer
+        match slot.cached(config) {
+            Cached::Usable(addr) => Ok(addr),
+            Cached::Failed(err) => Err(anyhow!(err)),
+            Cached::Stale(_) | Cached::Missing => {
+                slot.refresh(&*self.resolver, config, host, port).await
+            }
+        }
+    }
+
+    fn refresh_in_background(
+        &self,
+        slot: Arc<Slot>,
+        config: DnsConfig,
+        host: &str,
+        port: Option<u16>,
+    ) {
+        {
+            let mut entry = slot.entry.lock().unwrap();
+            if entry.refreshing {
+                return;
+            }
+            entry.refreshing = true;
+        }
+        let resolver = self.resolver.clone();
+        let host = host.to_string();
+        tokio::spawn(async move {
+            let _lookup = slot.lookup.lock().await;
+            // `refresh` remembers the failure and logs falling back to the old answer
+            let _ = slot.refresh(&*resolver, &config, &host, port).await;
+            slot.entry.lock().unwrap().refreshing = false;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers from fixed records and counts the lookups made.
+    #[derive(Default)]
+    struct StubResolver {
+        srv: Vec<(&'static str, u16, u16, u16)>,
+        srv_ttl: Duration,
+        ips: StdMutex<HashMap<&'static str, IpAddr>>,
+        ip_ttl: Duration,
+        lookups: StdMutex<u32>,
+    }
+
+    impl StubResolver {
+        fn new(host: &'static str, ip: &str) -> Self {
+            let resolver = Self {
+                srv_ttl: Duration::from_secs(300),
+                ip_ttl: Duration::from_secs(300),
+                ..Self::default()
+            };
+            resolver.set_ip(host, ip);
+            resolver
+        }
+
+        fn set_ip(&self, host: &'static str, ip: &str) {
+            self.ips.lock().unwrap().insert(host, ip.parse().unwrap());
+        }
+
+        fn lookups(&self) -> u32 {
+            *self.lookups.lock().unwrap()
+        }
+    }
+
+    impl Resolver for StubResolver {
+        fn lookup_srv<'a>(&'a self, _name: &'a str) -> LookupFuture<'a, SrvRecord> {
+            let records = self
+                .srv
+                .iter()
+                .map(|&(target, port, priority, weight)| SrvRecord {
+                    target: target.to_string(),
+                    port,
+                    priority,
+                    weight,
+                })
+                .collect();
+            Box::pin(async move {
+                Ok(Answer {
+                    records,
+                    ttl: self.srv_ttl,
+                })
+            })
+        }
+
+        fn lookup_ip<'a>(&'a self, host: &'a str) -> LookupFuture<'a, IpAddr> {
+            *self.lookups.lock().unwrap() += 1;
+            let ip = self.ips.lock().unwrap().get(host).copied();
+            Box::pin(async move {
+                let ip = ip.ok_or_else(|| anyhow!("no such host"))?;
+                Ok(Answer {
+                    records: vec![ip],
+                    ttl: self.ip_ttl,
+                })
+            })
+        }
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    fn expire(dns: &Dns, host: &str, port: Option<u16>) {
+        let slot = dns.slot(host, port);
+        let mut entry = slot.entry.lock().unwrap();
+        let (addr, _) = entry.answer.unwrap();
+        entry.answer = Some((addr, Instant::now() - Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn srv_records_pick_the_lowest_priority() {
+        let mut resolver = StubResolver::new("b.example.com", "10.0.0.2");
+        resolver.srv = vec![
+            ("a.example.com", 25570, 10, 100),
+            ("c.example.com", 25571, 5, 1),
+            ("b.example.com", 25572, 5, 10),
+        ];
+        let dns = Dns::new(Arc::new(resolver));
+        let config = DnsConfig::default();
+        let resolved = dns.resolve(&config, "example.com", None).await.unwrap();
+        assert_eq!(resolved, addr("10.0.0.2:25572"));
+    }
+
+    #[tokio::test]
+    async fn hosts_without_srv_records_use_the_default_port() {
+        let dns = Dns::new(Arc::new(StubResolver::new("example.com", "10.0.0.1")));
+        let config = DnsConfig::default();
+        let resolved = dns.resolve(&config, "example.com", None).await.unwrap();
+        assert_eq!(
+            resolved,
+            SocketAddr::new("10.0.0.1".parse().unwrap(), DEFAULT_PORT)
+        );
+        let resolved = dns
+            .resolve(&config, "example.com", Some(25570))
+            .await
+            .unwrap();
+        assert_eq!(resolved, addr("10.0.0.1:25570"));
+    }
+
+    #[tokio::test]
+    async fn ip_addresses_skip_lookups() {
+        let resolver = Arc::new(StubResolver::default());
+        let dns = Dns::new(resolver.clone());
+        let config = DnsConfig::default();
+        let resolved = dns.resolve(&config, "::1", Some(25570)).await.unwrap();
+        assert_eq!(resolved, addr("[::1]:25570"));
+        assert_eq!(resolver.lookups(), 0);
+    }
+
+    #[tokio::test]
+    async fn ttls_are_clamped_and_cached() {
+        let mut resolver = StubResolver::new("example.com", "10.0.0.1");
+        resolver.ip_ttl = Duration::from_secs(1);
+        let resolver = Arc::new(resolver);
+        let dns = Dns::new(resolver.clone());
+        let config = DnsConfig::default();
+        dns.resolve(&config, "example.com", Some(25565))
+            .await
+            .unwrap();
+        dns.resolve(&config, "example.com", Some(25565))
+            .await
+            .unwrap();
+        assert_eq!(resolver.lookups(), 1);
+
+        let (_, expires_at) = dns
+            .slot("example.com", Some(25565))
+            .entry
+            .lock()
+            .unwrap()
+            .answer
+            .unwrap();
+        let ttl = ttl_until(expires_at);
+        let min_ttl = Duration::from_secs(config.min_ttl_seconds);
+        assert!(ttl <= min_ttl && ttl > min_ttl - Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn stale_answers_are_served_while_refreshing() {
+        let resolver = Arc::new(StubResolver::new("example.com", "10.0.0.1"));
+        let dns = Dns::new(resolver.clone());
+        let config = DnsConfig::default();
+        dns.resolve(&config, "example.com", Some(25565))
+            .await
+            .unwrap();
+        expire(&dns, "example.com", Some(25565));
+        resolver.set_ip("example.com", "10.0.0.2");
+
+        let resolved = dns
+            .resolve(&config, "example.com", Some(25565))
+            .await
+            .unwrap();
+        assert_eq!(resolved, addr("10.0.0.1:25565"));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let resolved = dns
+            .resolve(&config, "example.com", Some(25565))
+            .await
+            .unwrap();
+        assert_eq!(resolved, addr("10.0.0.2:25565"));
+        assert_eq!(resolver.lookups(), 2);
+    }
+
+    #[tokio::test]
+    async fn blocking_refreshes_wait_for_the_new_answer() {
+        let resolver = Arc::new(StubResolver::new("example.com", "10.0.0.1"));
+        let dns = Dns::new(resolver.clone());
+        let config = DnsConfig {
+            refresh: DnsRefresh::Blocking,
+            ..DnsConfig::default()
+        };
+        dns.resolve(&config, "example.com", Some(25565))
+            .await
+            .unwrap();
+        expire(&dns, "example.com", Some(25565));
+        resolver.set_ip("example.com", "10.0.0.2");
+        let resolved = dns
+            .resolve(&config, "example.com", Some(25565))
+            .await
+            .unwrap();
+        assert_eq!(resolved, addr("10.0.0.2:25565"));
+    }
+
+    #[tokio::test]
+    async fn failures_are_cached() {
+        let resolver = Arc::new(StubResolver::default());
+        let dns = Dns::new(resolver.clone());
+        let config = DnsConfig::default();
+        assert!(dns
+            .resolve(&config, "example.com", Some(25565))
+            .await
+            .is_err());
+        assert!(dns
+            .resolve(&config, "example.com", Some(25565))
+            .await
+            .is_err());
+        assert_eq!(resolver.lookups(), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_refreshes_keep_the_old_answer() {
+        let resolver = Arc::new(StubResolver::new("example.com", "10.0.0.1"));
+        let dns = Dns::new(resolver.clone());
+        let config = DnsConfig {
+            refresh: DnsRefresh::Blocking,
+            ..DnsConfig::default()
+        };
+        dns.resolve(&config, "example.com", Some(25565))
+            .await
+            .unwrap();
+        expire(&dns, "example.com", Some(25565));
+        resolver.ips.lock().unwrap().clear();
+        for _ in 0..2 {
+            let resolved = dns
+                .resolve(&config, "example.com", Some(25565))
+                .await
+                .unwrap();
+            assert_eq!(resolved, addr("10.0.0.1:25565"));
+        }
+        assert_eq!(resolver.lookups(), 2);
+    }
+}
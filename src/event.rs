@@ -0,0 +1,302 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+
+use mcprotocol::chat::Chat;
+use mcprotocol::protocol::handshaking::sb::Handshake;
+use mcprotocol::status::StatusBuilder;
+
+use crate::cfg::ServerInfo;
+use crate::player::ClientInfo;
+
+pub trait Event: Send + 'static {}
+
+pub trait Cancellable {
+    fn is_cancelled(&self) -> bool;
+
+    fn set_cancelled(&mut self, cancelled: bool);
+}
+
+/// Handlers run from `First` to `Last`; handlers with the same priority run in the order
+/// they were registered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EventPriority {
+    First,
+    Early,
+    Normal,
+    Late,
+    Last,
+}
+
+pub type EventFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+type Handler<E> = dyn for<'a> Fn(&'a mut E) -> EventFuture<'a> + Send + Sync;
+
+struct RegisteredHandler {
+    priority: EventPriority,
+    // always an `Arc<Handler<E>>` for the `TypeId` it is stored under
+    handler: Arc<dyn Any + Send + Sync>,
+}
+
+#[derive(Default)]
+pub struct EventBus {
+    handlers: RwLock<HashMap<TypeId, Vec<RegisteredHandler>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<E, F>(&self, priority: EventPriority, handler: F)
+    where
+        E: Event,
+        F: for<'a> Fn(&'a mut E) -> EventFuture<'a> + Send + Sync + 'static,
+    {
+        let handler: Arc<Handler<E>> = Arc::new(handler);
+        let mut handlers = self.handlers.write().unwrap();
+        let handlers = handlers.entry(TypeId::of::<E>()).or_default();
+        handlers.push(RegisteredHandler {
+            priority,
+            handler: Arc::new(handler),
+        });
+        // stable, so registration order is kept within a priority
+        handlers.sort_by_key(|registered| registered.priority);
+    }
+
+    /// Runs every handler for the event in priority order and hands the event back.
+    /// Handlers still run after an event is cancelled so later ones may un-cancel it.
+    pub async fn fire<E: Event>(&self, mut event: E) -> E {
+        let handlers: Vec<Arc<Handler<E>>> =
+            match self.handlers.read().unwrap().get(&TypeId::of::<E>()) {
+                Some(handlers) => handlers
+                    .iter()
+                    .filter_map(|registered| {
+                        registered
+                            .handler
+                            .downcast_ref::<Arc<Handler<E>>>()
+                            .cloned()
+                    })
+                    .collect(),
+                None => return event,
+            };
+        for handler in handlers {
+            handler(&mut event).await;
+        }
+        event
+    }
+}
+
+macro_rules! cancellable {
+    ($event:ty) => {
+        impl Cancellable for $event {
+            fn is_cancelled(&self) -> bool {
+                self.cancelled
+            }
+
+            fn set_cancelled(&mut self, cancelled: bool) {
+                self.cancelled = cancelled;
+            }
+        }
+    };
+}
+
+/// Fired once the client has authenticated, before it is registered or connected anywhere.
+/// Cancelling disconnects the client with `reason`.
+pub struct PreLoginEvent {
+    pub client_info: ClientInfo,
+    pub reason: Chat,
+    pub cancelled: bool,
+}
+
+impl Event for PreLoginEvent {}
+cancellable!(PreLoginEvent);
+
+/// Fired after the player has been registered, before connecting to the initial server.
+/// Cancelling disconnects the player with `reason`.
+pub struct PostLoginEvent {
+    pub client_info: ClientInfo,
+    pub session_id: u64,
+    pub reason: Chat,
+    pub cancelled: bool,
+}
+
+impl Event for PostLoginEvent {}
+cancellable!(PostLoginEvent);
+
+/// Fired for every status request, handlers may change the response.
+pub struct ProxyPingEvent {
+    pub handshake: Handshake,
+    pub status: StatusBuilder,
+}
+
+impl Event for ProxyPingEvent {}
+
+/// Fired before connecting a player to a backend, `target` may be replaced.
+pub struct ServerPreConnectEvent {
+    pub client_info: ClientInfo,
+    pub session_id: u64,
+    pub current_server: Option<String>,
+    pub target_id: String,
+    pub target: ServerInfo,
+    pub cancelled: bool,
+}
+
+impl Event for ServerPreConnectEvent {}
+cancellable!(ServerPreConnectEvent);
+
+/// Fired once a player's connection has moved to a new backend. Not cancellable, use
+/// `ServerPreConnectEvent` to stop the switch.
+pub struct ServerConnectedEvent {
+    pub client_info: ClientInfo,
+    pub session_id: u64,
+    pub previous_server: Option<String>,
+    pub server_id: String,
+}
+
+impl Event for ServerConnectedEvent {}
+
+/// Fired when a backend disconnects a player. By default the player is moved to a fallback
+/// server, or to `redirect` if a handler sets it. Cancelling disconnects the player from the
+/// proxy with `reason` instead.
+pub struct ServerKickEvent {
+    pub client_info: ClientInfo,
+    pub session_id: u64,
+    pub server_id: String,
+    pub reason: Chat,
+    pub redirect: Option<String>,
+    pub cancelled: bool,
+}
+
+impl Event for ServerKickEvent {}
+cancellable!(ServerKickEvent);
+
+/// Fired for chat messages and commands sent by the client. Cancelling drops the message.
+/// A changed `message` is used when running proxy commands, signed chat is always forwarded
+/// to the backend as the client sent it.
+pub struct ChatEvent {
+    pub client_info: ClientInfo,
+    pub session_id: u64,
+    /// Commands are given without their leading slash.
+    pub message: String,
+    pub is_command: bool,
+    pub cancelled: bool,
+}
+
+impl Event for ChatEvent {}
+cancellable!(ChatEvent);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PluginMessageDirection {
+    ToServer,
+    ToClient,
+}
+
+/// Fired for plugin messages passing through the proxy. Cancelling drops the message.
+pub struct PluginMessageEvent {
+    pub client_info: ClientInfo,
+    pub session_id: u64,
+    pub direction: PluginMessageDirection,
+    pub channel: String,
+    pub data: Vec<u8>,
+    pub cancelled: bool,
+}
+
+impl Event for PluginMessageEvent {}
+cancellable!(PluginMessageEvent);
+
+/// Fired after a player has left the proxy and been removed from the registry.
+pub struct DisconnectEvent {
+    pub client_info: ClientInfo,
+    pub session_id: u64,
+}
+
+impl Event for DisconnectEvent {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct TestEvent {
+        seen: Vec<&'static str>,
+        cancelled: bool,
+    }
+
+    impl Event for TestEvent {}
+    cancellable!(TestEvent);
+
+    /// Records the handler's name, then sets the cancelled flag to `cancel` if given.
+    fn record(bus: &EventBus, priority: EventPriority, name: &'static str, cancel: Option<bool>) {
+        bus.register(priority, move |event: &mut TestEvent| {
+            Box::pin(async move {
+                event.seen.push(name);
+                if let Some(cancelled) = cancel {
+                    event.set_cancelled(cancelled);
+                }
+            })
+        });
+    }
+
+    #[tokio::test]
+    async fn runs_handlers_by_priority_then_registration() {
+        let bus = EventBus::new();
+        record(&bus, EventPriority::Last, "last", None);
+        record(&bus, EventPriority::Normal, "normal", None);
+        record(&bus, EventPriority::First, "first", None);
+        record(&bus, EventPriority::Normal, "normal again", None);
+        record(&bus, EventPriority::Early, "early", None);
+        let event = bus.fire(TestEvent::default()).await;
+        assert_eq!(
+            event.seen,
+            ["first", "early", "normal", "normal again", "last"]
+        );
+    }
+
+    #[tokio::test]
+    async fn later_handlers_see_the_cancellation() {
+        let bus = EventBus::new();
+        record(&bus, EventPriority::Early, "cancel", Some(true));
+        let seen_cancelled = Arc::new(RwLock::new(None));
+        let seen = seen_cancelled.clone();
+        bus.register(EventPriority::Late, move |event: &mut TestEvent| {
+            let seen = seen.clone();
+            Box::pin(async move {
+                *seen.write().unwrap() = Some(event.is_cancelled());
+            })
+        });
+        let event = bus.fire(TestEvent::default()).await;
+        assert!(event.is_cancelled());
+        assert_eq!(*seen_cancelled.read().unwrap(), Some(true));
+    }
+
+    #[tokio::test]
+    async fn handlers_after_a_cancel_still_run_and_may_uncancel() {
+        let bus = EventBus::new();
+        record(&bus, EventPriority::First, "cancel", Some(true));
+        record(&bus, EventPriority::Normal, "watch", None);
+        record(&bus, EventPriority::Last, "uncancel", Some(false));
+        let event = bus.fire(TestEvent::default()).await;
+        assert_eq!(event.seen, ["cancel", "watch", "uncancel"]);
+        assert!(!event.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn events_without_handlers_come_back_unchanged() {
+        let bus = EventBus::new();
+        record(&bus, EventPriority::Normal, "other", None);
+        let event = bus
+            .fire(DisconnectEvent {
+                client_info: crate::player::test_client_info(
+                    "Steve",
+                    uuid::Uuid::nil(),
+                    "10.0.0.1:25565".parse().unwrap(),
+                ),
+                session_id: 3,
+            })
+            .await;
+        assert_eq!(event.session_id, 3);
+    }
+}
@@ -0,0 +1,206 @@
+use std::time::Duration;
+
+use mcprotocol::protocol::handshaking::sb::{Handshake, NextState};
+use tokio::time::Instant;
+
+use crate::net::codec::{self, ReadError};
+use crate::net::Stream;
+
+/// Longest handshake vanilla sends: the packet id, protocol version, a 255 character address
+/// at up to four bytes a character, the port and the next state. Var ints are counted at
+/// their longest.
+const MAX_HANDSHAKE_LEN: usize = 1 + 5 + 5 + 255 * 4 + 2 + 5;
+/// The handshake and its length.
+const PEEK_LIMIT: usize = 5 + MAX_HANDSHAKE_LEN;
+const PEEK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Intent {
+    Status,
+    /// Includes transfers, which log in like a regular join.
+    Login,
+    /// A pre-1.7 server list ping, see `legacy::answer`.
+    LegacyPing,
+    /// The handshake couldn't be read in time or isn't one we recognize.
+    Unknown,
+}
+
+/// What the client connected for and the handshake it sent, unless it's a legacy ping or a
+/// login with more in its address than can be read ahead.
+pub struct Peeked {
+    pub intent: Intent,
+    pub handshake: Option<Handshake>,
+}
+
+impl Peeked {
+    fn unknown() -> Self {
+        Self {
+            intent: Intent::Unknown,
+            handshake: None,
+        }
+    }
+}
+
+fn parse_handshake(mut data: &[u8]) -> Result<Peeked, ReadError> {
+    // like the vanilla server, a leading 0xFE is always taken for a legacy ping
+    if data.first() == Some(&0xFE) {
+        return Ok(Peeked {
+            intent: Intent::LegacyPing,
+            handshake: None,
+        });
+    }
+    let len = codec::read_var_int(&mut data)?;
+    if len <= 0 {
+        return Err(ReadError::Invalid);
+    }
+    // only logins carry more than vanilla's address, with forwarding data from a proxy in front
+    if len as usize > MAX_HANDSHAKE_LEN {
+        return match codec::read_var_int(&mut data)? {
+            0 => Ok(Peeked {
+                intent: Intent::Login,
+                handshake: None,
+            }),
+            _ => Err(ReadError::Invalid),
+        };
+    }
+    let mut data = data.get(..len as usize).ok_or(ReadError::Incomplete)?;
+    // within the frame, running out of data means the handshake is malformed
+    let invalid = |_| ReadError::Invalid;
+    let packet_id = codec::read_var_int(&mut data).map_err(invalid)?;
+    if packet_id != 0 {
+        return Err(ReadError::Invalid);
+    }
+    let protocol_version = codec::read_var_int(&mut data).map_err(invalid)?;
+    let address_len = codec::read_var_int(&mut data).map_err(invalid)?;
+    // forwarded addresses can be longer than the 255 characters vanilla allows
+    let address = data
+        .get(..address_len.max(0) as usize)
+        .ok_or(ReadError::Invalid)?;
+    let server_address = std::str::from_utf8(address)
+        .map_err(|_| ReadError::Invalid)?
+        .to_string();
+    let mut data = &data[address.len()..];
+    let port = data.get(..2).ok_or(ReadError::Invalid)?;
+    let server_port = u16::from_be_bytes([port[0], port[1]]);
+    data = &data[2..];
+    let (intent, next_state) = match codec::read_var_int(&mut data).map_err(invalid)? {
+        1 => (Intent::Status, NextState::Status),
+        2 | 3 => (Intent::Login, NextState::Login),
+        _ => return Ok(Peeked::unknown()),
+    };
+    Ok(Peeked {
+        intent,
+        handshake: Some(Handshake {
+            protocol_version,
+            server_address,
+            server_port,
+            next_state,
+        }),
+    })
+}
+
+/// Reads the client's handshake. What is read is kept in the stream, so the connection can
+/// still be handed to the protocol library as if nothing was read.
+pub async fn peek(stream: &mut Stream) -> Peeked {
+    let deadline = Instant::now() + PEEK_TIMEOUT;
+    loop {
+        match tokio::time::timeout_at(deadline, stream.read_ahead(PEEK_LIMIT)).await {
+            Ok(Ok(read)) if read > 0 => {}
+            _ => return Peeked::unknown(),
+        }
+        match parse_handshake(stream.read_ahead_bytes()) {
+            Ok(peeked) => return peeked,
+            Err(ReadError::Invalid) => return Peeked::unknown(),
+            Err(ReadError::Incomplete) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_intent(data: &[u8]) -> Result<Intent, ReadError> {
+        parse_handshake(data).map(|peeked| peeked.intent)
+    }
+
+    fn handshake(next_state: i32) -> Vec<u8> {
+        handshake_to("localhost", next_state)
+    }
+
+    fn handshake_to(address: &str, next_state: i32) -> Vec<u8> {
+        let mut packet = vec![0x00];
+        codec::write_var_int(&mut packet, 760);
+        codec::write_string(&mut packet, address);
+        packet.extend_from_slice(&25565u16.to_be_bytes());
+        codec::write_var_int(&mut packet, next_state);
+        let mut frame = vec![];
+        codec::write_var_int(&mut frame, packet.len() as i32);
+        frame.extend_from_slice(&packet);
+        frame
+    }
+
+    #[test]
+    fn reads_the_next_state() {
+        assert_eq!(parse_intent(&handshake(1)), Ok(Intent::Status));
+        assert_eq!(parse_intent(&handshake(2)), Ok(Intent::Login));
+        assert_eq!(parse_intent(&handshake(3)), Ok(Intent::Login));
+        assert_eq!(parse_intent(&handshake(7)), Ok(Intent::Unknown));
+    }
+
+    #[test]
+    fn keeps_the_handshake_fields() {
+        let handshake = parse_handshake(&handshake(2)).unwrap().handshake.unwrap();
+        assert_eq!(handshake.protocol_version, 760);
+        assert_eq!(handshake.server_address, "localhost");
+        assert_eq!(handshake.server_port, 25565);
+        assert!(parse_handshake(&[0xFE, 0x01]).unwrap().handshake.is_none());
+    }
+
+    #[test]
+    fn ignores_what_follows_the_handshake() {
+        let mut data = handshake(2);
+        data.extend_from_slice(&[0x05, 0x00, 0x01, 0x02]);
+        assert_eq!(parse_intent(&data), Ok(Intent::Login));
+    }
+
+    #[test]
+    fn waits_for_the_whole_handshake() {
+        let data = handshake(1);
+        for len in 0..data.len() {
+            assert_eq!(parse_intent(&data[..len]), Err(ReadError::Incomplete));
+        }
+    }
+
+    #[test]
+    fn reads_the_longest_vanilla_handshake() {
+        let data = handshake_to(&"\u{10FFFF}".repeat(255), 1);
+        assert!(data.len() <= PEEK_LIMIT);
+        assert_eq!(parse_intent(&data), Ok(Intent::Status));
+    }
+
+    #[test]
+    fn takes_longer_handshakes_for_forwarded_logins() {
+        let address = format!("localhost\01.2.3.4\0{}", "x".repeat(4096));
+        let data = handshake_to(&address, 2);
+        assert_eq!(parse_intent(&data[..PEEK_LIMIT]), Ok(Intent::Login));
+        assert_eq!(parse_intent(&[0xFF, 0x7F, 0x01]), Err(ReadError::Invalid));
+    }
+
+    #[test]
+    fn recognizes_legacy_pings() {
+        assert_eq!(parse_intent(&[0xFE, 0x01]), Ok(Intent::LegacyPing));
+    }
+
+    #[test]
+    fn rejects_other_packets() {
+        for data in [
+            &[0x00][..],
+            &[0x02, 0x01, 0x00],
+            &[0xFF, 0xFF, 0xFF, 0xFF, 0x7F],
+            &[0x80, 0x80, 0x80, 0x80, 0x80],
+        ] {
+            assert_eq!(parse_intent(data), Err(ReadError::Invalid));
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use tokio::task::JoinSet;
+
+use crate::net::Stream;
+use crate::ProxyInfo;
+
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy)]
+pub struct HealthStatus {
+    pub reachable: bool,
+    pub checked_at: DateTime<Local>,
+}
+
+/// Outcome of the most recent connection to each backend, whether a player's or a probe's.
+#[derive(Default)]
+pub struct ServerHealth {
+    servers: RwLock<HashMap<String, HealthStatus>>,
+}
+
+impl ServerHealth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, server_id: &str, reachable: bool) {
+        self.servers.write().unwrap().insert(
+            server_id.to_string(),
+            HealthStatus {
+                reachable,
+                checked_at: Local::now(),
+            },
+        );
+    }
+
+    /// `None` if the server hasn't been probed or connected to yet.
+    pub fn get(&self, server_id: &str) -> Option<HealthStatus> {
+        self.servers.read().unwrap().get(server_id).copied()
+    }
+
+    /// Servers which haven't been tried yet are assumed to be reachable, which only lasts
+    /// until the first probe.
+    pub fn is_reachable(&self, server_id: &str) -> bool {
+        self.get(server_id).map_or(true, |status| status.reachable)
+    }
+}
+
+/// Opens a connection to every server right away and then periodically, so servers nobody
+/// joins still get a current status.
+pub fn spawn_probe(proxy_info: Arc<ProxyInfo>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PROBE_INTERVAL);
+        loop {
+            interval.tick().await;
+            probe(&proxy_info).await;
+        }
+    });
+}
+
+async fn probe(proxy_info: &Arc<ProxyInfo>) {
+    let mut probes = JoinSet::new();
+    for (server_id, server_info) in proxy_info.config().servers.clone() {
+        let proxy_info = proxy_info.clone();
+        probes.spawn(async move {
+            let config = proxy_info.config();
+            let connect = Stream::connect(&proxy_info.dns, &config.dns, &server_info);
+            let reachable = matches!(
+                tokio::time::timeout(PROBE_TIMEOUT, connect).await,
+                Ok(Ok(_))
+            );
+            proxy_info.health.record(&server_id, reachable);
+        });
+    }
+    while probes.join_next().await.is_some() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_last_outcome() {
+        let health = ServerHealth::new();
+        assert!(health.get("lobby").is_none());
+        assert!(health.is_reachable("lobby"));
+        health.record("lobby", false);
+        assert!(!health.is_reachable("lobby"));
+        health.record("lobby", true);
+        assert!(health.get("lobby").unwrap().reachable);
+    }
+}
@@ -0,0 +1,303 @@
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::bail;
+use mcprotocol::chat::Chat;
+use mcprotocol::protocol::handshaking::sb::{Handshake, NextState};
+use mcprotocol::status::StatusBuilder;
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::net::Stream;
+
+/// Clients from 1.4 on send more right after the first byte, Beta clients send nothing else.
+const FOLLOW_UP_TIMEOUT: Duration = Duration::from_millis(100);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// Shown by 1.4+ clients, which always see the proxy as incompatible.
+const VERSION_NAME: &str = "Umbrella";
+/// Never matches a client, so the version name above is shown instead of the player count.
+const PROTOCOL_VERSION: i32 = 127;
+/// Handed to the status responder in place of a modern protocol version.
+pub const LEGACY_PROTOCOL_VERSION: i32 = -1;
+
+enum Ping {
+    /// Beta 1.8 to 1.3 only send `0xFE`.
+    Beta,
+    /// 1.4 and 1.5 send `0xFE 0x01`, 1.6 follows it with an `MC|PingHost` plugin message.
+    Extended { host: Option<(String, u16)> },
+}
+
+async fn read_string(stream: &mut Stream) -> anyhow::Result<String> {
+    let len = stream.read_u16().await? as usize;
+    if len > 255 {
+        bail!("string too long");
+    }
+    let mut units = Vec::with_capacity(len);
+    for _ in 0..len {
+        units.push(stream.read_u16().await?);
+    }
+    Ok(String::from_utf16_lossy(&units))
+}
+
+async fn read_ping_host(stream: &mut Stream) -> anyhow::Result<(String, u16)> {
+    if read_string(stream).await? != "MC|PingHost" {
+        bail!("not a ping host message");
+    }
+    let _data_len = stream.read_u16().await?;
+    let _protocol_version = stream.read_u8().await?;
+    let host = read_string(stream).await?;
+    let port = stream.read_i32().await?;
+    Ok((host, port as u16))
+}
+
+async fn read_ping(stream: &mut Stream) -> anyhow::Result<Ping> {
+    if stream.read_u8().await? != 0xFE {
+        bail!("not a legacy ping");
+    }
+    let follow_up = match tokio::time::timeout(FOLLOW_UP_TIMEOUT, stream.read_u8()).await {
+        Err(_) => return Ok(Ping::Beta),
+        Ok(byte) => byte?,
+    };
+    if follow_up != 0x01 {
+        bail!("unknown legacy ping");
+    }
+    let host = match tokio::time::timeout(FOLLOW_UP_TIMEOUT, stream.read_u8()).await {
+        Ok(Ok(0xFA)) => Some(read_ping_host(stream).await?),
+        _ => None,
+    };
+    Ok(Ping::Extended { host })
+}
+
+fn color_code(color: &str) -> Option<char> {
+    Some(match color {
+        "black" => '0',
+        "dark_blue" => '1',
+        "dark_green" => '2',
+        "dark_aqua" => '3',
+        "dark_red" => '4',
+        "dark_purple" => '5',
+        "gold" => '6',
+        "gray" => '7',
+        "dark_gray" => '8',
+        "blue" => '9',
+        "green" => 'a',
+        "aqua" => 'b',
+        "red" => 'c',
+        "light_purple" => 'd',
+        "yellow" => 'e',
+        "white" => 'f',
+        // hex colors have no legacy equivalent
+        _ => return None,
+    })
+}
+
+const FORMATS: [(&str, char); 5] = [
+    ("obfuscated", 'k'),
+    ("bold", 'l'),
+    ("strikethrough", 'm'),
+    ("underlined", 'n'),
+    ("italic", 'o'),
+];
+
+/// Appends a JSON text component, with children inheriting their parent's style.
+fn append_component(
+    out: &mut String,
+    component: &Value,
+    inherited: &serde_json::Map<String, Value>,
+) {
+    let object = match component {
+        Value::String(text) => {
+            out.push_str(text);
+            return;
+        }
+        Value::Array(components) => {
+            for component in components {
+                append_component(out, component, inherited);
+            }
+            return;
+        }
+        Value::Object(object) => object,
+        _ => return,
+    };
+    let mut style = inherited.clone();
+    for key in FORMATS.iter().map(|(key, _)| *key).chain(["color"]) {
+        if let Some(value) = object.get(key) {
+            style.insert(key.to_string(), value.clone());
+        }
+    }
+    let text = object
+        .get("text")
+        .or_else(|| object.get("translate"))
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    if !text.is_empty() {
+        if let Some(code) = style
+            .get("color")
+            .and_then(Value::as_str)
+            .and_then(color_code)
+        {
+            out.push('§');
+            out.push(code);
+        }
+        for (key, code) in FORMATS {
+            if style.get(key).and_then(Value::as_bool) == Some(true) {
+                out.push('§');
+                out.push(code);
+            }
+        }
+        out.push_str(text);
+    }
+    if let Some(extra) = object.get("extra") {
+        append_component(out, extra, &style);
+    }
+}
+
+/// Renders a chat component as section sign formatted text.
+pub fn to_legacy_text(chat: &Chat) -> String {
+    let mut out = String::new();
+    if let Ok(component) = serde_json::to_value(chat) {
+        append_component(&mut out, &component, &serde_json::Map::new());
+    }
+    out
+}
+
+pub(crate) fn strip_formatting(text: &str) -> String {
+    let mut out = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '§' {
+            chars.next();
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn render_response(ping: &Ping, version_name: &str, status: &StatusBuilder) -> String {
+    let motd = to_legacy_text(&status.description);
+    let (online, max) = (status.players.online, status.players.max);
+    match ping {
+        // fields are separated by section signs, so the MOTD can't have any
+        Ping::Beta => format!("{}§{}§{}", strip_formatting(&motd), online, max),
+        Ping::Extended { .. } => format!(
+            "§1\0{}\0{}\0{}\0{}\0{}",
+            PROTOCOL_VERSION, version_name, motd, online, max
+        ),
+    }
+}
+
+/// Answers a pre-1.7 server list ping with the status `respond` builds. It gets a handshake
+/// with `LEGACY_PROTOCOL_VERSION` and the address from 1.6 clients, if there was one.
+/// `version_name` replaces the proxy's own version text for clients that show one.
+pub async fn answer<F, Fut>(
+    stream: &mut Stream,
+    version_name: Option<&str>,
+    respond: F,
+) -> anyhow::Result<()>
+where
+    F: FnOnce(Handshake) -> Fut,
+    Fut: Future<Output = StatusBuilder>,
+{
+    let ping = tokio::time::timeout(READ_TIMEOUT, read_ping(stream)).await??;
+    let (server_address, server_port) = match &ping {
+        Ping::Extended {
+            host: Some((host, port)),
+        } => (host.clone(), *port),
+        _ => (String::new(), 0),
+    };
+    let status = respond(Handshake {
+        protocol_version: LEGACY_PROTOCOL_VERSION,
+        server_address,
+        server_port,
+        next_state: NextState::Status,
+    })
+    .await;
+
+    let units: Vec<u16> = render_response(&ping, version_name.unwrap_or(VERSION_NAME), &status)
+        .encode_utf16()
+        .collect();
+    let mut response = Vec::with_capacity(3 + units.len() * 2);
+    // kick packet, which ends the connection on the client's side
+    response.push(0xFF);
+    response.extend_from_slice(&(units.len() as u16).to_be_bytes());
+    for unit in units {
+        response.extend_from_slice(&unit.to_be_bytes());
+    }
+    stream.write_all(&response).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mcprotocol::protocol::status::cb::StatusResponsePlayers;
+    use serde_json::json;
+
+    fn render(component: Value) -> String {
+        let mut out = String::new();
+        append_component(&mut out, &component, &serde_json::Map::new());
+        out
+    }
+
+    fn status(motd: &str) -> StatusBuilder {
+        StatusBuilder {
+            players: StatusResponsePlayers {
+                max: 100,
+                online: 5,
+                sample: vec![],
+            },
+            description: Chat::literal(motd),
+            favicon: None,
+        }
+    }
+
+    #[test]
+    fn renders_styles_as_codes() {
+        assert_eq!(render(json!("plain")), "plain");
+        assert_eq!(
+            render(json!({"text": "Hi", "color": "red", "bold": true})),
+            "§c§lHi"
+        );
+        assert_eq!(render(json!({"text": "Hi", "color": "#123456"})), "Hi");
+    }
+
+    #[test]
+    fn children_inherit_their_parents_style() {
+        let component = json!({
+            "text": "A",
+            "color": "gold",
+            "extra": [{"text": "B", "italic": true}, {"text": "C", "color": "aqua"}, "D"],
+        });
+        assert_eq!(render(component), "§6A§6§oB§bCD");
+    }
+
+    #[test]
+    fn keeps_literal_codes() {
+        assert_eq!(to_legacy_text(&Chat::literal("§aWelcome")), "§aWelcome");
+    }
+
+    #[test]
+    fn strips_formatting() {
+        assert_eq!(strip_formatting("§c§lHello §rworld"), "Hello world");
+        assert_eq!(strip_formatting("trailing§"), "trailing");
+    }
+
+    #[test]
+    fn beta_responses_have_no_formatting() {
+        assert_eq!(
+            render_response(&Ping::Beta, VERSION_NAME, &status("§aA server")),
+            "A server§5§100"
+        );
+    }
+
+    #[test]
+    fn extended_responses_carry_the_version() {
+        let ping = Ping::Extended { host: None };
+        assert_eq!(
+            render_response(&ping, "§cMaintenance", &status("§aA server")),
+            "§1\0127\0§cMaintenance\0§aA server\05\0100"
+        );
+    }
+}
@@ -0,0 +1,749 @@
+#![feature(addr_parse_ascii)]
+
+use anyhow::Context;
+use mcprotocol::auth::AuthenticatedClient;
+use mcprotocol::chat::Chat;
+use mcprotocol::pin_fut;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock as StdRwLock};
+use std::time::{Duration, Instant};
+
+use crate::cfg::{IncomingAuthMethod, Players};
+use mcprotocol::protocol::handshaking::sb::Handshake;
+use mcprotocol::protocol::login::cb::Disconnect;
+use mcprotocol::protocol::status::cb::StatusResponsePlayers;
+use mcprotocol::registry::RegistryError;
+use mcprotocol::server_loop::{BaseConfiguration, IncomingAuthenticationOption, ServerLoop};
+use mcprotocol::status::StatusBuilder;
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::task::JoinSet;
+
+use crate::access::{Maintenance, Whitelist};
+use crate::antibot::AntiBot;
+use crate::channel::ChannelTracker;
+use crate::client::Client;
+use crate::command::CommandRegistry;
+use crate::console::ConsoleOutput;
+use crate::dns::{Dns, Resolver, SystemResolver};
+use crate::event::{
+    Cancellable, DisconnectEvent, EventBus, PostLoginEvent, PreLoginEvent, ProxyPingEvent,
+};
+use crate::handshake::{Intent, Peeked};
+use crate::health::ServerHealth;
+use crate::metrics::Metrics;
+use crate::moderation::{Moderation, PunishmentKind};
+use crate::net::{Listener, ReadHalf, WriteHalf};
+use crate::permission::{FilePermissionProvider, PermissionProvider};
+use crate::player::{ClientInfo, ConnectedPlayer, KeepAliveTracker, PlayerSession};
+use crate::plugin::Plugin;
+use crate::registry::{PlayerHandle, PlayerRegistry, RegisterError};
+use crate::script::ScriptHooks;
+use crate::throttle::{ConnectionPermit, Throttle};
+
+pub mod access;
+pub mod antibot;
+pub mod api;
+pub mod backend;
+pub mod cfg;
+pub mod channel;
+pub mod cidr;
+mod client;
+pub mod command;
+pub mod console;
+pub mod dns;
+pub mod event;
+pub mod handshake;
+pub mod health;
+pub mod legacy;
+mod limbo;
+pub mod metrics;
+pub mod moderation;
+pub mod net;
+pub mod permission;
+pub mod player;
+pub mod plugin;
+pub mod proxy_protocol;
+pub mod query;
+pub mod registry;
+pub mod script;
+mod shutdown;
+mod status;
+pub mod throttle;
+
+/// How long a login waits for the session it kicked as a duplicate to close.
+const KICK_TEARDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct ProxyInfo {
+    pub players: PlayerRegistry,
+    pub commands: CommandRegistry,
+    pub permissions: Box<dyn PermissionProvider>,
+    pub events: EventBus,
+    pub scripts: ScriptHooks,
+    pub health: ServerHealth,
+    pub metrics: Metrics,
+    pub moderation: Moderation,
+    pub maintenance: Maintenance,
+    pub whitelist: Whitelist,
+    pub throttle: Throttle,
+    pub antibot: AntiBot,
+    pub dns: Dns,
+    config: StdRwLock<Arc<cfg::UmbrellaConfig>>,
+    shutdown: watch::Sender<bool>,
+}
+
+impl ProxyInfo {
+    pub fn config(&self) -> Arc<cfg::UmbrellaConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Re-reads the config and everything loaded alongside it. Listener addresses, auth and
+    /// compression settings only take effect after a restart.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let (config, warnings) = cfg::load()?;
+        for warning in warnings {
+            log::warn!("{}", warning);
+        }
+        self.commands.reload_aliases(&config.commands);
+        self.permissions.reload(&config.permissions)?;
+        self.scripts.reload(&config.scripts)?;
+        self.moderation.store().reload()?;
+        self.whitelist.reload()?;
+        *self.config.write().unwrap() = Arc::new(config);
+        log::info!("Configuration reloaded.");
+        Ok(())
+    }
+
+    /// Stops accepting connections and starts draining players, see `shutdown::drain`.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    pub fn shutdown_requested(&self) -> watch::Receiver<bool> {
+        self.shutdown.subscribe()
+    }
+}
+
+/// Entry point for running the proxy, with any plugins registered before `start`.
+#[derive(Default)]
+pub struct Umbrella {
+    plugins: Vec<Box<dyn Plugin>>,
+    console: bool,
+    resolver: Option<Arc<dyn Resolver>>,
+}
+
+impl Umbrella {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn plugin(mut self, plugin: impl Plugin + 'static) -> Self {
+        self.plugins.push(Box::new(plugin));
+        self
+    }
+
+    /// Replaces the system DNS resolver used for backend hostnames.
+    pub fn resolver(mut self, resolver: impl Resolver + 'static) -> Self {
+        self.resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Reads commands from stdin, see `console::spawn`.
+    pub fn console(mut self) -> Self {
+        self.console = true;
+        self
+    }
+
+    /// Loads `./config.json`, enables the plugins and runs the proxy until the listener fails
+    /// or a shutdown is requested, in which case players are drained before returning.
+    pub async fn start(self) -> anyhow::Result<()> {
+        let (config, warnings) = cfg::load()?;
+
+        let path = Path::new("./server-icon.png");
+        let favicon = Arc::new(if path.exists() {
+            let base_64 = image_base64::to_base64(path.to_str().unwrap());
+            Some(base_64)
+        } else {
+            None
+        });
+
+        let console_output = ConsoleOutput::default();
+        fern::Dispatch::new()
+            .format(move |out, message, record| {
+                out.finish(format_args!(
+                    "{} [{}/{}]: {}",
+                    chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
+                    record.target(),
+                    record.level(),
+                    message
+                ))
+            })
+            .level(config.log_level)
+            .chain(console_output.clone().into_fern())
+            .apply()?;
+
+        log::info!("Umbrella logger initialized.");
+        for warning in warnings {
+            log::warn!("{}", warning);
+        }
+
+        let proxy_info = Arc::new(ProxyInfo {
+            players: PlayerRegistry::new(),
+            commands: CommandRegistry::new(&config.commands),
+            permissions: Box::new(FilePermissionProvider::load(Path::new(
+                &config.permissions.file,
+            ))?),
+            events: EventBus::new(),
+            scripts: ScriptHooks::load(&config.scripts)?,
+            health: ServerHealth::new(),
+            metrics: Metrics::new(),
+            moderation: Moderation::open(&config.moderation)?,
+            maintenance: Maintenance::new(config.maintenance.enabled),
+            whitelist: Whitelist::load(Path::new(&config.whitelist.file))?,
+            throttle: Throttle::new(),
+            antibot: AntiBot::new(),
+            dns: Dns::new(match self.resolver.clone() {
+                Some(resolver) => resolver,
+                None => Arc::new(SystemResolver::new()?),
+            }),
+            config: StdRwLock::new(Arc::new(config)),
+            shutdown: watch::channel(false).0,
+        });
+
+        for plugin in &self.plugins {
+            plugin.enable(proxy_info.clone())?;
+            log::info!("Enabled plugin {}.", plugin.name());
+        }
+
+        let startup_config = proxy_info.config();
+        if let Some(admin_api) = startup_config.admin_api.as_ref() {
+            let proxy_info = proxy_info.clone();
+            let bind = admin_api.bind.clone();
+            tokio::spawn(async move {
+                if let Err(err) = api::serve(proxy_info, bind).await {
+                    log::error!("Admin API stopped: {:#}", err);
+                }
+            });
+        }
+        if let Some(metrics) = startup_config.metrics.as_ref() {
+            let proxy_info = proxy_info.clone();
+            let bind = metrics.bind.clone();
+            tokio::spawn(async move {
+                if let Err(err) = metrics::serve(proxy_info, bind).await {
+                    log::error!("Metrics endpoint stopped: {:#}", err);
+                }
+            });
+        }
+        if let Some(query) = startup_config.query.as_ref() {
+            let proxy_info = proxy_info.clone();
+            let bind = query.bind.clone();
+            tokio::spawn(async move {
+                if let Err(err) = query::serve(proxy_info, bind).await {
+                    log::error!("Query listener stopped: {:#}", err);
+                }
+            });
+        }
+
+        let base_configuration = || {
+            let (auth_option, auth_url) = match &startup_config.auth.incoming_auth {
+                IncomingAuthMethod::Mojang {
+                    override_sessionserver,
+                } => (
+                    IncomingAuthenticationOption::MOJANG,
+                    override_sessionserver.as_ref().cloned(),
+                ),
+                IncomingAuthMethod::BungeeLegacy => (IncomingAuthenticationOption::BUNGEE, None),
+                IncomingAuthMethod::VelocityModern { secret_key } => (
+                    IncomingAuthenticationOption::VELOCITY {
+                        secret_key: secret_key.clone(),
+                    },
+                    None,
+                ),
+            };
+            BaseConfiguration {
+                auth_option,
+                compression_threshold: startup_config.compression_threshold,
+                force_key_authentication: startup_config.auth.force_key_authentication,
+                auth_url,
+            }
+        };
+
+        // every listener gets its own server loop so status pings know where they came from
+        let mut listeners = Vec::new();
+        for bind in startup_config.listener_binds() {
+            let bind: Arc<str> = bind.into();
+            let status_proxy_info = proxy_info.clone();
+            let status_favicon = favicon.clone();
+            let status_bind = bind.clone();
+            let server_loop = Arc::new(ServerLoop::new(
+                base_configuration(),
+                pin_fut!(wrapped_client_acceptor),
+                move |h| {
+                    Box::pin(status_responder(
+                        status_proxy_info.clone(),
+                        status_favicon.clone(),
+                        status_bind.clone(),
+                        h,
+                    ))
+                },
+            ));
+            log::info!("Binding to {}", bind);
+            let listener = Listener::bind(&bind)
+                .await
+                .with_context(|| format!("Failed to bind {}", bind))?;
+            listeners.push((bind, listener, server_loop));
+        }
+
+        if self.console {
+            console::spawn(proxy_info.clone(), console_output)?;
+        }
+
+        shutdown::handle_signals(proxy_info.clone())?;
+        throttle::spawn_purge(proxy_info.clone());
+        health::spawn_probe(proxy_info.clone());
+        let mut accept_loops = JoinSet::new();
+        for (bind, listener, server_loop) in listeners {
+            let proxy_info = proxy_info.clone();
+            let favicon = favicon.clone();
+            // returns early only if the listener fails, which stops the proxy
+            accept_loops.spawn(async move {
+                let mut shutdown = proxy_info.shutdown_requested();
+                loop {
+                    let (mut stream, peer) = tokio::select! {
+                        accepted = listener.accept() => accepted?,
+                        _ = shutdown.changed() => return anyhow::Ok(()),
+                    };
+                    let accepted_at = Instant::now();
+                    let loop_clone = server_loop.clone();
+                    let proxy_info = proxy_info.clone();
+                    let bind = bind.clone();
+                    let favicon = favicon.clone();
+                    tokio::spawn(async move {
+                        let (socket_addr, _permit, peeked) =
+                            match admit(&proxy_info, &bind, &mut stream, peer).await {
+                                Some(admitted) => admitted,
+                                None => return,
+                            };
+                        let intent = peeked.intent;
+                        // the protocol library always sends its own version text
+                        let maintenance_version = proxy_info
+                            .maintenance
+                            .is_enabled()
+                            .then(|| proxy_info.config().maintenance.version.clone());
+                        if intent == Intent::LegacyPing {
+                            let respond = |handshake| {
+                                status_responder(proxy_info.clone(), favicon, bind, handshake)
+                            };
+                            let version_name = maintenance_version.as_deref();
+                            if let Err(err) =
+                                legacy::answer(&mut stream, version_name, respond).await
+                            {
+                                log::debug!(
+                                    "Failed to answer a legacy ping from {}: {:#}",
+                                    socket_addr,
+                                    err
+                                );
+                            }
+                            return;
+                        }
+                        if let (Intent::Status, Some(version_name)) = (intent, &maintenance_version)
+                        {
+                            let respond = |handshake| {
+                                status_responder(proxy_info.clone(), favicon, bind, handshake)
+                            };
+                            if let Err(err) = status::answer(stream, version_name, respond).await {
+                                log::debug!(
+                                    "Failed to answer a status ping from {}: {:#}",
+                                    socket_addr,
+                                    err
+                                );
+                            }
+                            return;
+                        }
+                        let (read, write) = stream.into_split();
+                        let (read, write) = proxy_info.metrics.count_client(read, write);
+                        if let Err(registry_error) = ServerLoop::accept_client(
+                            loop_clone,
+                            ClientContext {
+                                socket_addr,
+                                bind,
+                                proxy_info,
+                                accepted_at,
+                                handshake: peeked.handshake,
+                            },
+                            read,
+                            write,
+                        )
+                        .await
+                        {
+                            if !matches!(
+                                registry_error,
+                                RegistryError::DraxTransportError(
+                                    mcprotocol::prelude::drax::transport::Error::EOF
+                                )
+                            ) {
+                                log::warn!(
+                                    "Registry error encountered when accepting client: {}",
+                                    registry_error
+                                );
+                            }
+                        }
+                    });
+                }
+            });
+        }
+        while let Some(result) = accept_loops.join_next().await {
+            result??;
+        }
+
+        log::info!("Shutting down.");
+        shutdown::drain(&proxy_info).await;
+        Ok(())
+    }
+}
+
+/// Reads the client's address and handshake and applies the listener's connection limits,
+/// `None` if the connection was refused.
+async fn admit(
+    proxy_info: &ProxyInfo,
+    bind: &Arc<str>,
+    stream: &mut net::Stream,
+    peer: Option<SocketAddr>,
+) -> Option<(SocketAddr, ConnectionPermit, Peeked)> {
+    let config = proxy_info.config();
+    let socket_addr =
+        match proxy_protocol::client_addr(config.listener_proxy_protocol(bind), stream, peer).await
+        {
+            Ok(client_addr) => client_addr,
+            Err(err) => {
+                log::debug!("Dropping connection on {}: {:#}", bind, err);
+                return None;
+            }
+        };
+    // peers on Unix sockets have no address to limit or ban
+    let ip = Some(socket_addr.ip()).filter(|ip| !ip.is_unspecified());
+    let throttle_config = config.listener_throttle(bind);
+    let permit = match proxy_info.throttle.accept(throttle_config, bind, ip) {
+        Ok(permit) => permit,
+        Err(refused) => {
+            throttle::refused(proxy_info, throttle_config, ip, refused).await;
+            return None;
+        }
+    };
+    let peeked = handshake::peek(stream).await;
+    if let Err(refused) = proxy_info
+        .throttle
+        .check_intent(throttle_config, bind, ip, peeked.intent)
+    {
+        throttle::refused(proxy_info, throttle_config, ip, refused).await;
+        return None;
+    }
+    Some((socket_addr, permit, peeked))
+}
+
+async fn status_responder(
+    proxy_info: Arc<ProxyInfo>,
+    favicon: Arc<Option<String>>,
+    bind: Arc<str>,
+    handshake: Handshake,
+) -> StatusBuilder {
+    proxy_info.metrics.status_pings.inc();
+    let players = proxy_info.players.player_count().await as i32;
+    let config = proxy_info.config();
+    let status_config = config.listener_status(&bind);
+
+    let (online, max) = status_config.players.counts(players);
+    let players = StatusResponsePlayers {
+        max,
+        online,
+        sample: vec![],
+    };
+
+    let mut status = StatusBuilder {
+        players,
+        description: if proxy_info.maintenance.is_enabled() {
+            config.maintenance.motd.clone()
+        } else {
+            status_config.motd.clone()
+        },
+        favicon: (*favicon).as_ref().cloned(),
+    };
+    // keep pings cheap while connections are being throttled
+    if proxy_info.throttle.under_pressure() {
+        return status;
+    }
+    proxy_info
+        .scripts
+        .status(&proxy_info, &handshake, &mut status)
+        .await;
+    proxy_info
+        .events
+        .fire(ProxyPingEvent { handshake, status })
+        .await
+        .status
+}
+
+pub struct ClientContext {
+    socket_addr: SocketAddr,
+    /// Address of the listener the client connected through.
+    bind: Arc<str>,
+    proxy_info: Arc<ProxyInfo>,
+    accepted_at: Instant,
+    /// The handshake as read ahead of the protocol library, `None` if it couldn't be.
+    handshake: Option<Handshake>,
+}
+
+async fn wrapped_client_acceptor(
+    mut context: ClientContext,
+    mut rw: AuthenticatedClient<ReadHalf, WriteHalf>,
+) -> Result<(), RegistryError> {
+    if let Some(overridden) = rw.overridden_address.as_ref() {
+        context.socket_addr = SocketAddr::parse_ascii(overridden.as_bytes()).map_err(|_| {
+            drax::transport::Error::Unknown(Some(format!("Failed to parse address {}", overridden)))
+        })?;
+    }
+    let client_info = ClientInfo {
+        protocol_version: rw.protocol_version,
+        remote_addr: context.socket_addr,
+        mojang_key: rw.key.as_ref().cloned(),
+        sig_holder: rw.sig_holder.as_ref().cloned(),
+        profile: rw.profile.clone(),
+    };
+
+    if let Some(ban) = context
+        .proxy_info
+        .moderation
+        .find(PunishmentKind::Ban, &client_info)
+        .await
+    {
+        context.proxy_info.metrics.login("banned");
+        let reason = Chat::literal(ban.render(&context.proxy_info.config().moderation.ban_screen));
+        rw.read_write.1.write_packet(&Disconnect { reason }).await?;
+        return Ok(());
+    }
+
+    let config = context.proxy_info.config();
+    let refused = if !context
+        .proxy_info
+        .maintenance
+        .may_join(&context.proxy_info, &client_info)
+    {
+        Some(("maintenance", &config.maintenance.message))
+    } else if config.whitelist.enabled && !context.proxy_info.whitelist.contains(&client_info) {
+        Some(("not_whitelisted", &config.whitelist.message))
+    } else {
+        None
+    };
+    if let Some((outcome, message)) = refused {
+        context.proxy_info.metrics.login(outcome);
+        rw.read_write
+            .1
+            .write_packet(&Disconnect {
+                reason: Chat::literal(message.clone()),
+            })
+            .await?;
+        return Ok(());
+    }
+
+    if let Err(reason) = context
+        .proxy_info
+        .scripts
+        .login(
+            &context.proxy_info,
+            context.handshake.as_ref(),
+            &client_info,
+        )
+        .await
+    {
+        context.proxy_info.metrics.login("denied");
+        rw.read_write.1.write_packet(&Disconnect { reason }).await?;
+        return Ok(());
+    }
+
+    let pre_login = context
+        .proxy_info
+        .events
+        .fire(PreLoginEvent {
+            client_info: client_info.clone(),
+            reason: Chat::literal("You are not allowed to join."),
+            cancelled: false,
+        })
+        .await;
+    if pre_login.is_cancelled() {
+        context.proxy_info.metrics.login("denied");
+        rw.read_write
+            .1
+            .write_packet(&Disconnect {
+                reason: pre_login.reason,
+            })
+            .await?;
+        return Ok(());
+    }
+
+    let limit = match config.listener_status(&context.bind).players {
+        Players::Capped { max_players } => Some(max_players.max(0) as usize),
+        _ => None,
+    };
+    let (sender, commands) = mpsc::unbounded_channel();
+    let (teardown, closed) = watch::channel(());
+    let (session_id, kicked) = match context
+        .proxy_info
+        .players
+        .try_register(
+            client_info.clone(),
+            PlayerHandle::new(sender, closed),
+            limit,
+            &context.proxy_info.config().duplicate_login,
+        )
+        .await
+    {
+        Ok(registered) => registered,
+        Err(register_error) => {
+            let (outcome, reason) = match register_error {
+                RegisterError::Full => ("full", "Player limit reached."),
+                RegisterError::AlreadyConnected => {
+                    ("duplicate", "You are already connected to this proxy.")
+                }
+            };
+            context.proxy_info.metrics.login(outcome);
+            rw.read_write
+                .1
+                .write_packet(&Disconnect {
+                    reason: Chat::literal(reason),
+                })
+                .await?;
+            return Ok(());
+        }
+    };
+    // the kicked session's backend connection has to be gone before the backend sees this one
+    for handle in kicked {
+        if tokio::time::timeout(KICK_TEARDOWN_TIMEOUT, handle.closed())
+            .await
+            .is_err()
+        {
+            log::warn!(
+                "The previous session of {} is still closing, connecting anyway.",
+                client_info.profile.name
+            );
+        }
+    }
+    let proxy_info = context.proxy_info.clone();
+    let ret = client_acceptor(context, rw, client_info.clone(), session_id, commands).await;
+    proxy_info.players.unregister(session_id).await;
+    proxy_info
+        .events
+        .fire(DisconnectEvent {
+            client_info,
+            session_id,
+        })
+        .await;
+    drop(teardown);
+    ret
+}
+
+async fn client_acceptor(
+    context: ClientContext,
+    rw: AuthenticatedClient<ReadHalf, WriteHalf>,
+    client_info: ClientInfo,
+    session_id: u64,
+    commands: mpsc::UnboundedReceiver<registry::PlayerCommand>,
+) -> Result<(), RegistryError> {
+    let accepted_at = context.accepted_at;
+    let bind = context.bind;
+    let handshake = context.handshake;
+    let (read, write) = rw.read_write;
+    let session = PlayerSession {
+        proxy_info: context.proxy_info,
+        client_info,
+        session_id,
+        client_write: Arc::new(Mutex::new(write)),
+        keep_alives: KeepAliveTracker::default(),
+        channels: ChannelTracker::default(),
+    };
+
+    let post_login = session
+        .proxy_info
+        .events
+        .fire(PostLoginEvent {
+            client_info: session.client_info.clone(),
+            session_id,
+            reason: Chat::literal("You are not allowed to join."),
+            cancelled: false,
+        })
+        .await;
+    if post_login.is_cancelled() {
+        session.proxy_info.metrics.login("denied");
+        session
+            .client_write
+            .lock()
+            .await
+            .write_packet(&Disconnect {
+                reason: post_login.reason,
+            })
+            .await?;
+        return Ok(());
+    }
+
+    let config = session.proxy_info.config();
+    let candidates = session
+        .proxy_info
+        .scripts
+        .initial_servers(
+            &session.proxy_info,
+            handshake.as_ref(),
+            &session.client_info,
+            config.listener_try(&bind),
+        )
+        .await;
+    let check = session
+        .proxy_info
+        .antibot
+        .start_check(&config.antibot, &session.client_info);
+    let mut read: limbo::RawPipeline = read.clear_registry();
+    let mut replay = vec![];
+    if let Some(check) = &check {
+        let outcome = limbo::run_check(&session, &mut read, check, &config.antibot).await?;
+        if !outcome.passed {
+            session.proxy_info.metrics.login("bot_check");
+            session
+                .disconnect(Chat::literal(config.antibot.failed_message.clone()))
+                .await?;
+            return Ok(());
+        }
+        session
+            .proxy_info
+            .antibot
+            .pass(&config.antibot, &session.client_info);
+        replay = outcome.replay;
+    }
+    let mut initial_server = match player::connect_any(&session, None, &candidates).await {
+        Some(initial_server) => initial_server,
+        None => {
+            session.proxy_info.metrics.login("no_server");
+            session
+                .client_write
+                .lock()
+                .await
+                .write_packet(&Disconnect {
+                    reason: Chat::literal("Unable to connect you to a server."),
+                })
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let metrics = &session.proxy_info.metrics;
+    metrics.login("success");
+    metrics
+        .login_duration
+        .observe(accepted_at.elapsed().as_secs_f64());
+
+    initial_server.replay(replay).await?;
+
+    let client = Client::create(read, session.clone());
+    let joined_limbo = check.is_some();
+    let player =
+        ConnectedPlayer::start(session, client, commands, initial_server, joined_limbo).await;
+    player.run().await?;
+    Ok(())
+}
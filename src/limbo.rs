@@ -0,0 +1,374 @@
+use std::time::Duration;
+
+use drax::transport::frame::PacketFrame;
+use drax::VarInt;
+use mcprotocol::chat::Chat;
+use mcprotocol::pipeline::AsyncMinecraftProtocolPipeline;
+use mcprotocol::protocol::play::cb::KeepAlive;
+use mcprotocol::registry::{MappedAsyncPacketRegistry, RegistryError};
+use tokio::task::JoinHandle;
+
+use crate::antibot::Check;
+use crate::backend::ClientWriter;
+use crate::cfg::AntiBotConfig;
+use crate::net::{codec, ReadHalf};
+use crate::player::PlayerSession;
+
+mod map;
+mod nbt;
+mod world;
+
+/// Client read side without any handlers, every packet comes back as `NoHandlerFound`.
+pub type RawPipeline =
+    AsyncMinecraftProtocolPipeline<ReadHalf, (), (), MappedAsyncPacketRegistry<(), ()>>;
+
+const ADVENTURE: u8 = 2;
+/// Far enough above the floor for any number of falling moves the check asks for.
+const FALL_START: (f64, f64, f64) = (8.0, 240.0, 8.0);
+const TELEPORT_ID: VarInt = 1;
+const GRAVITY: f64 = 0.08;
+const DRAG: f64 = 0.98;
+/// Clients compute the same doubles, this only allows for rounding on the way.
+const TOLERANCE: f64 = 1e-6;
+/// Clients drop the connection after 30 seconds without a keep alive.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_CHAT_LEN: usize = 256;
+const MAX_IDENTIFIER_LEN: usize = 32767;
+const BRAND_CHANNEL: &str = "minecraft:brand";
+
+/// Ids of the packets the protocol library has no types for, and of the filled map item.
+struct PacketIds {
+    join_game: VarInt,
+    chunk_data: VarInt,
+    sync_position: VarInt,
+    map_data: VarInt,
+    set_slot: VarInt,
+    confirm_teleport: VarInt,
+    chat_message: VarInt,
+    client_information: VarInt,
+    plugin_message: VarInt,
+    set_position: VarInt,
+    set_position_rotation: VarInt,
+    filled_map: VarInt,
+}
+
+fn packet_ids(protocol_version: VarInt) -> Option<PacketIds> {
+    match protocol_version {
+        759 => Some(PacketIds {
+            join_game: 0x23,
+            chunk_data: 0x1F,
+            sync_position: 0x36,
+            map_data: 0x24,
+            set_slot: 0x13,
+            confirm_teleport: 0x00,
+            chat_message: 0x04,
+            client_information: 0x07,
+            plugin_message: 0x0C,
+            set_position: 0x13,
+            set_position_rotation: 0x14,
+            filled_map: 886,
+        }),
+        760 => Some(PacketIds {
+            join_game: 0x25,
+            chunk_data: 0x21,
+            sync_position: 0x39,
+            map_data: 0x26,
+            set_slot: 0x13,
+            confirm_teleport: 0x00,
+            chat_message: 0x05,
+            client_information: 0x08,
+            plugin_message: 0x0D,
+            set_position: 0x14,
+            set_position_rotation: 0x15,
+            filled_map: 886,
+        }),
+        _ => None,
+    }
+}
+
+/// Whether the backend needs the packet. Clients send their settings and brand once after
+/// joining a world, which for a checked client is the limbo's.
+fn kept_for_backend(ids: &PacketIds, packet_id: VarInt, mut payload: &[u8]) -> bool {
+    packet_id == ids.client_information
+        || (packet_id == ids.plugin_message
+            && codec::read_string(&mut payload, MAX_IDENTIFIER_LEN).as_deref() == Ok(BRAND_CHANNEL))
+}
+
+fn unexpected(message: &str) -> RegistryError {
+    RegistryError::DraxTransportError(drax::transport::Error::Unknown(Some(message.to_string())))
+}
+
+/// How a client did in the limbo.
+#[derive(Default)]
+pub struct Outcome {
+    pub passed: bool,
+    /// Packets read in the limbo that belong to the backend, as read.
+    pub replay: Vec<Vec<u8>>,
+}
+
+struct Limbo<'a> {
+    read: &'a mut RawPipeline,
+    ids: PacketIds,
+    replay: Vec<Vec<u8>>,
+}
+
+impl Limbo<'_> {
+    /// The id and payload of the next packet the check may care about.
+    async fn next_packet(&mut self) -> Result<(VarInt, Vec<u8>), RegistryError> {
+        loop {
+            let data = match self.read.execute_next_packet(&mut ()).await {
+                Ok(()) => continue,
+                Err(RegistryError::NoHandlerFound(_, data)) => data,
+                Err(err) => return Err(err),
+            };
+            let mut payload = data.as_slice();
+            let packet_id = match codec::read_var_int(&mut payload) {
+                Ok(packet_id) => packet_id,
+                Err(_) => return Err(unexpected("unreadable packet id")),
+            };
+            if kept_for_backend(&self.ids, packet_id, payload) {
+                self.replay.push(data);
+                continue;
+            }
+            return Ok((packet_id, payload.to_vec()));
+        }
+    }
+
+    /// Returns whether the client fell like a real client for `falling_moves` moves.
+    async fn watch_fall(&mut self, config: &AntiBotConfig) -> Result<bool, RegistryError> {
+        let mut teleported = false;
+        let mut fall = Fall::default();
+        while fall.moves < config.falling_moves {
+            let (packet_id, payload) = self.next_packet().await?;
+            let mut packet = payload.as_slice();
+            if packet_id == self.ids.confirm_teleport {
+                teleported |= codec::read_var_int(&mut packet) == Ok(TELEPORT_ID);
+                continue;
+            }
+            if packet_id != self.ids.set_position && packet_id != self.ids.set_position_rotation {
+                continue;
+            }
+            // moves from before the teleport was confirmed aren't from the start
+            if !teleported {
+                continue;
+            }
+            let y = match packet.get(8..16) {
+                Some(y) => f64::from_be_bytes(y.try_into().unwrap()),
+                None => return Ok(false),
+            };
+            if !fall.step(y) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns whether the player typed `code` within the allowed attempts.
+    async fn read_code(
+        &mut self,
+        session: &PlayerSession,
+        code: &str,
+        config: &AntiBotConfig,
+    ) -> Result<bool, RegistryError> {
+        let mut attempts = 0;
+        loop {
+            let (packet_id, payload) = self.next_packet().await?;
+            if packet_id != self.ids.chat_message {
+                continue;
+            }
+            let message = match codec::read_string(&mut payload.as_slice(), MAX_CHAT_LEN) {
+                Ok(message) => message,
+                Err(_) => return Ok(false),
+            };
+            if message.trim().eq_ignore_ascii_case(code) {
+                return Ok(true);
+            }
+            attempts += 1;
+            if attempts >= config.attempts {
+                return Ok(false);
+            }
+            session
+                .send_message(Chat::literal("§cThat's not the code, try again."))
+                .await?;
+        }
+    }
+}
+
+fn spawn_keep_alive(client_write: ClientWriter) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(KEEP_ALIVE_INTERVAL);
+        for id in 0.. {
+            interval.tick().await;
+            let sent = client_write
+                .lock()
+                .await
+                .write_packet(&KeepAlive { id })
+                .await;
+            if sent.is_err() {
+                return;
+            }
+        }
+    })
+}
+
+/// Joins the client into the proxy's own world and runs the check there. The check fails
+/// if the client's version has no limbo or the check's timeout passes.
+pub async fn run_check(
+    session: &PlayerSession,
+    read: &mut RawPipeline,
+    check: &Check,
+    config: &AntiBotConfig,
+) -> Result<Outcome, RegistryError> {
+    let protocol_version = session.client_info.protocol_version;
+    let ids = match packet_ids(protocol_version) {
+        Some(ids) => ids,
+        None => {
+            log::warn!(
+                "The limbo doesn't support protocol {}, {} fails the bot check.",
+                protocol_version,
+                session.client_info.profile.name
+            );
+            return Ok(Outcome::default());
+        }
+    };
+    let start = match check {
+        Check::Falling => FALL_START,
+        _ => (FALL_START.0, world::FLOOR, FALL_START.2),
+    };
+    let mut packets = vec![
+        world::join_game(ids.join_game, protocol_version, ADVENTURE),
+        world::chunk_data(ids.chunk_data),
+        world::sync_position(ids.sync_position, start, TELEPORT_ID),
+    ];
+    if let Check::Map(code) = check {
+        let pixels = map::render(code, &mut rand::thread_rng());
+        packets.push(map::map_item(ids.set_slot, ids.filled_map));
+        packets.push(map::map_data(ids.map_data, &pixels));
+    }
+    {
+        let mut client_write = session.client_write.lock().await;
+        for data in packets {
+            client_write
+                .write_buffered_packet(PacketFrame { data })
+                .await?;
+        }
+    }
+    match check {
+        Check::Falling => {}
+        Check::Chat(code) => {
+            session
+                .send_message(Chat::literal(config.prompt.replace("{code}", code)))
+                .await?
+        }
+        Check::Map(_) => {
+            session
+                .send_message(Chat::literal(config.map_prompt.clone()))
+                .await?
+        }
+    }
+
+    let keep_alive = spawn_keep_alive(session.client_write.clone());
+    let mut limbo = Limbo {
+        read,
+        ids,
+        replay: vec![],
+    };
+    let watch = async {
+        match check {
+            Check::Falling => limbo.watch_fall(config).await,
+            Check::Chat(code) | Check::Map(code) => limbo.read_code(session, code, config).await,
+        }
+    };
+    let passed = tokio::time::timeout(Duration::from_secs(config.timeout_seconds), watch).await;
+    keep_alive.abort();
+    Ok(Outcome {
+        passed: passed.unwrap_or(Ok(false))?,
+        replay: limbo.replay,
+    })
+}
+
+/// Follows the height a client reports against the vanilla gravity and drag.
+#[derive(Default)]
+struct Fall {
+    last_y: Option<f64>,
+    /// The next fall distance, known once the client started falling.
+    expected: Option<f64>,
+    moves: u32,
+}
+
+impl Fall {
+    /// Returns false as soon as the client moves in a way the game wouldn't have moved it.
+    fn step(&mut self, y: f64) -> bool {
+        let last_y = match self.last_y.replace(y) {
+            Some(last_y) => last_y,
+            None => return true,
+        };
+        let distance = y - last_y;
+        let expected = match self.expected {
+            Some(expected) => expected,
+            // the first tick after the teleport only gains velocity
+            None if distance == 0.0 => return true,
+            None => -GRAVITY * DRAG,
+        };
+        if (distance - expected).abs() > TOLERANCE {
+            return false;
+        }
+        self.expected = Some((distance - GRAVITY) * DRAG);
+        self.moves += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vanilla_fall(ticks: usize) -> Vec<f64> {
+        let (mut y, mut velocity) = (FALL_START.1, 0.0);
+        let mut heights = vec![y];
+        for _ in 0..ticks {
+            y += velocity;
+            velocity = (velocity - GRAVITY) * DRAG;
+            heights.push(y);
+        }
+        heights
+    }
+
+    #[test]
+    fn vanilla_fall_passes() {
+        let mut fall = Fall::default();
+        for y in vanilla_fall(12) {
+            assert!(fall.step(y));
+        }
+        assert_eq!(fall.moves, 11);
+    }
+
+    #[test]
+    fn constant_speed_fails() {
+        let mut fall = Fall::default();
+        assert!(fall.step(FALL_START.1));
+        assert!(fall.step(FALL_START.1 - 0.0784));
+        assert!(!fall.step(FALL_START.1 - 0.0784 * 2.0));
+    }
+
+    #[test]
+    fn keeps_settings_and_brand_for_the_backend() {
+        let ids = packet_ids(760).unwrap();
+        assert!(kept_for_backend(&ids, ids.client_information, &[]));
+        let mut brand = vec![];
+        codec::write_string(&mut brand, BRAND_CHANNEL);
+        brand.extend_from_slice(b"\x07vanilla");
+        assert!(kept_for_backend(&ids, ids.plugin_message, &brand));
+        let mut register = vec![];
+        codec::write_string(&mut register, "minecraft:register");
+        assert!(!kept_for_backend(&ids, ids.plugin_message, &register));
+        assert!(!kept_for_backend(&ids, ids.chat_message, &brand));
+    }
+
+    #[test]
+    fn supports_what_the_proxy_speaks() {
+        for protocol_version in crate::player::SUPPORTED_PROTOCOL_VERSIONS {
+            assert!(packet_ids(protocol_version).is_some());
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use drax::VarInt;
+use rand::Rng;
+
+use super::nbt::{compound, Tag};
+use crate::net::codec;
+
+const SIZE: usize = 128;
+/// Map colors are a base color times four plus a shade.
+const WHITE: u8 = 8 * 4 + 2;
+const BLACK: u8 = 29 * 4 + 2;
+const SCALE: usize = 4;
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+const SPACING: usize = SCALE;
+/// How far each digit may be moved up or down.
+const JITTER: usize = 10;
+/// One in this many pixels is flipped.
+const NOISE: u32 = 16;
+/// The first hotbar slot of the player's inventory.
+const HOTBAR_SLOT: i16 = 36;
+const MAP_ID: VarInt = 0;
+
+/// 5x7 digits, one row per byte with the leftmost pixel in the highest of five bits.
+const DIGITS: [[u8; GLYPH_HEIGHT]; 10] = [
+    [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+    [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+    [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+    [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+    [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+    [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+    [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+    [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+    [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+    [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+];
+
+fn draw_digit(pixels: &mut [u8], digit: usize, left: usize, top: usize) {
+    for (row, bits) in DIGITS[digit].iter().enumerate() {
+        for column in 0..GLYPH_WIDTH {
+            if bits & (0x10 >> column) == 0 {
+                continue;
+            }
+            for y in top + row * SCALE..top + (row + 1) * SCALE {
+                let start = y * SIZE + left + column * SCALE;
+                pixels[start..start + SCALE].fill(BLACK);
+            }
+        }
+    }
+}
+
+/// Draws the digits of `code` at random heights on a noisy background, row by row.
+pub fn render(code: &str, rng: &mut impl Rng) -> Vec<u8> {
+    let mut pixels = vec![WHITE; SIZE * SIZE];
+    let glyph_width = GLYPH_WIDTH * SCALE;
+    let glyph_height = GLYPH_HEIGHT * SCALE;
+    let digits: Vec<usize> = code
+        .chars()
+        .filter_map(|c| c.to_digit(10))
+        .map(|digit| digit as usize)
+        .collect();
+    let width = digits.len() * (glyph_width + SPACING) - SPACING;
+    let mut left = SIZE.saturating_sub(width) / 2;
+    for digit in digits {
+        let top = (SIZE - glyph_height) / 2 - JITTER + rng.gen_range(0..=2 * JITTER);
+        draw_digit(&mut pixels, digit, left, top);
+        left += glyph_width + SPACING;
+    }
+    for pixel in pixels.iter_mut() {
+        if rng.gen_ratio(1, NOISE) {
+            *pixel = if *pixel == WHITE { BLACK } else { WHITE };
+        }
+    }
+    pixels
+}
+
+/// The whole map at once, locked so the client doesn't update it from the world.
+pub fn map_data(packet_id: VarInt, pixels: &[u8]) -> Vec<u8> {
+    let mut data = vec![];
+    codec::write_var_int(&mut data, packet_id);
+    codec::write_var_int(&mut data, MAP_ID);
+    // scale, locked and no icons
+    data.extend_from_slice(&[0, 1, 0]);
+    // columns, rows and the offset of the update
+    data.extend_from_slice(&[SIZE as u8, SIZE as u8, 0, 0]);
+    codec::write_var_int(&mut data, pixels.len() as VarInt);
+    data.extend_from_slice(pixels);
+    data
+}
+
+/// Puts a filled map showing the map into the first hotbar slot, which is selected on join.
+pub fn map_item(packet_id: VarInt, filled_map: VarInt) -> Vec<u8> {
+    let mut data = vec![];
+    codec::write_var_int(&mut data, packet_id);
+    // the player's inventory and its state id
+    data.push(0);
+    codec::write_var_int(&mut data, 0);
+    data.extend_from_slice(&HOTBAR_SLOT.to_be_bytes());
+    data.push(1);
+    codec::write_var_int(&mut data, filled_map);
+    data.push(1);
+    compound(vec![("map", Tag::Int(MAP_ID))]).write(&mut data);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn draws_digits_scaled() {
+        let mut pixels = vec![WHITE; SIZE * SIZE];
+        draw_digit(&mut pixels, 1, 0, 0);
+        // the stem of the one runs down the middle column
+        for y in 0..GLYPH_HEIGHT * SCALE {
+            assert_eq!(pixels[y * SIZE + 2 * SCALE], BLACK);
+        }
+        assert_eq!(pixels[0], WHITE);
+        assert!(pixels[GLYPH_HEIGHT * SCALE * SIZE..]
+            .iter()
+            .all(|pixel| *pixel == WHITE));
+    }
+
+    #[test]
+    fn renders_codes_onto_the_map() {
+        let pixels = render("31415", &mut StdRng::seed_from_u64(7));
+        assert_eq!(pixels.len(), SIZE * SIZE);
+        assert!(pixels
+            .iter()
+            .all(|pixel| *pixel == WHITE || *pixel == BLACK));
+        let black = pixels.iter().filter(|pixel| **pixel == BLACK).count();
+        // the digits and the noise, but far from a black map
+        assert!(black > SIZE * SIZE / NOISE as usize);
+        assert!(black < SIZE * SIZE / 2);
+    }
+
+    #[test]
+    fn digits_are_distinct() {
+        for (i, digit) in DIGITS.iter().enumerate() {
+            assert!(DIGITS[i + 1..].iter().all(|other| other != digit));
+        }
+    }
+}
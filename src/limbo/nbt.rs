@@ -0,0 +1,94 @@
+/// The NBT tags the limbo sends, written the way the network protocol expects them.
+pub enum Tag {
+    Byte(i8),
+    Int(i32),
+    Float(f32),
+    Double(f64),
+    String(String),
+    List(Vec<Tag>),
+    Compound(Vec<(&'static str, Tag)>),
+}
+
+pub fn string(value: &str) -> Tag {
+    Tag::String(value.to_string())
+}
+
+pub fn compound(entries: Vec<(&'static str, Tag)>) -> Tag {
+    Tag::Compound(entries)
+}
+
+/// Strings are modified UTF-8 in NBT, which only differs from UTF-8 for text the limbo
+/// never sends.
+fn write_str(out: &mut Vec<u8>, value: &str) {
+    out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+impl Tag {
+    fn id(&self) -> u8 {
+        match self {
+            Tag::Byte(_) => 1,
+            Tag::Int(_) => 3,
+            Tag::Float(_) => 5,
+            Tag::Double(_) => 6,
+            Tag::String(_) => 8,
+            Tag::List(_) => 9,
+            Tag::Compound(_) => 10,
+        }
+    }
+
+    fn write_payload(&self, out: &mut Vec<u8>) {
+        match self {
+            Tag::Byte(value) => out.push(*value as u8),
+            Tag::Int(value) => out.extend_from_slice(&value.to_be_bytes()),
+            Tag::Float(value) => out.extend_from_slice(&value.to_be_bytes()),
+            Tag::Double(value) => out.extend_from_slice(&value.to_be_bytes()),
+            Tag::String(value) => write_str(out, value),
+            Tag::List(elements) => {
+                // empty lists are typed as lists of end tags
+                out.push(elements.first().map_or(0, Tag::id));
+                out.extend_from_slice(&(elements.len() as i32).to_be_bytes());
+                for element in elements {
+                    element.write_payload(out);
+                }
+            }
+            Tag::Compound(entries) => {
+                for (name, tag) in entries {
+                    out.push(tag.id());
+                    write_str(out, name);
+                    tag.write_payload(out);
+                }
+                out.push(0);
+            }
+        }
+    }
+
+    /// Writes the tag as the unnamed root of an NBT document.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.push(self.id());
+        write_str(out, "");
+        self.write_payload(out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_compounds() {
+        let mut out = vec![];
+        compound(vec![("map", Tag::Int(7))]).write(&mut out);
+        assert_eq!(out, [10, 0, 0, 3, 0, 3, b'm', b'a', b'p', 0, 0, 0, 7, 0]);
+    }
+
+    #[test]
+    fn writes_lists() {
+        let mut out = vec![];
+        Tag::List(vec![string("a"), string("b")]).write_payload(&mut out);
+        assert_eq!(out, [8, 0, 0, 0, 2, 0, 1, b'a', 0, 1, b'b']);
+        out.clear();
+        Tag::List(vec![]).write_payload(&mut out);
+        assert_eq!(out, [0, 0, 0, 0, 0]);
+    }
+}
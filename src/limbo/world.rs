@@ -0,0 +1,220 @@
+use drax::VarInt;
+
+use super::nbt::{compound, string, Tag};
+use crate::net::codec;
+
+const DIMENSION: &str = "minecraft:overworld";
+const HEIGHT: i32 = 256;
+const SECTIONS: usize = HEIGHT as usize / 16;
+const STONE: VarInt = 1;
+const BLOCKS_PER_SECTION: i16 = 16 * 16 * 16;
+/// The height players stand at on the chunk's floor.
+pub const FLOOR: f64 = 16.0;
+
+fn registry(kind: &'static str, entries: Vec<(&str, Tag)>) -> Tag {
+    let entries = entries
+        .into_iter()
+        .enumerate()
+        .map(|(id, (name, element))| {
+            compound(vec![
+                ("name", string(name)),
+                ("id", Tag::Int(id as i32)),
+                ("element", element),
+            ])
+        })
+        .collect();
+    compound(vec![("type", string(kind)), ("value", Tag::List(entries))])
+}
+
+fn dimension_type() -> Tag {
+    compound(vec![
+        ("piglin_safe", Tag::Byte(0)),
+        ("natural", Tag::Byte(1)),
+        ("ambient_light", Tag::Float(0.0)),
+        ("infiniburn", string("#minecraft:infiniburn_overworld")),
+        ("respawn_anchor_works", Tag::Byte(0)),
+        ("has_skylight", Tag::Byte(1)),
+        ("bed_works", Tag::Byte(0)),
+        ("effects", string("minecraft:overworld")),
+        ("has_raids", Tag::Byte(0)),
+        ("monster_spawn_light_level", Tag::Int(0)),
+        ("monster_spawn_block_light_limit", Tag::Int(0)),
+        ("min_y", Tag::Int(0)),
+        ("height", Tag::Int(HEIGHT)),
+        ("logical_height", Tag::Int(HEIGHT)),
+        ("coordinate_scale", Tag::Double(1.0)),
+        ("ultrawarm", Tag::Byte(0)),
+        ("has_ceiling", Tag::Byte(0)),
+    ])
+}
+
+fn biome() -> Tag {
+    compound(vec![
+        ("precipitation", string("none")),
+        ("temperature", Tag::Float(0.8)),
+        ("downfall", Tag::Float(0.4)),
+        (
+            "effects",
+            compound(vec![
+                ("sky_color", Tag::Int(0x78A7FF)),
+                ("water_fog_color", Tag::Int(0x050533)),
+                ("fog_color", Tag::Int(0xC0D8FF)),
+                ("water_color", Tag::Int(0x3F76E4)),
+            ]),
+        ),
+    ])
+}
+
+/// The chat types the client needs to show system messages. 1.19 refers to them by id in
+/// every system message, 1.19.1 only requires the registry to exist.
+fn chat_types(protocol_version: VarInt) -> Vec<(&'static str, Tag)> {
+    if protocol_version == 759 {
+        let plain = |priority| {
+            compound(vec![
+                ("chat", compound(vec![])),
+                ("narration", compound(vec![("priority", string(priority))])),
+            ])
+        };
+        return vec![
+            ("minecraft:chat", plain("chat")),
+            ("minecraft:system", plain("system")),
+            (
+                "minecraft:game_info",
+                compound(vec![("overlay", compound(vec![]))]),
+            ),
+        ];
+    }
+    let decoration = |translation_key| {
+        compound(vec![
+            ("translation_key", string(translation_key)),
+            (
+                "parameters",
+                Tag::List(vec![string("sender"), string("content")]),
+            ),
+        ])
+    };
+    vec![(
+        "minecraft:chat",
+        compound(vec![
+            ("chat", decoration("chat.type.text")),
+            ("narration", decoration("chat.type.text.narrate")),
+        ]),
+    )]
+}
+
+fn registry_codec(protocol_version: VarInt) -> Tag {
+    compound(vec![
+        (
+            "minecraft:dimension_type",
+            registry(
+                "minecraft:dimension_type",
+                vec![(DIMENSION, dimension_type())],
+            ),
+        ),
+        (
+            "minecraft:worldgen/biome",
+            registry(
+                "minecraft:worldgen/biome",
+                vec![("minecraft:plains", biome())],
+            ),
+        ),
+        (
+            "minecraft:chat_type",
+            registry("minecraft:chat_type", chat_types(protocol_version)),
+        ),
+    ])
+}
+
+/// Joins the client into the limbo's world, which only has the registries it needs itself.
+/// The backend's join replaces them later, like on a server switch.
+pub fn join_game(packet_id: VarInt, protocol_version: VarInt, game_mode: u8) -> Vec<u8> {
+    let mut data = vec![];
+    codec::write_var_int(&mut data, packet_id);
+    // entity id, hardcore, game mode and no previous game mode
+    data.extend_from_slice(&0i32.to_be_bytes());
+    data.push(0);
+    data.push(game_mode);
+    data.push(-1i8 as u8);
+    codec::write_var_int(&mut data, 1);
+    codec::write_string(&mut data, DIMENSION);
+    registry_codec(protocol_version).write(&mut data);
+    // dimension type and name
+    codec::write_string(&mut data, DIMENSION);
+    codec::write_string(&mut data, DIMENSION);
+    data.extend_from_slice(&0i64.to_be_bytes());
+    // max players, view and simulation distance
+    for value in [1, 2, 2] {
+        codec::write_var_int(&mut data, value);
+    }
+    // reduced debug info, respawn screen, debug and flat world, then no death location
+    data.extend_from_slice(&[0, 1, 0, 1, 0]);
+    data
+}
+
+/// A chunk with a stone floor at the bottom and air above it. The client only runs physics
+/// for players in loaded chunks.
+pub fn chunk_data(packet_id: VarInt) -> Vec<u8> {
+    let mut data = vec![];
+    codec::write_var_int(&mut data, packet_id);
+    data.extend_from_slice(&0i32.to_be_bytes());
+    data.extend_from_slice(&0i32.to_be_bytes());
+    // heightmaps, an empty compound
+    compound(vec![]).write(&mut data);
+    // every section holds a single block state and the first biome
+    let mut sections = vec![];
+    for section in 0..SECTIONS {
+        let (block_count, block) = match section {
+            0 => (BLOCKS_PER_SECTION, STONE),
+            _ => (0, 0),
+        };
+        sections.extend_from_slice(&block_count.to_be_bytes());
+        sections.push(0);
+        codec::write_var_int(&mut sections, block);
+        codec::write_var_int(&mut sections, 0);
+        sections.extend_from_slice(&[0, 0, 0]);
+    }
+    codec::write_var_int(&mut data, sections.len() as VarInt);
+    data.extend_from_slice(&sections);
+    // no block entities, trust edges, then four empty light masks and no light arrays
+    data.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    data
+}
+
+pub fn sync_position(packet_id: VarInt, position: (f64, f64, f64), teleport_id: VarInt) -> Vec<u8> {
+    let mut data = vec![];
+    codec::write_var_int(&mut data, packet_id);
+    for coordinate in [position.0, position.1, position.2] {
+        data.extend_from_slice(&coordinate.to_be_bytes());
+    }
+    data.extend_from_slice(&0f32.to_be_bytes());
+    data.extend_from_slice(&0f32.to_be_bytes());
+    // absolute position, so the client's velocity is reset
+    data.push(0);
+    codec::write_var_int(&mut data, teleport_id);
+    data.push(0);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_cover_the_dimension() {
+        let data = chunk_data(0x21);
+        let mut rest = &data[1 + 8 + 4..];
+        let len = codec::read_var_int(&mut rest).unwrap() as usize;
+        // the floor's section is followed by empty ones
+        assert_eq!(len, 8 + (SECTIONS - 1) * 8);
+        assert_eq!(&rest[..8], &[0x10, 0x00, 0, STONE as u8, 0, 0, 0, 0]);
+        assert_eq!(&rest[8..16], &[0; 8]);
+        assert_eq!(rest.len(), len + 8);
+    }
+
+    #[test]
+    fn joins_start_with_the_entity_and_game_mode() {
+        let data = join_game(0x25, 760, 2);
+        assert_eq!(&data[..8], &[0x25, 0, 0, 0, 0, 0, 2, 0xFF]);
+        assert_eq!(&data[data.len() - 5..], &[0, 1, 0, 1, 0]);
+    }
+}
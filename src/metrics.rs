@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::net::{ReadHalf, WriteHalf};
+use crate::ProxyInfo;
+
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Everything exported on `/metrics`. Gauges are filled in from the registry when scraped,
+/// the rest is recorded where it happens.
+pub struct Metrics {
+    registry: Registry,
+    players_online: IntGauge,
+    players_per_server: IntGaugeVec,
+    players_per_protocol: IntGaugeVec,
+    backend_up: IntGaugeVec,
+    /// By `outcome`: success, banned, maintenance, not_whitelisted, denied, full, duplicate or
+    /// no_server.
+    pub logins: IntCounterVec,
+    /// By `reason`: server for backend kicks, proxy for kicks issued through the proxy and
+    /// no_fallback when a lost player had nowhere left to go.
+    pub kicks: IntCounterVec,
+    pub server_switches: IntCounter,
+    pub status_pings: IntCounter,
+    /// Connections refused by `limit`, see `throttle::Limit`.
+    pub throttled: IntCounterVec,
+    /// Bytes on client connections by `direction`, `in` from clients and `out` to them, as
+    /// they go over the wire.
+    pub bytes: IntCounterVec,
+    pub login_duration: Histogram,
+    /// By `server` and `result`, `ok` or `error`, so failed attempts show up too.
+    pub backend_connect_latency: HistogramVec,
+    pub keep_alive_rtt: Histogram,
+}
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(registry: &Registry, metric: T) -> T {
+    registry
+        .register(Box::new(metric.clone()))
+        .expect("metric names are unique");
+    metric
+}
+
+struct Counted<T> {
+    inner: T,
+    counter: IntCounter,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counted<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.counter.inc_by((buf.filled().len() - filled) as u64);
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counted<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            this.counter.inc_by(written as u64);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("umbrella".to_string()), None).expect("the prefix is valid");
+        let histogram = |name: &str, help: &str| {
+            HistogramOpts::new(name, help).buckets(LATENCY_BUCKETS.to_vec())
+        };
+        Self {
+            players_online: register(
+                &registry,
+                IntGauge::new("players_online", "Players connected to the proxy").unwrap(),
+            ),
+            players_per_server: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("server_players", "Players connected to each server"),
+                    &["server"],
+                )
+                .unwrap(),
+            ),
+            players_per_protocol: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("protocol_players", "Players by client protocol version"),
+                    &["protocol_version"],
+                )
+                .unwrap(),
+            ),
+            backend_up: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new(
+                        "backend_up",
+                        "Whether the last probe or player connection to the server succeeded",
+                    ),
+                    &["server"],
+                )
+                .unwrap(),
+            ),
+            logins: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("logins_total", "Login attempts by outcome"),
+                    &["outcome"],
+                )
+                .unwrap(),
+            ),
+            kicks: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("kicks_total", "Players disconnected by reason class"),
+                    &["reason"],
+                )
+                .unwrap(),
+            ),
+            server_switches: register(
+                &registry,
+                IntCounter::new("server_switches_total", "Completed server switches").unwrap(),
+            ),
+            status_pings: register(
+                &registry,
+                IntCounter::new("status_pings_total", "Status requests answered").unwrap(),
+            ),
+            throttled: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "throttled_connections_total",
+                        "Connections refused by limit",
+                    ),
+                    &["limit"],
+                )
+                .unwrap(),
+            ),
+            bytes: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "relayed_bytes_total",
+                        "Client connection bytes by direction",
+                    ),
+                    &["direction"],
+                )
+                .unwrap(),
+            ),
+            login_duration: register(
+                &registry,
+                Histogram::with_opts(histogram(
+                    "login_duration_seconds",
+                    "Time from accepting a connection to the player joining a server",
+                ))
+                .unwrap(),
+            ),
+            backend_connect_latency: register(
+                &registry,
+                HistogramVec::new(
+                    histogram(
+                        "backend_connect_seconds",
+                        "Time to connect and log in to a backend, by result",
+                    ),
+                    &["server", "result"],
+                )
+                .unwrap(),
+            ),
+            keep_alive_rtt: register(
+                &registry,
+                Histogram::with_opts(histogram(
+                    "keep_alive_rtt_seconds",
+                    "Round trip time of keep alives between backend and client",
+                ))
+                .unwrap(),
+            ),
+            registry,
+        }
+    }
+
+    pub fn login(&self, outcome: &str) {
+        self.logins.with_label_values(&[outcome]).inc();
+    }
+
+    pub fn kick(&self, reason: &str) {
+        self.kicks.with_label_values(&[reason]).inc();
+    }
+
+    /// Counts everything read from and written to a client, including what the proxy sends
+    /// on its own.
+    pub fn count_client(&self, read: ReadHalf, write: WriteHalf) -> (ReadHalf, WriteHalf) {
+        (
+            Box::new(Counted {
+                inner: read,
+                counter: self.bytes.with_label_values(&["in"]),
+            }),
+            Box::new(Counted {
+                inner: write,
+                counter: self.bytes.with_label_values(&["out"]),
+            }),
+        )
+    }
+
+    async fn render(&self, proxy_info: &ProxyInfo) -> String {
+        let players = proxy_info.players.players().await;
+        self.players_online.set(players.len() as i64);
+
+        let mut per_protocol: HashMap<i32, i64> = HashMap::new();
+        for player in &players {
+            *per_protocol
+                .entry(player.client_info.protocol_version)
+                .or_insert(0) += 1;
+        }
+        self.players_per_protocol.reset();
+        for (protocol_version, count) in per_protocol {
+            self.players_per_protocol
+                .with_label_values(&[&protocol_version.to_string()])
+                .set(count);
+        }
+
+        let counts = proxy_info.players.server_counts().await;
+        self.players_per_server.reset();
+        self.backend_up.reset();
+        for server_id in proxy_info.config().servers.keys() {
+            self.players_per_server
+                .with_label_values(&[server_id])
+                .set(counts.get(server_id).copied().unwrap_or(0) as i64);
+            if let Some(health) = proxy_info.health.get(server_id) {
+                self.backend_up
+                    .with_label_values(&[server_id])
+                    .set(health.reachable as i64);
+            }
+        }
+
+        let mut buffer = vec![];
+        if let Err(err) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            log::warn!("Failed to encode metrics: {}", err);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+async fn metrics(State(proxy_info): State<Arc<ProxyInfo>>) -> impl IntoResponse {
+    (
+        [(
+            header::CONTENT_TYPE,
+            TextEncoder::new().format_type().to_string(),
+        )],
+        proxy_info.metrics.render(&proxy_info).await,
+    )
+}
+
+pub async fn serve(proxy_info: Arc<ProxyInfo>, bind: String) -> anyhow::Result<()> {
+    let addr: SocketAddr = bind.parse()?;
+    let router = Router::new()
+        .route("/metrics", get(metrics))
+        .with_state(proxy_info);
+    log::info!("Metrics listening on {}", addr);
+    axum::Server::bind(&addr)
+        .serve(router.into_make_service())
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,517 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use chrono::{Local, TimeZone, Utc};
+use mcprotocol::chat::Chat;
+use uuid::Uuid;
+
+use crate::cfg::{self, ModerationConfig, ModerationStorage};
+use crate::cidr::Cidr;
+use crate::player::ClientInfo;
+use crate::ProxyInfo;
+
+mod sqlite;
+
+pub use sqlite::SqlitePunishmentStore;
+
+#[derive(serde_derive::Deserialize, serde_derive::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PunishmentKind {
+    Ban,
+    Mute,
+}
+
+impl PunishmentKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PunishmentKind::Ban => "ban",
+            PunishmentKind::Mute => "mute",
+        }
+    }
+}
+
+#[derive(serde_derive::Deserialize, serde_derive::Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum PunishmentTarget {
+    Uuid(Uuid),
+    /// Matched case insensitively, for players who haven't joined yet.
+    Name(String),
+    Ip(Cidr),
+}
+
+impl PunishmentTarget {
+    /// Reads a uuid, an IP address or range, and anything else as a player name.
+    pub fn parse(value: &str) -> Self {
+        if let Ok(uuid) = Uuid::parse_str(value) {
+            PunishmentTarget::Uuid(uuid)
+        } else if let Ok(cidr) = value.parse() {
+            PunishmentTarget::Ip(cidr)
+        } else {
+            PunishmentTarget::Name(value.to_string())
+        }
+    }
+
+    /// Like `parse`, but names of online players become their uuid so the entry survives
+    /// name changes.
+    pub async fn resolve(proxy_info: &ProxyInfo, value: &str) -> Self {
+        match Self::parse(value) {
+            PunishmentTarget::Name(name) => match proxy_info.players.by_name(&name).await {
+                Some(player) => PunishmentTarget::Uuid(player.uuid()),
+                None => PunishmentTarget::Name(name),
+            },
+            target => target,
+        }
+    }
+
+    pub fn matches(&self, client_info: &ClientInfo) -> bool {
+        match self {
+            PunishmentTarget::Uuid(uuid) => client_info.profile.id == *uuid,
+            PunishmentTarget::Name(name) => client_info.profile.name.eq_ignore_ascii_case(name),
+            // players on Unix sockets have no address of their own
+            PunishmentTarget::Ip(cidr) => {
+                let ip = client_info.remote_addr.ip();
+                !ip.is_unspecified() && cidr.contains(ip)
+            }
+        }
+    }
+
+    fn same(&self, other: &PunishmentTarget) -> bool {
+        match (self, other) {
+            (PunishmentTarget::Name(a), PunishmentTarget::Name(b)) => a.eq_ignore_ascii_case(b),
+            _ => self == other,
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            PunishmentTarget::Uuid(_) => "uuid",
+            PunishmentTarget::Name(_) => "name",
+            PunishmentTarget::Ip(_) => "ip",
+        }
+    }
+
+    fn from_parts(type_name: &str, value: &str) -> Option<Self> {
+        match type_name {
+            "uuid" => Uuid::parse_str(value).ok().map(PunishmentTarget::Uuid),
+            "name" => Some(PunishmentTarget::Name(value.to_string())),
+            "ip" => value.parse().ok().map(PunishmentTarget::Ip),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PunishmentTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PunishmentTarget::Uuid(uuid) => write!(f, "{}", uuid),
+            PunishmentTarget::Name(name) => write!(f, "{}", name),
+            PunishmentTarget::Ip(cidr) => write!(f, "{}", cidr),
+        }
+    }
+}
+
+/// Timestamps are unix seconds.
+#[derive(serde_derive::Deserialize, serde_derive::Serialize, Debug, Clone)]
+pub struct Punishment {
+    pub kind: PunishmentKind,
+    pub target: PunishmentTarget,
+    pub reason: String,
+    pub issuer: String,
+    pub created_at: i64,
+    /// `None` for permanent entries.
+    pub expires_at: Option<i64>,
+}
+
+fn now() -> i64 {
+    Utc::now().timestamp()
+}
+
+impl Punishment {
+    /// An entry starting now, lasting `duration` or forever.
+    pub fn new(
+        kind: PunishmentKind,
+        target: PunishmentTarget,
+        reason: String,
+        issuer: String,
+        duration: Option<Duration>,
+    ) -> Self {
+        let created_at = now();
+        Self {
+            kind,
+            target,
+            reason,
+            issuer,
+            created_at,
+            expires_at: duration.map(|duration| created_at + duration.as_secs() as i64),
+        }
+    }
+
+    fn is_active(&self, now: i64) -> bool {
+        self.expires_at.map_or(true, |expires_at| expires_at > now)
+    }
+
+    pub fn remaining(&self) -> Option<Duration> {
+        self.expires_at
+            .map(|expires_at| Duration::from_secs((expires_at - now()).max(0) as u64))
+    }
+
+    /// Fills in `{reason}`, `{issuer}`, `{expires}` and `{remaining}`.
+    pub fn render(&self, template: &str) -> String {
+        let expires = match self.expires_at {
+            Some(expires_at) => Local.timestamp_opt(expires_at, 0).single().map_or_else(
+                || expires_at.to_string(),
+                |expires| expires.format("%Y-%m-%d %H:%M").to_string(),
+            ),
+            None => "never".to_string(),
+        };
+        let remaining = self
+            .remaining()
+            .map_or_else(|| "permanent".to_string(), format_duration);
+        template
+            .replace("{reason}", &self.reason)
+            .replace("{issuer}", &self.issuer)
+            .replace("{expires}", &expires)
+            .replace("{remaining}", &remaining)
+    }
+}
+
+const UNITS: [(char, u64); 5] = [
+    ('w', 7 * 24 * 60 * 60),
+    ('d', 24 * 60 * 60),
+    ('h', 60 * 60),
+    ('m', 60),
+    ('s', 1),
+];
+
+/// Reads durations like `30m` or `1d12h`.
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let mut total = 0u64;
+    let mut number = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let (_, seconds) = UNITS
+            .iter()
+            .find(|(unit, _)| *unit == c.to_ascii_lowercase())?;
+        total = total.checked_add(number.parse::<u64>().ok()?.checked_mul(*seconds)?)?;
+        number.clear();
+    }
+    (number.is_empty() && total > 0).then_some(Duration::from_secs(total))
+}
+
+/// Largest two units, e.g. `2d 5h`, or `45s` for anything under a minute.
+pub fn format_duration(duration: Duration) -> String {
+    let mut remaining = duration.as_secs();
+    let mut parts = vec![];
+    for (unit, seconds) in UNITS.iter().skip(1) {
+        if remaining >= *seconds && parts.len() < 2 {
+            parts.push(format!("{}{}", remaining / seconds, unit));
+            remaining %= seconds;
+        }
+    }
+    if parts.is_empty() {
+        "0s".to_string()
+    } else {
+        parts.join(" ")
+    }
+}
+
+pub trait PunishmentStore: Send + Sync {
+    /// Adds the entry, replacing any entry of the same kind for the same target.
+    fn add(&self, punishment: Punishment) -> anyhow::Result<()>;
+
+    /// Returns false if there was no such entry.
+    fn remove(&self, kind: PunishmentKind, target: &PunishmentTarget) -> anyhow::Result<bool>;
+
+    /// Entries of the kind that haven't expired.
+    fn active(&self, kind: PunishmentKind) -> anyhow::Result<Vec<Punishment>>;
+
+    /// The active entry of the kind applying to the client.
+    fn find(
+        &self,
+        kind: PunishmentKind,
+        client_info: &ClientInfo,
+    ) -> anyhow::Result<Option<Punishment>> {
+        Ok(self
+            .active(kind)?
+            .into_iter()
+            .find(|punishment| punishment.target.matches(client_info)))
+    }
+
+    fn reload(&self) -> anyhow::Result<()>;
+}
+
+/// Keeps every entry in memory and rewrites the whole file on changes, dropping expired
+/// entries as it goes.
+pub struct JsonPunishmentStore {
+    path: PathBuf,
+    data: RwLock<Vec<Punishment>>,
+}
+
+impl JsonPunishmentStore {
+    /// Loads the file, creating it if it doesn't exist yet.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let store = Self {
+            path: path.to_path_buf(),
+            data: RwLock::new(vec![]),
+        };
+        if path.exists() {
+            store.reload()?;
+        } else {
+            store.save(&store.data.read().unwrap())?;
+        }
+        Ok(store)
+    }
+
+    fn save(&self, data: &[Punishment]) -> anyhow::Result<()> {
+        cfg::write_atomically(&self.path, &serde_json::to_string_pretty(data)?)?;
+        Ok(())
+    }
+
+    fn modify<T>(&self, f: impl FnOnce(&mut Vec<Punishment>) -> T) -> anyhow::Result<T> {
+        let mut data = self.data.write().unwrap();
+        let result = f(&mut data);
+        let now = now();
+        data.retain(|punishment| punishment.is_active(now));
+        self.save(&data)?;
+        Ok(result)
+    }
+}
+
+impl PunishmentStore for JsonPunishmentStore {
+    fn add(&self, punishment: Punishment) -> anyhow::Result<()> {
+        self.modify(|data| {
+            data.retain(|existing| {
+                existing.kind != punishment.kind || !existing.target.same(&punishment.target)
+            });
+            data.push(punishment);
+        })
+    }
+
+    fn remove(&self, kind: PunishmentKind, target: &PunishmentTarget) -> anyhow::Result<bool> {
+        self.modify(|data| {
+            let len = data.len();
+            data.retain(|existing| existing.kind != kind || !existing.target.same(target));
+            data.len() != len
+        })
+    }
+
+    fn active(&self, kind: PunishmentKind) -> anyhow::Result<Vec<Punishment>> {
+        let now = now();
+        Ok(self
+            .data
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|punishment| punishment.kind == kind && punishment.is_active(now))
+            .cloned()
+            .collect())
+    }
+
+    fn reload(&self) -> anyhow::Result<()> {
+        let data: Vec<Punishment> = serde_json::from_reader(fs::File::open(&self.path)?)?;
+        *self.data.write().unwrap() = data;
+        Ok(())
+    }
+}
+
+/// Bans and mutes, backed by the store picked in the config.
+pub struct Moderation {
+    store: Arc<dyn PunishmentStore>,
+}
+
+impl Moderation {
+    pub fn open(config: &ModerationConfig) -> anyhow::Result<Self> {
+        let store: Arc<dyn PunishmentStore> = match &config.storage {
+            ModerationStorage::Json { file } => {
+                Arc::new(JsonPunishmentStore::load(Path::new(file))?)
+            }
+            ModerationStorage::Sqlite { file } => {
+                Arc::new(SqlitePunishmentStore::open(Path::new(file))?)
+            }
+        };
+        Ok(Self { store })
+    }
+
+    pub fn store(&self) -> &dyn PunishmentStore {
+        self.store.as_ref()
+    }
+
+    /// The entry of the kind applying to the client. Storage errors are logged and let the
+    /// client through rather than locking everyone out. Runs on every login and chat message,
+    /// so the lookup goes to the blocking pool, where a store waiting on its database doesn't
+    /// stall the other connections.
+    pub async fn find(&self, kind: PunishmentKind, client_info: &ClientInfo) -> Option<Punishment> {
+        let store = self.store.clone();
+        let client_info = client_info.clone();
+        let found = match tokio::task::spawn_blocking(move || store.find(kind, &client_info)).await
+        {
+            Ok(found) => found,
+            Err(err) => Err(err.into()),
+        };
+        match found {
+            Ok(found) => found,
+            Err(err) => {
+                log::error!("Failed to check {}s: {:#}", kind.as_str(), err);
+                None
+            }
+        }
+    }
+
+    /// Stores the entry and applies it to online players, kicking the banned and telling the
+    /// muted. Returns how many players it affected.
+    pub async fn punish(
+        &self,
+        proxy_info: &ProxyInfo,
+        punishment: Punishment,
+    ) -> anyhow::Result<usize> {
+        self.store.add(punishment.clone())?;
+        let config = proxy_info.config();
+        let mut affected = 0;
+        for player in proxy_info.players.players().await {
+            if !punishment.target.matches(&player.client_info) {
+                continue;
+            }
+            affected += 1;
+            match punishment.kind {
+                PunishmentKind::Ban => {
+                    player.handle.kick(Chat::literal(
+                        punishment.render(&config.moderation.ban_screen),
+                    ));
+                }
+                PunishmentKind::Mute => {
+                    player.handle.send_message(Chat::literal(
+                        punishment.render(&config.moderation.mute_message),
+                    ));
+                }
+            }
+        }
+        log::info!(
+            "{} issued a {} for {}: {}",
+            punishment.issuer,
+            punishment.kind.as_str(),
+            punishment.target,
+            punishment.reason
+        );
+        Ok(affected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("30m"), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(
+            parse_duration("1d12h"),
+            Some(Duration::from_secs(36 * 60 * 60))
+        );
+        assert_eq!(
+            parse_duration("2W"),
+            Some(Duration::from_secs(14 * 24 * 60 * 60))
+        );
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for value in [
+            "",
+            "30",
+            "m",
+            "0s",
+            "5y",
+            "1h30",
+            "-5m",
+            "99999999999999999999w",
+        ] {
+            assert_eq!(parse_duration(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn formats_the_largest_two_units() {
+        assert_eq!(format_duration(Duration::from_secs(0)), "0s");
+        assert_eq!(format_duration(Duration::from_secs(45)), "45s");
+        assert_eq!(format_duration(Duration::from_secs(90)), "1m 30s");
+        assert_eq!(
+            format_duration(Duration::from_secs(2 * 24 * 60 * 60 + 5 * 60 * 60 + 59)),
+            "2d 5h"
+        );
+        assert_eq!(
+            format_duration(Duration::from_secs(14 * 24 * 60 * 60)),
+            "14d"
+        );
+    }
+
+    #[test]
+    fn parses_targets() {
+        assert_eq!(
+            PunishmentTarget::parse("069a79f4-44e9-4726-a5be-fca90e38aaf5"),
+            PunishmentTarget::Uuid(Uuid::parse_str("069a79f444e94726a5befca90e38aaf5").unwrap())
+        );
+        assert_eq!(
+            PunishmentTarget::parse("10.0.0.0/8"),
+            PunishmentTarget::Ip("10.0.0.0/8".parse().unwrap())
+        );
+        assert_eq!(
+            PunishmentTarget::parse("Notch"),
+            PunishmentTarget::Name("Notch".to_string())
+        );
+        assert!(PunishmentTarget::parse("notch").same(&PunishmentTarget::parse("NOTCH")));
+    }
+
+    #[test]
+    fn renders_messages() {
+        let mut punishment = Punishment::new(
+            PunishmentKind::Ban,
+            PunishmentTarget::parse("Notch"),
+            "Griefing".to_string(),
+            "Console".to_string(),
+            None,
+        );
+        assert!(punishment.is_active(now()));
+        assert_eq!(
+            punishment.render("{reason} by {issuer}, {remaining}, expires {expires}"),
+            "Griefing by Console, permanent, expires never"
+        );
+        punishment.expires_at = Some(punishment.created_at - 1);
+        assert!(!punishment.is_active(now()));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn finds_mutes_without_a_multi_threaded_runtime() {
+        let path = std::env::temp_dir().join(format!("umbrella-mutes-{}.json", std::process::id()));
+        let store = JsonPunishmentStore::load(&path).unwrap();
+        let id = Uuid::from_u128(7);
+        store
+            .add(Punishment {
+                kind: PunishmentKind::Mute,
+                target: PunishmentTarget::Uuid(id),
+                reason: "Spam".to_string(),
+                issuer: "Console".to_string(),
+                created_at: now(),
+                expires_at: None,
+            })
+            .unwrap();
+        let moderation = Moderation {
+            store: Arc::new(store),
+        };
+        let client_info =
+            crate::player::test_client_info("Steve", id, "10.0.0.1:25565".parse().unwrap());
+        let mute = moderation.find(PunishmentKind::Mute, &client_info).await;
+        assert_eq!(mute.map(|mute| mute.reason), Some("Spam".to_string()));
+        assert!(moderation
+            .find(PunishmentKind::Ban, &client_info)
+            .await
+            .is_none());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+use std::path::Path;
+use std::sync::Mutex as StdMutex;
+
+use rusqlite::{params, Connection, Statement};
+
+use super::{now, Punishment, PunishmentKind, PunishmentStore, PunishmentTarget};
+use crate::player::ClientInfo;
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS punishments (
+    kind TEXT NOT NULL,
+    target_type TEXT NOT NULL,
+    target TEXT NOT NULL COLLATE NOCASE,
+    reason TEXT NOT NULL,
+    issuer TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER,
+    PRIMARY KEY (kind, target_type, target)
+)";
+
+/// Queries the database on every check, so entries added by other proxies sharing the file
+/// apply right away.
+pub struct SqlitePunishmentStore {
+    connection: StdMutex<Connection>,
+}
+
+impl SqlitePunishmentStore {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute(SCHEMA, [])?;
+        Ok(Self {
+            connection: StdMutex::new(connection),
+        })
+    }
+}
+
+impl PunishmentStore for SqlitePunishmentStore {
+    fn add(&self, punishment: Punishment) -> anyhow::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO punishments
+                (kind, target_type, target, reason, issuer, created_at, expires_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                punishment.kind.as_str(),
+                punishment.target.type_name(),
+                punishment.target.to_string(),
+                punishment.reason,
+                punishment.issuer,
+                punishment.created_at,
+                punishment.expires_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn remove(&self, kind: PunishmentKind, target: &PunishmentTarget) -> anyhow::Result<bool> {
+        let removed = self.connection.lock().unwrap().execute(
+            "DELETE FROM punishments WHERE kind = ?1 AND target_type = ?2 AND target = ?3",
+            params![kind.as_str(), target.type_name(), target.to_string()],
+        )?;
+        Ok(removed > 0)
+    }
+
+    fn active(&self, kind: PunishmentKind) -> anyhow::Result<Vec<Punishment>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT target_type, target, reason, issuer, created_at, expires_at
+                FROM punishments
+                WHERE kind = ?1 AND (expires_at IS NULL OR expires_at > ?2)",
+        )?;
+        read_rows(&mut statement, kind, params![kind.as_str(), now()])
+    }
+
+    /// Looks up the client's uuid and name directly. Address ranges can't be matched in SQL,
+    /// so those are all read and checked here.
+    fn find(
+        &self,
+        kind: PunishmentKind,
+        client_info: &ClientInfo,
+    ) -> anyhow::Result<Option<Punishment>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT target_type, target, reason, issuer, created_at, expires_at
+                FROM punishments
+                WHERE kind = ?1 AND (expires_at IS NULL OR expires_at > ?2) AND (
+                    (target_type = 'uuid' AND target = ?3)
+                    OR (target_type = 'name' AND target = ?4)
+                    OR target_type = 'ip'
+                )",
+        )?;
+        let candidates = read_rows(
+            &mut statement,
+            kind,
+            params![
+                kind.as_str(),
+                now(),
+                PunishmentTarget::Uuid(client_info.profile.id).to_string(),
+                client_info.profile.name,
+            ],
+        )?;
+        Ok(candidates
+            .into_iter()
+            .find(|punishment| punishment.target.matches(client_info)))
+    }
+
+    /// Nothing is cached.
+    fn reload(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+fn read_rows(
+    statement: &mut Statement<'_>,
+    kind: PunishmentKind,
+    params: impl rusqlite::Params,
+) -> anyhow::Result<Vec<Punishment>> {
+    let rows = statement.query_map(params, |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            row.get(4)?,
+            row.get(5)?,
+        ))
+    })?;
+    let mut active = vec![];
+    for row in rows {
+        let (target_type, target, reason, issuer, created_at, expires_at) = row?;
+        match PunishmentTarget::from_parts(&target_type, &target) {
+            Some(target) => active.push(Punishment {
+                kind,
+                target,
+                reason,
+                issuer,
+                created_at,
+                expires_at,
+            }),
+            None => log::warn!("Skipping unreadable {} entry {}.", target_type, target),
+        }
+    }
+    Ok(active)
+}
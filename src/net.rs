@@ -0,0 +1,311 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::cfg::{DnsConfig, ServerInfo};
+use crate::dns::Dns;
+
+/// Var-ints and strings as the protocol encodes them, for packets the protocol library has
+/// no types for.
+pub mod codec;
+
+/// Read side of a client or backend connection, whatever kind of socket it is on.
+pub type ReadHalf = Box<dyn AsyncRead + Send + Sync + Unpin>;
+/// Write side of a client or backend connection, whatever kind of socket it is on.
+pub type WriteHalf = Box<dyn AsyncWrite + Send + Sync + Unpin>;
+
+/// Stands in for the address of peers on Unix sockets, which have none.
+pub const UNKNOWN_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// The socket path of a `unix:<path>` address.
+pub fn unix_path(addr: &str) -> Option<&Path> {
+    addr.strip_prefix(UNIX_PREFIX).map(Path::new)
+}
+
+#[cfg(not(unix))]
+fn unix_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix sockets aren't available on this platform",
+    )
+}
+
+enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &str) -> io::Result<Socket> {
+    Ok(Socket::Unix(UnixStream::connect(path).await?))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_: &str) -> io::Result<Socket> {
+    Err(unix_unsupported())
+}
+
+pub struct Stream {
+    socket: Socket,
+    /// Bytes taken off the socket by `read_ahead`, they are read again before anything else.
+    read_ahead: Vec<u8>,
+}
+
+impl From<Socket> for Stream {
+    fn from(socket: Socket) -> Self {
+        Stream {
+            socket,
+            read_ahead: vec![],
+        }
+    }
+}
+
+impl Stream {
+    /// Connects to the backend's socket path if it has one, its resolved address otherwise.
+    pub async fn connect(
+        dns: &Dns,
+        config: &DnsConfig,
+        server: &ServerInfo,
+    ) -> anyhow::Result<Stream> {
+        let socket = match &server.socket_path {
+            Some(path) => connect_unix(path).await?,
+            None => {
+                let addr = dns
+                    .resolve(config, &server.server_ip, server.server_port)
+                    .await?;
+                Socket::Tcp(TcpStream::connect(addr).await?)
+            }
+        };
+        Ok(socket.into())
+    }
+
+    /// The remote address, `None` on Unix sockets.
+    pub fn peer_addr(&self) -> io::Result<Option<SocketAddr>> {
+        match &self.socket {
+            Socket::Tcp(stream) => stream.peer_addr().map(Some),
+            #[cfg(unix)]
+            Socket::Unix(_) => Ok(None),
+        }
+    }
+
+    /// Waits for more bytes and keeps them to be read again later, up to `limit` bytes in
+    /// total. Returns how many came in, 0 once the peer closed the connection.
+    pub async fn read_ahead(&mut self, limit: usize) -> io::Result<usize> {
+        let mut buffer = vec![0; limit.saturating_sub(self.read_ahead.len())];
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let read = match &mut self.socket {
+            Socket::Tcp(stream) => stream.read(&mut buffer).await?,
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.read(&mut buffer).await?,
+        };
+        self.read_ahead.extend_from_slice(&buffer[..read]);
+        Ok(read)
+    }
+
+    /// Everything `read_ahead` took off the socket that wasn't read yet.
+    pub fn read_ahead_bytes(&self) -> &[u8] {
+        &self.read_ahead
+    }
+
+    pub fn into_split(self) -> (ReadHalf, WriteHalf) {
+        let (read, write): (ReadHalf, WriteHalf) = match self.socket {
+            Socket::Tcp(stream) => {
+                let (read, write) = stream.into_split();
+                (Box::new(read), Box::new(write))
+            }
+            #[cfg(unix)]
+            Socket::Unix(stream) => {
+                let (read, write) = stream.into_split();
+                (Box::new(read), Box::new(write))
+            }
+        };
+        if self.read_ahead.is_empty() {
+            return (read, write);
+        }
+        (
+            Box::new(io::Cursor::new(self.read_ahead).chain(read)),
+            write,
+        )
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.read_ahead.is_empty() {
+            let len = this.read_ahead.len().min(buf.remaining());
+            buf.put_slice(&this.read_ahead[..len]);
+            this.read_ahead.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+        match &mut this.socket {
+            Socket::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Socket::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().socket {
+            Socket::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Socket::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().socket {
+            Socket::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Socket::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().socket {
+            Socket::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Socket::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Binds `host:port`, with IPv6 hosts in brackets, or `unix:<path>`. A socket file left
+    /// behind at the path is replaced, anything else there is an error.
+    pub async fn bind(addr: &str) -> io::Result<Listener> {
+        match unix_path(addr) {
+            Some(path) => Self::bind_unix(path),
+            None => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+        }
+    }
+
+    #[cfg(unix)]
+    fn bind_unix(path: &Path) -> io::Result<Listener> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+            _ => {}
+        }
+        Ok(Listener::Unix(
+            UnixListener::bind(path)?,
+            path.to_path_buf(),
+        ))
+    }
+
+    #[cfg(not(unix))]
+    fn bind_unix(_: &Path) -> io::Result<Listener> {
+        Err(unix_unsupported())
+    }
+
+    /// Accepts the next connection, with its address if it is a TCP connection.
+    pub async fn accept(&self) -> io::Result<(Stream, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Socket::Tcp(stream).into(), Some(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Socket::Unix(stream).into(), None))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[test]
+    fn reads_unix_addresses() {
+        assert_eq!(
+            unix_path("unix:/run/umbrella.sock"),
+            Some(Path::new("/run/umbrella.sock"))
+        );
+        assert_eq!(unix_path("[::]:25565"), None);
+        assert_eq!(unix_path("0.0.0.0:25565"), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serves_unix_sockets_and_cleans_up_after_them() {
+        let path = std::env::temp_dir().join(format!("umbrella-{}.sock", std::process::id()));
+        let addr = format!("unix:{}", path.display());
+        // a socket left behind by an earlier run is replaced
+        std::mem::forget(Listener::bind(&addr).await.unwrap());
+        let listener = Listener::bind(&addr).await.unwrap();
+
+        let mut client: Stream = connect_unix(path.to_str().unwrap()).await.unwrap().into();
+        client.write_all(b"handshake").await.unwrap();
+        let (mut stream, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, None);
+        assert_eq!(stream.peer_addr().unwrap(), None);
+        while stream.read_ahead_bytes().len() < 9 {
+            stream.read_ahead(64).await.unwrap();
+        }
+        // what was read ahead is read again
+        let (mut read, _) = stream.into_split();
+        let mut data = [0; 9];
+        read.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"handshake");
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn leaves_other_files_at_the_socket_path_alone() {
+        let path = std::env::temp_dir().join(format!("umbrella-{}.file", std::process::id()));
+        std::fs::write(&path, "data").unwrap();
+        assert!(Listener::bind(&format!("unix:{}", path.display()))
+            .await
+            .is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "data");
+        std::fs::remove_file(path).unwrap();
+    }
+}
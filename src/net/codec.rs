@@ -0,0 +1,115 @@
+use drax::VarInt;
+
+/// Longest var-int encoding, five bytes for 32 bits.
+const MAX_VAR_INT_LEN: usize = 5;
+
+/// Why a value couldn't be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadError {
+    /// The data ends before the value does.
+    Incomplete,
+    /// Not something the protocol would send, like a var-int over five bytes.
+    Invalid,
+}
+
+/// Reads a var-int off the front of `data`, which is left untouched if it fails.
+pub fn read_var_int(data: &mut &[u8]) -> Result<VarInt, ReadError> {
+    let mut value = 0u32;
+    for (index, byte) in data.iter().enumerate().take(MAX_VAR_INT_LEN) {
+        value |= ((byte & 0x7F) as u32) << (7 * index);
+        if byte & 0x80 == 0 {
+            *data = &data[index + 1..];
+            return Ok(value as VarInt);
+        }
+    }
+    if data.len() >= MAX_VAR_INT_LEN {
+        Err(ReadError::Invalid)
+    } else {
+        Err(ReadError::Incomplete)
+    }
+}
+
+pub fn write_var_int(out: &mut Vec<u8>, value: VarInt) {
+    let mut value = value as u32;
+    loop {
+        if value & !0x7F == 0 {
+            out.push(value as u8);
+            return;
+        }
+        out.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+}
+
+/// Reads a string of at most `max_len` characters, as the protocol limits them.
+pub fn read_string(data: &mut &[u8], max_len: usize) -> Result<String, ReadError> {
+    let mut rest = *data;
+    let len = read_var_int(&mut rest)?;
+    // every character takes up to four bytes
+    if len < 0 || len as usize > max_len * 4 {
+        return Err(ReadError::Invalid);
+    }
+    let bytes = rest.get(..len as usize).ok_or(ReadError::Incomplete)?;
+    let value = std::str::from_utf8(bytes).map_err(|_| ReadError::Invalid)?;
+    if value.chars().count() > max_len {
+        return Err(ReadError::Invalid);
+    }
+    *data = &rest[len as usize..];
+    Ok(value.to_string())
+}
+
+pub fn write_string(out: &mut Vec<u8>, value: &str) {
+    write_var_int(out, value.len() as VarInt);
+    out.extend_from_slice(value.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn var_ints_round_trip() {
+        for value in [0, 1, 127, 128, 255, 25565, 2097151, i32::MAX, -1, i32::MIN] {
+            let mut out = vec![];
+            write_var_int(&mut out, value);
+            assert!(out.len() <= MAX_VAR_INT_LEN);
+            let mut data = out.as_slice();
+            assert_eq!(read_var_int(&mut data), Ok(value));
+            assert!(data.is_empty());
+        }
+    }
+
+    #[test]
+    fn reads_var_ints_off_the_front() {
+        let mut data: &[u8] = &[0xDD, 0xC7, 0x01, 0x05];
+        assert_eq!(read_var_int(&mut data), Ok(25565));
+        assert_eq!(data, &[0x05]);
+    }
+
+    #[test]
+    fn tells_incomplete_from_invalid_var_ints() {
+        let mut data: &[u8] = &[0x80, 0x80];
+        assert_eq!(read_var_int(&mut data), Err(ReadError::Incomplete));
+        assert_eq!(data.len(), 2);
+        let mut data: &[u8] = &[0x80; 5];
+        assert_eq!(read_var_int(&mut data), Err(ReadError::Invalid));
+    }
+
+    #[test]
+    fn strings_round_trip() {
+        let mut out = vec![];
+        write_string(&mut out, "héllo");
+        out.push(0x01);
+        let mut data = out.as_slice();
+        assert_eq!(read_string(&mut data, 16).as_deref(), Ok("héllo"));
+        assert_eq!(data, &[0x01]);
+    }
+
+    #[test]
+    fn limits_string_length() {
+        let mut out = vec![];
+        write_string(&mut out, "abcdef");
+        assert_eq!(read_string(&mut out.as_slice(), 5), Err(ReadError::Invalid));
+        assert_eq!(read_string(&mut &out[..4], 16), Err(ReadError::Incomplete));
+    }
+}
@@ -0,0 +1,461 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use uuid::Uuid;
+
+use crate::cfg::{self, PermissionsConfig};
+
+/// Group every player belongs to without it being assigned.
+pub const DEFAULT_GROUP: &str = "default";
+
+pub enum PermissionSubject {
+    User(Uuid),
+    Group(String),
+}
+
+pub trait PermissionProvider: Send + Sync {
+    /// Checks a node for the player, `server` being the server they are currently on.
+    fn has_permission(&self, uuid: &Uuid, node: &str, server: Option<&str>) -> bool;
+
+    fn grant(
+        &self,
+        subject: PermissionSubject,
+        node: &str,
+        server: Option<&str>,
+    ) -> anyhow::Result<()>;
+
+    fn revoke(
+        &self,
+        subject: PermissionSubject,
+        node: &str,
+        server: Option<&str>,
+    ) -> anyhow::Result<()>;
+
+    fn add_group(&self, uuid: &Uuid, group: &str) -> anyhow::Result<()>;
+
+    fn remove_group(&self, uuid: &Uuid, group: &str) -> anyhow::Result<()>;
+
+    /// Re-reads the permissions, following any change to their settings.
+    fn reload(&self, config: &PermissionsConfig) -> anyhow::Result<()>;
+}
+
+/// A list of nodes, optionally negated with a leading `-`, plus per server overrides.
+#[derive(serde_derive::Deserialize, serde_derive::Serialize, Debug, Default, Clone)]
+pub struct NodeSet {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub permissions: Vec<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub servers: HashMap<String, Vec<String>>,
+}
+
+impl NodeSet {
+    fn nodes_mut(&mut self, server: Option<&str>) -> &mut Vec<String> {
+        match server {
+            Some(server) => self.servers.entry(server.to_string()).or_default(),
+            None => &mut self.permissions,
+        }
+    }
+
+    fn grant(&mut self, node: &str, server: Option<&str>) {
+        let nodes = self.nodes_mut(server);
+        let negated = format!("-{}", node);
+        nodes.retain(|existing| *existing != negated);
+        if !nodes.iter().any(|existing| existing == node) {
+            nodes.push(node.to_string());
+        }
+    }
+
+    fn revoke(&mut self, node: &str, server: Option<&str>) {
+        let nodes = self.nodes_mut(server);
+        nodes.retain(|existing| existing != node);
+        if let Some(server) = server {
+            if nodes.is_empty() {
+                self.servers.remove(server);
+            }
+        }
+    }
+
+    /// `Some(value)` if a node in this set decides the check, server specific nodes first.
+    fn check(&self, node: &str, server: Option<&str>) -> Option<bool> {
+        server
+            .and_then(|server| self.servers.get(server))
+            .and_then(|nodes| check_nodes(nodes, node))
+            .or_else(|| check_nodes(&self.permissions, node))
+    }
+}
+
+#[derive(serde_derive::Deserialize, serde_derive::Serialize, Debug, Default, Clone)]
+pub struct Group {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inherits: Vec<String>,
+    #[serde(flatten)]
+    pub nodes: NodeSet,
+}
+
+#[derive(serde_derive::Deserialize, serde_derive::Serialize, Debug, Default, Clone)]
+pub struct User {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    #[serde(flatten)]
+    pub nodes: NodeSet,
+}
+
+#[derive(serde_derive::Deserialize, serde_derive::Serialize, Debug, Default, Clone)]
+pub struct PermissionsFile {
+    #[serde(default)]
+    pub groups: HashMap<String, Group>,
+    #[serde(default)]
+    pub users: HashMap<Uuid, User>,
+}
+
+impl PermissionsFile {
+    fn initial() -> Self {
+        let mut groups = HashMap::new();
+        groups.insert(
+            DEFAULT_GROUP.to_string(),
+            Group {
+                inherits: vec![],
+                nodes: NodeSet {
+                    permissions: vec![
+                        "umbrella.command.server".to_string(),
+                        "umbrella.command.ping".to_string(),
+                    ],
+                    servers: HashMap::new(),
+                },
+            },
+        );
+        Self {
+            groups,
+            users: HashMap::new(),
+        }
+    }
+
+    fn check_group(
+        &self,
+        group: &str,
+        node: &str,
+        server: Option<&str>,
+        visited: &mut HashSet<String>,
+    ) -> Option<bool> {
+        if !visited.insert(group.to_string()) {
+            return None;
+        }
+        let group = self.groups.get(group)?;
+        group.nodes.check(node, server).or_else(|| {
+            group
+                .inherits
+                .iter()
+                .find_map(|parent| self.check_group(parent, node, server, visited))
+        })
+    }
+
+    pub fn has_permission(&self, uuid: &Uuid, node: &str, server: Option<&str>) -> bool {
+        let mut visited = HashSet::new();
+        let user = self.users.get(uuid);
+        user.and_then(|user| user.nodes.check(node, server))
+            .or_else(|| {
+                user.into_iter()
+                    .flat_map(|user| user.groups.iter())
+                    .find_map(|group| self.check_group(group, node, server, &mut visited))
+            })
+            .or_else(|| self.check_group(DEFAULT_GROUP, node, server, &mut visited))
+            .unwrap_or(false)
+    }
+}
+
+/// How specifically `pattern` matches `node`, `None` if it doesn't match at all.
+fn match_specificity(pattern: &str, node: &str) -> Option<usize> {
+    if pattern == node {
+        return Some(usize::MAX);
+    }
+    if pattern == "*" {
+        return Some(0);
+    }
+    let prefix = pattern.strip_suffix('*')?;
+    if prefix.ends_with('.') && node.starts_with(prefix) {
+        Some(prefix.len())
+    } else {
+        None
+    }
+}
+
+/// The most specific matching node decides, a negated node wins a tie.
+fn check_nodes(nodes: &[String], node: &str) -> Option<bool> {
+    let mut best: Option<(usize, bool)> = None;
+    for pattern in nodes {
+        let (pattern, value) = match pattern.strip_prefix('-') {
+            Some(negated) => (negated, false),
+            None => (pattern.as_str(), true),
+        };
+        if let Some(specificity) = match_specificity(pattern, node) {
+            best = match best {
+                Some((best_specificity, best_value))
+                    if best_specificity > specificity
+                        || (best_specificity == specificity && !best_value) =>
+                {
+                    Some((best_specificity, best_value))
+                }
+                _ => Some((specificity, value)),
+            };
+        }
+    }
+    best.map(|(_, value)| value)
+}
+
+pub struct FilePermissionProvider {
+    /// Only locked while `data` is, so changes are always saved to the file they came from.
+    path: RwLock<PathBuf>,
+    data: RwLock<PermissionsFile>,
+}
+
+impl FilePermissionProvider {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let provider = Self {
+            path: RwLock::new(path.to_path_buf()),
+            data: RwLock::new(PermissionsFile::initial()),
+        };
+        provider.open(path)?;
+        Ok(provider)
+    }
+
+    /// Reads the file, creating it with a default group if it doesn't exist yet.
+    fn open(&self, path: &Path) -> anyhow::Result<()> {
+        let mut data = self.data.write().unwrap();
+        let loaded = if path.exists() {
+            serde_json::from_reader(fs::File::open(path)?)?
+        } else {
+            let initial = PermissionsFile::initial();
+            write_file(path, &initial)?;
+            initial
+        };
+        *self.path.write().unwrap() = path.to_path_buf();
+        *data = loaded;
+        Ok(())
+    }
+
+    fn save(&self, data: &PermissionsFile) -> anyhow::Result<()> {
+        write_file(&self.path.read().unwrap(), data)
+    }
+
+    fn modify(&self, f: impl FnOnce(&mut PermissionsFile)) -> anyhow::Result<()> {
+        let mut data = self.data.write().unwrap();
+        f(&mut data);
+        self.save(&data)
+    }
+}
+
+impl PermissionProvider for FilePermissionProvider {
+    fn has_permission(&self, uuid: &Uuid, node: &str, server: Option<&str>) -> bool {
+        self.data.read().unwrap().has_permission(uuid, node, server)
+    }
+
+    fn grant(
+        &self,
+        subject: PermissionSubject,
+        node: &str,
+        server: Option<&str>,
+    ) -> anyhow::Result<()> {
+        self.modify(|data| match subject {
+            PermissionSubject::User(uuid) => data
+                .users
+                .entry(uuid)
+                .or_default()
+                .nodes
+                .grant(node, server),
+            PermissionSubject::Group(group) => data
+                .groups
+                .entry(group)
+                .or_default()
+                .nodes
+                .grant(node, server),
+        })
+    }
+
+    fn revoke(
+        &self,
+        subject: PermissionSubject,
+        node: &str,
+        server: Option<&str>,
+    ) -> anyhow::Result<()> {
+        self.modify(|data| match subject {
+            PermissionSubject::User(uuid) => {
+                if let Some(user) = data.users.get_mut(&uuid) {
+                    user.nodes.revoke(node, server);
+                }
+            }
+            PermissionSubject::Group(group) => {
+                if let Some(group) = data.groups.get_mut(&group) {
+                    group.nodes.revoke(node, server);
+                }
+            }
+        })
+    }
+
+    fn add_group(&self, uuid: &Uuid, group: &str) -> anyhow::Result<()> {
+        if !self.data.read().unwrap().groups.contains_key(group) {
+            anyhow::bail!("The group {} does not exist", group);
+        }
+        self.modify(|data| {
+            let user = data.users.entry(*uuid).or_default();
+            if !user.groups.iter().any(|existing| existing == group) {
+                user.groups.push(group.to_string());
+            }
+        })
+    }
+
+    fn remove_group(&self, uuid: &Uuid, group: &str) -> anyhow::Result<()> {
+        self.modify(|data| {
+            if let Some(user) = data.users.get_mut(uuid) {
+                user.groups.retain(|existing| existing != group);
+            }
+        })
+    }
+
+    fn reload(&self, config: &PermissionsConfig) -> anyhow::Result<()> {
+        self.open(Path::new(&config.file))
+    }
+}
+
+fn write_file(path: &Path, data: &PermissionsFile) -> anyhow::Result<()> {
+    cfg::write_atomically(path, &serde_json::to_string_pretty(data)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(nodes: &[&str]) -> Vec<String> {
+        nodes.iter().map(|node| node.to_string()).collect()
+    }
+
+    fn group(inherits: &[&str], permissions: &[&str]) -> Group {
+        Group {
+            inherits: nodes(inherits),
+            nodes: NodeSet {
+                permissions: nodes(permissions),
+                servers: HashMap::new(),
+            },
+        }
+    }
+
+    fn user(groups: &[&str], permissions: &[&str]) -> User {
+        User {
+            groups: nodes(groups),
+            nodes: NodeSet {
+                permissions: nodes(permissions),
+                servers: HashMap::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn the_most_specific_node_decides() {
+        let set = nodes(&["umbrella.*", "-umbrella.command.*", "umbrella.command.ping"]);
+        assert_eq!(check_nodes(&set, "umbrella.alert"), Some(true));
+        assert_eq!(check_nodes(&set, "umbrella.command.kick"), Some(false));
+        assert_eq!(check_nodes(&set, "umbrella.command.ping"), Some(true));
+        assert_eq!(check_nodes(&set, "other.node"), None);
+        assert_eq!(check_nodes(&nodes(&["*"]), "other.node"), Some(true));
+    }
+
+    #[test]
+    fn negated_nodes_win_ties() {
+        let set = nodes(&["umbrella.alert", "-umbrella.alert"]);
+        assert_eq!(check_nodes(&set, "umbrella.alert"), Some(false));
+    }
+
+    #[test]
+    fn wildcards_only_match_whole_segments() {
+        assert_eq!(match_specificity("umbrella.*", "umbrella.alert"), Some(9));
+        assert_eq!(match_specificity("umbrella*", "umbrella.alert"), None);
+        assert_eq!(match_specificity("umbrella.*", "umbrellas.alert"), None);
+    }
+
+    #[test]
+    fn server_nodes_come_first() {
+        let mut set = NodeSet::default();
+        set.grant("umbrella.alert", None);
+        set.grant("-umbrella.alert", Some("lobby"));
+        assert_eq!(set.check("umbrella.alert", Some("lobby")), Some(false));
+        assert_eq!(set.check("umbrella.alert", Some("survival")), Some(true));
+        assert_eq!(set.check("umbrella.alert", None), Some(true));
+        set.revoke("-umbrella.alert", Some("lobby"));
+        assert!(set.servers.is_empty());
+    }
+
+    #[test]
+    fn granting_replaces_the_negation() {
+        let mut set = NodeSet::default();
+        set.grant("-umbrella.alert", None);
+        set.grant("umbrella.alert", None);
+        set.grant("umbrella.alert", None);
+        assert_eq!(set.permissions, nodes(&["umbrella.alert"]));
+    }
+
+    #[test]
+    fn groups_inherit_and_users_override() {
+        let (admin, player, nobody) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        let mut file = PermissionsFile::initial();
+        file.groups.insert(
+            "mod".to_string(),
+            group(&[DEFAULT_GROUP], &["umbrella.command.kick"]),
+        );
+        file.groups
+            .insert("admin".to_string(), group(&["mod"], &["umbrella.*"]));
+        file.users
+            .insert(admin, user(&["admin"], &["-umbrella.command.kick"]));
+        file.users.insert(player, user(&["mod"], &[]));
+
+        assert!(file.has_permission(&admin, "umbrella.alert", None));
+        assert!(!file.has_permission(&admin, "umbrella.command.kick", None));
+        assert!(file.has_permission(&player, "umbrella.command.kick", None));
+        assert!(file.has_permission(&player, "umbrella.command.ping", None));
+        assert!(!file.has_permission(&player, "umbrella.alert", None));
+        assert!(file.has_permission(&nobody, "umbrella.command.server", None));
+        assert!(!file.has_permission(&nobody, "umbrella.command.kick", None));
+    }
+
+    #[test]
+    fn inheritance_cycles_terminate() {
+        let mut file = PermissionsFile::default();
+        file.groups.insert("a".to_string(), group(&["b"], &[]));
+        file.groups.insert("b".to_string(), group(&["a"], &[]));
+        let uuid = Uuid::from_u128(1);
+        file.users.insert(uuid, user(&["a"], &[]));
+        assert!(!file.has_permission(&uuid, "umbrella.alert", None));
+    }
+
+    #[test]
+    fn reloads_from_the_configured_file() {
+        let path = |name: &str| {
+            std::env::temp_dir().join(format!("umbrella-{}-{}.json", name, std::process::id()))
+        };
+        let (first, second) = (path("permissions-a"), path("permissions-b"));
+        let provider = FilePermissionProvider::load(&first).unwrap();
+        let uuid = Uuid::from_u128(1);
+        provider
+            .grant(PermissionSubject::User(uuid), "umbrella.alert", None)
+            .unwrap();
+        let saved: PermissionsFile =
+            serde_json::from_reader(fs::File::open(&first).unwrap()).unwrap();
+        assert!(saved.has_permission(&uuid, "umbrella.alert", None));
+
+        let config = PermissionsConfig {
+            file: second.to_string_lossy().to_string(),
+        };
+        provider.reload(&config).unwrap();
+        assert!(!provider.has_permission(&uuid, "umbrella.alert", None));
+        provider
+            .grant(PermissionSubject::User(uuid), "umbrella.command.kick", None)
+            .unwrap();
+        let saved: PermissionsFile =
+            serde_json::from_reader(fs::File::open(&second).unwrap()).unwrap();
+        assert!(saved.has_permission(&uuid, "umbrella.command.kick", None));
+        assert!(saved.groups.contains_key(DEFAULT_GROUP));
+        fs::remove_file(first).unwrap();
+        fs::remove_file(second).unwrap();
+    }
+}
@@ -1,10 +1,61 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::collections::HashMap;
+use std::sync::Mutex as StdMutex;
+use std::time::Instant;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
+use drax::transport::frame::PacketFrame;
 use drax::VarInt;
+use mcprotocol::chat::Chat;
+use mcprotocol::protocol::play::cb::{Disconnect, SystemChatMessage};
+use mcprotocol::protocol::play::sb::PluginMessage;
 use mcprotocol::protocol::{login::MojangIdentifiedKey, GameProfile};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::task::JoinHandle;
 use uuid::Uuid;
 
-use crate::{backend::BackendEndpoint, client::Client, ProxyInfo};
+use crate::backend::{
+    BackendEndpoint, BackendEndpointWithNoContext, BackendEvent, ClientWriter, EndpointResolution,
+    ForwardToServerType, ServerWriter,
+};
+use crate::cfg::{AccessDenied, ServerInfo};
+use crate::channel::{self, ChannelTracker};
+use crate::client::{Client, ClientEvent, ClientFunctionResponse};
+use crate::command::CommandSource;
+use crate::event::{Cancellable, ServerConnectedEvent, ServerKickEvent, ServerPreConnectEvent};
+use crate::registry::PlayerCommand;
+use crate::ProxyInfo;
+
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Protocol versions the protocol library speaks, 1.19 to 1.19.2.
+pub const SUPPORTED_PROTOCOL_VERSIONS: [VarInt; 2] = [759, 760];
+
+fn lost_connection() -> Chat {
+    Chat::literal("Lost connection to the server.")
+}
+
+/// Matches keep alives sent by the backend with the client's answers to measure ping.
+#[derive(Clone, Default)]
+pub struct KeepAliveTracker {
+    pending: Arc<StdMutex<HashMap<i64, Instant>>>,
+}
+
+impl KeepAliveTracker {
+    pub fn sent(&self, id: i64) {
+        let mut pending = self.pending.lock().unwrap();
+        // a client which never answers shouldn't grow this forever
+        pending.retain(|_, sent_at| sent_at.elapsed() < READ_TIMEOUT);
+        pending.insert(id, Instant::now());
+    }
+
+    pub fn answered(&self, id: i64) -> Option<Duration> {
+        self.pending
+            .lock()
+            .unwrap()
+            .remove(&id)
+            .map(|sent_at| sent_at.elapsed())
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct ClientInfo {
@@ -15,11 +66,567 @@ pub struct ClientInfo {
     pub profile: GameProfile,
 }
 
+/// A client with no properties and no signing key, for tests.
+#[cfg(test)]
+pub fn test_client_info(name: &str, id: Uuid, remote_addr: SocketAddr) -> ClientInfo {
+    ClientInfo {
+        protocol_version: SUPPORTED_PROTOCOL_VERSIONS[1],
+        remote_addr,
+        mojang_key: None,
+        sig_holder: None,
+        profile: GameProfile {
+            id,
+            name: name.to_string(),
+            properties: vec![],
+        },
+    }
+}
+
+pub fn check_server_access(
+    proxy_info: &ProxyInfo,
+    client_info: &ClientInfo,
+    server_id: &str,
+    server_info: &ServerInfo,
+) -> Result<(), AccessDenied> {
+    let uuid = client_info.profile.id;
+    server_info.check_access(server_id, &uuid, client_info.protocol_version, |node| {
+        proxy_info.permissions.has_permission(&uuid, node, None)
+    })
+}
+
+pub fn access_denied_message(denied: AccessDenied, server_id: &str) -> Chat {
+    Chat::literal(match denied {
+        AccessDenied::Permission => format!("§cYou do not have permission to join {}.", server_id),
+        AccessDenied::Whitelist => format!("§cYou are not whitelisted on {}.", server_id),
+        AccessDenied::Protocol => {
+            format!("§cYour client version is not supported on {}.", server_id)
+        }
+    })
+}
+
+/// Why `try_connect` has no connection to hand back.
+enum ConnectFailure {
+    Cancelled,
+    /// The player may not join the server the event handlers settled on, given by id.
+    Denied(String, AccessDenied),
+    Unreachable,
+}
+
+/// Fires `ServerPreConnectEvent` for the server and connects to whichever target the handlers
+/// settled on, if the player may join it.
+async fn try_connect(
+    session: &PlayerSession,
+    current_server: Option<&str>,
+    server_id: &str,
+    server_info: ServerInfo,
+) -> Result<BackendEndpointWithNoContext, ConnectFailure> {
+    let proxy_info = &session.proxy_info;
+    let client_info = &session.client_info;
+    let event = proxy_info
+        .events
+        .fire(ServerPreConnectEvent {
+            client_info: client_info.clone(),
+            session_id: session.session_id,
+            current_server: current_server.map(str::to_string),
+            target_id: server_id.to_string(),
+            target: server_info,
+            cancelled: false,
+        })
+        .await;
+    if event.is_cancelled() {
+        log::debug!(
+            "Connection of {} to {} was cancelled.",
+            client_info.profile.name,
+            server_id
+        );
+        return Err(ConnectFailure::Cancelled);
+    }
+    if let Err(denied) =
+        check_server_access(proxy_info, client_info, &event.target_id, &event.target)
+    {
+        log::debug!(
+            "Skipping {} for {}: {:?}",
+            event.target_id,
+            client_info.profile.name,
+            denied
+        );
+        return Err(ConnectFailure::Denied(event.target_id, denied));
+    }
+    match BackendEndpoint::create_partial_connection(
+        proxy_info.clone(),
+        &event.target_id,
+        &event.target,
+        client_info,
+    )
+    .await
+    {
+        Ok(connection) => {
+            proxy_info.health.record(&event.target_id, true);
+            Ok(connection)
+        }
+        Err(err) => {
+            proxy_info.health.record(&event.target_id, false);
+            log::warn!(
+                "Failed to connect {} to {}: {}",
+                client_info.profile.name,
+                event.target_id,
+                err
+            );
+            Err(ConnectFailure::Unreachable)
+        }
+    }
+}
+
+/// The id and info of the server a switch asks for. Returns the message to tell the player
+/// instead if they are already there or the server doesn't exist.
+fn switch_target(
+    target: ForwardToServerType,
+    current_server: &str,
+    servers: &HashMap<String, ServerInfo>,
+) -> Result<(String, ServerInfo), Chat> {
+    let (server_id, server_info) = match target {
+        ForwardToServerType::ById(server_id) => match servers.get(&server_id) {
+            Some(server_info) => (server_id, server_info.clone()),
+            None => {
+                return Err(Chat::literal(format!(
+                    "§cThe server {} does not exist.",
+                    server_id
+                )))
+            }
+        },
+        ForwardToServerType::Info(server_info) => (
+            server_info
+                .server_id
+                .clone()
+                .unwrap_or_else(|| server_info.server_name.clone()),
+            server_info,
+        ),
+    };
+    if server_id == current_server {
+        return Err(Chat::literal("You are already connected to this server."));
+    }
+    Ok((server_id, server_info))
+}
+
+/// Tries each server in order, returning the first one which accepts the client.
+/// Servers the client may not join are skipped.
+pub async fn connect_any(
+    session: &PlayerSession,
+    current_server: Option<&str>,
+    candidates: &[String],
+) -> Option<BackendEndpointWithNoContext> {
+    let config = session.proxy_info.config();
+    for server_id in candidates {
+        let server_info = match config.servers.get(server_id) {
+            Some(server_info) => server_info.clone(),
+            None => {
+                log::warn!("Unknown server {} in server list, skipping.", server_id);
+                continue;
+            }
+        };
+        if let Ok(connection) = try_connect(session, current_server, server_id, server_info).await {
+            return Some(connection);
+        }
+    }
+    None
+}
+
+/// State shared by a player's client reader, backend reader and relay loop.
+#[derive(Clone)]
+pub struct PlayerSession {
+    pub proxy_info: Arc<ProxyInfo>,
+    pub client_info: ClientInfo,
+    pub session_id: u64,
+    pub client_write: ClientWriter,
+    pub keep_alives: KeepAliveTracker,
+    pub channels: ChannelTracker,
+}
+
+impl PlayerSession {
+    pub async fn command_source(&self) -> CommandSource {
+        CommandSource {
+            proxy_info: self.proxy_info.clone(),
+            player: self.proxy_info.players.by_session(self.session_id).await,
+        }
+    }
+
+    pub async fn send_message(&self, message: Chat) -> Result<(), drax::transport::Error> {
+        self.client_write
+            .lock()
+            .await
+            .write_packet(&SystemChatMessage {
+                content: message,
+                overlay: false,
+            })
+            .await?;
+        Ok(())
+    }
+
+    pub async fn disconnect(&self, reason: Chat) -> Result<(), drax::transport::Error> {
+        self.client_write
+            .lock()
+            .await
+            .write_packet(&Disconnect { reason })
+            .await?;
+        Ok(())
+    }
+}
+
 pub struct ConnectedPlayer {
-    client_info: ClientInfo,
-    proxy_info: Arc<ProxyInfo>,
-    client: Client,
-    backend_endpoint: BackendEndpoint,
+    session: PlayerSession,
+    current_server: String,
+    server_write: ServerWriter,
+    client_task: JoinHandle<()>,
+    client_events: UnboundedReceiver<ClientEvent>,
+    backend_task: JoinHandle<()>,
+    backend_events: UnboundedReceiver<BackendEvent>,
+    commands: UnboundedReceiver<PlayerCommand>,
 }
 
-impl ConnectedPlayer {}
+impl ConnectedPlayer {
+    pub async fn start(
+        session: PlayerSession,
+        client: Client,
+        commands: UnboundedReceiver<PlayerCommand>,
+        initial_server: BackendEndpointWithNoContext,
+        joined_limbo: bool,
+    ) -> ConnectedPlayer {
+        let (client_sender, client_events) = mpsc::unbounded_channel();
+        let client_task = client.spawn(READ_TIMEOUT, client_sender);
+
+        let current_server = initial_server.server_id().to_string();
+        let (endpoint, server_write) =
+            BackendEndpoint::attach(session.clone(), initial_server, joined_limbo);
+        let (backend_sender, backend_events) = mpsc::unbounded_channel();
+        let backend_task = endpoint.spawn(READ_TIMEOUT, backend_sender);
+
+        session
+            .proxy_info
+            .players
+            .set_server(session.session_id, Some(current_server.clone()))
+            .await;
+        session
+            .proxy_info
+            .events
+            .fire(ServerConnectedEvent {
+                client_info: session.client_info.clone(),
+                session_id: session.session_id,
+                previous_server: None,
+                server_id: current_server.clone(),
+            })
+            .await;
+
+        ConnectedPlayer {
+            session,
+            current_server,
+            server_write,
+            client_task,
+            client_events,
+            backend_task,
+            backend_events,
+            commands,
+        }
+    }
+
+    pub async fn run(mut self) -> Result<(), drax::transport::Error> {
+        let result = self.relay().await;
+        self.client_task.abort();
+        self.backend_task.abort();
+        result
+    }
+
+    async fn relay(&mut self) -> Result<(), drax::transport::Error> {
+        loop {
+            tokio::select! {
+                event = self.client_events.recv() => match event {
+                    Some(event) => match event? {
+                        ClientFunctionResponse::DoNothing => {}
+                        ClientFunctionResponse::ForwardPacket(data) => {
+                            self.server_write
+                                .lock()
+                                .await
+                                .write_buffered_packet(PacketFrame { data })
+                                .await?;
+                        }
+                        ClientFunctionResponse::ForwardPackets(packets) => {
+                            let mut server_write = self.server_write.lock().await;
+                            for data in packets {
+                                server_write.write_buffered_packet(PacketFrame { data }).await?;
+                            }
+                        }
+                    },
+                    None => return Ok(()),
+                },
+                event = self.backend_events.recv() => match event {
+                    Some(Ok(EndpointResolution::DoNothing)) => {}
+                    Some(Ok(EndpointResolution::ForwardToServer(target))) => {
+                        self.switch_server(target).await?;
+                    }
+                    Some(Ok(EndpointResolution::Kicked(reason))) => {
+                        self.session.proxy_info.metrics.kick("server");
+                        if !self.kicked(reason).await? {
+                            return Ok(());
+                        }
+                    }
+                    Some(Ok(EndpointResolution::DisconnectGracefully)) | None => {
+                        if !self.fallback(lost_connection()).await? {
+                            return Ok(());
+                        }
+                    }
+                    Some(Err(err)) => {
+                        log::debug!(
+                            "Lost connection between {} and {}: {}",
+                            self.session.client_info.profile.name,
+                            self.current_server,
+                            err
+                        );
+                        if !self.fallback(lost_connection()).await? {
+                            return Ok(());
+                        }
+                    }
+                },
+                Some(command) = self.commands.recv() => match command {
+                    PlayerCommand::SendMessage(message) => self.session.send_message(message).await?,
+                    PlayerCommand::Kick(reason) => {
+                        self.session.proxy_info.metrics.kick("proxy");
+                        self.session.disconnect(reason).await?;
+                        return Ok(());
+                    }
+                    PlayerCommand::Transfer(target) => self.switch_server(target).await?,
+                    PlayerCommand::SendPluginMessage { channel, data } => {
+                        self.server_write
+                            .lock()
+                            .await
+                            .write_packet(&PluginMessage { channel, data })
+                            .await?;
+                    }
+                },
+            }
+        }
+    }
+
+    async fn switch_server(
+        &mut self,
+        target: ForwardToServerType,
+    ) -> Result<(), drax::transport::Error> {
+        let config = self.session.proxy_info.config();
+        let (server_id, server_info) =
+            match switch_target(target, &self.current_server, &config.servers) {
+                Ok(target) => target,
+                Err(message) => return self.session.send_message(message).await,
+            };
+        // access is checked once the event handlers settled on a target
+        let connection = try_connect(
+            &self.session,
+            Some(&self.current_server),
+            &server_id,
+            server_info,
+        )
+        .await;
+        match connection {
+            Ok(connection) => {
+                self.attach(connection).await;
+                Ok(())
+            }
+            Err(ConnectFailure::Denied(server_id, denied)) => {
+                self.session
+                    .send_message(access_denied_message(denied, &server_id))
+                    .await
+            }
+            Err(ConnectFailure::Cancelled | ConnectFailure::Unreachable) => {
+                self.session
+                    .send_message(Chat::literal("Could not connect to that server."))
+                    .await
+            }
+        }
+    }
+
+    /// Handles the backend kicking the player, see `ServerKickEvent`.
+    /// Returns false if the player had to be disconnected.
+    async fn kicked(&mut self, reason: Chat) -> Result<bool, drax::transport::Error> {
+        let event = self
+            .session
+            .proxy_info
+            .events
+            .fire(ServerKickEvent {
+                client_info: self.session.client_info.clone(),
+                session_id: self.session.session_id,
+                server_id: self.current_server.clone(),
+                reason,
+                redirect: None,
+                cancelled: false,
+            })
+            .await;
+        if event.is_cancelled() {
+            self.session.disconnect(event.reason).await?;
+            return Ok(false);
+        }
+        if let Some(redirect) = event.redirect.as_ref() {
+            let server_info = self
+                .session
+                .proxy_info
+                .config()
+                .servers
+                .get(redirect)
+                .cloned();
+            match server_info {
+                Some(server_info) => {
+                    if let Ok(connection) = try_connect(
+                        &self.session,
+                        Some(&self.current_server),
+                        redirect,
+                        server_info,
+                    )
+                    .await
+                    {
+                        self.attach(connection).await;
+                        return Ok(true);
+                    }
+                }
+                None => log::warn!("Kick redirect to unknown server {}.", redirect),
+            }
+        }
+        self.fallback(event.reason).await
+    }
+
+    /// Moves the player to the first available fallback server after losing their current one,
+    /// disconnecting them with `reason` if none is available. Returns false if disconnected.
+    async fn fallback(&mut self, reason: Chat) -> Result<bool, drax::transport::Error> {
+        let proxy_info = &self.session.proxy_info;
+        let candidates: Vec<String> = proxy_info
+            .scripts
+            .fallback_servers(
+                proxy_info,
+                &self.session.client_info,
+                &self.current_server,
+                &proxy_info.config().fallback,
+            )
+            .await
+            .into_iter()
+            .filter(|server_id| *server_id != self.current_server)
+            .collect();
+        match connect_any(&self.session, Some(&self.current_server), &candidates).await {
+            Some(connection) => {
+                self.attach(connection).await;
+                Ok(true)
+            }
+            None => {
+                self.session.proxy_info.metrics.kick("no_fallback");
+                self.session.disconnect(reason).await?;
+                Ok(false)
+            }
+        }
+    }
+
+    async fn attach(&mut self, connection: BackendEndpointWithNoContext) {
+        self.backend_task.abort();
+        let new_server = connection.server_id().to_string();
+        let previous_server = std::mem::replace(&mut self.current_server, new_server);
+        self.session.proxy_info.metrics.server_switches.inc();
+        let (endpoint, server_write) =
+            BackendEndpoint::attach(self.session.clone(), connection, true);
+        let (backend_sender, backend_events) = mpsc::unbounded_channel();
+        self.backend_task = endpoint.spawn(READ_TIMEOUT, backend_sender);
+        self.backend_events = backend_events;
+        self.server_write = server_write;
+        if let Some(data) = self.session.channels.registration() {
+            let channel = channel::register_channel(self.session.client_info.protocol_version);
+            let result = self
+                .server_write
+                .lock()
+                .await
+                .write_packet(&PluginMessage {
+                    channel: channel.to_string(),
+                    data,
+                })
+                .await;
+            if let Err(err) = result {
+                log::debug!(
+                    "Failed to replay channel registrations to {}: {}",
+                    self.current_server,
+                    err
+                );
+            }
+        }
+        self.session
+            .proxy_info
+            .players
+            .set_server(self.session.session_id, Some(self.current_server.clone()))
+            .await;
+        self.session
+            .proxy_info
+            .events
+            .fire(ServerConnectedEvent {
+                client_info: self.session.client_info.clone(),
+                session_id: self.session.session_id,
+                previous_server: Some(previous_server),
+                server_id: self.current_server.clone(),
+            })
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_info(name: &str, server_id: Option<&str>) -> ServerInfo {
+        serde_json::from_value(serde_json::json!({
+            "server_id": server_id,
+            "server_name": name,
+        }))
+        .unwrap()
+    }
+
+    fn servers() -> HashMap<String, ServerInfo> {
+        ["lobby", "survival"]
+            .into_iter()
+            .map(|id| (id.to_string(), server_info(id, None)))
+            .collect()
+    }
+
+    #[test]
+    fn switches_by_id_to_configured_servers() {
+        let (server_id, server_info) = switch_target(
+            ForwardToServerType::ById("survival".to_string()),
+            "lobby",
+            &servers(),
+        )
+        .unwrap();
+        assert_eq!(server_id, "survival");
+        assert_eq!(server_info.server_name, "survival");
+    }
+
+    #[test]
+    fn refuses_switches_to_the_current_or_an_unknown_server() {
+        let by_id = |id: &str| ForwardToServerType::ById(id.to_string());
+        assert!(switch_target(by_id("lobby"), "lobby", &servers()).is_err());
+        assert!(switch_target(by_id("creative"), "lobby", &servers()).is_err());
+        let current = ForwardToServerType::Info(server_info("Lobby", Some("lobby")));
+        assert!(switch_target(current, "lobby", &servers()).is_err());
+    }
+
+    #[test]
+    fn switches_by_info_to_unlisted_servers() {
+        let info = ForwardToServerType::Info(server_info("Event", Some("event-1")));
+        let (server_id, _) = switch_target(info, "lobby", &servers()).unwrap();
+        assert_eq!(server_id, "event-1");
+        // servers without an id go by their name
+        let info = ForwardToServerType::Info(server_info("Event", None));
+        let (server_id, _) = switch_target(info, "lobby", &servers()).unwrap();
+        assert_eq!(server_id, "Event");
+    }
+
+    #[test]
+    fn matches_keep_alive_answers() {
+        let keep_alives = KeepAliveTracker::default();
+        keep_alives.sent(1);
+        keep_alives.sent(2);
+        assert!(keep_alives.answered(2).is_some());
+        // answered once only, and never for ids the backend didn't send
+        assert!(keep_alives.answered(2).is_none());
+        assert!(keep_alives.answered(3).is_none());
+        // the client and backend readers each hold a clone
+        assert!(keep_alives.clone().answered(1).is_some());
+    }
+}
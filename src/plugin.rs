@@ -0,0 +1,14 @@
+use std::sync::Arc;
+
+use crate::ProxyInfo;
+
+pub mod wasm;
+
+/// Behaviour added to the proxy when embedding Umbrella as a library.
+pub trait Plugin: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Called once before the proxy starts listening. Commands are registered through
+    /// `proxy_info.commands` and event handlers through `proxy_info.events`.
+    fn enable(&self, proxy_info: Arc<ProxyInfo>) -> anyhow::Result<()>;
+}
@@ -0,0 +1,620 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use mcprotocol::chat::Chat;
+use serde_json::{json, Value};
+use tokio::sync::{Mutex, OnceCell};
+use wasmtime::{
+    Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+    Trap, TypedFunc,
+};
+
+use crate::backend::ForwardToServerType;
+use crate::cfg::WasmPluginsConfig;
+use crate::event::{
+    Cancellable, ChatEvent, DisconnectEvent, Event, EventBus, EventPriority,
+    PluginMessageDirection, PluginMessageEvent, PostLoginEvent, PreLoginEvent, ProxyPingEvent,
+    ServerConnectedEvent, ServerKickEvent, ServerPreConnectEvent,
+};
+use crate::legacy::to_legacy_text;
+use crate::player::ClientInfo;
+use crate::plugin::Plugin;
+use crate::registry::RegisteredPlayer;
+use crate::ProxyInfo;
+
+/// Version of the host ABI, guests report the version they target from
+/// `umbrella_abi_version` and are refused on a mismatch.
+pub const ABI_VERSION: i32 = 1;
+
+/// Module the host functions are imported from.
+///
+/// * `log(level, ptr, len)`, levels 0 to 4 going from error to trace
+/// * `subscribe(kind)`, only during `umbrella_enable`
+/// * `player_count() -> i32`
+/// * `find_player(ptr, len) -> i64`, by name or uuid, the player as JSON or 0
+/// * `send_message(player_ptr, player_len, message_ptr, message_len) -> i32`
+/// * `connect(player_ptr, player_len, server_ptr, server_len) -> i32`
+///
+/// Strings are UTF-8 in guest memory. JSON returned to the guest is written to memory from
+/// `umbrella_alloc` and returned as `ptr << 32 | len`.
+pub const ABI_MODULE: &str = "umbrella_v1";
+
+/// Event kinds for `subscribe` and `umbrella_on_event`. Events are passed as JSON and the
+/// guest returns `CANCEL` to cancel those which can be cancelled.
+pub mod kind {
+    pub const PRE_LOGIN: i32 = 1;
+    pub const POST_LOGIN: i32 = 2;
+    pub const SERVER_PRE_CONNECT: i32 = 3;
+    pub const SERVER_CONNECTED: i32 = 4;
+    pub const SERVER_KICK: i32 = 5;
+    pub const CHAT: i32 = 6;
+    pub const PLUGIN_MESSAGE: i32 = 7;
+    pub const DISCONNECT: i32 = 8;
+    /// Only informs, the status can't be changed from a plugin.
+    pub const PROXY_PING: i32 = 9;
+}
+
+pub const CANCEL: i32 = 1;
+
+struct PluginState {
+    name: String,
+    proxy_info: Arc<ProxyInfo>,
+    subscriptions: HashSet<i32>,
+    limits: StoreLimits,
+}
+
+struct WasmPlugin {
+    name: String,
+    subscriptions: HashSet<i32>,
+    fuel_per_call: u64,
+    store: Mutex<Store<PluginState>>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    on_event: TypedFunc<(i32, i32, i32), i32>,
+}
+
+fn refuel(store: &mut Store<PluginState>, fuel: u64) -> anyhow::Result<()> {
+    let remaining = store.consume_fuel(0)?;
+    store.add_fuel(fuel.saturating_sub(remaining))?;
+    Ok(())
+}
+
+impl WasmPlugin {
+    async fn load(
+        engine: &Engine,
+        linker: &Linker<PluginState>,
+        proxy_info: Arc<ProxyInfo>,
+        config: &WasmPluginsConfig,
+        path: &Path,
+    ) -> anyhow::Result<Self> {
+        let name = path
+            .file_stem()
+            .map_or_else(String::new, |stem| stem.to_string_lossy().to_string());
+        let module = Module::from_file(engine, path)?;
+        let mut store = Store::new(
+            engine,
+            PluginState {
+                name: name.clone(),
+                proxy_info,
+                subscriptions: HashSet::new(),
+                limits: StoreLimitsBuilder::new()
+                    .memory_size(config.max_memory_bytes)
+                    .instances(1)
+                    .build(),
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        refuel(&mut store, config.fuel_per_call)?;
+
+        let instance = linker.instantiate_async(&mut store, &module).await?;
+        let abi_version: TypedFunc<(), i32> =
+            instance.get_typed_func(&mut store, "umbrella_abi_version")?;
+        let abi_version = abi_version.call_async(&mut store, ()).await?;
+        if abi_version != ABI_VERSION {
+            anyhow::bail!(
+                "Plugin targets ABI version {}, the host provides {}",
+                abi_version,
+                ABI_VERSION
+            );
+        }
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow::anyhow!("Plugin does not export its memory"))?;
+        let alloc = instance.get_typed_func(&mut store, "umbrella_alloc")?;
+        let on_event = instance.get_typed_func(&mut store, "umbrella_on_event")?;
+
+        let enable: anyhow::Result<TypedFunc<(), ()>> =
+            instance.get_typed_func(&mut store, "umbrella_enable");
+        if let Ok(enable) = enable {
+            refuel(&mut store, config.fuel_per_call)?;
+            enable.call_async(&mut store, ()).await?;
+        }
+
+        Ok(Self {
+            name,
+            subscriptions: store.data().subscriptions.clone(),
+            fuel_per_call: config.fuel_per_call,
+            store: Mutex::new(store),
+            memory,
+            alloc,
+            on_event,
+        })
+    }
+
+    async fn on_event(&self, kind: i32, payload: &[u8]) -> anyhow::Result<i32> {
+        let mut store = self.store.lock().await;
+        refuel(&mut store, self.fuel_per_call)?;
+        let ptr = self
+            .alloc
+            .call_async(&mut *store, payload.len() as i32)
+            .await?;
+        self.memory
+            .write(&mut *store, ptr as u32 as usize, payload)?;
+        Ok(self
+            .on_event
+            .call_async(&mut *store, (kind, ptr, payload.len() as i32))
+            .await?)
+    }
+}
+
+fn trap(message: impl ToString) -> Trap {
+    Trap::new(message.to_string())
+}
+
+fn guest_memory(caller: &mut Caller<'_, PluginState>) -> Result<Memory, Trap> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| trap("plugin does not export its memory"))
+}
+
+fn read_string(caller: &mut Caller<'_, PluginState>, ptr: i32, len: i32) -> Result<String, Trap> {
+    let memory = guest_memory(caller)?;
+    if len < 0 || len as usize > memory.data_size(&*caller) {
+        return Err(trap("string length out of bounds"));
+    }
+    let mut buffer = vec![0; len as usize];
+    memory
+        .read(&*caller, ptr as u32 as usize, &mut buffer)
+        .map_err(trap)?;
+    String::from_utf8(buffer).map_err(|_| trap("string passed to the host is not UTF-8"))
+}
+
+async fn write_json(caller: &mut Caller<'_, PluginState>, value: &Value) -> Result<i64, Trap> {
+    let bytes = value.to_string().into_bytes();
+    let alloc: TypedFunc<i32, i32> = caller
+        .get_export("umbrella_alloc")
+        .and_then(Extern::into_func)
+        .ok_or_else(|| trap("plugin does not export umbrella_alloc"))?
+        .typed(&*caller)
+        .map_err(trap)?;
+    let ptr = alloc.call_async(&mut *caller, bytes.len() as i32).await?;
+    guest_memory(caller)?
+        .write(&mut *caller, ptr as u32 as usize, &bytes)
+        .map_err(trap)?;
+    Ok(((ptr as u32 as i64) << 32) | bytes.len() as i64)
+}
+
+async fn find_player(
+    caller: &mut Caller<'_, PluginState>,
+    ptr: i32,
+    len: i32,
+) -> Result<i64, Trap> {
+    let target = read_string(caller, ptr, len)?;
+    let proxy_info = caller.data().proxy_info.clone();
+    match proxy_info.players.find(&target).await {
+        Some(player) => write_json(caller, &player_json(&player)).await,
+        None => Ok(0),
+    }
+}
+
+/// Runs `action` on the player named by the guest string, 1 if it was sent to them.
+async fn with_player(
+    caller: &mut Caller<'_, PluginState>,
+    ptr: i32,
+    len: i32,
+    action: impl FnOnce(RegisteredPlayer) -> bool,
+) -> Result<i32, Trap> {
+    let target = read_string(caller, ptr, len)?;
+    let proxy_info = caller.data().proxy_info.clone();
+    let sent = proxy_info.players.find(&target).await.map_or(false, action);
+    Ok(sent as i32)
+}
+
+/// Fuel for every call and async support, which lets host functions wait on the registry
+/// without holding up a runtime thread.
+fn engine() -> anyhow::Result<Engine> {
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    config.async_support(true);
+    Engine::new(&config)
+}
+
+fn link(linker: &mut Linker<PluginState>) -> anyhow::Result<()> {
+    linker.func_wrap(
+        ABI_MODULE,
+        "log",
+        |mut caller: Caller<'_, PluginState>, level: i32, ptr: i32, len: i32| -> Result<(), Trap> {
+            let message = read_string(&mut caller, ptr, len)?;
+            let level = match level {
+                0 => log::Level::Error,
+                1 => log::Level::Warn,
+                2 => log::Level::Info,
+                3 => log::Level::Debug,
+                _ => log::Level::Trace,
+            };
+            log::log!(level, "[{}] {}", caller.data().name, message);
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        ABI_MODULE,
+        "subscribe",
+        |mut caller: Caller<'_, PluginState>, kind: i32| {
+            caller.data_mut().subscriptions.insert(kind);
+        },
+    )?;
+    linker.func_wrap0_async(
+        ABI_MODULE,
+        "player_count",
+        |caller: Caller<'_, PluginState>| {
+            let proxy_info = caller.data().proxy_info.clone();
+            Box::new(async move { proxy_info.players.player_count().await as i32 })
+        },
+    )?;
+    linker.func_wrap2_async(
+        ABI_MODULE,
+        "find_player",
+        |mut caller: Caller<'_, PluginState>, ptr: i32, len: i32| {
+            Box::new(async move { find_player(&mut caller, ptr, len).await })
+        },
+    )?;
+    linker.func_wrap4_async(
+        ABI_MODULE,
+        "send_message",
+        |mut caller: Caller<'_, PluginState>,
+         player_ptr: i32,
+         player_len: i32,
+         message_ptr: i32,
+         message_len: i32| {
+            Box::new(async move {
+                let message = read_string(&mut caller, message_ptr, message_len)?;
+                with_player(&mut caller, player_ptr, player_len, |player| {
+                    player.handle.send_message(Chat::literal(message))
+                })
+                .await
+            })
+        },
+    )?;
+    linker.func_wrap4_async(
+        ABI_MODULE,
+        "connect",
+        |mut caller: Caller<'_, PluginState>,
+         player_ptr: i32,
+         player_len: i32,
+         server_ptr: i32,
+         server_len: i32| {
+            Box::new(async move {
+                let server_id = read_string(&mut caller, server_ptr, server_len)?;
+                with_player(&mut caller, player_ptr, player_len, |player| {
+                    player.handle.transfer(ForwardToServerType::ById(server_id))
+                })
+                .await
+            })
+        },
+    )?;
+    Ok(())
+}
+
+fn client_json(client_info: &ClientInfo) -> Value {
+    json!({
+        "uuid": client_info.profile.id.to_string(),
+        "name": client_info.profile.name,
+        "address": client_info.remote_addr.to_string(),
+        "protocol_version": client_info.protocol_version,
+    })
+}
+
+fn player_json(player: &RegisteredPlayer) -> Value {
+    json!({
+        "uuid": player.uuid().to_string(),
+        "name": player.name(),
+        "server": player.current_server,
+        "ping_ms": player.ping.map(|ping| ping.as_millis() as u64),
+    })
+}
+
+fn ping_json(event: &ProxyPingEvent) -> Value {
+    json!({
+        "protocol_version": event.handshake.protocol_version,
+        "server_address": event.handshake.server_address,
+        "server_port": event.handshake.server_port,
+        "motd": to_legacy_text(&event.status.description),
+        "online_players": event.status.players.online,
+        "max_players": event.status.players.max,
+    })
+}
+
+struct LoadedPlugins {
+    proxy_info: Arc<ProxyInfo>,
+    engine: Engine,
+    linker: Linker<PluginState>,
+    paths: Vec<PathBuf>,
+    plugins: OnceCell<RwLock<Vec<Arc<WasmPlugin>>>>,
+}
+
+impl LoadedPlugins {
+    /// Instantiates the plugins the first time it's called, `enable` starts that right away
+    /// and events fired in the meantime wait for it.
+    async fn loaded(&self) -> &RwLock<Vec<Arc<WasmPlugin>>> {
+        self.plugins
+            .get_or_init(|| async {
+                let config = self.proxy_info.config();
+                let mut plugins = vec![];
+                for path in &self.paths {
+                    match WasmPlugin::load(
+                        &self.engine,
+                        &self.linker,
+                        self.proxy_info.clone(),
+                        &config.wasm_plugins,
+                        path,
+                    )
+                    .await
+                    {
+                        Ok(plugin) => {
+                            log::info!("Loaded WASM plugin {}.", plugin.name);
+                            plugins.push(Arc::new(plugin));
+                        }
+                        Err(err) => {
+                            log::error!("Failed to load WASM plugin {}: {:#}", path.display(), err)
+                        }
+                    }
+                }
+                RwLock::new(plugins)
+            })
+            .await
+    }
+
+    /// Passes the event to every plugin subscribed to it, returns true if any asked to cancel.
+    /// A plugin which traps, runs out of fuel or memory is unloaded.
+    async fn dispatch(&self, kind: i32, payload: impl FnOnce() -> Value) -> bool {
+        let loaded = self.loaded().await;
+        let plugins: Vec<Arc<WasmPlugin>> = loaded
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|plugin| plugin.subscriptions.contains(&kind))
+            .cloned()
+            .collect();
+        if plugins.is_empty() {
+            return false;
+        }
+
+        let payload = payload().to_string();
+        let mut cancelled = false;
+        for plugin in plugins {
+            match plugin.on_event(kind, payload.as_bytes()).await {
+                Ok(flags) => cancelled |= flags & CANCEL != 0,
+                Err(err) => {
+                    loaded
+                        .write()
+                        .unwrap()
+                        .retain(|loaded| !Arc::ptr_eq(loaded, &plugin));
+                    log::error!(
+                        "Unloaded WASM plugin {} after it failed: {:#}",
+                        plugin.name,
+                        err
+                    );
+                }
+            }
+        }
+        cancelled
+    }
+}
+
+fn forward<E: Event>(
+    events: &EventBus,
+    plugins: &Arc<LoadedPlugins>,
+    kind: i32,
+    payload: fn(&E) -> Value,
+    cancel: fn(&mut E),
+) {
+    let plugins = plugins.clone();
+    events.register(EventPriority::Normal, move |event: &mut E| {
+        let plugins = plugins.clone();
+        Box::pin(async move {
+            if plugins.dispatch(kind, || payload(event)).await {
+                cancel(event);
+            }
+        })
+    });
+}
+
+/// Loads `.wasm` plugins from the configured directory into their own sandboxed stores.
+#[derive(Default)]
+pub struct WasmPluginHost;
+
+impl WasmPluginHost {
+    pub fn new() -> Self {
+        Self
+    }
+
+    fn plugin_paths(config: &WasmPluginsConfig) -> anyhow::Result<Vec<PathBuf>> {
+        let directory = Path::new(&config.directory);
+        if !directory.exists() {
+            return Ok(vec![]);
+        }
+        let mut paths = fs::read_dir(directory)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.retain(|path| path.extension().map_or(false, |ext| ext == "wasm"));
+        paths.sort();
+        Ok(paths)
+    }
+}
+
+impl Plugin for WasmPluginHost {
+    fn name(&self) -> &str {
+        "wasm"
+    }
+
+    fn enable(&self, proxy_info: Arc<ProxyInfo>) -> anyhow::Result<()> {
+        let config = proxy_info.config();
+        let engine = engine()?;
+        let mut linker = Linker::new(&engine);
+        link(&mut linker)?;
+        let plugins = Arc::new(LoadedPlugins {
+            proxy_info: proxy_info.clone(),
+            engine,
+            linker,
+            paths: Self::plugin_paths(&config.wasm_plugins)?,
+            plugins: OnceCell::new(),
+        });
+        let loading = plugins.clone();
+        tokio::spawn(async move {
+            loading.loaded().await;
+        });
+
+        let events = &proxy_info.events;
+        forward(
+            events,
+            &plugins,
+            kind::PRE_LOGIN,
+            |event: &PreLoginEvent| json!({ "player": client_json(&event.client_info) }),
+            |event| event.set_cancelled(true),
+        );
+        forward(
+            events,
+            &plugins,
+            kind::POST_LOGIN,
+            |event: &PostLoginEvent| json!({ "player": client_json(&event.client_info) }),
+            |event| event.set_cancelled(true),
+        );
+        forward(
+            events,
+            &plugins,
+            kind::SERVER_PRE_CONNECT,
+            |event: &ServerPreConnectEvent| {
+                json!({
+                    "player": client_json(&event.client_info),
+                    "from": event.current_server,
+                    "to": event.target_id,
+                })
+            },
+            |event| event.set_cancelled(true),
+        );
+        forward(
+            events,
+            &plugins,
+            kind::SERVER_CONNECTED,
+            |event: &ServerConnectedEvent| {
+                json!({
+                    "player": client_json(&event.client_info),
+                    "from": event.previous_server,
+                    "to": event.server_id,
+                })
+            },
+            |_| {},
+        );
+        forward(
+            events,
+            &plugins,
+            kind::SERVER_KICK,
+            |event: &ServerKickEvent| {
+                json!({
+                    "player": client_json(&event.client_info),
+                    "server": event.server_id,
+                })
+            },
+            |event| event.set_cancelled(true),
+        );
+        forward(
+            events,
+            &plugins,
+            kind::CHAT,
+            |event: &ChatEvent| {
+                json!({
+                    "player": client_json(&event.client_info),
+                    "message": event.message,
+                    "command": event.is_command,
+                })
+            },
+            |event| event.set_cancelled(true),
+        );
+        forward(
+            events,
+            &plugins,
+            kind::PLUGIN_MESSAGE,
+            |event: &PluginMessageEvent| {
+                json!({
+                    "player": client_json(&event.client_info),
+                    "direction": match event.direction {
+                        PluginMessageDirection::ToServer => "to_server",
+                        PluginMessageDirection::ToClient => "to_client",
+                    },
+                    "channel": event.channel,
+                    "data": event.data,
+                })
+            },
+            |event| event.set_cancelled(true),
+        );
+        forward(
+            events,
+            &plugins,
+            kind::DISCONNECT,
+            |event: &DisconnectEvent| json!({ "player": client_json(&event.client_info) }),
+            |_| {},
+        );
+        forward(events, &plugins, kind::PROXY_PING, ping_json, |_| {});
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mcprotocol::protocol::handshaking::sb::{Handshake, NextState};
+    use mcprotocol::protocol::status::cb::StatusResponsePlayers;
+    use mcprotocol::status::StatusBuilder;
+
+    use super::*;
+
+    #[test]
+    fn links_the_host_functions_into_an_async_engine() {
+        let engine = engine().unwrap();
+        link(&mut Linker::new(&engine)).unwrap();
+    }
+
+    #[test]
+    fn passes_pings_with_the_handshake_and_status() {
+        let event = ProxyPingEvent {
+            handshake: Handshake {
+                protocol_version: 760,
+                server_address: "play.example.com".to_string(),
+                server_port: 25565,
+                next_state: NextState::Status,
+            },
+            status: StatusBuilder {
+                players: StatusResponsePlayers {
+                    max: 100,
+                    online: 5,
+                    sample: vec![],
+                },
+                description: Chat::literal("§aWelcome"),
+                favicon: None,
+            },
+        };
+        assert_eq!(
+            ping_json(&event),
+            json!({
+                "protocol_version": 760,
+                "server_address": "play.example.com",
+                "server_port": 25565,
+                "motd": "§aWelcome",
+                "online_players": 5,
+                "max_players": 100,
+            })
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::cfg::{ProxyProtocolConfig, ProxyProtocolVersion};
+use crate::net;
+
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+/// Longest v1 header allowed by the spec, including the line ending.
+const V1_MAX_LEN: usize = 107;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn parse_v1(line: &str) -> anyhow::Result<Option<SocketAddr>> {
+    let mut parts = line.split(' ');
+    if parts.next() != Some("PROXY") {
+        bail!("not a PROXY header");
+    }
+    match parts.next() {
+        Some("TCP4") | Some("TCP6") => {}
+        // the balancer doesn't know the client, e.g. for its own health checks
+        Some("UNKNOWN") => return Ok(None),
+        protocol => bail!("unsupported protocol {:?}", protocol),
+    }
+    let source: IpAddr = parts.next().context("missing source")?.parse()?;
+    let _destination = parts.next().context("missing destination")?;
+    let port: u16 = parts.next().context("missing source port")?.parse()?;
+    Ok(Some(SocketAddr::new(source, port)))
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    mut header: Vec<u8>,
+) -> anyhow::Result<Option<SocketAddr>> {
+    // byte by byte so nothing after the header is consumed
+    while !header.ends_with(b"\r\n") {
+        if header.len() >= V1_MAX_LEN {
+            bail!("v1 header too long");
+        }
+        header.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&header[..header.len() - 2])?;
+    parse_v1(line)
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<Option<SocketAddr>> {
+    let mut fixed = [0; 4];
+    stream.read_exact(&mut fixed).await?;
+    let [version_command, family, len_high, len_low] = fixed;
+    if version_command >> 4 != 2 {
+        bail!("unsupported v2 version {}", version_command >> 4);
+    }
+    let mut payload = vec![0; u16::from_be_bytes([len_high, len_low]) as usize];
+    stream.read_exact(&mut payload).await?;
+    // LOCAL connections come from the balancer itself
+    if version_command & 0x0F == 0 {
+        return Ok(None);
+    }
+    match family >> 4 {
+        0x1 if payload.len() >= 12 => {
+            let source = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Ok(Some(SocketAddr::new(source.into(), port)))
+        }
+        0x2 if payload.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&payload[..16]);
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        // unix sockets and unspecified families carry no usable client address
+        _ => Ok(None),
+    }
+}
+
+async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<Option<SocketAddr>> {
+    // both versions are at least this long, so this never reads past the header
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, start.to_vec()).await
+    } else {
+        bail!("missing PROXY protocol header")
+    }
+}
+
+/// The client's address for a new connection. Connections from trusted peers must start with
+/// a PROXY protocol header, which is consumed here. Everyone else is taken at face value.
+/// Peers on Unix sockets have no address and are all trusted, the socket's permissions
+/// decide who can connect.
+pub async fn client_addr<S: AsyncRead + Unpin>(
+    config: &ProxyProtocolConfig,
+    stream: &mut S,
+    peer: Option<SocketAddr>,
+) -> anyhow::Result<SocketAddr> {
+    let trusted = match peer {
+        Some(peer) => config.trusted.iter().any(|cidr| cidr.contains(peer.ip())),
+        None => true,
+    };
+    let peer = peer.unwrap_or(net::UNKNOWN_ADDR);
+    if !config.enabled || !trusted {
+        return Ok(peer);
+    }
+    let addr = tokio::time::timeout(HEADER_TIMEOUT, read_header(stream))
+        .await
+        .context("timed out reading the PROXY protocol header")??;
+    Ok(addr.unwrap_or(peer))
+}
+
+/// Both ends have to be of the same family, IPv4 is mapped into IPv6 if they differ.
+fn same_family(source: IpAddr, destination: IpAddr) -> (IpAddr, IpAddr) {
+    let to_v6 = |addr: IpAddr| match addr {
+        IpAddr::V4(v4) => IpAddr::V6(v4.to_ipv6_mapped()),
+        IpAddr::V6(_) => addr,
+    };
+    if source.is_ipv4() == destination.is_ipv4() {
+        (source, destination)
+    } else {
+        (to_v6(source), to_v6(destination))
+    }
+}
+
+/// Header announcing a proxied connection from `source` to `destination`.
+pub fn encode_header(
+    version: ProxyProtocolVersion,
+    source: SocketAddr,
+    destination: SocketAddr,
+) -> Vec<u8> {
+    let (source_ip, destination_ip) = same_family(source.ip(), destination.ip());
+    match version {
+        ProxyProtocolVersion::V1 => format!(
+            "PROXY {} {} {} {} {}\r\n",
+            if source_ip.is_ipv4() { "TCP4" } else { "TCP6" },
+            source_ip,
+            destination_ip,
+            source.port(),
+            destination.port()
+        )
+        .into_bytes(),
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            // version 2, PROXY command
+            header.push(0x21);
+            let mut addresses = vec![];
+            match (source_ip, destination_ip) {
+                (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                    // TCP over IPv4
+                    header.push(0x11);
+                    addresses.extend_from_slice(&source_ip.octets());
+                    addresses.extend_from_slice(&destination_ip.octets());
+                }
+                (source_ip, destination_ip) => {
+                    // TCP over IPv6
+                    header.push(0x21);
+                    for ip in [source_ip, destination_ip] {
+                        let v6 = match ip {
+                            IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                            IpAddr::V6(v6) => v6,
+                        };
+                        addresses.extend_from_slice(&v6.octets());
+                    }
+                }
+            }
+            addresses.extend_from_slice(&source.port().to_be_bytes());
+            addresses.extend_from_slice(&destination.port().to_be_bytes());
+            header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+            header.extend_from_slice(&addresses);
+            header
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    fn trusting(peer: &str) -> ProxyProtocolConfig {
+        ProxyProtocolConfig {
+            enabled: true,
+            trusted: vec![peer.parse().unwrap()],
+        }
+    }
+
+    async fn read(
+        config: &ProxyProtocolConfig,
+        data: &[u8],
+    ) -> (anyhow::Result<SocketAddr>, Vec<u8>) {
+        let mut stream = data;
+        let result = client_addr(config, &mut stream, Some(addr("10.0.0.1:4000"))).await;
+        (result, stream.to_vec())
+    }
+
+    #[test]
+    fn parses_v1_lines() {
+        let line = "PROXY TCP4 1.2.3.4 10.0.0.2 5555 25565";
+        assert_eq!(parse_v1(line).unwrap(), Some(addr("1.2.3.4:5555")));
+        let line = "PROXY TCP6 2001:db8::1 ::1 5555 25565";
+        assert_eq!(parse_v1(line).unwrap(), Some(addr("[2001:db8::1]:5555")));
+        assert_eq!(parse_v1("PROXY UNKNOWN").unwrap(), None);
+        assert!(parse_v1("PROXY UDP4 1.2.3.4 10.0.0.2 5555 25565").is_err());
+        assert!(parse_v1("PROXY TCP4 1.2.3.4").is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_headers_it_encodes() {
+        let config = trusting("10.0.0.1");
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            for (source, destination) in [
+                ("1.2.3.4:5555", "10.0.0.2:25565"),
+                ("[2001:db8::1]:5555", "[::1]:25565"),
+                ("1.2.3.4:5555", "[::1]:25565"),
+            ] {
+                let mut data = encode_header(version, addr(source), addr(destination));
+                data.extend_from_slice(b"rest");
+                let (result, rest) = read(&config, &data).await;
+                let expected = match addr(source) {
+                    // mixed families are sent as IPv6
+                    SocketAddr::V4(v4) if addr(destination).is_ipv6() => {
+                        SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+                    }
+                    source => source,
+                };
+                assert_eq!(result.unwrap(), expected);
+                assert_eq!(rest, b"rest");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn v2_local_keeps_the_peer() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        let (result, _) = read(&trusting("10.0.0.1"), &data).await;
+        assert_eq!(result.unwrap(), addr("10.0.0.1:4000"));
+    }
+
+    #[tokio::test]
+    async fn trusted_peers_need_a_header() {
+        let (result, _) = read(&trusting("10.0.0.1"), b"\x10\x00not a header").await;
+        assert!(result.is_err());
+        let (result, _) = read(&trusting("10.0.0.1"), b"PROXY TCP4 1.2.3.4 10.0.0.2 5555").await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn untrusted_peers_are_taken_as_they_are() {
+        let data = b"PROXY TCP4 1.2.3.4 10.0.0.2 5555 25565\r\n";
+        let (result, rest) = read(&trusting("192.168.0.0/16"), data).await;
+        assert_eq!(result.unwrap(), addr("10.0.0.1:4000"));
+        assert_eq!(rest, data);
+        let (result, _) = read(&ProxyProtocolConfig::default(), data).await;
+        assert_eq!(result.unwrap(), addr("10.0.0.1:4000"));
+    }
+
+    #[test]
+    fn encodes_v2_ipv4() {
+        let header = encode_header(
+            ProxyProtocolVersion::V2,
+            addr("1.2.3.4:5555"),
+            addr("10.0.0.2:25565"),
+        );
+        assert_eq!(header[..12], V2_SIGNATURE);
+        assert_eq!(header[12..16], [0x21, 0x11, 0x00, 12]);
+        assert_eq!(header[16..20], [1, 2, 3, 4]);
+        assert_eq!(header[24..26], 5555u16.to_be_bytes());
+    }
+
+    #[test]
+    fn announces_unix_backends_with_an_unspecified_destination() {
+        let header = encode_header(
+            ProxyProtocolVersion::V1,
+            addr("1.2.3.4:5555"),
+            crate::net::UNKNOWN_ADDR,
+        );
+        assert_eq!(header, b"PROXY TCP4 1.2.3.4 0.0.0.0 5555 0\r\n");
+    }
+}
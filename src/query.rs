@@ -0,0 +1,369 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+
+use crate::cfg::{QueryConfig, UmbrellaConfig, DEFAULT_PORT};
+use crate::legacy;
+use crate::ProxyInfo;
+
+const MAGIC: [u8; 2] = [0xFE, 0xFD];
+const TYPE_HANDSHAKE: u8 = 9;
+const TYPE_STAT: u8 = 0;
+/// Header and token of a basic stat request, full stat requests add 4 bytes of padding.
+const BASIC_STAT_LEN: usize = 11;
+const FULL_STAT_LEN: usize = 15;
+const CHALLENGE_LIFETIME: Duration = Duration::from_secs(30);
+const FULL_STAT_PADDING: &[u8] = b"splitnum\0\x80\0";
+const PLAYERS_PADDING: &[u8] = b"\x01player_\0\0";
+
+/// Challenge tokens, derived from the address and a secret key. A stat is only sent to
+/// addresses which received a token, so the responses can't be aimed at someone else. The key
+/// is replaced every `CHALLENGE_LIFETIME` and tokens from the one before stay valid, so
+/// nothing is kept per address however many handshakes arrive.
+struct Challenges {
+    current: RandomState,
+    previous: RandomState,
+    rotated: Instant,
+}
+
+impl Challenges {
+    fn new() -> Self {
+        Self {
+            current: RandomState::new(),
+            previous: RandomState::new(),
+            rotated: Instant::now(),
+        }
+    }
+
+    fn rotate(&mut self) {
+        let elapsed = self.rotated.elapsed();
+        if elapsed >= CHALLENGE_LIFETIME * 2 {
+            // both keys are past their lifetime after a quiet spell
+            self.previous = RandomState::new();
+        } else if elapsed >= CHALLENGE_LIFETIME {
+            std::mem::swap(&mut self.previous, &mut self.current);
+        } else {
+            return;
+        }
+        self.current = RandomState::new();
+        self.rotated = Instant::now();
+    }
+
+    fn token(key: &RandomState, addr: SocketAddr) -> i32 {
+        let mut hasher = key.build_hasher();
+        addr.hash(&mut hasher);
+        hasher.finish() as i32 & 0x7FFF_FFFF
+    }
+
+    fn issue(&mut self, addr: SocketAddr) -> i32 {
+        self.rotate();
+        Self::token(&self.current, addr)
+    }
+
+    fn is_valid(&mut self, addr: SocketAddr, token: i32) -> bool {
+        self.rotate();
+        token == Self::token(&self.current, addr) || token == Self::token(&self.previous, addr)
+    }
+}
+
+struct Stats {
+    motd: String,
+    version: String,
+    online: i32,
+    max: i32,
+    host_ip: String,
+    host_port: u16,
+    names: Vec<String>,
+}
+
+fn write_string(out: &mut Vec<u8>, value: &str) {
+    out.extend_from_slice(value.as_bytes());
+    out.push(0);
+}
+
+/// Where players connect, taken from the first listener.
+fn host(config: &UmbrellaConfig) -> (String, u16) {
+    config
+        .listener_binds()
+        .first()
+        .and_then(|bind| bind.parse::<SocketAddr>().ok())
+        .map_or(("0.0.0.0".to_string(), DEFAULT_PORT), |addr| {
+            (addr.ip().to_string(), addr.port())
+        })
+}
+
+async fn stats(proxy_info: &ProxyInfo) -> Stats {
+    let config = proxy_info.config();
+    let names: Vec<String> = proxy_info
+        .players
+        .players()
+        .await
+        .iter()
+        .map(|player| player.name().to_string())
+        .collect();
+    let (online, max) = config.status.players.counts(names.len() as i32);
+    let (motd, version) = match config.query.as_ref() {
+        _ if proxy_info.maintenance.is_enabled() => (
+            &config.maintenance.motd,
+            legacy::strip_formatting(&config.maintenance.version),
+        ),
+        Some(query) => (&config.status.motd, query.version.clone()),
+        None => (&config.status.motd, String::new()),
+    };
+    let (host_ip, host_port) = host(&config);
+    Stats {
+        motd: legacy::to_legacy_text(motd),
+        version,
+        online,
+        max,
+        host_ip,
+        host_port,
+        names,
+    }
+}
+
+fn basic_stat(out: &mut Vec<u8>, query: &QueryConfig, stats: &Stats) {
+    write_string(out, &stats.motd);
+    write_string(out, "SMP");
+    write_string(out, &query.map);
+    write_string(out, &stats.online.to_string());
+    write_string(out, &stats.max.to_string());
+    out.extend_from_slice(&stats.host_port.to_le_bytes());
+    write_string(out, &stats.host_ip);
+}
+
+fn full_stat(out: &mut Vec<u8>, query: &QueryConfig, stats: &Stats) {
+    let plugins = if query.plugins.is_empty() {
+        stats.version.clone()
+    } else {
+        format!("{}: {}", stats.version, query.plugins.join("; "))
+    };
+    let (online, max) = (stats.online.to_string(), stats.max.to_string());
+    let host_port = stats.host_port.to_string();
+    out.extend_from_slice(FULL_STAT_PADDING);
+    for (key, value) in [
+        ("hostname", stats.motd.as_str()),
+        ("gametype", "SMP"),
+        ("game_id", "MINECRAFT"),
+        ("version", stats.version.as_str()),
+        ("plugins", plugins.as_str()),
+        ("map", query.map.as_str()),
+        ("numplayers", online.as_str()),
+        ("maxplayers", max.as_str()),
+        ("hostport", host_port.as_str()),
+        ("hostip", stats.host_ip.as_str()),
+    ] {
+        write_string(out, key);
+        write_string(out, value);
+    }
+    out.push(0);
+    out.extend_from_slice(PLAYERS_PADDING);
+    for name in &stats.names {
+        write_string(out, name);
+    }
+    out.push(0);
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Request {
+    /// Carries the token to send back.
+    Handshake(i32),
+    BasicStat,
+    FullStat,
+}
+
+/// `None` for anything malformed or without a valid token.
+fn read_request(challenges: &mut Challenges, addr: SocketAddr, request: &[u8]) -> Option<Request> {
+    if request.len() < 7 || request[..2] != MAGIC {
+        return None;
+    }
+    match request[2] {
+        TYPE_HANDSHAKE => Some(Request::Handshake(challenges.issue(addr))),
+        TYPE_STAT if request.len() == BASIC_STAT_LEN || request.len() == FULL_STAT_LEN => {
+            let token = i32::from_be_bytes(request[7..11].try_into().unwrap());
+            if !challenges.is_valid(addr, token) {
+                None
+            } else if request.len() == FULL_STAT_LEN {
+                Some(Request::FullStat)
+            } else {
+                Some(Request::BasicStat)
+            }
+        }
+        _ => None,
+    }
+}
+
+/// The response to a request, `None` if it goes unanswered.
+async fn respond(
+    proxy_info: &ProxyInfo,
+    challenges: &mut Challenges,
+    addr: SocketAddr,
+    request: &[u8],
+) -> Option<Vec<u8>> {
+    let parsed = read_request(challenges, addr, request)?;
+    // the type and session id are echoed back
+    let mut out = request[2..7].to_vec();
+    match parsed {
+        Request::Handshake(token) => write_string(&mut out, &token.to_string()),
+        Request::BasicStat | Request::FullStat => {
+            let config = proxy_info.config();
+            let query = config.query.as_ref()?;
+            let stats = stats(proxy_info).await;
+            if parsed == Request::FullStat {
+                full_stat(&mut out, query, &stats);
+            } else {
+                basic_stat(&mut out, query, &stats);
+            }
+        }
+    }
+    Some(out)
+}
+
+pub async fn serve(proxy_info: Arc<ProxyInfo>, bind: String) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind(&bind).await?;
+    log::info!("Query listening on {}", socket.local_addr()?);
+    let mut challenges = Challenges::new();
+    let mut buffer = [0; 1500];
+    loop {
+        let (len, addr) = socket.recv_from(&mut buffer).await?;
+        let response = match respond(&proxy_info, &mut challenges, addr, &buffer[..len]).await {
+            Some(response) => response,
+            None => continue,
+        };
+        if let Err(err) = socket.send_to(&response, addr).await {
+            log::debug!("Failed to answer a query from {}: {}", addr, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    fn stat_request(token: i32, full: bool) -> Vec<u8> {
+        let mut request = MAGIC.to_vec();
+        request.push(TYPE_STAT);
+        request.extend_from_slice(&[0, 0, 0, 1]);
+        request.extend_from_slice(&token.to_be_bytes());
+        if full {
+            request.extend_from_slice(&[0; 4]);
+        }
+        request
+    }
+
+    fn handshake(challenges: &mut Challenges, from: SocketAddr) -> i32 {
+        let request = [0xFE, 0xFD, TYPE_HANDSHAKE, 0, 0, 0, 1];
+        match read_request(challenges, from, &request) {
+            Some(Request::Handshake(token)) => token,
+            other => panic!("expected a handshake, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stats_need_the_token_for_the_address() {
+        let mut challenges = Challenges::new();
+        let client = addr("1.2.3.4:5000");
+        let token = handshake(&mut challenges, client);
+        assert!(token >= 0);
+        assert_eq!(
+            read_request(&mut challenges, client, &stat_request(token, false)),
+            Some(Request::BasicStat)
+        );
+        assert_eq!(
+            read_request(&mut challenges, client, &stat_request(token, true)),
+            Some(Request::FullStat)
+        );
+        let other = addr("1.2.3.4:5001");
+        assert_eq!(
+            read_request(&mut challenges, other, &stat_request(token, false)),
+            None
+        );
+        assert_eq!(
+            read_request(&mut challenges, client, &stat_request(token ^ 1, false)),
+            None
+        );
+    }
+
+    #[test]
+    fn tokens_outlive_one_rotation() {
+        let mut challenges = Challenges::new();
+        let client = addr("1.2.3.4:5000");
+        let token = handshake(&mut challenges, client);
+        challenges.rotated -= CHALLENGE_LIFETIME;
+        assert!(challenges.is_valid(client, token));
+        assert_ne!(handshake(&mut challenges, client), token);
+        challenges.rotated -= CHALLENGE_LIFETIME;
+        assert!(!challenges.is_valid(client, token));
+    }
+
+    #[test]
+    fn tokens_expire_after_a_quiet_spell() {
+        let mut challenges = Challenges::new();
+        let client = addr("1.2.3.4:5000");
+        let token = handshake(&mut challenges, client);
+        challenges.rotated -= CHALLENGE_LIFETIME * 2;
+        assert!(!challenges.is_valid(client, token));
+    }
+
+    #[test]
+    fn ignores_malformed_requests() {
+        let mut challenges = Challenges::new();
+        let client = addr("1.2.3.4:5000");
+        assert_eq!(
+            read_request(&mut challenges, client, &[0xFE, 0xFD, 9]),
+            None
+        );
+        assert_eq!(
+            read_request(&mut challenges, client, &[0xFE, 0xFC, 9, 0, 0, 0, 1]),
+            None
+        );
+        assert_eq!(
+            read_request(&mut challenges, client, &[0xFE, 0xFD, 7, 0, 0, 0, 1]),
+            None
+        );
+        let token = handshake(&mut challenges, client);
+        let mut request = stat_request(token, false);
+        request.push(0);
+        assert_eq!(read_request(&mut challenges, client, &request), None);
+    }
+
+    #[test]
+    fn writes_basic_stats() {
+        let query = QueryConfig {
+            bind: "0.0.0.0:25565".to_string(),
+            version: "1.19.2".to_string(),
+            map: "world".to_string(),
+            plugins: vec![],
+        };
+        let stats = Stats {
+            motd: "A server".to_string(),
+            version: "1.19.2".to_string(),
+            online: 5,
+            max: 100,
+            host_ip: "127.0.0.1".to_string(),
+            host_port: 25565,
+            names: vec!["Notch".to_string()],
+        };
+        let mut out = vec![];
+        basic_stat(&mut out, &query, &stats);
+        let mut expected = b"A server\0SMP\0world\x005\0100\0".to_vec();
+        expected.extend_from_slice(&25565u16.to_le_bytes());
+        expected.extend_from_slice(b"127.0.0.1\0");
+        assert_eq!(out, expected);
+
+        let mut out = vec![];
+        full_stat(&mut out, &query, &stats);
+        assert!(out.starts_with(FULL_STAT_PADDING));
+        let text = String::from_utf8_lossy(&out);
+        assert!(text.contains("\0version\x001.19.2\0plugins\x001.19.2\0"));
+        assert!(text.ends_with("\x01player_\0\0Notch\0\0"));
+    }
+}
@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use mcprotocol::chat::Chat;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{watch, RwLock};
+use uuid::Uuid;
+
+use crate::backend::ForwardToServerType;
+use crate::cfg::{DuplicateLoginAction, DuplicateLoginConfig};
+use crate::player::ClientInfo;
+
+pub enum PlayerCommand {
+    SendMessage(Chat),
+    Kick(Chat),
+    Transfer(ForwardToServerType),
+    /// Sent to the backend the player is connected to.
+    SendPluginMessage {
+        channel: String,
+        data: Vec<u8>,
+    },
+}
+
+#[derive(Clone)]
+pub struct PlayerHandle {
+    sender: UnboundedSender<PlayerCommand>,
+    /// Its sender is dropped once the session is torn down.
+    closed: watch::Receiver<()>,
+}
+
+impl PlayerHandle {
+    pub fn new(sender: UnboundedSender<PlayerCommand>, closed: watch::Receiver<()>) -> Self {
+        Self { sender, closed }
+    }
+
+    /// Waits until the session is torn down, along with its backend connection.
+    pub async fn closed(&self) {
+        let mut closed = self.closed.clone();
+        while closed.changed().await.is_ok() {}
+    }
+
+    /// Returns false if the player's connection has already gone away.
+    pub fn send_message(&self, message: Chat) -> bool {
+        self.sender
+            .send(PlayerCommand::SendMessage(message))
+            .is_ok()
+    }
+
+    pub fn kick(&self, reason: Chat) -> bool {
+        self.sender.send(PlayerCommand::Kick(reason)).is_ok()
+    }
+
+    pub fn transfer(&self, target: ForwardToServerType) -> bool {
+        self.sender.send(PlayerCommand::Transfer(target)).is_ok()
+    }
+
+    pub fn send_plugin_message(&self, channel: String, data: Vec<u8>) -> bool {
+        self.sender
+            .send(PlayerCommand::SendPluginMessage { channel, data })
+            .is_ok()
+    }
+}
+
+#[derive(Clone)]
+pub struct RegisteredPlayer {
+    pub session_id: u64,
+    pub client_info: ClientInfo,
+    pub current_server: Option<String>,
+    pub connected_at: DateTime<Local>,
+    pub ping: Option<Duration>,
+    pub handle: PlayerHandle,
+}
+
+impl RegisteredPlayer {
+    pub fn uuid(&self) -> Uuid {
+        self.client_info.profile.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.client_info.profile.name
+    }
+}
+
+#[derive(Debug)]
+pub enum RegisterError {
+    Full,
+    AlreadyConnected,
+}
+
+#[derive(Default)]
+struct RegistryInner {
+    sessions: HashMap<u64, RegisteredPlayer>,
+    // both indexes point at the most recent session for the player
+    by_uuid: HashMap<Uuid, u64>,
+    // names are stored lowercase, the client sends them case-insensitively in commands
+    by_name: HashMap<String, u64>,
+}
+
+impl RegistryInner {
+    fn remove_session(&mut self, session_id: u64) -> Option<RegisteredPlayer> {
+        let player = self.sessions.remove(&session_id)?;
+        let uuid = player.uuid();
+        let name = player.name().to_lowercase();
+        // another session for the same player may still be around if duplicates are allowed
+        let replacement = self
+            .sessions
+            .values()
+            .filter(|other| other.uuid() == uuid)
+            .map(|other| other.session_id)
+            .max();
+        if self.by_uuid.get(&uuid) == Some(&session_id) {
+            match replacement {
+                Some(replacement) => self.by_uuid.insert(uuid, replacement),
+                None => self.by_uuid.remove(&uuid),
+            };
+        }
+        if self.by_name.get(&name) == Some(&session_id) {
+            match replacement {
+                Some(replacement) => self.by_name.insert(name, replacement),
+                None => self.by_name.remove(&name),
+            };
+        }
+        Some(player)
+    }
+}
+
+#[derive(Default)]
+pub struct PlayerRegistry {
+    inner: RwLock<RegistryInner>,
+    next_session_id: AtomicU64,
+}
+
+impl PlayerRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a new session, refusing it if `limit` players are already online or
+    /// the duplicate login policy forbids it. Returns the session id which must be handed
+    /// back to `unregister`, and the handles of sessions kicked to make room for it.
+    pub async fn try_register(
+        &self,
+        client_info: ClientInfo,
+        handle: PlayerHandle,
+        limit: Option<usize>,
+        duplicate_login: &DuplicateLoginConfig,
+    ) -> Result<(u64, Vec<PlayerHandle>), RegisterError> {
+        let mut inner = self.inner.write().await;
+        let uuid = client_info.profile.id;
+        let mut kicked_handles = vec![];
+
+        if let Some(existing) = inner.by_uuid.get(&uuid).copied() {
+            let same_ip = inner.sessions[&existing].client_info.remote_addr.ip()
+                == client_info.remote_addr.ip();
+            match duplicate_login.action {
+                DuplicateLoginAction::KickExisting
+                    if !(duplicate_login.kick_only_from_different_ip && same_ip) =>
+                {
+                    let kicked: Vec<u64> = inner
+                        .sessions
+                        .values()
+                        .filter(|other| other.uuid() == uuid)
+                        .map(|other| other.session_id)
+                        .collect();
+                    for session_id in kicked {
+                        if let Some(player) = inner.remove_session(session_id) {
+                            player
+                                .handle
+                                .kick(Chat::literal("You logged in from another location."));
+                            kicked_handles.push(player.handle);
+                        }
+                    }
+                }
+                DuplicateLoginAction::AllowBoth => {}
+                _ => return Err(RegisterError::AlreadyConnected),
+            }
+        }
+
+        if let Some(limit) = limit {
+            if inner.sessions.len() >= limit {
+                return Err(RegisterError::Full);
+            }
+        }
+        let session_id = self.next_session_id.fetch_add(1, Ordering::Relaxed);
+        inner.by_uuid.insert(uuid, session_id);
+        inner
+            .by_name
+            .insert(client_info.profile.name.to_lowercase(), session_id);
+        inner.sessions.insert(
+            session_id,
+            RegisteredPlayer {
+                session_id,
+                client_info,
+                current_server: None,
+                connected_at: Local::now(),
+                ping: None,
+                handle,
+            },
+        );
+        Ok((session_id, kicked_handles))
+    }
+
+    /// Removes the session if it is still registered. Sessions kicked for a duplicate
+    /// login have already been removed, so this is a no-op for them.
+    pub async fn unregister(&self, session_id: u64) {
+        self.inner.write().await.remove_session(session_id);
+    }
+
+    pub async fn set_server(&self, session_id: u64, server: Option<String>) {
+        if let Some(player) = self.inner.write().await.sessions.get_mut(&session_id) {
+            player.current_server = server;
+        }
+    }
+
+    pub async fn set_ping(&self, session_id: u64, ping: Duration) {
+        if let Some(player) = self.inner.write().await.sessions.get_mut(&session_id) {
+            player.ping = Some(ping);
+        }
+    }
+
+    pub async fn by_session(&self, session_id: u64) -> Option<RegisteredPlayer> {
+        self.inner.read().await.sessions.get(&session_id).cloned()
+    }
+
+    pub async fn by_uuid(&self, uuid: &Uuid) -> Option<RegisteredPlayer> {
+        let inner = self.inner.read().await;
+        inner
+            .by_uuid
+            .get(uuid)
+            .and_then(|session_id| inner.sessions.get(session_id))
+            .cloned()
+    }
+
+    pub async fn by_name(&self, name: &str) -> Option<RegisteredPlayer> {
+        let inner = self.inner.read().await;
+        inner
+            .by_name
+            .get(&name.to_lowercase())
+            .and_then(|session_id| inner.sessions.get(session_id))
+            .cloned()
+    }
+
+    /// Looks a player up by uuid if `target` parses as one, by name otherwise.
+    pub async fn find(&self, target: &str) -> Option<RegisteredPlayer> {
+        match Uuid::parse_str(target) {
+            Ok(uuid) => self.by_uuid(&uuid).await,
+            Err(_) => self.by_name(target).await,
+        }
+    }
+
+    pub async fn players(&self) -> Vec<RegisteredPlayer> {
+        self.inner.read().await.sessions.values().cloned().collect()
+    }
+
+    pub async fn players_on(&self, server_id: &str) -> Vec<RegisteredPlayer> {
+        self.inner
+            .read()
+            .await
+            .sessions
+            .values()
+            .filter(|player| player.current_server.as_deref() == Some(server_id))
+            .cloned()
+            .collect()
+    }
+
+    pub async fn player_count(&self) -> usize {
+        self.inner.read().await.sessions.len()
+    }
+
+    pub async fn server_count(&self, server_id: &str) -> usize {
+        self.inner
+            .read()
+            .await
+            .sessions
+            .values()
+            .filter(|player| player.current_server.as_deref() == Some(server_id))
+            .count()
+    }
+
+    pub async fn server_counts(&self) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for player in self.inner.read().await.sessions.values() {
+            if let Some(server) = player.current_server.as_ref() {
+                *counts.entry(server.clone()).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+    fn new_handle() -> (PlayerHandle, UnboundedReceiver<PlayerCommand>) {
+        let (sender, commands) = mpsc::unbounded_channel();
+        let (_closed, closed_receiver) = watch::channel(());
+        (PlayerHandle::new(sender, closed_receiver), commands)
+    }
+
+    fn client(name: &str, id: u128, ip: &str) -> ClientInfo {
+        crate::player::test_client_info(
+            name,
+            Uuid::from_u128(id),
+            format!("{}:25565", ip).parse().unwrap(),
+        )
+    }
+
+    fn policy(
+        action: DuplicateLoginAction,
+        kick_only_from_different_ip: bool,
+    ) -> DuplicateLoginConfig {
+        DuplicateLoginConfig {
+            action,
+            kick_only_from_different_ip,
+        }
+    }
+
+    async fn register(
+        registry: &PlayerRegistry,
+        client_info: ClientInfo,
+        duplicate_login: &DuplicateLoginConfig,
+    ) -> Result<(u64, Vec<PlayerHandle>, UnboundedReceiver<PlayerCommand>), RegisterError> {
+        let (handle, commands) = new_handle();
+        let (session_id, kicked) = registry
+            .try_register(client_info, handle, None, duplicate_login)
+            .await?;
+        Ok((session_id, kicked, commands))
+    }
+
+    #[tokio::test]
+    async fn kicks_the_existing_session() {
+        let registry = PlayerRegistry::new();
+        let kick = policy(DuplicateLoginAction::KickExisting, false);
+        let (first, _, mut first_commands) =
+            register(&registry, client("Steve", 1, "10.0.0.1"), &kick)
+                .await
+                .unwrap();
+        let (second, kicked, _) = register(&registry, client("Steve", 1, "10.0.0.2"), &kick)
+            .await
+            .unwrap();
+        assert_eq!(kicked.len(), 1);
+        assert!(matches!(
+            first_commands.try_recv(),
+            Ok(PlayerCommand::Kick(_))
+        ));
+        assert!(registry.by_session(first).await.is_none());
+        assert_eq!(registry.find("steve").await.unwrap().session_id, second);
+        assert_eq!(registry.player_count().await, 1);
+        // the kicked session's own teardown doesn't touch the new one
+        registry.unregister(first).await;
+        assert_eq!(
+            registry
+                .by_uuid(&Uuid::from_u128(1))
+                .await
+                .unwrap()
+                .session_id,
+            second
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_the_new_login() {
+        let registry = PlayerRegistry::new();
+        let reject = policy(DuplicateLoginAction::RejectNew, false);
+        let (first, _, _) = register(&registry, client("Steve", 1, "10.0.0.1"), &reject)
+            .await
+            .unwrap();
+        let second = register(&registry, client("Steve", 1, "10.0.0.2"), &reject).await;
+        assert!(matches!(second, Err(RegisterError::AlreadyConnected)));
+        assert_eq!(registry.find("Steve").await.unwrap().session_id, first);
+    }
+
+    #[tokio::test]
+    async fn kicks_only_for_logins_from_another_address() {
+        let registry = PlayerRegistry::new();
+        let kick = policy(DuplicateLoginAction::KickExisting, true);
+        register(&registry, client("Steve", 1, "10.0.0.1"), &kick)
+            .await
+            .unwrap();
+        let same_ip = register(&registry, client("Steve", 1, "10.0.0.1"), &kick).await;
+        assert!(matches!(same_ip, Err(RegisterError::AlreadyConnected)));
+        let (_, kicked, _) = register(&registry, client("Steve", 1, "10.0.0.2"), &kick)
+            .await
+            .unwrap();
+        assert_eq!(kicked.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn allows_both_and_falls_back_to_the_older_session() {
+        let registry = PlayerRegistry::new();
+        let allow = policy(DuplicateLoginAction::AllowBoth, false);
+        let (first, _, _) = register(&registry, client("Steve", 1, "10.0.0.1"), &allow)
+            .await
+            .unwrap();
+        let (second, kicked, _) = register(&registry, client("Steve", 1, "10.0.0.2"), &allow)
+            .await
+            .unwrap();
+        assert!(kicked.is_empty());
+        assert_eq!(registry.player_count().await, 2);
+        assert_eq!(registry.find("Steve").await.unwrap().session_id, second);
+        registry.unregister(second).await;
+        assert_eq!(registry.find("Steve").await.unwrap().session_id, first);
+        assert_eq!(
+            registry
+                .by_uuid(&Uuid::from_u128(1))
+                .await
+                .unwrap()
+                .session_id,
+            first
+        );
+    }
+
+    #[tokio::test]
+    async fn duplicates_are_keyed_by_uuid() {
+        let registry = PlayerRegistry::new();
+        let reject = policy(DuplicateLoginAction::RejectNew, false);
+        // an online mode player who changed their name is still the same player
+        register(&registry, client("Steve", 1, "10.0.0.1"), &reject)
+            .await
+            .unwrap();
+        let renamed = register(&registry, client("Alex", 1, "10.0.0.1"), &reject).await;
+        assert!(matches!(renamed, Err(RegisterError::AlreadyConnected)));
+        // offline mode uuids come from the name as typed, so names only differing in case
+        // are different players, the name index follows the latest of them
+        let (steve, _, _) = register(&registry, client("STEVE", 2, "10.0.0.2"), &reject)
+            .await
+            .unwrap();
+        assert_eq!(registry.player_count().await, 2);
+        assert_eq!(registry.find("steve").await.unwrap().session_id, steve);
+        assert_eq!(
+            registry.by_uuid(&Uuid::from_u128(1)).await.unwrap().name(),
+            "Steve"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_logins_when_full() {
+        let registry = PlayerRegistry::new();
+        let kick = DuplicateLoginConfig::default();
+        let (handle, _commands) = new_handle();
+        assert!(registry
+            .try_register(client("Steve", 1, "10.0.0.1"), handle, Some(1), &kick)
+            .await
+            .is_ok());
+        let (handle, _commands) = new_handle();
+        let full = registry
+            .try_register(client("Alex", 2, "10.0.0.2"), handle, Some(1), &kick)
+            .await;
+        assert!(matches!(full, Err(RegisterError::Full)));
+    }
+}
@@ -0,0 +1,408 @@
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use chrono::{Datelike, Local, Timelike};
+use mcprotocol::chat::Chat;
+use mcprotocol::protocol::handshaking::sb::Handshake;
+use mcprotocol::status::StatusBuilder;
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+
+use crate::cfg::ScriptsConfig;
+use crate::legacy::to_legacy_text;
+use crate::player::ClientInfo;
+use crate::ProxyInfo;
+
+thread_local! {
+    /// Deadline of the hook running on this thread, checked by the engine's progress callback.
+    static DEADLINE: Cell<Option<Instant>> = Cell::new(None);
+}
+
+/// `now()` in scripts, `weekday` runs from 1 for Monday to 7 for Sunday.
+fn now() -> Map {
+    let now = Local::now();
+    let mut map = Map::new();
+    map.insert("year".into(), (now.year() as i64).into());
+    map.insert("month".into(), (now.month() as i64).into());
+    map.insert("day".into(), (now.day() as i64).into());
+    map.insert(
+        "weekday".into(),
+        (now.weekday().number_from_monday() as i64).into(),
+    );
+    map.insert("hour".into(), (now.hour() as i64).into());
+    map.insert("minute".into(), (now.minute() as i64).into());
+    map
+}
+
+fn client_map(client_info: &ClientInfo) -> Map {
+    let mut map = Map::new();
+    map.insert("name".into(), client_info.profile.name.clone().into());
+    map.insert("uuid".into(), client_info.profile.id.to_string().into());
+    map.insert("ip".into(), client_info.remote_addr.ip().to_string().into());
+    map.insert(
+        "protocol_version".into(),
+        (client_info.protocol_version as i64).into(),
+    );
+    map
+}
+
+/// Empty if the handshake couldn't be read ahead of the protocol library.
+fn handshake_map(handshake: Option<&Handshake>) -> Map {
+    let mut map = Map::new();
+    let handshake = match handshake {
+        Some(handshake) => handshake,
+        None => return map,
+    };
+    map.insert(
+        "protocol_version".into(),
+        (handshake.protocol_version as i64).into(),
+    );
+    map.insert(
+        "server_address".into(),
+        handshake.server_address.clone().into(),
+    );
+    map.insert("server_port".into(), (handshake.server_port as i64).into());
+    map
+}
+
+async fn servers_map(proxy_info: &ProxyInfo) -> Map {
+    let counts = proxy_info.players.server_counts().await;
+    let mut servers = Map::new();
+    for (server_id, server_info) in &proxy_info.config().servers {
+        let mut map = Map::new();
+        map.insert("name".into(), server_info.server_name.clone().into());
+        map.insert(
+            "players".into(),
+            (counts.get(server_id).copied().unwrap_or(0) as i64).into(),
+        );
+        map.insert(
+            "reachable".into(),
+            proxy_info.health.is_reachable(server_id).into(),
+        );
+        map.insert("restricted".into(), server_info.restricted.into());
+        servers.insert(server_id.as_str().into(), map.into());
+    }
+    servers
+}
+
+/// A server id or an array of them, anything else keeps the default.
+fn server_list(value: Dynamic) -> Option<Vec<String>> {
+    if value.is::<Array>() {
+        value
+            .cast::<Array>()
+            .into_iter()
+            .map(|server_id| server_id.into_string().ok())
+            .collect()
+    } else if value.is_string() {
+        value.into_string().ok().map(|server_id| vec![server_id])
+    } else {
+        None
+    }
+}
+
+struct LoadedScript {
+    engine: Engine,
+    ast: AST,
+    hooks: HashSet<String>,
+    timeout: Duration,
+}
+
+impl LoadedScript {
+    fn load(path: &Path, config: &ScriptsConfig) -> anyhow::Result<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(config.max_operations);
+        engine.on_progress(|_| {
+            DEADLINE.with(|deadline| match deadline.get() {
+                Some(deadline) if Instant::now() > deadline => {
+                    Some("execution time limit exceeded".into())
+                }
+                _ => None,
+            })
+        });
+        engine.register_fn("now", now);
+        let ast = engine.compile_file(path.to_path_buf())?;
+        let hooks = ast
+            .iter_functions()
+            .map(|function| function.name.to_string())
+            .collect();
+        Ok(Self {
+            engine,
+            ast,
+            hooks,
+            timeout: Duration::from_millis(config.timeout_ms),
+        })
+    }
+
+    fn defines(&self, hook: &str) -> bool {
+        self.hooks.contains(hook)
+    }
+
+    fn run(&self, hook: &str, mut args: Vec<Dynamic>) -> Option<Dynamic> {
+        DEADLINE.with(|deadline| deadline.set(Some(Instant::now() + self.timeout)));
+        let result = self.engine.call_fn_raw(
+            &mut Scope::new(),
+            &self.ast,
+            false,
+            false,
+            hook,
+            None,
+            &mut args,
+        );
+        DEADLINE.with(|deadline| deadline.set(None));
+        match result {
+            Ok(value) => Some(value),
+            Err(err) => {
+                log::warn!("Script hook {} failed: {}", hook, err);
+                None
+            }
+        }
+    }
+
+    /// `None` if the hook failed, failures are logged and the default behaviour is kept.
+    /// Hooks run on the blocking pool, a slow script only holds up its own caller.
+    async fn call(self: Arc<Self>, hook: &'static str, args: Vec<Dynamic>) -> Option<Dynamic> {
+        match tokio::task::spawn_blocking(move || self.run(hook, args)).await {
+            Ok(value) => value,
+            Err(err) => {
+                log::warn!("Script hook {} failed: {}", hook, err);
+                None
+            }
+        }
+    }
+}
+
+/// Rhai hooks for small routing and status rules. Each hook is optional:
+///
+/// * `login(client, handshake, servers)`, returning false or a kick message denies the login
+/// * `initial_server(client, handshake, servers)`, a server id or array of them replacing `try`
+/// * `fallback_server(client, from, servers)`, likewise replacing `fallback`
+/// * `status(handshake, status, servers)`, returning a map with any of `motd`,
+///   `max_players` and `online_players`, which `status` holds the current values of
+///
+/// `handshake` has the `server_address`, `server_port` and `protocol_version` the client
+/// connected with.
+///
+/// Arguments are copies, so scripts can't change proxy state through them.
+#[derive(Default)]
+pub struct ScriptHooks {
+    script: RwLock<Option<Arc<LoadedScript>>>,
+}
+
+impl ScriptHooks {
+    pub fn load(config: &ScriptsConfig) -> anyhow::Result<Self> {
+        let hooks = Self::default();
+        hooks.reload(config)?;
+        Ok(hooks)
+    }
+
+    /// Recompiles the script, the previous one stays active if this fails.
+    pub fn reload(&self, config: &ScriptsConfig) -> anyhow::Result<()> {
+        let script = match &config.file {
+            Some(file) => Some(Arc::new(LoadedScript::load(Path::new(file), config)?)),
+            None => None,
+        };
+        *self.script.write().unwrap() = script;
+        Ok(())
+    }
+
+    fn hook(&self, hook: &str) -> Option<Arc<LoadedScript>> {
+        self.script
+            .read()
+            .unwrap()
+            .as_ref()
+            .filter(|script| script.defines(hook))
+            .cloned()
+    }
+
+    pub async fn login(
+        &self,
+        proxy_info: &ProxyInfo,
+        handshake: Option<&Handshake>,
+        client_info: &ClientInfo,
+    ) -> Result<(), Chat> {
+        let script = match self.hook("login") {
+            Some(script) => script,
+            None => return Ok(()),
+        };
+        let args = vec![
+            client_map(client_info).into(),
+            handshake_map(handshake).into(),
+            servers_map(proxy_info).await.into(),
+        ];
+        match script.call("login", args).await {
+            Some(value) if value.is_string() => {
+                Err(Chat::literal(value.into_string().unwrap_or_default()))
+            }
+            Some(value) if value.as_bool() == Ok(false) => {
+                Err(Chat::literal("You are not allowed to join."))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn initial_servers(
+        &self,
+        proxy_info: &ProxyInfo,
+        handshake: Option<&Handshake>,
+        client_info: &ClientInfo,
+        default: &[String],
+    ) -> Vec<String> {
+        let script = match self.hook("initial_server") {
+            Some(script) => script,
+            None => return default.to_vec(),
+        };
+        let args = vec![
+            client_map(client_info).into(),
+            handshake_map(handshake).into(),
+            servers_map(proxy_info).await.into(),
+        ];
+        script
+            .call("initial_server", args)
+            .await
+            .and_then(server_list)
+            .unwrap_or_else(|| default.to_vec())
+    }
+
+    pub async fn fallback_servers(
+        &self,
+        proxy_info: &ProxyInfo,
+        client_info: &ClientInfo,
+        from: &str,
+        default: &[String],
+    ) -> Vec<String> {
+        let script = match self.hook("fallback_server") {
+            Some(script) => script,
+            None => return default.to_vec(),
+        };
+        let args = vec![
+            client_map(client_info).into(),
+            from.to_string().into(),
+            servers_map(proxy_info).await.into(),
+        ];
+        script
+            .call("fallback_server", args)
+            .await
+            .and_then(server_list)
+            .unwrap_or_else(|| default.to_vec())
+    }
+
+    pub async fn status(
+        &self,
+        proxy_info: &ProxyInfo,
+        handshake: &Handshake,
+        status: &mut StatusBuilder,
+    ) {
+        let script = match self.hook("status") {
+            Some(script) => script,
+            None => return,
+        };
+        let mut current = Map::new();
+        current.insert("motd".into(), to_legacy_text(&status.description).into());
+        current.insert("max_players".into(), (status.players.max as i64).into());
+        current.insert(
+            "online_players".into(),
+            (status.players.online as i64).into(),
+        );
+        let args = vec![
+            handshake_map(Some(handshake)).into(),
+            current.into(),
+            servers_map(proxy_info).await.into(),
+        ];
+        let changes = match script
+            .call("status", args)
+            .await
+            .and_then(|value| value.try_cast::<Map>())
+        {
+            Some(changes) => changes,
+            None => return,
+        };
+        if let Some(motd) = changes
+            .get("motd")
+            .and_then(|motd| motd.clone().into_string().ok())
+        {
+            status.description = Chat::literal(motd);
+        }
+        if let Some(max) = changes.get("max_players").and_then(|max| max.as_int().ok()) {
+            status.players.max = max as i32;
+        }
+        if let Some(online) = changes
+            .get("online_players")
+            .and_then(|online| online.as_int().ok())
+        {
+            status.players.online = online as i32;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mcprotocol::protocol::handshaking::sb::NextState;
+
+    use super::*;
+
+    fn script(name: &str, source: &str) -> LoadedScript {
+        let path =
+            std::env::temp_dir().join(format!("umbrella-{}-{}.rhai", name, std::process::id()));
+        std::fs::write(&path, source).unwrap();
+        let config = ScriptsConfig {
+            file: None,
+            timeout_ms: 100,
+            max_operations: 0,
+        };
+        let script = LoadedScript::load(&path, &config).unwrap();
+        std::fs::remove_file(path).unwrap();
+        script
+    }
+
+    fn handshake() -> Handshake {
+        Handshake {
+            protocol_version: 760,
+            server_address: "play.example.com".to_string(),
+            server_port: 25565,
+            next_state: NextState::Login,
+        }
+    }
+
+    #[test]
+    fn hands_the_handshake_to_hooks() {
+        let script = script(
+            "handshake",
+            "fn login(client, handshake, servers) { `${handshake.server_address}:${handshake.server_port}` }",
+        );
+        assert!(script.defines("login"));
+        assert!(!script.defines("status"));
+        let args = vec![
+            Map::new().into(),
+            handshake_map(Some(&handshake())).into(),
+            Map::new().into(),
+        ];
+        let value = script.run("login", args).unwrap();
+        assert_eq!(value.into_string().unwrap(), "play.example.com:25565");
+        assert!(handshake_map(None).is_empty());
+    }
+
+    #[test]
+    fn stops_scripts_running_past_the_timeout() {
+        let script = script(
+            "timeout",
+            "fn login(client, handshake, servers) { loop {} }",
+        );
+        let args = vec![Map::new().into(), Map::new().into(), Map::new().into()];
+        assert!(script.run("login", args).is_none());
+    }
+
+    #[test]
+    fn takes_one_server_or_an_array_of_them() {
+        assert_eq!(server_list("lobby".into()), Some(vec!["lobby".to_string()]));
+        let servers: Array = vec!["lobby".into(), "hub".into()];
+        assert_eq!(
+            server_list(servers.into()),
+            Some(vec!["lobby".to_string(), "hub".to_string()])
+        );
+        assert_eq!(server_list(Dynamic::UNIT), None);
+        let mixed: Array = vec!["lobby".into(), Dynamic::from(1_i64)];
+        assert_eq!(server_list(mixed.into()), None);
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use mcprotocol::chat::Chat;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::Instant;
+
+use crate::ProxyInfo;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How long disconnected players get for their last packets to be written.
+const DISCONNECT_GRACE: Duration = Duration::from_secs(2);
+
+fn received_signal(proxy_info: &ProxyInfo) {
+    if *proxy_info.shutdown_requested().borrow() {
+        log::warn!("Received a second signal, exiting immediately.");
+        std::process::exit(1);
+    }
+    proxy_info.shutdown();
+}
+
+/// The first SIGINT or SIGTERM starts a graceful shutdown, a second one exits immediately.
+#[cfg(unix)]
+pub fn handle_signals(proxy_info: Arc<ProxyInfo>) -> anyhow::Result<()> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = interrupt.recv() => {}
+                _ = terminate.recv() => {}
+            }
+            received_signal(&proxy_info);
+        }
+    });
+    Ok(())
+}
+
+/// Like on Unix, but only Ctrl-C is available.
+#[cfg(not(unix))]
+pub fn handle_signals(proxy_info: Arc<ProxyInfo>) -> anyhow::Result<()> {
+    tokio::spawn(async move {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            received_signal(&proxy_info);
+        }
+    });
+    Ok(())
+}
+
+/// Waits until everyone has left or the timeout passes.
+async fn wait_for_players(proxy_info: &ProxyInfo, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline && proxy_info.players.player_count().await > 0 {
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Gets the players off the proxy once it stopped accepting connections: they are warned,
+/// given the drain period to leave and disconnected.
+pub async fn drain(proxy_info: &ProxyInfo) {
+    let config = proxy_info.config();
+    let shutdown = &config.shutdown;
+    let players = proxy_info.players.players().await;
+    if players.is_empty() {
+        return;
+    }
+
+    log::info!("Draining {} player(s).", players.len());
+    for player in &players {
+        player
+            .handle
+            .send_message(Chat::literal(shutdown.message.clone()));
+    }
+    if shutdown.drain_seconds > 0 {
+        wait_for_players(proxy_info, Duration::from_secs(shutdown.drain_seconds)).await;
+    }
+
+    for player in proxy_info.players.players().await {
+        player.handle.kick(Chat::literal(shutdown.message.clone()));
+    }
+    wait_for_players(proxy_info, DISCONNECT_GRACE).await;
+}
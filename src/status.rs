@@ -0,0 +1,136 @@
+use std::future::Future;
+use std::time::Duration;
+
+use drax::transport::frame::PacketFrame;
+use drax::VarInt;
+use mcprotocol::pin_fut;
+use mcprotocol::pipeline::{AsyncMinecraftProtocolPipeline, MinecraftProtocolWriter};
+use mcprotocol::protocol::handshaking::sb::Handshake;
+use mcprotocol::registry::{RegistryError, UNKNOWN_VERSION};
+use mcprotocol::status::StatusBuilder;
+use serde_json::json;
+
+use crate::limbo::RawPipeline;
+use crate::net::{codec, Stream};
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// Never matches a client, so the version name is shown in place of the player count.
+const PROTOCOL_VERSION: VarInt = -1;
+const STATUS_RESPONSE: VarInt = 0x00;
+const PING: VarInt = 0x01;
+
+async fn handle_handshake(_: &mut (), handshake: Handshake) -> Result<Handshake, RegistryError> {
+    Ok(handshake)
+}
+
+/// The id and payload of the next packet, the pipeline has no handlers left to decode it.
+async fn next_packet(read: &mut RawPipeline) -> Result<(VarInt, Vec<u8>), RegistryError> {
+    let data = match read.execute_next_packet(&mut ()).await {
+        Err(RegistryError::NoHandlerFound(_, data)) => data,
+        Err(err) => return Err(err),
+        Ok(()) => unreachable!("raw pipelines have no handlers"),
+    };
+    let mut payload = data.as_slice();
+    match codec::read_var_int(&mut payload) {
+        Ok(packet_id) => Ok((packet_id, payload.to_vec())),
+        Err(_) => Err(unexpected("unreadable packet id")),
+    }
+}
+
+fn unexpected(message: &str) -> RegistryError {
+    RegistryError::DraxTransportError(drax::transport::Error::Unknown(Some(message.to_string())))
+}
+
+fn render_response(status: &StatusBuilder, version_name: &str) -> String {
+    let mut response = json!({
+        "version": { "name": version_name, "protocol": PROTOCOL_VERSION },
+        "players": {
+            "max": status.players.max,
+            "online": status.players.online,
+            "sample": [],
+        },
+        "description": serde_json::to_value(&status.description).unwrap_or_default(),
+    });
+    if let Some(favicon) = &status.favicon {
+        response["favicon"] = favicon.clone().into();
+    }
+    response.to_string()
+}
+
+async fn answer_inner<F, Fut>(
+    stream: Stream,
+    version_name: &str,
+    respond: F,
+) -> Result<(), RegistryError>
+where
+    F: FnOnce(Handshake) -> Fut,
+    Fut: Future<Output = StatusBuilder>,
+{
+    let (read, write) = stream.into_split();
+    let mut read = AsyncMinecraftProtocolPipeline::from_protocol_version(read, UNKNOWN_VERSION);
+    let mut write = MinecraftProtocolWriter::from_protocol_version(write, UNKNOWN_VERSION);
+    read.register(pin_fut!(handle_handshake));
+    let handshake = read.execute_next_packet(&mut ()).await??;
+    // the status request carries nothing, whatever comes next is answered
+    let mut read: RawPipeline = read.clear_registry();
+    next_packet(&mut read).await?;
+    let status = respond(handshake).await;
+
+    let mut data = vec![];
+    codec::write_var_int(&mut data, STATUS_RESPONSE);
+    codec::write_string(&mut data, &render_response(&status, version_name));
+    write.write_buffered_packet(PacketFrame { data }).await?;
+    // clients that only fetch the status close the connection here
+    if let Ok((PING, payload)) = next_packet(&mut read).await {
+        let mut data = vec![];
+        codec::write_var_int(&mut data, PING);
+        data.extend_from_slice(&payload);
+        write.write_buffered_packet(PacketFrame { data }).await?;
+    }
+    Ok(())
+}
+
+/// Answers a server list ping with the status `respond` builds, but with `version_name` in
+/// place of the player count. The protocol library's own answer always carries its version.
+pub async fn answer<F, Fut>(
+    stream: Stream,
+    version_name: &str,
+    respond: F,
+) -> Result<(), RegistryError>
+where
+    F: FnOnce(Handshake) -> Fut,
+    Fut: Future<Output = StatusBuilder>,
+{
+    match tokio::time::timeout(READ_TIMEOUT, answer_inner(stream, version_name, respond)).await {
+        Ok(result) => result,
+        Err(_) => Err(unexpected("timed out")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mcprotocol::chat::Chat;
+    use mcprotocol::protocol::status::cb::StatusResponsePlayers;
+    use serde_json::Value;
+
+    #[test]
+    fn shows_the_version_name_in_place_of_the_player_count() {
+        let status = StatusBuilder {
+            players: StatusResponsePlayers {
+                max: 100,
+                online: 5,
+                sample: vec![],
+            },
+            description: Chat::literal("Back soon"),
+            favicon: None,
+        };
+        let response: Value =
+            serde_json::from_str(&render_response(&status, "§cMaintenance")).unwrap();
+        assert_eq!(response["version"]["name"], "§cMaintenance");
+        assert_eq!(response["version"]["protocol"], -1);
+        assert_eq!(response["players"]["online"], 5);
+        assert_eq!(response["players"]["max"], 100);
+        assert!(response.get("favicon").is_none());
+    }
+}
@@ -0,0 +1,446 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+
+use crate::cfg::{RateLimit, ThrottleConfig};
+use crate::handshake::Intent;
+use crate::moderation::{Punishment, PunishmentKind, PunishmentTarget};
+use crate::ProxyInfo;
+
+/// How long after a limit was last hit status pings are still answered the cheap way.
+const PRESSURE_DURATION: Duration = Duration::from_secs(10);
+/// Idle addresses are forgotten after this long.
+const IDLE_EXPIRY: Duration = Duration::from_secs(300);
+const VIOLATION_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+pub enum Limit {
+    Blocked,
+    Handshakes,
+    Connections,
+    Concurrent,
+    Logins,
+    Statuses,
+}
+
+impl Limit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Limit::Blocked => "blocked",
+            Limit::Handshakes => "handshakes",
+            Limit::Connections => "connections",
+            Limit::Concurrent => "concurrent",
+            Limit::Logins => "logins",
+            Limit::Statuses => "statuses",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Refused {
+    pub limit: Limit,
+    /// Set on the refusal that got the address blocked.
+    pub newly_blocked: bool,
+}
+
+#[derive(Default)]
+struct Window {
+    started: Option<Instant>,
+    count: u32,
+}
+
+impl Window {
+    fn hit(&mut self, now: Instant, period: Duration) -> u32 {
+        match self.started {
+            Some(started) if now.duration_since(started) < period => {}
+            _ => {
+                self.started = Some(now);
+                self.count = 0;
+            }
+        }
+        self.count += 1;
+        self.count
+    }
+
+    fn try_acquire(&mut self, now: Instant, limit: &RateLimit) -> bool {
+        limit.count == 0 || self.hit(now, Duration::from_secs(limit.seconds)) <= limit.count
+    }
+}
+
+#[derive(Default)]
+struct IpState {
+    connections: Window,
+    logins: Window,
+    statuses: Window,
+    violations: Window,
+    open: u32,
+    blocked_until: Option<Instant>,
+    last_seen: Option<Instant>,
+}
+
+/// Addresses are tracked per listener, each listener has its own limits.
+type IpKey = (Arc<str>, IpAddr);
+
+#[derive(Default)]
+struct ThrottleState {
+    ips: HashMap<IpKey, IpState>,
+    handshakes: HashMap<Arc<str>, Window>,
+    last_limited: Option<Instant>,
+}
+
+impl ThrottleState {
+    fn refuse(&mut self, key: Option<&IpKey>, limit: Limit, config: &ThrottleConfig) -> Refused {
+        let now = Instant::now();
+        self.last_limited = Some(now);
+        let mut newly_blocked = false;
+        if let Some(state) = key.and_then(|key| self.ips.get_mut(key)) {
+            let violations = state.violations.hit(now, VIOLATION_WINDOW);
+            if config.block_after_violations > 0
+                && violations >= config.block_after_violations
+                && state.blocked_until.map_or(true, |until| until <= now)
+            {
+                state.blocked_until = Some(now + Duration::from_secs(config.block_seconds));
+                newly_blocked = true;
+            }
+        }
+        Refused {
+            limit,
+            newly_blocked,
+        }
+    }
+}
+
+/// Held for as long as a connection is open, counting towards the per address cap.
+pub struct ConnectionPermit {
+    state: Arc<StdMutex<ThrottleState>>,
+    key: Option<IpKey>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let key = match &self.key {
+            Some(key) => key,
+            None => return,
+        };
+        if let Some(state) = self.state.lock().unwrap().ips.get_mut(key) {
+            state.open = state.open.saturating_sub(1);
+        }
+    }
+}
+
+/// Limits on new connections per address and overall. Limits are read from the current
+/// config on every check so they follow reloads.
+#[derive(Default)]
+pub struct Throttle {
+    state: Arc<StdMutex<ThrottleState>>,
+}
+
+impl Throttle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checked for every accepted connection on `bind` before anything is read from it.
+    /// Connections without an address, like those on Unix sockets, only count towards the
+    /// listener's handshake rate.
+    pub fn accept(
+        &self,
+        config: &ThrottleConfig,
+        bind: &Arc<str>,
+        ip: Option<IpAddr>,
+    ) -> Result<ConnectionPermit, Refused> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let handshakes = state.handshakes.entry(bind.clone()).or_default();
+        if config.handshakes_per_second > 0
+            && handshakes.hit(now, Duration::from_secs(1)) > config.handshakes_per_second
+        {
+            state.last_limited = Some(now);
+            return Err(Refused {
+                limit: Limit::Handshakes,
+                newly_blocked: false,
+            });
+        }
+
+        let key = match ip {
+            Some(ip) => (bind.clone(), ip),
+            None => {
+                return Ok(ConnectionPermit {
+                    state: self.state.clone(),
+                    key: None,
+                })
+            }
+        };
+        let ip_state = state.ips.entry(key.clone()).or_default();
+        ip_state.last_seen = Some(now);
+        let limit = if ip_state.blocked_until.map_or(false, |until| until > now) {
+            Some(Limit::Blocked)
+        } else if !ip_state.connections.try_acquire(now, &config.connections) {
+            Some(Limit::Connections)
+        } else if config.max_connections_per_ip > 0
+            && ip_state.open >= config.max_connections_per_ip
+        {
+            Some(Limit::Concurrent)
+        } else {
+            None
+        };
+        match limit {
+            // blocked addresses aren't counted again, or the block would never end
+            Some(Limit::Blocked) => {
+                state.last_limited = Some(now);
+                Err(Refused {
+                    limit: Limit::Blocked,
+                    newly_blocked: false,
+                })
+            }
+            Some(limit) => Err(state.refuse(Some(&key), limit, config)),
+            None => {
+                ip_state.open += 1;
+                Ok(ConnectionPermit {
+                    state: self.state.clone(),
+                    key: Some(key),
+                })
+            }
+        }
+    }
+
+    /// Checked once the handshake shows whether the client wants to log in or ping.
+    pub fn check_intent(
+        &self,
+        config: &ThrottleConfig,
+        bind: &Arc<str>,
+        ip: Option<IpAddr>,
+        intent: Intent,
+    ) -> Result<(), Refused> {
+        let key = match ip {
+            Some(ip) => (bind.clone(), ip),
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let ip_state = state.ips.entry(key.clone()).or_default();
+        let (allowed, limit) = match intent {
+            // whatever the handshake didn't reveal may still turn into a login
+            Intent::Login | Intent::Unknown => (
+                ip_state.logins.try_acquire(now, &config.logins),
+                Limit::Logins,
+            ),
+            Intent::Status | Intent::LegacyPing => (
+                ip_state.statuses.try_acquire(now, &config.statuses),
+                Limit::Statuses,
+            ),
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(state.refuse(Some(&key), limit, config))
+        }
+    }
+
+    /// Whether a limit was hit recently, in which case status pings skip script hooks and
+    /// plugins.
+    pub fn under_pressure(&self) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .last_limited
+            .map_or(false, |last| last.elapsed() < PRESSURE_DURATION)
+    }
+
+    fn purge(&self) {
+        let now = Instant::now();
+        self.state.lock().unwrap().ips.retain(|_, state| {
+            state.open > 0
+                || state.blocked_until.map_or(false, |until| until > now)
+                || state.last_seen.map_or(false, |last_seen| {
+                    now.duration_since(last_seen) < IDLE_EXPIRY
+                })
+        });
+    }
+}
+
+/// Forgets idle addresses every minute.
+pub fn spawn_purge(proxy_info: Arc<ProxyInfo>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            proxy_info.throttle.purge();
+        }
+    });
+}
+
+/// Logs blocks and turns them into IP bans if configured. Other refusals are only logged at
+/// debug level, a flood would drown the log otherwise.
+pub async fn refused(
+    proxy_info: &ProxyInfo,
+    config: &ThrottleConfig,
+    ip: Option<IpAddr>,
+    refused: Refused,
+) {
+    proxy_info
+        .metrics
+        .throttled
+        .with_label_values(&[refused.limit.as_str()])
+        .inc();
+    // only connections with an address are ever blocked
+    let ip = match ip {
+        Some(ip) if refused.newly_blocked => ip,
+        _ => {
+            log::debug!(
+                "Refused a connection from {}: {}",
+                ip.map_or_else(|| "a Unix socket".to_string(), |ip| ip.to_string()),
+                refused.limit.as_str()
+            );
+            return;
+        }
+    };
+    log::warn!(
+        "Blocking {} for {}s after repeatedly hitting connection limits.",
+        ip,
+        config.block_seconds
+    );
+    if !config.auto_ban {
+        return;
+    }
+    let punishment = Punishment::new(
+        PunishmentKind::Ban,
+        PunishmentTarget::Ip(ip.into()),
+        "Too many connections.".to_string(),
+        "Throttle".to_string(),
+        Some(Duration::from_secs(config.block_seconds)),
+    );
+    if let Err(err) = proxy_info.moderation.punish(proxy_info, punishment).await {
+        log::error!("Failed to ban {}: {:#}", ip, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ThrottleConfig {
+        ThrottleConfig {
+            connections: RateLimit {
+                count: 100,
+                seconds: 10,
+            },
+            logins: RateLimit {
+                count: 2,
+                seconds: 10,
+            },
+            max_connections_per_ip: 2,
+            handshakes_per_second: 0,
+            block_after_violations: 3,
+            ..ThrottleConfig::default()
+        }
+    }
+
+    fn ip(addr: &str) -> Option<IpAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn window_resets_after_its_period() {
+        let mut window = Window::default();
+        let start = Instant::now();
+        let period = Duration::from_secs(10);
+        assert_eq!(window.hit(start, period), 1);
+        assert_eq!(window.hit(start + Duration::from_secs(9), period), 2);
+        assert_eq!(window.hit(start + period, period), 1);
+    }
+
+    #[test]
+    fn window_limits() {
+        let mut window = Window::default();
+        let now = Instant::now();
+        let limit = RateLimit {
+            count: 2,
+            seconds: 10,
+        };
+        assert!(window.try_acquire(now, &limit));
+        assert!(window.try_acquire(now, &limit));
+        assert!(!window.try_acquire(now, &limit));
+        let unlimited = RateLimit {
+            count: 0,
+            seconds: 10,
+        };
+        assert!(window.try_acquire(now, &unlimited));
+    }
+
+    #[test]
+    fn caps_open_connections_until_released() {
+        let throttle = Throttle::new();
+        let bind: Arc<str> = "0.0.0.0:25565".into();
+        let first = throttle.accept(&config(), &bind, ip("1.2.3.4")).unwrap();
+        let _second = throttle.accept(&config(), &bind, ip("1.2.3.4")).unwrap();
+        let refused = throttle
+            .accept(&config(), &bind, ip("1.2.3.4"))
+            .err()
+            .unwrap();
+        assert!(matches!(refused.limit, Limit::Concurrent));
+        drop(first);
+        assert!(throttle.accept(&config(), &bind, ip("1.2.3.4")).is_ok());
+    }
+
+    #[test]
+    fn tracks_addresses_per_listener() {
+        let throttle = Throttle::new();
+        let (first, second): (Arc<str>, Arc<str>) =
+            ("0.0.0.0:25565".into(), "0.0.0.0:25566".into());
+        let _permits = [
+            throttle.accept(&config(), &first, ip("1.2.3.4")).unwrap(),
+            throttle.accept(&config(), &first, ip("1.2.3.4")).unwrap(),
+        ];
+        assert!(throttle.accept(&config(), &second, ip("1.2.3.4")).is_ok());
+    }
+
+    #[test]
+    fn address_less_peers_skip_per_address_limits() {
+        let throttle = Throttle::new();
+        let bind: Arc<str> = "/run/umbrella.sock".into();
+        let permits: Vec<_> = (0..10)
+            .map(|_| throttle.accept(&config(), &bind, None).unwrap())
+            .collect();
+        assert_eq!(permits.len(), 10);
+        for _ in 0..10 {
+            assert!(throttle
+                .check_intent(&config(), &bind, None, Intent::Login)
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn unknown_intents_count_as_logins() {
+        let throttle = Throttle::new();
+        let bind: Arc<str> = "0.0.0.0:25565".into();
+        let check = |intent| throttle.check_intent(&config(), &bind, ip("1.2.3.4"), intent);
+        assert!(check(Intent::Login).is_ok());
+        assert!(check(Intent::Unknown).is_ok());
+        assert!(matches!(
+            check(Intent::Unknown).err().unwrap().limit,
+            Limit::Logins
+        ));
+        assert!(check(Intent::Status).is_ok());
+    }
+
+    #[test]
+    fn blocks_after_repeated_violations() {
+        let throttle = Throttle::new();
+        let bind: Arc<str> = "0.0.0.0:25565".into();
+        let check = || throttle.check_intent(&config(), &bind, ip("1.2.3.4"), Intent::Login);
+        check().unwrap();
+        check().unwrap();
+        assert!(!check().err().unwrap().newly_blocked);
+        assert!(!check().err().unwrap().newly_blocked);
+        assert!(check().err().unwrap().newly_blocked);
+        let refused = throttle
+            .accept(&config(), &bind, ip("1.2.3.4"))
+            .err()
+            .unwrap();
+        assert!(matches!(refused.limit, Limit::Blocked));
+        assert!(throttle.under_pressure());
+        assert!(throttle.accept(&config(), &bind, ip("5.6.7.8")).is_ok());
+    }
+}